@@ -25,6 +25,10 @@
 #define SCALE       20
 #define TICK_MS     150
 
+// Must match ABI_VERSION in rust/gtk/src/shared.rs; the containers refuse modules built
+// against another version.
+#define ABI_VERSION 1
+
 typedef enum {
   WALKING,
   RUNNING,
@@ -25,19 +25,23 @@
 #include "common.h"
 
 enum ExportFuncs {
+  FN_ABI_VERSION,
   FN_MALLOC,
   FN_CREATE_CONTEXT,
   FN_UPDATE_CONTEXT,
   FN_INIT,
+  FN_OBSERVE,
   FN_TICK,
   FN_MODIFY_GRID,
 };
 
 const char *kExportFuncNames[] = {
+  "shared_buffers_abi_version",
   "malloc_",
   "create_context",
   "update_context",
   "init",
+  "observe",
   "tick",
   "modify_grid",
 };
@@ -90,6 +94,16 @@ static bool error(const char *fmt, ...) {
   return false;
 }
 
+// A module built against another version would misread the buffers rather than fail.
+static bool check_abi_version() {
+  CallResult res = wasm_call(FN_ABI_VERSION);
+  if (res.ok && res.val != ABI_VERSION) {
+    return error("Module has ABI version %d, but the container expects %d; rebuild it",
+                 res.val, ABI_VERSION);
+  }
+  return res.ok;
+}
+
 static bool map_shared_buffers() {
   // Call wasm.malloc to reserve enough space for the shared buffers plus alignment concerns.
   int page_size = sysconf(_SC_PAGESIZE);
@@ -166,7 +180,9 @@ static void command_loop() {
         ok = wasm_call(FN_INIT, ctx.wasm_context, time(NULL)).ok;
         break;
       case CMD_TICK:
-        ok = wasm_call(FN_TICK, ctx.wasm_context).ok;
+        // This host doesn't hold the containers in lockstep, so observing first only narrows the
+        // window in which a module sees the other part way through its tick.
+        ok = wasm_call(FN_OBSERVE, ctx.wasm_context).ok && wasm_call(FN_TICK, ctx.wasm_context).ok;
         break;
       case CMD_MODIFY_GRID:
         ok = wasm_call(FN_MODIFY_GRID, ctx.wasm_context).ok;
@@ -200,7 +216,7 @@ int main(int argc, const char *argv[]) {
   ctx.rw_size = atoi(argv[8]);
 
   info("Container started; module '%s', pid %d", module_name, getpid());
-  if (init_module(module_name) && map_shared_buffers()) {
+  if (init_module(module_name) && check_abi_version() && map_shared_buffers()) {
     send(CMD_READY);
     command_loop();
   }
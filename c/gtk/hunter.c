@@ -35,9 +35,13 @@ void init(Context *ctx, int rand_seed) {
   ctx->hunter->y = GRID_H / 2;
 }
 
+// The offset to the closest runner, as observed at the start of the tick.
+static int closest_dx = 0;
+static int closest_dy = 0;
+
+// Finds the closest runner before any container moves its actors.
 EMSCRIPTEN_KEEPALIVE
-void tick(Context *ctx) {
-  // Find the closest runner and move towards it.
+void observe(Context *ctx) {
   int min_dx = 0;
   int min_dy = 0;
   int min_dist = 99999;
@@ -54,7 +58,14 @@ void tick(Context *ctx) {
       min_dist = dist;
     }
   }
-  move(ctx, &ctx->hunter->x, &ctx->hunter->y, step(min_dx), step(min_dy));
+  closest_dx = min_dx;
+  closest_dy = min_dy;
+}
+
+// Moves towards the runner found by observe().
+EMSCRIPTEN_KEEPALIVE
+void tick(Context *ctx) {
+  move(ctx, &ctx->hunter->x, &ctx->hunter->y, step(closest_dx), step(closest_dy));
 }
 
 EMSCRIPTEN_KEEPALIVE
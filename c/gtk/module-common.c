@@ -29,13 +29,19 @@ void print(const char *fmt, ...) {
   print_callback(len, msg);
 }
 
+EMSCRIPTEN_KEEPALIVE
+int shared_buffers_abi_version() {
+  return ABI_VERSION;
+}
+
 EMSCRIPTEN_KEEPALIVE
 void *malloc_(size_t size) {
   return malloc(size);
 }
 
+// The sizes are ignored: the C modules only track the initial set of runners.
 EMSCRIPTEN_KEEPALIVE
-void update_context(Context *ctx, void *ro_ptr, void *rw_ptr) {
+void update_context(Context *ctx, void *ro_ptr, void *rw_ptr, int ro_size, int rw_size) {
   ctx->grid = ro_ptr;
   ctx->hunter = rw_ptr;
   ctx->runners = rw_ptr + sizeof(Hunter);
@@ -44,10 +50,15 @@ void update_context(Context *ctx, void *ro_ptr, void *rw_ptr) {
 EMSCRIPTEN_KEEPALIVE
 Context *create_context(void *ro_ptr, void *rw_ptr) {
   Context *ctx = malloc(sizeof(Context));
-  update_context(ctx, ro_ptr, rw_ptr);
+  update_context(ctx, ro_ptr, rw_ptr, 0, 0);
   return ctx;
 }
 
+// The Rust host passes any extra buffers (e.g. tick stats) here; the C modules don't use them.
+EMSCRIPTEN_KEEPALIVE
+void set_buffer(Context *ctx, int id, void *ptr, int size) {
+}
+
 EMSCRIPTEN_KEEPALIVE
 void large_alloc() {
   // Not implemented.
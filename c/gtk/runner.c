@@ -39,13 +39,23 @@ void init(Context *ctx, int rand_seed) {
   }
 }
 
+// Where the hunter was at the start of the tick.
+static int hunter_x = 0;
+static int hunter_y = 0;
+
+EMSCRIPTEN_KEEPALIVE
+void observe(Context *ctx) {
+  hunter_x = ctx->hunter->x;
+  hunter_y = ctx->hunter->y;
+}
+
 EMSCRIPTEN_KEEPALIVE
 void tick(Context *ctx) {
   Runner *r = ctx->runners;
   for (int i = 0; i < N_RUNNERS; r++, i++) {
     // If the hunter has reached us, we're dead.
-    int dx = r->x - ctx->hunter->x;
-    int dy = r->y - ctx->hunter->y;
+    int dx = r->x - hunter_x;
+    int dy = r->y - hunter_y;
     if (r->state == DEAD || (dx == 0 && dy == 0)) {
       r->state = DEAD;
       continue;
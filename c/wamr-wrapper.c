@@ -16,6 +16,11 @@
 
 // Inlined via #include in gtk/container.c and heap-guard/container.c
 
+#include <errno.h>
+#include <string.h>
+#include <time.h>
+#include <sys/random.h>
+
 #define N_FUNCS  (sizeof(kExportFuncNames) / sizeof(*kExportFuncNames))
 
 // Ownership indicator as used by the wasm-c-api code.
@@ -89,20 +94,162 @@ static CallResult wasm_call(int index, ...) {
   return result;
 }
 
+// Must match FILL_RANDOM_FAULT and FILL_RANDOM_UNAVAILABLE in rust/gtk/src/host_common.rs.
+#define FILL_RANDOM_FAULT       1
+#define FILL_RANDOM_UNAVAILABLE 2
+
+// Returns the address of the 'len' bytes at 'offset' in linear memory, or NULL if any of them
+// are out of bounds.
+static char *wasm_bytes(int offset, int len) {
+  size_t size = wasm_memory_data_size(wc.memory);
+  if (offset < 0 || len < 0 || (size_t)offset + len > size) {
+    return NULL;
+  }
+  return wasm_memory_data(wc.memory) + offset;
+}
+
+static wasm_trap_t *new_trap(const char *text) {
+  // Trap messages include the terminating null.
+  own wasm_message_t msg;
+  wasm_name_new(&msg, strlen(text) + 1, text);
+  own wasm_trap_t *trap = wasm_trap_new(wc.store, &msg);
+  wasm_byte_vec_delete(&msg);
+  return trap;
+}
+
+static int64_t clock_us(clockid_t clock) {
+  struct timespec ts;
+  int rc = clock_gettime(clock, &ts);
+  assert(rc == 0);
+  (void)rc;
+  return (int64_t)ts.tv_sec * 1000000 + ts.tv_nsec / 1000;
+}
+
 static wasm_trap_t *print_callback(const wasm_val_vec_t *args, wasm_val_vec_t *results) {
   // args: int len, const char *msg
-  assert(args->size == 2);
-  assert(args->data[0].kind == WASM_I32);
-  assert(args->data[1].kind == WASM_I32);
+  int len = args->data[0].of.i32;
+  const char *msg = wasm_bytes(args->data[1].of.i32, len);
+  if (msg == NULL) {
+    return new_trap("print_callback: message is outside linear memory");
+  }
+  // The C modules end their messages with a newline and the Rust modules don't.
+  printf("%.*s%s", len, msg, len > 0 && msg[len - 1] == '\n' ? "" : "\n");
+  return NULL;
+}
 
-  // With the C implementation, 'msg' string should be null-terminated.
-  char *wasm_memory_base = wasm_memory_data(wc.memory);
-  assert(*(wasm_memory_base + args->data[0].of.i32) == 0);
+// The remaining imports are only used by the Rust modules (see module_common.rs).
 
-  printf("%s", wasm_memory_data(wc.memory) + args->data[1].of.i32);
+static wasm_trap_t *assert_callback(const wasm_val_vec_t *args, wasm_val_vec_t *results) {
+  // args: int ok, int len, const char *msg; the module traps itself after a failure.
+  if (args->data[0].of.i32 == 0) {
+    int len = args->data[1].of.i32;
+    const char *msg = wasm_bytes(args->data[2].of.i32, len);
+    fprintf(stderr, "module assertion failed: %.*s\n", msg ? len : 0, msg ? msg : "");
+  }
+  return NULL;
+}
+
+static wasm_trap_t *time_callback(const wasm_val_vec_t *args, wasm_val_vec_t *results) {
+  results->data[0].kind = WASM_I64;
+  results->data[0].of.i64 = clock_us(CLOCK_REALTIME);
+  return NULL;
+}
+
+static wasm_trap_t *now_micros(const wasm_val_vec_t *args, wasm_val_vec_t *results) {
+  results->data[0].kind = WASM_I64;
+  results->data[0].of.i64 = clock_us(CLOCK_MONOTONIC);
+  return NULL;
+}
+
+// This container has no event ring, so events are printed like print_callback's messages.
+static wasm_trap_t *event_callback(const wasm_val_vec_t *args, wasm_val_vec_t *results) {
+  // args: int kind, long value, int len, const char *msg
+  int len = args->data[2].of.i32;
+  const char *msg = wasm_bytes(args->data[3].of.i32, len);
+  if (msg == NULL) {
+    return new_trap("event_callback: message is outside linear memory");
+  }
+  printf("event %d (%lld): %.*s\n", args->data[0].of.i32, (long long)args->data[1].of.i64, len,
+         msg);
   return NULL;
 }
 
+static wasm_trap_t *fill_random(const wasm_val_vec_t *args, wasm_val_vec_t *results) {
+  // args: void *ptr, int len
+  int len = args->data[1].of.i32;
+  char *buf = wasm_bytes(args->data[0].of.i32, len);
+  int status = buf == NULL ? FILL_RANDOM_FAULT : 0;
+  for (int filled = 0; status == 0 && filled < len;) {
+    ssize_t n = getrandom(buf + filled, len - filled, 0);
+    if (n > 0) {
+      filled += n;
+    } else if (n == -1 && errno != EINTR) {
+      status = FILL_RANDOM_UNAVAILABLE;
+    }
+  }
+  results->data[0].kind = WASM_I32;
+  results->data[0].of.i32 = status;
+  return NULL;
+}
+
+typedef struct {
+  const char *name;
+  wasm_func_callback_t callback;
+  int n_params;
+  int n_results;
+} HostFunc;
+
+// The "env" imports this container provides.
+static const HostFunc kHostFuncs[] = {
+  { "print_callback", print_callback, 2, 0 },
+  { "assert_callback", assert_callback, 3, 0 },
+  { "time_callback", time_callback, 0, 1 },
+  { "now_micros", now_micros, 0, 1 },
+  { "event_callback", event_callback, 4, 0 },
+  { "fill_random", fill_random, 2, 1 },
+};
+
+// Any other function import is bound to this, which traps with the message in 'env' if the
+// module ever calls it.
+static wasm_trap_t *missing_import(void *env, const wasm_val_vec_t *args, wasm_val_vec_t *results) {
+  return new_trap(env);
+}
+
+static bool name_is(const wasm_name_t *name, const char *str) {
+  return name->size == strlen(str) && strncmp(name->data, str, name->size) == 0;
+}
+
+// Creates the function for 'import', using the module's own type for it. Returns NULL if the
+// import isn't a function, or is one of kHostFuncs with the wrong type.
+static wasm_func_t *new_import_func(const wasm_importtype_t *import) {
+  const wasm_name_t *module = wasm_importtype_module(import);
+  const wasm_name_t *name = wasm_importtype_name(import);
+  const wasm_functype_t *type = wasm_externtype_as_functype_const(wasm_importtype_type(import));
+  if (type == NULL) {
+    fprintf(stderr, "Import '%.*s.%.*s' is not a function", (int)module->size, module->data,
+            (int)name->size, name->data);
+    return NULL;
+  }
+  if (name_is(module, "env")) {
+    for (int i = 0; i < sizeof(kHostFuncs) / sizeof(*kHostFuncs); i++) {
+      const HostFunc *func = &kHostFuncs[i];
+      if (!name_is(name, func->name)) {
+        continue;
+      }
+      if (wasm_functype_params(type)->size != func->n_params ||
+          wasm_functype_results(type)->size != func->n_results) {
+        fprintf(stderr, "Import '%s' has the wrong type", func->name);
+        return NULL;
+      }
+      return wasm_func_new(wc.store, type, func->callback);
+    }
+  }
+  char *msg = malloc(100 + module->size + name->size);
+  sprintf(msg, "import '%.*s.%.*s' is not provided by the C container", (int)module->size,
+          module->data, (int)name->size, name->data);
+  return wasm_func_new_with_env(wc.store, type, missing_import, msg, free);
+}
+
 static bool init_module(const char *module_name) {
   FILE *file = fopen(module_name, "r");
   if (file == NULL) {
@@ -126,21 +273,30 @@ static bool init_module(const char *module_name) {
   }
   free(wasm_bytes.data);
 
-  // Set up the 'print_callback' import function.
+  // Provide the module's imports by name, in the order it declares them.
   own wasm_importtype_vec_t expected_imports;
   wasm_module_imports(wc.module, &expected_imports);
-  assert(expected_imports.size == 1);
+  own wasm_extern_vec_t import_object;
+  wasm_extern_vec_new_uninitialized(&import_object, expected_imports.size);
+  bool imports_ok = true;
+  for (int i = 0; i < expected_imports.size; i++) {
+    wasm_func_t *func = new_import_func(expected_imports.data[i]);
+    if (func == NULL) {
+      imports_ok = false;
+      break;
+    }
+    import_object.data[i] = wasm_func_as_extern(func);
+    import_object.num_elems = i + 1;
+  }
   wasm_importtype_vec_delete(&expected_imports);
-
-  own wasm_functype_t *print_func_type = wasm_functype_new_1_0(wasm_valtype_new_i32());
-  own wasm_func_t *print_func = wasm_func_new(wc.store, print_func_type, print_callback);
-  wasm_functype_delete(print_func_type);
-  wasm_extern_t *imports[] = { wasm_func_as_extern(print_func) };
-  wasm_extern_vec_t import_object = WASM_ARRAY_VEC(imports);
+  if (!imports_ok) {
+    wasm_extern_vec_delete(&import_object);
+    return false;
+  }
 
   // Instantiate module.
   wc.instance = wasm_instance_new(wc.store, wc.module, &import_object, NULL);
-  wasm_func_delete(print_func);
+  wasm_extern_vec_delete(&import_object);
   if (wc.instance == NULL) {
     fprintf(stderr, "Error instantiating module");
     return false;
@@ -42,6 +42,16 @@ ln -sf ../target/release/{container-wasmer,container-wasmi} .
 ln -sf ../src-c/{container-wamr,module-c.wasm} .
 echo
 
+# Module size and startup times are appended to startup.jsonl, tagged with the current commit,
+# so they can be compared across changes.
+COMMIT=$(git describe --always --dirty)
+for ENGINE in wamr wasmer wasmi; do
+  for MODULE in c rust; do
+    ./container-$ENGINE module-$MODULE.wasm --startup | sed "s/^{/{\"commit\": \"$COMMIT\", /"
+  done
+done | tee -a startup.jsonl
+echo
+
 for ENGINE in wamr wasmer wasmi; do
   for MODULE in c rust; do
     ./container-$ENGINE module-$MODULE.wasm &
@@ -15,12 +15,14 @@
 //
 #include <stdio.h>
 #include <stdlib.h>
+#include <time.h>
 #include <unistd.h>
 #include "wasm_c_api.h"
 
 const int INSTANCE_LIMIT = 100;
 const int START_DELAY_SECS = 2;
 const int LOOP_DELAY_SECS = 1;
+const int STARTUP_REPS = 10;
 
 wasm_func_t *instantiate(wasm_store_t *store, wasm_module_t *module) {
   wasm_instance_t *instance = wasm_instance_new(store, module, NULL, NULL);
@@ -40,8 +42,55 @@ wasm_func_t *instantiate(wasm_store_t *store, wasm_module_t *module) {
   assert(false);
 }
 
+long elapsed_us(struct timespec *start) {
+  struct timespec end;
+  clock_gettime(CLOCK_MONOTONIC, &end);
+  return (end.tv_sec - start->tv_sec) * 1000000 + (end.tv_nsec - start->tv_nsec) / 1000;
+}
+
+// Matches the JSON output of the Rust containers' --startup mode; each stage keeps the
+// fastest of STARTUP_REPS runs.
+void report_startup(const char *module_name, wasm_byte_vec_t *wasm_bytes) {
+  wasm_engine_t *engine = wasm_engine_new();
+  wasm_store_t *store = wasm_store_new(engine);
+  long validate_us = -1, load_us = -1, instantiate_us = -1;
+  struct timespec start;
+  wasm_module_t *module = NULL;
+  for (int i = 0; i < STARTUP_REPS; i++) {
+    clock_gettime(CLOCK_MONOTONIC, &start);
+    assert(wasm_module_validate(store, wasm_bytes));
+    long us = elapsed_us(&start);
+    if (validate_us < 0 || us < validate_us) validate_us = us;
+  }
+  for (int i = 0; i < STARTUP_REPS; i++) {
+    if (module != NULL) wasm_module_delete(module);
+    clock_gettime(CLOCK_MONOTONIC, &start);
+    module = wasm_module_new(store, wasm_bytes);
+    long us = elapsed_us(&start);
+    assert(module != NULL);
+    if (load_us < 0 || us < load_us) load_us = us;
+  }
+  for (int i = 0; i < STARTUP_REPS; i++) {
+    clock_gettime(CLOCK_MONOTONIC, &start);
+    wasm_instance_t *instance = wasm_instance_new(store, module, NULL, NULL);
+    long us = elapsed_us(&start);
+    assert(instance != NULL);
+    wasm_instance_delete(instance);
+    if (instantiate_us < 0 || us < instantiate_us) instantiate_us = us;
+  }
+  printf("{\"engine\": \"wamr\", \"module\": \"%s\", \"size_bytes\": %zu, \"validate_us\": %ld, "
+         "\"load_us\": %ld, \"instantiate_us\": %ld}\n",
+         module_name, wasm_bytes->size, validate_us, load_us, instantiate_us);
+  wasm_module_delete(module);
+  wasm_store_delete(store);
+  wasm_engine_delete(engine);
+}
+
 int main(int argc, const char *argv[]) {
-  printf("container-wamr %s\n", argv[1]);
+  bool startup = argc > 2 && strcmp(argv[2], "--startup") == 0;
+  if (!startup) {
+    printf("container-wamr %s\n", argv[1]);
+  }
 
   FILE *file = fopen(argv[1], "r");
   assert(file != NULL);
@@ -53,6 +102,12 @@ int main(int argc, const char *argv[]) {
   fread(wasm_bytes.data, 1, wasm_bytes.size, file);
   fclose(file);
 
+  if (startup) {
+    report_startup(argv[1], &wasm_bytes);
+    free(wasm_bytes.data);
+    return 0;
+  }
+
   wasm_engine_t *engine = wasm_engine_new();
   wasm_store_t *store = wasm_store_new(engine);
   wasm_module_t *module = wasm_module_new(store, &wasm_bytes);
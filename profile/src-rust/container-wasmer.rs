@@ -13,8 +13,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
+mod startup;
+
+use startup::{time_min, StartupReport};
 use std::{env, fs, io::prelude::*, thread, time::Duration};
-use wasmer_runtime::{Func, ImportObject, Instance, instantiate};
+use wasmer_runtime::{compile, validate, Func, ImportObject, Instance, instantiate};
 
 const INSTANCE_LIMIT: usize = 100;
 const START_DELAY_SECS: u64 = 2;
@@ -22,6 +25,10 @@ const LOOP_DELAY_SECS: u64 = 1;
 
 fn main() {
     let module_name = env::args().nth(1).expect("missing module name arg");
+    if env::args().nth(2).as_deref() == Some("--startup") {
+        report_startup(&module_name);
+        return;
+    }
     println!("container-wasmer {}", module_name);
 
     let mut bytes = Vec::new();
@@ -47,3 +54,22 @@ fn main() {
         thread::sleep(Duration::from_secs(LOOP_DELAY_SECS));
     }
 }
+
+fn report_startup(module_name: &str) {
+    let mut bytes = Vec::new();
+    fs::File::open(module_name).unwrap().read_to_end(&mut bytes).unwrap();
+    let (validate, valid) = time_min(|| validate(&bytes));
+    assert!(valid, "module failed validation");
+    let (load, module) = time_min(|| compile(&bytes).unwrap());
+    let imports = ImportObject::new();
+    let (instantiate, _) = time_min(|| module.instantiate(&imports).unwrap());
+    StartupReport {
+        engine: "wasmer",
+        module: module_name,
+        size: bytes.len(),
+        validate: Some(validate),
+        load,
+        instantiate,
+    }
+    .print();
+}
@@ -13,6 +13,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
+mod startup;
+
+use startup::{time_min, StartupReport};
 use std::{env, fs, io::prelude::*, thread, time::Duration};
 use wasmi::{ImportsBuilder, Module, ModuleInstance, ModuleRef, NopExternals, RuntimeValue};
 
@@ -22,6 +25,10 @@ const LOOP_DELAY_SECS: u64 = 1;
 
 fn main() {
     let module_name = env::args().nth(1).expect("missing module name arg");
+    if env::args().nth(2).as_deref() == Some("--startup") {
+        report_startup(&module_name);
+        return;
+    }
     println!("container-wasmi {}", module_name);
 
     let module = {
@@ -51,3 +58,21 @@ fn main() {
         thread::sleep(Duration::from_secs(LOOP_DELAY_SECS));
     }
 }
+
+// wasmi validates as part of Module::from_buffer, so validation is included in the load time.
+fn report_startup(module_name: &str) {
+    let mut bytes = Vec::new();
+    fs::File::open(module_name).unwrap().read_to_end(&mut bytes).unwrap();
+    let (load, module) = time_min(|| Module::from_buffer(&bytes).unwrap());
+    let imports = ImportsBuilder::default();
+    let (instantiate, _) = time_min(|| ModuleInstance::new(&module, &imports).unwrap().assert_no_start());
+    StartupReport {
+        engine: "wasmi",
+        module: module_name,
+        size: bytes.len(),
+        validate: None,
+        load,
+        instantiate,
+    }
+    .print();
+}
@@ -0,0 +1,56 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Included via `mod` in the Rust containers.
+
+use std::time::{Duration, Instant};
+
+// Each stage is repeated and the fastest time kept, to reduce noise from one-off costs.
+pub const STARTUP_REPS: usize = 10;
+
+pub struct StartupReport<'a> {
+    pub engine: &'a str,
+    pub module: &'a str,
+    pub size: usize,
+    // None if the engine doesn't validate separately from loading.
+    pub validate: Option<Duration>,
+    pub load: Duration,
+    pub instantiate: Duration,
+}
+
+impl StartupReport<'_> {
+    // Prints the report as a single line of JSON; profile.sh collects these across commits.
+    pub fn print(&self) {
+        let validate = self.validate.map_or("null".to_string(), |d| d.as_micros().to_string());
+        println!(
+            "{{\"engine\": \"{}\", \"module\": \"{}\", \"size_bytes\": {}, \"validate_us\": {}, \"load_us\": {}, \"instantiate_us\": {}}}",
+            self.engine, self.module, self.size, validate, self.load.as_micros(), self.instantiate.as_micros()
+        );
+    }
+}
+
+// Runs 'f' STARTUP_REPS times, returning the fastest duration and the last result.
+pub fn time_min<T, F: FnMut() -> T>(mut f: F) -> (Duration, T) {
+    let mut best = Duration::MAX;
+    let mut result = None;
+    for _ in 0..STARTUP_REPS {
+        let start = Instant::now();
+        let value = f();
+        best = best.min(start.elapsed());
+        result = Some(value);
+    }
+    (best, result.unwrap())
+}
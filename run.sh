@@ -42,6 +42,12 @@ setup_deps() {
   cd ..
 }
 
+# The demos' shm objects, for checking that none are left behind. macOS has no /dev/shm, so
+# nothing is checked there.
+list_shm() {
+  ls /dev/shm 2>/dev/null | grep -E '^(shared_|lookup|shm_signal|sem\.shm_signal|minimal_)' || true
+}
+
 get_rust_tooling() {
   if ! rustup -V &>/dev/null; then
     echo "Installing Rustup"
@@ -84,8 +90,12 @@ build_gtk_wasm_c() {
   cd ../..
 }
 
+# The Rust GTK modules export their linear memory by default; with -m they import it, and the
+# containers create it.
 build_gtk_wasm_rust() {
-  cargo build $MODE_FLAG --target "$RUST_WASM_TARGET" --manifest-path "$RUST_CONFIG" --features modules
+  local ENV=()
+  [ -z "$IMPORT_MEMORY" ] || ENV=(CARGO_TARGET_WASM32_UNKNOWN_UNKNOWN_RUSTFLAGS="-C link-arg=--import-memory")
+  env "${ENV[@]}" cargo build $MODE_FLAG --target "$RUST_WASM_TARGET" --manifest-path "$RUST_CONFIG" --features modules
 }
 
 build_wasm_container() {
@@ -112,6 +122,11 @@ if [[ $1 = -r ]]; then
   MODE_FLAG="--release"
   shift
 fi
+IMPORT_MEMORY=""
+if [[ $1 = -m ]]; then
+  IMPORT_MEMORY=1
+  shift
+fi
 
 BASE=$(dirname $(readlink -f $0))
 WAMR=$BASE/deps/wasm-micro-runtime
@@ -131,10 +146,11 @@ case "$1" in
     ;;
 
   gr) # Rust GTK demo
+    shift
     setup_deps
     build_gtk_wasm_rust
     cargo build $MODE_FLAG --manifest-path "$RUST_CONFIG" --features host
-    ./rust/gtk/target/${MODE}/host "${RUST_MODULES_OUT}/hunter.wasm" "${RUST_MODULES_OUT}/runner.wasm"
+    ./rust/gtk/target/${MODE}/host "${RUST_MODULES_OUT}/hunter.wasm" "${RUST_MODULES_OUT}/runner.wasm" "$@"
     ;;
 
   grc) # Rust GTK host/container with C wasm modules
@@ -152,6 +168,25 @@ case "$1" in
     ./host "../../${RUST_MODULES_OUT}/hunter.wasm" "../../${RUST_MODULES_OUT}/runner.wasm"
     ;;
 
+  d) # Differential engine test of the Rust GTK modules
+    shift
+    build_gtk_wasm_rust
+    cargo run $MODE_FLAG --manifest-path "$RUST_CONFIG" --features host --bin differential -- \
+      "${RUST_MODULES_OUT}/hunter.wasm" "${RUST_MODULES_OUT}/runner.wasm" "$@"
+    ;;
+
+  b) # IPC latency benchmark of the Rust GTK comms, e.g. './run.sh b 1000 futex queue'
+    shift
+    build_gtk_wasm_rust
+    cargo build $MODE_FLAG --manifest-path "$RUST_CONFIG" --features host
+    ./rust/gtk/target/${MODE}/ipc-bench ./rust/gtk/target/${MODE}/container-wasmi "${RUST_MODULES_OUT}/runner.wasm" "$@"
+    ;;
+
+  s) # Inspect, export or import the Rust GTK shared buffers, e.g. './run.sh s export --out world.tar'
+    shift
+    cargo run $MODE_FLAG --manifest-path "$RUST_CONFIG" --features host --bin shmtool -- "$@"
+    ;;
+
   h) # Heap guard demo
     cd c/heap-guard
     build_wasm_c module "-s TOTAL_MEMORY=64KB -s TOTAL_STACK=16KB"
@@ -169,6 +204,60 @@ case "$1" in
     cargo run --release --bin lookup --features lookup -- target/wasm32-unknown-unknown/release/reader.wasm "$@"
     ;;
 
+  ln) # Lookup store performance tests with a no_std reader
+    shift
+    cd rust/lookup
+    cargo build --release --bin reader --target wasm32-unknown-unknown --features no_std \
+      --target-dir target/no_std
+    cargo run --release --bin lookup --features lookup -- \
+      target/no_std/wasm32-unknown-unknown/release/reader.wasm "$@"
+    ;;
+
+  m) # Minimal example of a third-party integration
+    cd rust/examples/minimal
+    cargo build --release --bin module --target wasm32-unknown-unknown
+    cargo run --release --features host --bin host -- target/wasm32-unknown-unknown/release/module.wasm
+    ;;
+
+  q) # Quick deterministic checks of the Rust mapping paths (e.g. for CI)
+    SHM_BEFORE=$(list_shm)
+    build_gtk_wasm_rust
+    cargo run $MODE_FLAG --manifest-path "$RUST_CONFIG" --features host --bin differential -- \
+      "${RUST_MODULES_OUT}/hunter.wasm" "${RUST_MODULES_OUT}/runner.wasm" --quick
+    cd rust/lookup
+    cargo build --release --bin reader --target wasm32-unknown-unknown
+    cargo run --release --bin lookup --features lookup -- target/wasm32-unknown-unknown/release/reader.wasm --quick \
+      --mixed len:20
+    cargo run --release --bin lookup --features lookup -- target/wasm32-unknown-unknown/release/reader.wasm --quick \
+      -w 4096
+    cargo run --release --bin lookup --features lookup -- target/wasm32-unknown-unknown/release/reader.wasm --quick \
+      --hot 50
+    cargo run --release --bin lookup --features lookup -- target/wasm32-unknown-unknown/release/reader.wasm --quick \
+      -g 4
+    cargo run --release --bin lookup --features lookup -- target/wasm32-unknown-unknown/release/reader.wasm --quick \
+      --split
+    cargo run --release --bin lookup --features lookup -- target/wasm32-unknown-unknown/release/reader.wasm --quick \
+      --format all
+    cargo run --release --bin lookup --features lookup -- target/wasm32-unknown-unknown/release/reader.wasm --quick \
+      --delta 100
+    cd ../shm-signal
+    cargo build --release --examples
+    target/release/examples/ping
+    target/release/examples/ping 10000 eventfd
+    target/release/examples/ping 10000 semaphore
+    target/release/examples/ping 10000 signal
+    target/release/examples/ping 10000 poll
+    target/release/examples/fanout
+    cd ../examples/minimal
+    cargo build --release --bin module --target wasm32-unknown-unknown
+    cargo run --release --features host --bin host -- target/wasm32-unknown-unknown/release/module.wasm
+    if [ "$(list_shm)" != "$SHM_BEFORE" ]; then
+      echo "Leaked shm objects:"
+      diff <(echo "$SHM_BEFORE") <(list_shm) | grep '^>'
+      exit 1
+    fi
+    ;;
+
   t) # Terminal tests
     setup_deps
     cd terminal
@@ -187,17 +276,25 @@ case "$1" in
     rm -vf {c/{gtk,heap-guard},terminal}/{*.wasm,container,host} /dev/shm/{shared_ro,shared_rw}
     ( cd rust/gtk && cargo clean -v )
     ( cd rust/lookup && cargo clean -v )
+    ( cd rust/shm-signal && cargo clean -v )
+    ( cd rust/examples/minimal && cargo clean -v )
     ;;
 
-  *)  echo "Usage: ./run.sh [-r] (gc | gr | grc | gcr | h | t | i | clean)"
+  *)  echo "Usage: ./run.sh [-r] [-m] (gc | gr | grc | gcr | d | b | s | h | l | ln | m | q | t | i | clean)"
       echo "  gc: GTK demo in C"
       echo "  gr: GTK demo in Rust"
       echo "  grc: GTK demo with Rust host and C wasm modules"
       echo "  gcr: GTK demo with C host and Rust wasm modules"
+      echo "  d: Differential test of the Rust GTK modules under wasmi and wasmtime"
+      echo "  b: IPC latency benchmark of the Rust GTK comms"
       echo "  h: Heap guard demo"
       echo "  l: Lookup store performance tests"
+      echo "  ln: Lookup store performance tests with a no_std reader"
+      echo "  m: Minimal example of integrating the shared buffers into another project"
+      echo "  q: Quick deterministic checks of the Rust differential test and lookup benchmark"
       echo "  t: terminal-only tests"
       echo "  i: install dependencies"
       echo "  clean: cleans up build artifacts"
       echo "  -r: use release mode for rust"
+      echo "  -m: build the Rust GTK modules to import their linear memory"
 esac
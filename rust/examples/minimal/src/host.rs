@@ -0,0 +1,123 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+// The host side of the minimal example. Run with a module path, it creates the shared buffer
+// and starts itself again as the container (with --container), then drives it through the
+// buffer's signal slot.
+
+use minimal::{Shared, BUF_SIZE, EXIT, MAX_VALUES, TOTAL};
+use shm_signal::SignalSlot;
+use std::{env, ffi::CString, fs, process::Command, ptr, time::Duration};
+use wasmi::{ImportsBuilder, ModuleInstance, NopExternals, RuntimeValue};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match (args.get(1).map(String::as_str), args.get(2), args.get(3)) {
+        (Some("--container"), Some(module), Some(name)) => container(module, name),
+        (Some(module), None, None) => host(module),
+        _ => panic!("usage: host <module.wasm>"),
+    }
+}
+
+fn host(module: &str) {
+    let name = format!("/minimal_{}", std::process::id());
+    let buf = map_buffer(&name, true, ptr::null_mut());
+    let shared = buf as *mut Shared;
+    let signal = unsafe { SignalSlot::from_ptr(buf) };
+    let mut child = Command::new(env::current_exe().unwrap())
+        .args(["--container", module, &name])
+        .spawn()
+        .expect("failed to start the container");
+
+    for round in 1..=3 {
+        let values: Vec<u32> = (0..MAX_VALUES as u32 / round).map(|i| i * round).collect();
+        let input = unsafe { &mut *shared };
+        input.len = values.len() as u32;
+        input.values[..values.len()].copy_from_slice(&values);
+        // The module's writes are visible once the container has completed the signal.
+        signal.send(TOTAL);
+        assert!(signal.wait_idle(TIMEOUT), "the container didn't answer");
+        let total = unsafe { ptr::read_volatile(&(*shared).total) };
+        println!("round {}: the module totalled {} values to {}", round, values.len(), total);
+        assert_eq!(total, values.iter().map(|&value| value as u64).sum::<u64>());
+    }
+    signal.send(EXIT);
+    assert!(child.wait().unwrap().success(), "the container failed");
+    unsafe { libc::shm_unlink(CString::new(name).unwrap().as_ptr()) };
+}
+
+fn container(module: &str, name: &str) {
+    let bytes = fs::read(module).unwrap_or_else(|e| panic!("failed to read {}: {}", module, e));
+    let module = wasmi::Module::from_buffer(&bytes).expect("failed to load the module");
+    let instance = ModuleInstance::new(&module, &ImportsBuilder::default())
+        .expect("failed to instantiate the module")
+        .assert_no_start();
+    let memory = instance
+        .export_by_name("memory")
+        .and_then(|export| export.as_memory().cloned())
+        .expect("the module doesn't export its memory");
+    let call = |export: &str, args: &[RuntimeValue]| {
+        instance
+            .invoke_export(export, args, &mut NopExternals)
+            .unwrap_or_else(|e| panic!("calling {} failed: {:?}", export, e))
+    };
+
+    // Reserve a page more than the buffer in linear memory, then map the buffer over the
+    // page-aligned part of it. The module's memory must not grow after this, as that could move
+    // it away from the mapping.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let offset = match call("malloc_", &[RuntimeValue::I32((BUF_SIZE + page_size) as i32)]) {
+        Some(RuntimeValue::I32(offset)) => offset as u32 as usize,
+        _ => panic!("malloc_ didn't return an offset"),
+    };
+    let base = memory.with_direct_access(|mem| mem.as_ptr() as usize);
+    let aligned = (base + offset + page_size - 1) & !(page_size - 1);
+    let buf = map_buffer(name, false, aligned as *mut libc::c_void);
+    call("set_buffer", &[RuntimeValue::I32((aligned - base) as i32)]);
+
+    let signal = unsafe { SignalSlot::from_ptr(buf) };
+    loop {
+        match signal.wait(TIMEOUT).expect("no signal from the host") {
+            TOTAL => call("total", &[]),
+            EXIT => break,
+            other => panic!("unknown signal {}", other),
+        };
+        signal.complete();
+    }
+    signal.complete();
+}
+
+// Maps the shm object 'name', creating it first if 'create' is set. A non-null 'addr' maps it
+// there, replacing whatever was mapped before.
+fn map_buffer(name: &str, create: bool, addr: *mut libc::c_void) -> *mut u8 {
+    let c_name = CString::new(name).unwrap();
+    let open_flags = if create { libc::O_RDWR | libc::O_CREAT | libc::O_EXCL } else { libc::O_RDWR };
+    let map_flags = if addr.is_null() { libc::MAP_SHARED } else { libc::MAP_SHARED | libc::MAP_FIXED };
+    unsafe {
+        let fd = libc::shm_open(c_name.as_ptr(), open_flags, 0o600);
+        assert!(fd >= 0, "failed to open {}", name);
+        if create {
+            assert_eq!(libc::ftruncate(fd, BUF_SIZE as libc::off_t), 0, "failed to size {}", name);
+        }
+        let buf = libc::mmap(addr, BUF_SIZE, libc::PROT_READ | libc::PROT_WRITE, map_flags, fd, 0);
+        assert_ne!(buf, libc::MAP_FAILED, "failed to map {}", name);
+        libc::close(fd);
+        buf as *mut u8
+    }
+}
@@ -0,0 +1,52 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+// A minimal integration of the shared buffer technique, for projects adopting it: a host
+// creates a shared buffer and starts a container process, which maps the buffer into its wasm
+// module's linear memory and runs the module over it whenever the host signals. The host asks
+// the module to total some values it writes into the buffer, and checks the total.
+//
+//   cargo build --release --bin module --target wasm32-unknown-unknown
+//   cargo run --release --features host --bin host -- target/wasm32-unknown-unknown/release/module.wasm
+//
+// This file holds the buffer layout, which both sides build against.
+
+#![no_std]
+
+// The size of the shared buffer; a multiple of the page size, so it can be mapped over part of
+// linear memory.
+pub const BUF_SIZE: usize = 4096;
+
+pub const MAX_VALUES: usize = 64;
+
+// Signals from the host, sent through the buffer's signal slot.
+pub const TOTAL: u32 = 1;
+pub const EXIT: u32 = 2;
+
+// The buffer's contents. The layout is the same for the host and wasm32, as every field is
+// aligned to its size.
+#[repr(C)]
+pub struct Shared {
+    // An shm_signal::SignalSlot, used by the host and container; the module leaves it alone.
+    pub signal: u32,
+    // Written by the host: the number of values to total.
+    pub len: u32,
+    // Written by the module.
+    pub total: u64,
+    // Written by the host.
+    pub values: [u32; MAX_VALUES],
+}
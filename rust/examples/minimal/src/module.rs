@@ -0,0 +1,49 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+
+// The wasm side of the minimal example: the container calls malloc_ to reserve room for the
+// buffer, set_buffer with where it mapped it, then total for every TOTAL signal from the host.
+
+use minimal::{Shared, MAX_VALUES};
+
+static mut SHARED: *mut Shared = core::ptr::null_mut();
+
+#[no_mangle]
+pub extern "C" fn malloc_(size: usize) -> *mut u8 {
+    let mut vec: Vec<u8> = Vec::with_capacity(size);
+    let ptr = vec.as_mut_ptr();
+    core::mem::forget(vec); // Leak the vector
+    ptr
+}
+
+#[no_mangle]
+pub extern "C" fn set_buffer(ptr: *mut Shared) {
+    unsafe { SHARED = ptr };
+}
+
+#[no_mangle]
+pub extern "C" fn total() {
+    // The buffer is only touched by the host while the container is waiting for a signal, so
+    // it's safe to use as ordinary memory here.
+    let shared = unsafe { &mut *SHARED };
+    let len = (shared.len as usize).min(MAX_VALUES);
+    shared.total = shared.values[..len].iter().map(|&value| value as u64).sum();
+}
+
+fn main() {
+    println!("module: Not meant to be run as a main");
+}
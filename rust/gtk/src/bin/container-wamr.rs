@@ -0,0 +1,181 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// A container that runs its module under WAMR (wasm-micro-runtime), which Oak-adjacent embedded
+// deployments use, through its C API. It's built with the wamr feature, which builds WAMR from
+// source.
+//
+// WAMR allocates linear memory in one of two ways. With hardware bounds checks (the default on
+// 64-bit Linux) it reserves the whole 32-bit range up front and commits pages as memory grows, so
+// the buffers can be mapped inside it and stay put. Without them, linear memory is malloc'd and
+// may move when it grows; Buffers notices that and the container restarts. WAMR can also append
+// a heap of its own to linear memory, but only modules without a malloc export would use it, so
+// the instance is created without one and the buffers live in memory reserved by malloc_.
+
+use common::{
+    host_common::{self, fill_random, monotonic_us, now_us, run_container, CallError, Export, Instance, OutOfBounds},
+    wasm_binary::{binary_export, binary_exports},
+};
+use std::{
+    ffi::{c_void, CStr, CString},
+    os::raw::c_char,
+    ptr, slice,
+};
+use wamr_sys::*;
+
+// The wasm stack sizes of the instance and of the execution environment that calls into it.
+const STACK_SIZE: u32 = 256 * 1024;
+const EXEC_ENV_STACK_SIZE: u32 = 256 * 1024;
+const ERROR_BUF_SIZE: usize = 128;
+
+fn main() {
+    // Not reloadable: the runtime is the process's, and each WamrInstance leaks its module.
+    run_container(|bytes| Box::new(WamrInstance::new(bytes.to_vec())), false);
+}
+
+struct WamrInstance {
+    instance: wasm_module_inst_t,
+    exec_env: wasm_exec_env_t,
+    // The module's exports, read from its binary, for validate_exports and try_call.
+    exports: Option<Vec<(String, String)>>,
+}
+
+impl WamrInstance {
+    fn new(bytes: Vec<u8>) -> Self {
+        let exports = binary_exports(&bytes);
+        let mut error = [0 as c_char; ERROR_BUF_SIZE];
+        let error_text = |error: &[c_char]| unsafe { CStr::from_ptr(error.as_ptr()) }.to_string_lossy().into_owned();
+        unsafe {
+            assert!(wasm_runtime_init(), "WAMR failed to initialise");
+            // WAMR keeps the symbols, and for some modules the bytes, for as long as the runtime.
+            let symbols = Box::leak(Box::new(native_symbols()));
+            let registered = wasm_runtime_register_natives(
+                b"env\0".as_ptr() as *const c_char,
+                symbols.as_mut_ptr(),
+                symbols.len() as u32,
+            );
+            assert!(registered, "WAMR failed to register the imports");
+            let bytes = Box::leak(bytes.into_boxed_slice());
+            let module =
+                wasm_runtime_load(bytes.as_mut_ptr(), bytes.len() as u32, error.as_mut_ptr(), ERROR_BUF_SIZE as u32);
+            assert!(!module.is_null(), "WAMR failed to load module: {}", error_text(&error));
+            let instance = wasm_runtime_instantiate(module, STACK_SIZE, 0, error.as_mut_ptr(), ERROR_BUF_SIZE as u32);
+            assert!(!instance.is_null(), "WAMR failed to instantiate module: {}", error_text(&error));
+            let exec_env = wasm_runtime_create_exec_env(instance, EXEC_ENV_STACK_SIZE);
+            assert!(!exec_env.is_null(), "WAMR failed to create an execution environment");
+            Self { instance, exec_env, exports }
+        }
+    }
+}
+
+impl Instance for WamrInstance {
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError> {
+        let c_name = CString::new(name).unwrap();
+        unsafe {
+            // The WAMR that wamr-sys 0.1 builds can't say how many results a function has, so
+            // that comes from the binary's signature instead.
+            let function = wasm_runtime_lookup_function(self.instance, c_name.as_ptr(), ptr::null());
+            if function.is_null() {
+                return Err(CallError::new(name, format!("WAMR call '{}' failed: no such export", name)));
+            }
+            // Args and results are passed in the same cells; each export returns at most one i32.
+            let mut cells: Vec<u32> = args.iter().map(|&v| v as u32).collect();
+            cells.resize(cells.len().max(1), 0);
+            if !wasm_runtime_call_wasm(self.exec_env, function, args.len() as u32, cells.as_mut_ptr()) {
+                let exception = CStr::from_ptr(wasm_runtime_get_exception(self.instance)).to_string_lossy();
+                let err = format!("WAMR call '{}' failed: {}", name, exception);
+                wasm_runtime_clear_exception(self.instance);
+                return Err(CallError::new(name, err));
+            }
+            let has_result = match binary_export(&self.exports, name) {
+                Export::Function(signature) => !signature.ends_with(')'),
+                _ => false,
+            };
+            Ok(has_result.then(|| cells[0] as i32))
+        }
+    }
+
+    fn memory_base(&self) -> i64 {
+        unsafe { wasm_runtime_addr_app_to_native(self.instance, 0 as _) as i64 }
+    }
+
+    fn export(&mut self, name: &str) -> Export {
+        binary_export(&self.exports, name)
+    }
+}
+
+fn native_symbols() -> [NativeSymbol; 6] {
+    let symbol = |name: &'static [u8], func: *mut c_void, signature: &'static [u8]| NativeSymbol {
+        symbol: name.as_ptr() as *const c_char,
+        func_ptr: func,
+        signature: signature.as_ptr() as *const c_char,
+        attachment: ptr::null_mut(),
+    };
+    [
+        symbol(b"print_callback\0", print_callback as *mut c_void, b"(ii)\0"),
+        symbol(b"assert_callback\0", assert_callback as *mut c_void, b"(iii)\0"),
+        symbol(b"time_callback\0", time_callback as *mut c_void, b"()I\0"),
+        symbol(b"event_callback\0", event_callback as *mut c_void, b"(iIii)\0"),
+        symbol(b"now_micros\0", now_micros as *mut c_void, b"()I\0"),
+        symbol(b"fill_random\0", fill_random_callback as *mut c_void, b"(ii)i\0"),
+    ]
+}
+
+extern "C" fn print_callback(exec_env: wasm_exec_env_t, len: u32, msg: u32) {
+    let result = host_common::print_callback(unsafe { memory(exec_env) }, len.into(), msg.into());
+    raise(exec_env, result);
+}
+
+extern "C" fn assert_callback(exec_env: wasm_exec_env_t, ok: i32, len: u32, msg: u32) {
+    let result = host_common::assert_callback(unsafe { memory(exec_env) }, ok, len.into(), msg.into());
+    raise(exec_env, result);
+}
+
+extern "C" fn time_callback(_exec_env: wasm_exec_env_t) -> i64 {
+    now_us()
+}
+
+extern "C" fn now_micros(_exec_env: wasm_exec_env_t) -> i64 {
+    monotonic_us()
+}
+
+extern "C" fn fill_random_callback(exec_env: wasm_exec_env_t, ptr: u32, len: u32) -> i32 {
+    fill_random(unsafe { memory(exec_env) }, ptr.into(), len.into())
+}
+
+extern "C" fn event_callback(exec_env: wasm_exec_env_t, kind: u32, value: i64, len: u32, msg: u32) {
+    let result = host_common::event_callback(unsafe { memory(exec_env) }, kind, value, len.into(), msg.into());
+    raise(exec_env, result);
+}
+
+// The instance's linear memory as a slice, which the callbacks mustn't keep as it moves when it
+// grows. An empty memory has no range at 0.
+unsafe fn memory<'a>(exec_env: wasm_exec_env_t) -> &'a mut [u8] {
+    let instance = wasm_runtime_get_module_inst(exec_env);
+    let mut size = 0;
+    if !wasm_runtime_get_app_addr_range(instance, 0, ptr::null_mut(), &mut size) {
+        return &mut [];
+    }
+    slice::from_raw_parts_mut(wasm_runtime_addr_app_to_native(instance, 0) as *mut u8, size as usize)
+}
+
+// Raises a callback's OutOfBounds error as an exception on the instance, which traps the call once
+// the callback returns.
+fn raise(exec_env: wasm_exec_env_t, result: Result<(), OutOfBounds>) {
+    if result.is_err() {
+        let exception = b"out of bounds memory access\0".as_ptr() as *const c_char;
+        unsafe { wasm_runtime_set_exception(wasm_runtime_get_module_inst(exec_env), exception) };
+    }
+}
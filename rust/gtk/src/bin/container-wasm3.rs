@@ -0,0 +1,121 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// A container that runs its module under wasm3, a small interpreter meant for constrained
+// environments, to show the buffers can be mapped over the linear memory of a minimal engine too.
+// wasm3 is a C library, built by the wasm3 crate, so this is built with the wasm3 feature rather
+// than with the other containers.
+
+use common::{
+    host_common::{
+        assert_callback, event_callback, fill_random, monotonic_us, now_us, print_callback, run_container, CallError,
+        Export, Instance, OutOfBounds,
+    },
+    wasm_binary::{binary_export, binary_exports},
+};
+use wasm3::{
+    error::{Error, Trap},
+    CallContext, Environment, Runtime, WasmType,
+};
+
+// The interpreter's stack, in bytes.
+const STACK_SIZE: u32 = 256 * 1024;
+
+fn main() {
+    run_container(|bytes| Box::new(Wasm3Instance::new(bytes.to_vec())), true);
+}
+
+struct Wasm3Instance {
+    runtime: Runtime,
+    // The module's exports, read from its binary, for validate_exports.
+    exports: Option<Vec<(String, String)>>,
+}
+
+impl Wasm3Instance {
+    fn new(bytes: Vec<u8>) -> Self {
+        let exports = binary_exports(&bytes);
+        let env = Environment::new().expect("wasm3 failed to create an environment");
+        let runtime = env.create_runtime(STACK_SIZE).expect("wasm3 failed to create a runtime");
+        let mut module = runtime.parse_and_load_module(bytes).expect("wasm3 failed to load module");
+        let linked = [
+            module.link_closure("env", "print_callback", |ctx: CallContext, (len, msg): (u32, u32)| {
+                print_callback(unsafe { &*ctx.memory() }, len.into(), msg.into()).map_err(trap)
+            }),
+            module.link_closure("env", "assert_callback", |ctx: CallContext, (ok, len, msg): (i32, u32, u32)| {
+                assert_callback(unsafe { &*ctx.memory() }, ok, len.into(), msg.into()).map_err(trap)
+            }),
+            module.link_closure("env", "time_callback", |_: CallContext, ()| Ok(now_us())),
+            module.link_closure("env", "now_micros", |_: CallContext, ()| Ok(monotonic_us())),
+            module.link_closure("env", "fill_random", |ctx: CallContext, (ptr, len): (u32, u32)| {
+                Ok(fill_random(unsafe { &mut *ctx.memory_mut() }, ptr.into(), len.into()))
+            }),
+            module.link_closure(
+                "env",
+                "event_callback",
+                |ctx: CallContext, (kind, value, len, msg): (u32, i64, u32, u32)| {
+                    event_callback(unsafe { &*ctx.memory() }, kind, value, len.into(), msg.into()).map_err(trap)
+                },
+            ),
+        ];
+        // wasm3 only links the functions a module imports, and fails for the rest.
+        for result in linked {
+            match result {
+                Ok(()) | Err(Error::FunctionNotFound) => {}
+                Err(e) => panic!("wasm3 failed to link the imports: {}", e),
+            }
+        }
+        Self { runtime, exports }
+    }
+
+    // wasm3 looks up functions by their full signature, so the caller picks the result type.
+    fn call_as<R: WasmType>(&self, name: &str, args: &[i32]) -> Result<R, Error> {
+        let runtime = &self.runtime;
+        match *args {
+            [] => runtime.find_function::<(), R>(name)?.call(),
+            [a] => runtime.find_function::<i32, R>(name)?.call(a),
+            [a, b] => runtime.find_function::<(i32, i32), R>(name)?.call(a, b),
+            [a, b, c] => runtime.find_function::<(i32, i32, i32), R>(name)?.call(a, b, c),
+            [a, b, c, d] => runtime.find_function::<(i32, i32, i32, i32), R>(name)?.call(a, b, c, d),
+            [a, b, c, d, e] => runtime.find_function::<(i32, i32, i32, i32, i32), R>(name)?.call(a, b, c, d, e),
+            _ => panic!("wasm3 call '{}' has too many args", name),
+        }
+    }
+}
+
+impl Instance for Wasm3Instance {
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError> {
+        let result = match self.call_as::<i32>(name, args) {
+            Err(Error::InvalidFunctionSignature) => self.call_as::<()>(name, args).map(|()| None),
+            result => result.map(Some),
+        };
+        result.map_err(|e| CallError::new(name, format!("wasm3 call '{}' failed: {}", name, e)))
+    }
+
+    fn memory_base(&self) -> i64 {
+        unsafe { self.runtime.memory() as *const u8 as i64 }
+    }
+
+    fn memory_size(&self) -> Option<u64> {
+        Some(unsafe { &*self.runtime.memory() }.len() as u64)
+    }
+
+    fn export(&mut self, name: &str) -> Export {
+        binary_export(&self.exports, name)
+    }
+}
+
+fn trap(_: OutOfBounds) -> Trap {
+    Trap::OutOfBoundsMemoryAccess
+}
@@ -0,0 +1,151 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// A container that runs its module under WasmEdge's interpreter, to check that mapping the
+// buffers over linear memory isn't specific to wasmi and wasmer. It needs the WasmEdge library
+// installed, so it's built with the wasmedge feature rather than with the other containers.
+
+use common::{
+    host_common::{self, fill_random, monotonic_us, now_us, run_container, CallError, Export, Instance, OutOfBounds},
+    wasm_binary::{binary_export, binary_exports},
+};
+use std::slice;
+use wasmedge_sdk::{
+    error::HostFuncError, host_function, Caller, ImportObjectBuilder, NeverType, ValType, Vm, VmBuilder, WasmValue,
+};
+
+// The name the module is registered under in the Vm.
+const MODULE_NAME: &str = "module";
+// WasmEdge's error code for an out of bounds memory access, which host functions return to trap.
+const MEMORY_OUT_OF_BOUNDS: u32 = 0x88;
+
+fn main() {
+    run_container(|bytes| Box::new(WasmEdgeInstance::new(bytes)), true);
+}
+
+struct WasmEdgeInstance {
+    vm: Vm,
+    // The module's exports, read from its binary, for validate_exports.
+    exports: Option<Vec<(String, String)>>,
+}
+
+impl WasmEdgeInstance {
+    fn new(bytes: &[u8]) -> Self {
+        let imports = ImportObjectBuilder::new()
+            .with_func::<(i32, i32), (), NeverType>("print_callback", print_callback, None)
+            .and_then(|builder| {
+                builder.with_func::<(i32, i32, i32), (), NeverType>("assert_callback", assert_callback, None)
+            })
+            .and_then(|builder| builder.with_func::<(), i64, NeverType>("time_callback", time_callback, None))
+            .and_then(|builder| builder.with_func::<(), i64, NeverType>("now_micros", now_micros, None))
+            .and_then(|builder| {
+                builder.with_func::<(i32, i32), i32, NeverType>("fill_random", fill_random_callback, None)
+            })
+            .and_then(|builder| {
+                builder.with_func::<(i32, i64, i32, i32), (), NeverType>("event_callback", event_callback, None)
+            })
+            .and_then(|builder| builder.build::<NeverType>("env", None))
+            .expect("wasmedge failed to build the imports");
+        let mut vm = VmBuilder::new().build().expect("wasmedge failed to create a vm");
+        vm.register_import_module(&imports).expect("wasmedge failed to register the imports");
+        let vm = vm.register_module_from_bytes(MODULE_NAME, bytes).expect("wasmedge failed to instantiate module");
+        Self { vm, exports: binary_exports(bytes) }
+    }
+}
+
+impl Instance for WasmEdgeInstance {
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError> {
+        let args: Vec<_> = args.iter().map(|&v| WasmValue::from_i32(v)).collect();
+        match self.vm.run_func(Some(MODULE_NAME), name, args) {
+            Ok(results) => match results.first() {
+                Some(value) if value.ty() == ValType::I32 => Ok(Some(value.to_i32())),
+                _ => Ok(None),
+            },
+            Err(e) => Err(CallError::new(name, format!("wasmedge call '{}' failed: {}", name, e))),
+        }
+    }
+
+    fn memory_base(&self) -> i64 {
+        let memory = self
+            .vm
+            .named_module(MODULE_NAME)
+            .and_then(|module| module.memory("memory"))
+            .expect("module does not export memory");
+        memory.data_pointer(0, 1).expect("wasmedge failed to find the base of linear memory") as i64
+    }
+
+    fn export(&mut self, name: &str) -> Export {
+        binary_export(&self.exports, name)
+    }
+}
+
+#[host_function]
+fn print_callback(caller: Caller, args: Vec<WasmValue>) -> Result<Vec<WasmValue>, HostFuncError> {
+    let (len, msg) = (address(&args[0]), address(&args[1]));
+    with_memory(&caller, |memory| host_common::print_callback(memory, len, msg)).map_err(trap)?;
+    Ok(vec![])
+}
+
+#[host_function]
+fn assert_callback(caller: Caller, args: Vec<WasmValue>) -> Result<Vec<WasmValue>, HostFuncError> {
+    let (ok, len, msg) = (args[0].to_i32(), address(&args[1]), address(&args[2]));
+    with_memory(&caller, |memory| host_common::assert_callback(memory, ok, len, msg)).map_err(trap)?;
+    Ok(vec![])
+}
+
+#[host_function]
+fn time_callback(_caller: Caller, _args: Vec<WasmValue>) -> Result<Vec<WasmValue>, HostFuncError> {
+    Ok(vec![WasmValue::from_i64(now_us())])
+}
+
+#[host_function]
+fn now_micros(_caller: Caller, _args: Vec<WasmValue>) -> Result<Vec<WasmValue>, HostFuncError> {
+    Ok(vec![WasmValue::from_i64(monotonic_us())])
+}
+
+#[host_function]
+fn fill_random_callback(caller: Caller, args: Vec<WasmValue>) -> Result<Vec<WasmValue>, HostFuncError> {
+    let (ptr, len) = (address(&args[0]), address(&args[1]));
+    Ok(vec![WasmValue::from_i32(with_memory(&caller, |memory| fill_random(memory, ptr, len)))])
+}
+
+#[host_function]
+fn event_callback(caller: Caller, args: Vec<WasmValue>) -> Result<Vec<WasmValue>, HostFuncError> {
+    let (kind, value) = (args[0].to_i32() as u32, args[1].to_i64());
+    let (len, msg) = (address(&args[2]), address(&args[3]));
+    with_memory(&caller, |memory| host_common::event_callback(memory, kind, value, len, msg)).map_err(trap)?;
+    Ok(vec![])
+}
+
+// A pointer or length, which the modules pass as i32s.
+fn address(value: &WasmValue) -> u64 {
+    value.to_i32() as u32 as u64
+}
+
+fn trap(_: OutOfBounds) -> HostFuncError {
+    HostFuncError::Runtime(MEMORY_OUT_OF_BOUNDS)
+}
+
+// Runs 'f' over linear memory as a slice; WasmEdge only hands out pointers into it.
+fn with_memory<R>(caller: &Caller, f: impl FnOnce(&mut [u8]) -> R) -> R {
+    let mut memory = caller.memory(0).expect("module does not export memory");
+    let size = memory.size() as usize;
+    // An empty memory has nothing to point at.
+    if size == 0 {
+        return f(&mut []);
+    }
+    let base = memory.data_pointer_mut(0, 1).expect("wasmedge failed to find the base of linear memory");
+    f(unsafe { slice::from_raw_parts_mut(base, size) })
+}
@@ -0,0 +1,227 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+use common::{
+    host_common::{
+        self, fill_random, module_cache_dir, monotonic_us, now_us, run_container, signature, CallError, Export,
+        Instance, OutOfBounds,
+    },
+    wasi::{wasi_call, WasiMemory, WASI_MODULE},
+};
+use std::slice;
+use wasmer_runtime::{
+    cache::{Cache, FileSystemCache, WasmHash},
+    compile, func, imports,
+    types::{ExternDescriptor, MemoryDescriptor, Type},
+    units::Pages,
+    Ctx, DynFunc, Memory, Module, Value,
+};
+
+fn main() {
+    run_container(|bytes| Box::new(WasmerInstance::new(bytes)), true);
+}
+
+struct WasmerInstance {
+    instance: wasmer_runtime::Instance,
+    // The module's memory, if it imports it rather than exporting its own.
+    memory: Option<Memory>,
+}
+
+impl WasmerInstance {
+    fn new(bytes: &[u8]) -> Self {
+        let module = load_module(bytes);
+        let mut imports = imports! {
+            "env" => {
+                "print_callback" => func!(print_callback),
+                "assert_callback" => func!(assert_callback),
+                "time_callback" => func!(now_us),
+                "event_callback" => func!(event_callback),
+                "now_micros" => func!(monotonic_us),
+                "fill_random" => func!(fill_random_callback),
+            },
+            // See WASI_FUNCTIONS for what these do.
+            WASI_MODULE => {
+                "args_get" => func!(|ctx: &mut Ctx, a: i32, b: i32| wasi(ctx, "args_get", &[a, b])),
+                "args_sizes_get" => func!(|ctx: &mut Ctx, a: i32, b: i32| wasi(ctx, "args_sizes_get", &[a, b])),
+                "clock_res_get" => func!(|ctx: &mut Ctx, a: i32, b: i32| wasi(ctx, "clock_res_get", &[a, b])),
+                "clock_time_get" => func!(|ctx: &mut Ctx, a: i32, precision: i64, b: i32| {
+                    wasi_call(&mut CtxMemory(ctx), "clock_time_get", &[a as i64, precision, b as i64]).unwrap()
+                }),
+                "environ_get" => func!(|ctx: &mut Ctx, a: i32, b: i32| wasi(ctx, "environ_get", &[a, b])),
+                "environ_sizes_get" => func!(|ctx: &mut Ctx, a: i32, b: i32| wasi(ctx, "environ_sizes_get", &[a, b])),
+                "fd_prestat_get" => func!(|ctx: &mut Ctx, a: i32, b: i32| wasi(ctx, "fd_prestat_get", &[a, b])),
+                "fd_write" => func!(|ctx: &mut Ctx, a: i32, b: i32, c: i32, d: i32| {
+                    wasi(ctx, "fd_write", &[a, b, c, d])
+                }),
+                "proc_exit" => func!(|ctx: &mut Ctx, code: i32| {
+                    wasi_call(&mut CtxMemory(ctx), "proc_exit", &[code as i64]);
+                }),
+                "random_get" => func!(|ctx: &mut Ctx, a: i32, b: i32| wasi(ctx, "random_get", &[a, b])),
+                "sched_yield" => func!(|ctx: &mut Ctx| wasi(ctx, "sched_yield", &[])),
+            },
+        };
+        // Giving the memory a maximum makes wasmer reserve the whole 32-bit range for it up front,
+        // so it doesn't move when it grows.
+        let memory = module.imports().into_iter().find_map(|import| match import.ty {
+            ExternDescriptor::Memory(desc) if import.namespace == "env" && import.name == "memory" => {
+                let desc = MemoryDescriptor::new(desc.minimum, Some(Pages(65_536)), false).unwrap();
+                Some(Memory::new(desc).expect("wasmer failed to create memory"))
+            }
+            _ => None,
+        });
+        if let Some(memory) = &memory {
+            imports.extend(imports! { "env" => { "memory" => memory.clone() } });
+        }
+        let instance = module.instantiate(&imports).expect("wasmer failed to instantiate module");
+        Self { instance, memory }
+    }
+}
+
+impl Instance for WasmerInstance {
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError> {
+        let args: Vec<_> = args.iter().map(|&v| Value::I32(v)).collect();
+        match self.instance.call(name, &args) {
+            Ok(results) => match results.first() {
+                Some(Value::I32(v)) => Ok(Some(*v)),
+                _ => Ok(None),
+            },
+            Err(e) => Err(CallError::new(name, format!("wasmer call '{}' failed: {:?}", name, e))),
+        }
+    }
+
+    fn memory_base(&self) -> i64 {
+        self.instance.context().memory(0).view::<u8>().as_ptr() as i64
+    }
+
+    fn memory_size(&self) -> Option<u64> {
+        Some(self.instance.context().memory(0).view::<u8>().len() as u64)
+    }
+
+    fn export(&mut self, name: &str) -> Export {
+        let func = match self.instance.exports.get::<DynFunc>(name) {
+            Ok(func) => func,
+            Err(_) => return Export::Missing,
+        };
+        let sig = func.signature();
+        let (params, returns) = (sig.params().iter(), sig.returns().iter());
+        Export::Function(signature(params.map(wasm_type_letter), returns.map(wasm_type_letter)))
+    }
+
+    fn grow_memory(&mut self, pages: u32) -> Option<u32> {
+        let memory = self.memory.as_ref()?;
+        Some(memory.grow(Pages(pages)).expect("wasmer failed to grow memory").0)
+    }
+}
+
+// Compiles the module, or loads it from the module cache (see MODULE_CACHE_ENV) after the first
+// time, in wasmer's own cache format. Unlike write_cache_entry, wasmer writes its entries in place,
+// so two containers compiling the same module at once could see each other's half-written entry.
+fn load_module(bytes: &[u8]) -> Module {
+    let compile = || compile(bytes).expect("wasmer failed to compile module");
+    let dir = match module_cache_dir() {
+        Some(dir) => dir.join("wasmer"),
+        None => return compile(),
+    };
+    // Only as safe as the cache: wasmer runs the compiled code it loads without checking it.
+    let mut cache = unsafe { FileSystemCache::new(&dir) }
+        .unwrap_or_else(|err| panic!("can't use {} as the module cache: {}", dir.display(), err));
+    let key = WasmHash::generate(bytes);
+    if let Ok(module) = cache.load(key) {
+        println!("Container: loaded the compiled module from {}", dir.display());
+        return module;
+    }
+    // Storing a module consumes it, so the cached copy is loaded back.
+    match cache.store(key, compile()).and_then(|()| cache.load(key)) {
+        Ok(module) => {
+            println!("Container: cached the compiled module in {}", dir.display());
+            module
+        }
+        Err(err) => {
+            println!("Container: couldn't cache the compiled module in {}: {:?}", dir.display(), err);
+            compile()
+        }
+    }
+}
+
+// V128 has no letter in the signatures, so it never matches one.
+fn wasm_type_letter(ty: &Type) -> char {
+    match ty {
+        Type::I32 => 'i',
+        Type::I64 => 'I',
+        Type::F32 => 'f',
+        Type::F64 => 'F',
+        Type::V128 => '?',
+    }
+}
+
+fn print_callback(ctx: &mut Ctx, len: u32, msg: u32) -> Result<(), OutOfBounds> {
+    host_common::print_callback(memory_bytes(ctx), len.into(), msg.into())
+}
+
+fn assert_callback(ctx: &mut Ctx, cond: i32, len: u32, msg: u32) -> Result<(), OutOfBounds> {
+    host_common::assert_callback(memory_bytes(ctx), cond, len.into(), msg.into())
+}
+
+fn event_callback(ctx: &mut Ctx, kind: u32, value: i64, len: u32, msg: u32) -> Result<(), OutOfBounds> {
+    host_common::event_callback(memory_bytes(ctx), kind, value, len.into(), msg.into())
+}
+
+fn fill_random_callback(ctx: &mut Ctx, ptr: u32, len: u32) -> i32 {
+    fill_random(memory_bytes(ctx), ptr.into(), len.into())
+}
+
+// Linear memory as bytes. wasmer views it as cells, as the module can change it, and a Cell<u8> is
+// laid out as a u8.
+fn memory_bytes(ctx: &mut Ctx) -> &mut [u8] {
+    let view = ctx.memory(0).view::<u8>();
+    unsafe { slice::from_raw_parts_mut(view.as_ptr() as *mut u8, view.len()) }
+}
+
+// A WASI call with only i32 arguments that returns an errno.
+fn wasi(ctx: &mut Ctx, name: &str, args: &[i32]) -> i32 {
+    let args: Vec<i64> = args.iter().map(|&arg| arg as i64).collect();
+    wasi_call(&mut CtxMemory(ctx), name, &args).unwrap()
+}
+
+// The module's memory as its WASI calls see it.
+struct CtxMemory<'a>(&'a Ctx);
+
+impl WasiMemory for CtxMemory<'_> {
+    fn read(&self, offset: u32, buf: &mut [u8]) -> bool {
+        let view = self.0.memory(0).view::<u8>();
+        match view.get(offset as usize..offset as usize + buf.len()) {
+            Some(cells) => {
+                buf.iter_mut().zip(cells).for_each(|(byte, cell)| *byte = cell.get());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> bool {
+        let view = self.0.memory(0).view::<u8>();
+        match view.get(offset as usize..offset as usize + data.len()) {
+            Some(cells) => {
+                cells.iter().zip(data).for_each(|(cell, &byte)| cell.set(byte));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.0.memory(0).view::<u8>().len() as u64
+    }
+}
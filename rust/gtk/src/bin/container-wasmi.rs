@@ -0,0 +1,246 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+use common::{
+    host_common::{
+        self, fill_random, monotonic_us, now_us, run_container, signature, CallError, Export, Instance, OutOfBounds,
+        LAYOUT_GLOBALS, LAYOUT_MODULE,
+    },
+    wasi::{wasi_call, WasiMemory, WASI_FUNCTIONS, WASI_MODULE},
+};
+use std::cell::RefCell;
+use wasmi::{
+    memory_units::Pages, Error, ExternVal, Externals, FuncInstance, FuncRef, GlobalDescriptor, GlobalInstance,
+    GlobalRef, ImportsBuilder, MemoryDescriptor, MemoryInstance, MemoryRef, ModuleImportResolver, ModuleInstance,
+    ModuleRef, RuntimeArgs, RuntimeValue, Signature, Trap, TrapKind, ValueType,
+};
+
+fn main() {
+    run_container(|bytes| Box::new(WasmiInstance::new(bytes)), true);
+}
+
+struct WasmiInstance {
+    instance: ModuleRef,
+    externs: WasmiExterns,
+    // Whether the module imports its memory, so the container can grow it for the buffers.
+    imported_memory: bool,
+    // The layout globals the module imports, by their index in LAYOUT_GLOBALS.
+    layout: Vec<(usize, GlobalRef)>,
+}
+
+impl WasmiInstance {
+    fn new(bytes: &[u8]) -> Self {
+        let module = wasmi::Module::from_buffer(bytes).expect("wasmi failed to load module");
+        let resolver = WasmiResolver::default();
+        let layout = LayoutResolver::default();
+        let imports = ImportsBuilder::new()
+            .with_resolver("env", &resolver)
+            .with_resolver(WASI_MODULE, &WasiResolver)
+            .with_resolver(LAYOUT_MODULE, &layout);
+        let instance = ModuleInstance::new(&module, &imports)
+            .expect("wasmi failed to instantiate module")
+            .assert_no_start();
+        let imported = resolver.memory.into_inner();
+        let imported_memory = imported.is_some();
+        let memory = imported
+            .or_else(|| instance.export_by_name("memory").and_then(|export| export.as_memory().cloned()))
+            .expect("module neither imports nor exports memory");
+        Self { instance, externs: WasmiExterns { memory }, imported_memory, layout: layout.globals.into_inner() }
+    }
+}
+
+impl Instance for WasmiInstance {
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError> {
+        let args: Vec<_> = args.iter().map(|&v| RuntimeValue::I32(v)).collect();
+        match self.instance.invoke_export(name, &args, &mut self.externs) {
+            Ok(Some(RuntimeValue::I32(v))) => Ok(Some(v)),
+            Ok(_) => Ok(None),
+            Err(e) => Err(CallError::new(name, format!("wasmi call '{}' failed: {:?}", name, e))),
+        }
+    }
+
+    fn memory_base(&self) -> i64 {
+        self.externs.memory.with_direct_access(|buf| buf.as_ptr() as i64)
+    }
+
+    fn memory_size(&self) -> Option<u64> {
+        Some(self.externs.memory.with_direct_access(|buf| buf.len() as u64))
+    }
+
+    fn export(&mut self, name: &str) -> Export {
+        let func = match self.instance.export_by_name(name) {
+            Some(ExternVal::Func(func)) => func,
+            _ => return Export::Missing,
+        };
+        let sig = func.signature();
+        let (params, result) = (sig.params().iter(), sig.return_type());
+        Export::Function(signature(params.map(wasm_type_letter), result.iter().map(wasm_type_letter)))
+    }
+
+    fn grow_memory(&mut self, pages: u32) -> Option<u32> {
+        let memory = &self.externs.memory;
+        self.imported_memory.then(|| memory.grow(Pages(pages as usize)).expect("wasmi failed to grow memory").0 as u32)
+    }
+
+    fn set_layout(&mut self, layout: [i64; 4]) {
+        // Indexes and sizes in a 32-bit memory fit in a u32.
+        for (index, global) in &self.layout {
+            global.set(RuntimeValue::I32(layout[*index] as i32)).expect("wasmi failed to set a layout global");
+        }
+    }
+}
+
+const PRINT_CALLBACK: usize = 0;
+const ASSERT_CALLBACK: usize = 1;
+const TIME_CALLBACK: usize = 2;
+const EVENT_CALLBACK: usize = 3;
+const NOW_MICROS: usize = 4;
+const FILL_RANDOM: usize = 5;
+// The WASI functions follow, in WASI_FUNCTIONS order.
+const WASI_CALLBACKS: usize = 6;
+
+fn wasm_type_letter(ty: &ValueType) -> char {
+    match ty {
+        ValueType::I32 => 'i',
+        ValueType::I64 => 'I',
+        ValueType::F32 => 'f',
+        ValueType::F64 => 'F',
+    }
+}
+
+struct WasmiExterns {
+    memory: MemoryRef,
+}
+
+impl Externals for WasmiExterns {
+    fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        match index {
+            PRINT_CALLBACK => {
+                let (len, msg) = (args.nth::<u32>(0), args.nth::<u32>(1));
+                let result = self
+                    .memory
+                    .with_direct_access(|memory| host_common::print_callback(memory, len.into(), msg.into()));
+                result.map(|()| None).map_err(trap)
+            }
+            ASSERT_CALLBACK => {
+                let (ok, len, msg) = (args.nth::<i32>(0), args.nth::<u32>(1), args.nth::<u32>(2));
+                let result = self
+                    .memory
+                    .with_direct_access(|memory| host_common::assert_callback(memory, ok, len.into(), msg.into()));
+                result.map(|()| None).map_err(trap)
+            }
+            TIME_CALLBACK => Ok(Some(RuntimeValue::I64(now_us()))),
+            NOW_MICROS => Ok(Some(RuntimeValue::I64(monotonic_us()))),
+            FILL_RANDOM => {
+                let (ptr, len) = (args.nth::<u32>(0), args.nth::<u32>(1));
+                let status = self.memory.with_direct_access_mut(|memory| fill_random(memory, ptr.into(), len.into()));
+                Ok(Some(RuntimeValue::I32(status)))
+            }
+            EVENT_CALLBACK => {
+                let (kind, value, len, msg) = (args.nth(0), args.nth(1), args.nth::<u32>(2), args.nth::<u32>(3));
+                let result = self.memory.with_direct_access(|memory| {
+                    host_common::event_callback(memory, kind, value, len.into(), msg.into())
+                });
+                result.map(|()| None).map_err(trap)
+            }
+            _ if index >= WASI_CALLBACKS => {
+                let args: Vec<i64> = args
+                    .as_ref()
+                    .iter()
+                    .map(|&value| match value {
+                        RuntimeValue::I64(v) => v,
+                        value => value.try_into::<i32>().expect("WASI functions only take integers") as i64,
+                    })
+                    .collect();
+                Ok(wasi_call(self, WASI_FUNCTIONS[index - WASI_CALLBACKS].0, &args).map(RuntimeValue::I32))
+            }
+            _ => panic!("unimplemented function at {}", index),
+        }
+    }
+}
+
+fn trap(_: OutOfBounds) -> Trap {
+    Trap::new(TrapKind::MemoryAccessOutOfBounds)
+}
+
+impl WasiMemory for WasmiExterns {
+    fn read(&self, offset: u32, buf: &mut [u8]) -> bool {
+        self.memory.get_into(offset, buf).is_ok()
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> bool {
+        self.memory.set(offset, data).is_ok()
+    }
+
+    fn size(&self) -> u64 {
+        self.memory.with_direct_access(|buf| buf.len() as u64)
+    }
+}
+
+// Creates the module's memory if it imports one, keeping it for the container.
+#[derive(Default)]
+struct WasmiResolver {
+    memory: RefCell<Option<MemoryRef>>,
+}
+
+impl ModuleImportResolver for WasmiResolver {
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+        match field_name {
+            "print_callback" => Ok(FuncInstance::alloc_host(signature.clone(), PRINT_CALLBACK)),
+            "assert_callback" => Ok(FuncInstance::alloc_host(signature.clone(), ASSERT_CALLBACK)),
+            "time_callback" => Ok(FuncInstance::alloc_host(signature.clone(), TIME_CALLBACK)),
+            "event_callback" => Ok(FuncInstance::alloc_host(signature.clone(), EVENT_CALLBACK)),
+            "now_micros" => Ok(FuncInstance::alloc_host(signature.clone(), NOW_MICROS)),
+            "fill_random" => Ok(FuncInstance::alloc_host(signature.clone(), FILL_RANDOM)),
+            _ => panic!("unexpected import {}", field_name),
+        }
+    }
+
+    fn resolve_memory(&self, field_name: &str, descriptor: &MemoryDescriptor) -> Result<MemoryRef, Error> {
+        assert_eq!(field_name, "memory", "unexpected memory import");
+        let maximum = descriptor.maximum().map(|pages| Pages(pages as usize));
+        let memory = MemoryInstance::alloc(Pages(descriptor.initial() as usize), maximum)?;
+        *self.memory.borrow_mut() = Some(memory.clone());
+        Ok(memory)
+    }
+}
+
+// Creates the layout globals the module imports, keeping them for the container to set.
+#[derive(Default)]
+struct LayoutResolver {
+    globals: RefCell<Vec<(usize, GlobalRef)>>,
+}
+
+impl ModuleImportResolver for LayoutResolver {
+    fn resolve_global(&self, field_name: &str, descriptor: &GlobalDescriptor) -> Result<GlobalRef, Error> {
+        let index = LAYOUT_GLOBALS.iter().position(|&name| name == field_name);
+        let index = index.unwrap_or_else(|| panic!("unknown layout global {}", field_name));
+        assert_eq!(descriptor.value_type(), ValueType::I32, "layout global {} must be an i32", field_name);
+        assert!(descriptor.is_mutable(), "layout global {} must be mutable, as it follows the buffers", field_name);
+        let global = GlobalInstance::alloc(RuntimeValue::I32(0), true);
+        self.globals.borrow_mut().push((index, global.clone()));
+        Ok(global)
+    }
+}
+
+struct WasiResolver;
+
+impl ModuleImportResolver for WasiResolver {
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+        let index = WASI_FUNCTIONS.iter().position(|&(name, _)| name == field_name);
+        let index = index.unwrap_or_else(|| panic!("unsupported WASI import {}", field_name));
+        Ok(FuncInstance::alloc_host(signature.clone(), WASI_CALLBACKS + index))
+    }
+}
@@ -0,0 +1,506 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// A container that runs its module under wasmtime. Besides the usual mapping into the module's
+// linear memory, it supports modules that see the buffers as a dedicated second memory, as the
+// multi-memory proposal allows: a module that imports the buffer_memory.wat helpers (from the
+// "buffers" namespace) gets a memory of their own for the buffers, starting at offset 0, so the
+// buffers neither need fitting into the module's heap nor risk overlapping it. Such a module
+// must import its own memory too (see ./run.sh -m), as the helpers copy between the two.
+//
+// Calls can be limited by fuel (see FuelBudgets) and by the commands' deadlines, at which a
+// watchdog thread interrupts them. wasmtime 0.32 has no epochs, so the watchdog uses the store's
+// InterruptHandle, which makes the running wasm trap at its next loop or function entry.
+
+use common::{
+    host_common::{
+        self, fill_random, module_cache_dir, module_cache_key, monotonic_us, now_us, run_container, signature,
+        wasm_i32, write_cache_entry, CallError, Export, FuelBudgets, Instance, OutOfBounds, TrapKind, LAYOUT_GLOBALS,
+        LAYOUT_MODULE,
+    },
+    wasi::{wasi_call, WasiMemory, WASI_FUNCTIONS, WASI_MODULE},
+};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+use wasmtime::{
+    Caller, Config, Engine, ExternType, FuncType, Global, GlobalType, InterruptHandle, Linker, Memory, MemoryType,
+    Module, Mutability, Store, Trap, TrapCode, Val, ValType, WasmTy,
+};
+
+const BUFFER_MEMORY_WAT: &str = include_str!("../buffer_memory.wat");
+// The namespace of the helpers' exports, as the module imports them.
+const BUFFER_MEMORY_MODULE: &str = "buffers";
+
+fn main() {
+    run_container(|bytes| Box::new(WasmtimeInstance::new(bytes)), true);
+}
+
+struct WasmtimeInstance {
+    store: Store<()>,
+    instance: wasmtime::Instance,
+    // The memory the buffers go in: the module's own, or the dedicated buffer memory.
+    memory: Memory,
+    // Whether the container created 'memory', and so can grow it for the buffers.
+    created_memory: bool,
+    // Whether the module's own memory is 64-bit.
+    memory64: bool,
+    // The layout globals the module imports, by their index in LAYOUT_GLOBALS.
+    layout: Vec<(usize, Global)>,
+    // Empty unless the store consumes fuel.
+    fuel: FuelBudgets,
+    // Started for the first deadline.
+    watchdog: Option<Watchdog>,
+}
+
+impl WasmtimeInstance {
+    fn new(bytes: &[u8]) -> Self {
+        let fuel = FuelBudgets::from_env();
+        let mut config = Config::new();
+        config.wasm_multi_memory(true);
+        config.wasm_memory64(true);
+        config.consume_fuel(!fuel.is_empty());
+        config.interruptable(true);
+        let engine = Engine::new(&config).expect("wasmtime failed to create an engine");
+        let module = load_module(&engine, bytes, if fuel.is_empty() { "" } else { "-fuel" });
+        let mut store = Store::new(&engine, ());
+        let mut linker = Linker::new(&engine);
+        // wasmtime reserves the whole 32-bit range for the memories created here, so they don't
+        // move when they grow. A 64-bit one can, as a module's own can in wasmi.
+        let imported = module.imports().find_map(|import| match import.ty() {
+            ExternType::Memory(ty) if import.module() == "env" => {
+                let ty = if ty.is_64() {
+                    MemoryType::new64(ty.minimum(), None)
+                } else {
+                    MemoryType::new(ty.minimum() as u32, None)
+                };
+                Some(Memory::new(&mut store, ty).expect("wasmtime failed to create memory"))
+            }
+            _ => None,
+        });
+        if let Some(memory) = imported {
+            linker.define("env", "memory", memory).unwrap();
+        }
+        let buffer_memory = module.imports().any(|import| import.module() == BUFFER_MEMORY_MODULE).then(|| {
+            let memory = imported.expect("modules using the buffer memory must import their own memory");
+            // The helpers index both memories with i32s.
+            assert!(!memory.ty(&store).is_64(), "modules using the buffer memory can't have a 64-bit memory");
+            let buffers = Memory::new(&mut store, MemoryType::new(0, None)).expect("wasmtime failed to create memory");
+            let helpers = Module::new(&engine, BUFFER_MEMORY_WAT).expect("wasmtime failed to load buffer_memory.wat");
+            let mut helper_linker = Linker::new(&engine);
+            helper_linker.define("env", "memory", memory).unwrap();
+            helper_linker.define(BUFFER_MEMORY_MODULE, "memory", buffers).unwrap();
+            let helpers = helper_linker
+                .instantiate(&mut store, &helpers)
+                .expect("wasmtime failed to instantiate buffer_memory.wat");
+            linker.instance(&mut store, BUFFER_MEMORY_MODULE, helpers).unwrap();
+            buffers
+        });
+
+        let mut layout = Vec::new();
+        for import in module.imports().filter(|import| import.module() == LAYOUT_MODULE) {
+            let name = import.name().unwrap_or_default();
+            let index = LAYOUT_GLOBALS.iter().position(|&global| global == name);
+            let index = index.unwrap_or_else(|| panic!("unknown layout global {}", name));
+            let ty = match import.ty() {
+                ExternType::Global(ty) => ty,
+                _ => panic!("layout import {} must be a global", name),
+            };
+            let mutable = ty.mutability() == Mutability::Var;
+            assert!(mutable, "layout global {} must be mutable, as it follows the buffers", name);
+            let zero = if *ty.content() == ValType::I64 { Val::I64(0) } else { Val::I32(0) };
+            let ty = GlobalType::new(ty.content().clone(), Mutability::Var);
+            let global = Global::new(&mut store, ty, zero).expect("wasmtime failed to create a layout global");
+            linker.define(LAYOUT_MODULE, name, global).unwrap();
+            layout.push((index, global));
+        }
+
+        let memory_of = move |caller: &mut Caller<'_, ()>| {
+            imported.or_else(|| caller.get_export("memory").and_then(|e| e.into_memory())).unwrap()
+        };
+        // The callbacks' lengths and pointers are usizes, so u64s for a 64-bit memory.
+        let is_64 = |ty: ExternType| matches!(ty, ExternType::Memory(ty) if ty.is_64());
+        let memory64 = module.imports().any(|import| is_64(import.ty())) || module.exports().any(|e| is_64(e.ty()));
+        if memory64 {
+            define_callbacks::<u64>(&mut linker, memory_of);
+        } else {
+            define_callbacks::<u32>(&mut linker, memory_of);
+        }
+        linker.func_wrap("env", "time_callback", now_us).unwrap();
+        linker.func_wrap("env", "now_micros", monotonic_us).unwrap();
+        for &(name, signature) in WASI_FUNCTIONS.iter() {
+            let (params, results) = signature[1..].split_once(')').unwrap();
+            let ty = FuncType::new(params.chars().map(wasm_type), results.chars().map(wasm_type));
+            linker
+                .func_new(WASI_MODULE, name, ty, move |mut caller, params, results| {
+                    let arg = |v: &Val| v.i64().or_else(|| v.i32().map(i64::from)).unwrap();
+                    let args: Vec<i64> = params.iter().map(arg).collect();
+                    let memory = memory_of(&mut caller);
+                    if let Some(errno) = wasi_call(&mut CallerMemory { caller: &mut caller, memory }, name, &args) {
+                        results[0] = Val::I32(errno);
+                    }
+                    Ok(())
+                })
+                .unwrap();
+        }
+        let instance = linker.instantiate(&mut store, &module).expect("wasmtime failed to instantiate module");
+        let memory = buffer_memory
+            .or(imported)
+            .or_else(|| instance.get_memory(&mut store, "memory"))
+            .expect("module neither imports nor exports memory");
+        if buffer_memory.is_some() {
+            println!("Container: mapping the buffers into a dedicated memory");
+        }
+        if !fuel.is_empty() {
+            println!("Container: limiting calls to {:?}", fuel);
+        }
+        let created_memory = buffer_memory.or(imported).is_some();
+        Self { store, instance, memory, created_memory, memory64, layout, fuel, watchdog: None }
+    }
+
+    // Leaves the store with the fuel a call to 'name' may use, or as much as it can hold if the
+    // export has no budget.
+    fn refuel(&mut self, name: &str) {
+        let budget = self.fuel.budget(name).unwrap_or(u64::MAX);
+        // consume_fuel fails once the fuel is all gone.
+        let left = self.store.consume_fuel(0).unwrap_or(0);
+        if left < budget {
+            self.store.add_fuel(budget - left).expect("wasmtime failed to add fuel");
+        } else {
+            self.store.consume_fuel(left - budget).expect("wasmtime failed to remove fuel");
+        }
+    }
+
+    // An interrupt that lands once the wasm has stopped running can wait for the next call, which
+    // it traps on entry (or it can be lost as the call unwinds). This spends any such interrupt on
+    // a call that does nothing, so the next real call isn't lost.
+    fn absorb_interrupt(&mut self) {
+        let func = self.instance.get_func(&mut self.store, "shared_buffers_abi_version").unwrap();
+        let mut results = [Val::I32(0)];
+        func.call(&mut self.store, &[], &mut results).ok();
+    }
+}
+
+impl Instance for WasmtimeInstance {
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError> {
+        let args: Vec<_> = args.iter().map(|&arg| arg as i64).collect();
+        Ok(self.try_call_wide(name, &args)?.map(|result| result as i32))
+    }
+
+    fn try_call_wide(&mut self, name: &str, args: &[i64]) -> Result<Option<i64>, CallError> {
+        let func = self
+            .instance
+            .get_func(&mut self.store, name)
+            .ok_or_else(|| CallError::new(name, format!("module does not export {}", name)))?;
+        let ty = func.ty(&self.store);
+        let params: Vec<_> = ty.params().collect();
+        // Args past the params are left for the call to reject.
+        let arg = |(i, &arg): (usize, &i64)| match params.get(i) {
+            Some(ValType::I64) => Ok(Val::I64(arg)),
+            _ => wasm_i32(name, arg).map(Val::I32),
+        };
+        let args = args.iter().enumerate().map(arg).collect::<Result<Vec<_>, _>>()?;
+        let mut results = vec![Val::I32(0); ty.results().len()];
+        if !self.fuel.is_empty() {
+            self.refuel(name);
+        }
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.begin_call();
+        }
+        let result = func.call(&mut self.store, &args, &mut results);
+        let interrupted = self.watchdog.as_ref().is_some_and(Watchdog::end_call);
+        let trap = result.as_ref().err().and_then(|e| e.downcast_ref::<Trap>());
+        let trapped_on_interrupt = trap.and_then(Trap::trap_code) == Some(TrapCode::Interrupt);
+        if interrupted && !trapped_on_interrupt {
+            self.absorb_interrupt();
+        }
+        if let Err(e) = &result {
+            if trapped_on_interrupt {
+                let message = format!("wasmtime call '{}' was interrupted at its deadline", name);
+                return Err(CallError::new(name, message).with_kind(TrapKind::Interrupted));
+            }
+            if !self.fuel.is_empty() && self.store.consume_fuel(0).is_err() {
+                let budget = self.fuel.budget(name).unwrap();
+                let message = format!("wasmtime call '{}' ran out of fuel (budget {})", name, budget);
+                return Err(CallError::new(name, message).with_kind(TrapKind::OutOfFuel));
+            }
+            return Err(match trap {
+                Some(trap) => trap_error(name, trap),
+                None => CallError::new(name, format!("wasmtime call '{}' failed: {}", name, e)),
+            });
+        }
+        Ok(results.first().and_then(|v| v.i64().or_else(|| v.i32().map(|v| v as u32 as i64))))
+    }
+
+    fn memory_base(&self) -> i64 {
+        self.memory.data_ptr(&self.store) as i64
+    }
+
+    fn memory_size(&self) -> Option<u64> {
+        Some(self.memory.data_size(&self.store) as u64)
+    }
+
+    fn export(&mut self, name: &str) -> Export {
+        let ty = match self.instance.get_func(&mut self.store, name) {
+            Some(func) => func.ty(&self.store),
+            None => return Export::Missing,
+        };
+        Export::Function(signature(ty.params().map(wasm_type_letter), ty.results().map(wasm_type_letter)))
+    }
+
+    fn grow_memory(&mut self, pages: u32) -> Option<u32> {
+        let old_pages = self.created_memory.then(|| self.memory.grow(&mut self.store, pages as u64));
+        old_pages.map(|old_pages| old_pages.expect("wasmtime failed to grow memory") as u32)
+    }
+
+    fn meters_fuel(&self) -> bool {
+        true
+    }
+
+    fn memory64(&self) -> bool {
+        self.memory64
+    }
+
+    fn set_layout(&mut self, layout: [i64; 4]) {
+        for &(index, global) in &self.layout {
+            let value = match global.ty(&self.store).content() {
+                ValType::I64 => Val::I64(layout[index]),
+                _ => Val::I32(wasm_i32(LAYOUT_GLOBALS[index], layout[index]).unwrap_or_else(|err| panic!("{}", err))),
+            };
+            global.set(&mut self.store, value).expect("wasmtime failed to set a layout global");
+        }
+    }
+
+    fn set_deadline(&mut self, deadline_us: Option<i64>) -> bool {
+        if self.watchdog.is_none() && deadline_us.is_some() {
+            let handle = self.store.interrupt_handle().expect("wasmtime can't interrupt calls");
+            self.watchdog = Some(Watchdog::start(handle));
+        }
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.set_deadline(deadline_us);
+        }
+        true
+    }
+}
+
+// Defines the "env" callbacks other than the clocks, whose lengths and pointers are 'T's: u32s, or
+// u64s for a module with a 64-bit memory.
+fn define_callbacks<T: WasmTy + Into<u64> + 'static>(
+    linker: &mut Linker<()>,
+    memory_of: impl Fn(&mut Caller<'_, ()>) -> Memory + Copy + Send + Sync + 'static,
+) {
+    // A string outside linear memory traps the call.
+    let trap = |_: OutOfBounds| Trap::new("module passed a string outside linear memory");
+    linker
+        .func_wrap("env", "print_callback", move |mut caller: Caller<'_, ()>, len: T, msg: T| {
+            let memory = memory_of(&mut caller).data(&caller);
+            host_common::print_callback(memory, len.into(), msg.into()).map_err(trap)
+        })
+        .unwrap();
+    linker
+        .func_wrap("env", "assert_callback", move |mut caller: Caller<'_, ()>, cond: i32, len: T, msg: T| {
+            let memory = memory_of(&mut caller).data(&caller);
+            host_common::assert_callback(memory, cond, len.into(), msg.into()).map_err(trap)
+        })
+        .unwrap();
+    linker
+        .func_wrap("env", "event_callback", move |mut caller: Caller<'_, ()>, kind: u32, value: i64, len: T, msg: T| {
+            let memory = memory_of(&mut caller).data(&caller);
+            host_common::event_callback(memory, kind, value, len.into(), msg.into()).map_err(trap)
+        })
+        .unwrap();
+    linker
+        .func_wrap("env", "fill_random", move |mut caller: Caller<'_, ()>, ptr: T, len: T| {
+            let memory = memory_of(&mut caller);
+            fill_random(memory.data_mut(&mut caller), ptr.into(), len.into())
+        })
+        .unwrap();
+}
+
+// Compiles the module, or loads it from the module cache (see MODULE_CACHE_ENV) after the first
+// time. A module compiled for another configuration of the engine won't load, so 'variant' tells
+// them apart; one compiled by another version of wasmtime is compiled again and replaced.
+fn load_module(engine: &Engine, bytes: &[u8], variant: &str) -> Module {
+    let compile = || Module::new(engine, bytes).expect("wasmtime failed to load module");
+    let path = match module_cache_dir() {
+        Some(dir) => dir.join("wasmtime").join(format!("{}{}.cwasm", module_cache_key(bytes), variant)),
+        None => return compile(),
+    };
+    // Only as safe as the cache: wasmtime runs the compiled code it loads without checking it.
+    if let Ok(module) = unsafe { Module::deserialize_file(engine, &path) } {
+        println!("Container: loaded the compiled module from {}", path.display());
+        return module;
+    }
+    let module = compile();
+    let cached = module.serialize().map_err(|err| err.to_string());
+    match cached.and_then(|cached| write_cache_entry(&path, &cached).map_err(|err| err.to_string())) {
+        Ok(()) => println!("Container: cached the compiled module in {}", path.display()),
+        Err(err) => println!("Container: couldn't cache the compiled module in {}: {}", path.display(), err),
+    }
+    module
+}
+
+// wasmtime's Display for a trap appends its backtrace, which goes in the CallError's frames here.
+fn trap_error(name: &str, trap: &Trap) -> CallError {
+    let display = trap.to_string();
+    let reason = display.split("\nwasm backtrace:").next().unwrap();
+    let err = CallError::new(name, format!("wasmtime call '{}' failed: {}", name, reason));
+    let kind = match trap.trap_code() {
+        Some(TrapCode::MemoryOutOfBounds | TrapCode::HeapMisaligned | TrapCode::TableOutOfBounds) => {
+            TrapKind::OutOfBounds
+        }
+        Some(TrapCode::StackOverflow) => TrapKind::StackOverflow,
+        Some(TrapCode::IndirectCallToNull | TrapCode::BadSignature) => TrapKind::BadIndirectCall,
+        Some(TrapCode::IntegerOverflow) => TrapKind::IntegerOverflow,
+        Some(TrapCode::IntegerDivisionByZero) => TrapKind::DivideByZero,
+        Some(TrapCode::BadConversionToInteger) => TrapKind::BadConversion,
+        Some(TrapCode::UnreachableCodeReached) => TrapKind::Unreachable,
+        Some(TrapCode::Interrupt) => TrapKind::Interrupted,
+        // No code means an import returned the trap, so its message says what happened.
+        _ => err.kind,
+    };
+    let frames = trap.trace().iter().map(|frame| {
+        let function = frame.func_name().map_or_else(|| format!("function {}", frame.func_index()), str::to_string);
+        format!("{} at {:#x} in {}", function, frame.module_offset(), frame.module_name().unwrap_or("the module"))
+    });
+    err.with_kind(kind).with_backtrace(frames.collect())
+}
+
+// Interrupts the store's wasm when a call is running at the deadline.
+struct Watchdog {
+    state: Arc<(Mutex<Watch>, Condvar)>,
+}
+
+struct Watch {
+    handle: InterruptHandle,
+    deadline_us: Option<i64>,
+    calling: bool,
+    // Whether the current call has been interrupted.
+    fired: bool,
+    // Set when the instance is dropped (as it is on ReloadModule), to end the thread.
+    stopped: bool,
+}
+
+impl Watch {
+    // Interrupts the call if it's past the deadline, returning how long is left otherwise.
+    fn check(&mut self) -> Option<Duration> {
+        let deadline_us = self.deadline_us.filter(|_| self.calling && !self.fired)?;
+        let left = deadline_us - now_us();
+        if left > 0 {
+            return Some(Duration::from_micros(left as u64));
+        }
+        self.handle.interrupt();
+        self.fired = true;
+        None
+    }
+}
+
+impl Watchdog {
+    fn start(handle: InterruptHandle) -> Self {
+        let watch = Watch { handle, deadline_us: None, calling: false, fired: false, stopped: false };
+        let state = Arc::new((Mutex::new(watch), Condvar::new()));
+        let watched = state.clone();
+        thread::spawn(move || {
+            let (lock, changed) = &*watched;
+            let mut watch = lock.lock().unwrap();
+            while !watch.stopped {
+                watch = match watch.check() {
+                    Some(left) => changed.wait_timeout(watch, left).unwrap().0,
+                    None => changed.wait(watch).unwrap(),
+                };
+            }
+        });
+        Self { state }
+    }
+
+    fn set_deadline(&self, deadline_us: Option<i64>) {
+        self.update(|watch| watch.deadline_us = deadline_us);
+    }
+
+    // A call that starts after the deadline is interrupted here rather than by the thread, which
+    // might not wake until a short call is over.
+    fn begin_call(&self) {
+        self.update(|watch| {
+            watch.calling = true;
+            watch.fired = false;
+            watch.check();
+        });
+    }
+
+    // Returns whether the call was interrupted.
+    fn end_call(&self) -> bool {
+        let mut fired = false;
+        self.update(|watch| {
+            watch.calling = false;
+            fired = watch.fired;
+        });
+        fired
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Watch)) {
+        let (lock, changed) = &*self.state;
+        change(&mut lock.lock().unwrap());
+        changed.notify_one();
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.update(|watch| watch.stopped = true);
+    }
+}
+
+// A wasm type in WASI_FUNCTIONS' notation.
+fn wasm_type(code: char) -> ValType {
+    if code == 'I' {
+        ValType::I64
+    } else {
+        ValType::I32
+    }
+}
+
+// The other way, for any type; '?' for the reference types, which the notation has no letter for.
+fn wasm_type_letter(ty: ValType) -> char {
+    match ty {
+        ValType::I32 => 'i',
+        ValType::I64 => 'I',
+        ValType::F32 => 'f',
+        ValType::F64 => 'F',
+        _ => '?',
+    }
+}
+
+// The module's memory as its WASI calls see it.
+struct CallerMemory<'a, 'b> {
+    caller: &'a mut Caller<'b, ()>,
+    memory: Memory,
+}
+
+impl WasiMemory for CallerMemory<'_, '_> {
+    fn read(&self, offset: u32, buf: &mut [u8]) -> bool {
+        self.memory.read(&*self.caller, offset as usize, buf).is_ok()
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> bool {
+        self.memory.write(&mut *self.caller, offset as usize, data).is_ok()
+    }
+
+    fn size(&self) -> u64 {
+        self.memory.data_size(&*self.caller) as u64
+    }
+}
@@ -0,0 +1,512 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Runs the hunter and runner modules under two different engines in a single process,
+// with each engine's instances mapped onto their own read-write buffer, and compares the
+// actor data in those buffers after every tick. Any divergence indicates an engine-specific
+// dependency in the modules or the buffer mapping code.
+
+use common::host_common::*;
+use common::shared::{cptr, EventKind};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{cell::RefCell, fs::File, io::prelude::*, process, slice};
+use wasmi::{
+    memory_units::Pages, Error, Externals, FuncInstance, FuncRef, ImportsBuilder, MemoryDescriptor,
+    MemoryInstance, MemoryRef, ModuleImportResolver, ModuleInstance, ModuleRef, RuntimeArgs, RuntimeValue,
+    Signature, Trap,
+};
+use wasmtime::{Caller, Engine, ExternType, Linker, Memory, MemoryType, Store, Val};
+
+const DEFAULT_TICKS: usize = 500;
+// --quick runs a short, fixed test (e.g. for CI) in place of the ticks and seed args.
+const QUICK_TICKS: usize = 50;
+const QUICK_SEED: u64 = 1;
+
+fn main() {
+    let usage = "usage: differential hunter.wasm runner.wasm ([ticks] [seed] | --quick)";
+    let (flags, args): (Vec<_>, Vec<_>) = std::env::args().partition(|arg| arg == "--quick");
+    let hunter_path = args.get(1).expect(usage);
+    let runner_path = args.get(2).expect(usage);
+    let (ticks, seed) = if flags.is_empty() {
+        let ticks = args.get(3).map_or(DEFAULT_TICKS, |s| s.parse().expect(usage));
+        let seed = args.get(4).map_or_else(|| rand::thread_rng().gen(), |s| s.parse().expect(usage));
+        (ticks, seed)
+    } else {
+        (QUICK_TICKS, QUICK_SEED)
+    };
+    println!("Differential test: {} ticks, seed {}", ticks, seed);
+
+    // Both engines map the same read-only grid; each gets its own read-write buffer. The names are
+    // in a session of this process's own, so runs alongside a host or each other don't collide.
+    let ro_name = session_name(READ_ONLY_BUF_NAME, &diff_session(""));
+    let grid = SharedBuffer::create(&ro_name, READ_ONLY_BUF_SIZE);
+    init_grid(grid.ptr(), seed);
+
+    let hunter_bytes = read_module(hunter_path);
+    let runner_bytes = read_module(runner_path);
+    let mut worlds = [
+        World::new(
+            "wasmi",
+            &ro_name,
+            seed,
+            Box::new(WasmiInstance::new(&hunter_bytes, "h")),
+            Box::new(WasmiInstance::new(&runner_bytes, "r")),
+        ),
+        World::new(
+            "wasmtime",
+            &ro_name,
+            seed,
+            Box::new(WasmtimeInstance::new(&hunter_bytes, "h")),
+            Box::new(WasmtimeInstance::new(&runner_bytes, "r")),
+        ),
+    ];
+
+    let mut result = compare(&worlds, 0);
+    for tick in 1..=ticks {
+        if result.is_err() {
+            break;
+        }
+        for world in &mut worlds {
+            world.tick();
+        }
+        result = compare(&worlds, tick).and_then(|()| check_actors(&worlds[0], tick));
+    }
+
+    // process::exit() skips destructors, so the buffers are unlinked first.
+    drop(worlds);
+    drop(grid);
+    match result {
+        Ok(()) => println!("Engines matched for all {} ticks", ticks),
+        Err(msg) => {
+            println!(">> {}", msg);
+            process::exit(1);
+        }
+    }
+}
+
+fn read_module(path: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    File::open(path)
+        .unwrap_or_else(|_| panic!("failed to open {}", path))
+        .read_to_end(&mut bytes)
+        .unwrap();
+    bytes
+}
+
+// Same layout as the GTK host's Grid::init, but driven by the test seed.
+fn init_grid(grid: cptr, seed: u64) {
+    let grid = unsafe { grid.add(HEADER_BYTES as usize) as *mut i32 };
+    let data = unsafe { slice::from_raw_parts_mut(grid, (GRID_W * GRID_H) as usize) };
+    let mut rng = StdRng::seed_from_u64(seed);
+    for y in 0..GRID_H {
+        for x in 0..GRID_W {
+            let edge = x == 0 || y == 0 || x == GRID_W - 1 || y == GRID_H - 1;
+            data[(y * GRID_W + x) as usize] = edge as i32;
+        }
+    }
+    for _ in 0..N_BLOCKS {
+        let x = rng.gen_range(1..=GRID_W - 2);
+        let y = rng.gen_range(1..=GRID_H - 2);
+        data[(y * GRID_W + x) as usize] = 1;
+    }
+}
+
+// Checks that the actor data (everything after the header and signal bytes) is identical across engines.
+fn compare(worlds: &[World; 2], tick: usize) -> Result<(), String> {
+    let (a, b) = (worlds[0].actors(), worlds[1].actors());
+    match a.iter().zip(b).position(|(va, vb)| va != vb) {
+        None => Ok(()),
+        Some(i) => Err(format!(
+            "tick {}: {} differs; {} = {}, {} = {}",
+            tick, describe_index(i), worlds[0].engine, a[i], worlds[1].engine, b[i]
+        )),
+    }
+}
+
+// Checks invariants that hold whichever engine is used; the engines already match at this point.
+// Actors can never leave the grid's walled border, and every runner state must be valid.
+fn check_actors(world: &World, tick: usize) -> Result<(), String> {
+    let actors = world.actors();
+    // Indexes of the x field of each actor: the hunter, then each runner.
+    let positions = std::iter::once(0).chain((2..actors.len()).step_by(3));
+    for i in positions {
+        let (x, y) = (actors[i], actors[i + 1]);
+        if !(1..GRID_W - 1).contains(&x) || !(1..GRID_H - 1).contains(&y) {
+            return Err(format!("tick {}: {} is outside the grid at ({}, {})", tick, describe_index(i), x, y));
+        }
+        if i > 0 && !(0..3).contains(&actors[i + 2]) {
+            return Err(format!("tick {}: {} is {}", tick, describe_index(i + 2), actors[i + 2]));
+        }
+    }
+    Ok(())
+}
+
+// Converts an i32 index into the actor data into a readable field name.
+fn describe_index(i: usize) -> String {
+    let field = |j| ["x", "y", "state"][j];
+    match i {
+        0 | 1 => format!("hunter.{}", field(i)),
+        _ => format!("runner[{}].{}", (i - 2) / 3, field((i - 2) % 3)),
+    }
+}
+
+// The hunter and runner instances for one engine, sharing a read-write buffer.
+struct World {
+    engine: &'static str,
+    shared_rw: SharedBuffer,
+    hunter: Module,
+    runner: Module,
+}
+
+fn diff_session(engine: &str) -> String {
+    session_name(&format!("diff_{}", process::id()), engine)
+}
+
+impl World {
+    fn new(
+        engine: &'static str,
+        ro_name: &str,
+        seed: u64,
+        hunter: Box<dyn Instance>,
+        runner: Box<dyn Instance>,
+    ) -> Self {
+        let rw_name = session_name(READ_WRITE_BUF_NAME, &diff_session(engine));
+        let shared_rw = SharedBuffer::create(&rw_name, READ_WRITE_BUF_SIZE);
+        let mut world = Self {
+            engine,
+            hunter: Module::new(hunter, ro_name, shared_rw.name()),
+            runner: Module::new(runner, ro_name, shared_rw.name()),
+            shared_rw,
+        };
+        // The wasm modules take an i32 seed.
+        world.hunter.init(seed as i32);
+        world.runner.init(seed as i32);
+        world
+    }
+
+    // As in the containers, both modules observe before either moves.
+    fn tick(&mut self) {
+        self.hunter.observe();
+        self.runner.observe();
+        self.hunter.tick();
+        self.runner.tick();
+    }
+
+    fn actors(&self) -> &[i32] {
+        let len = (READ_WRITE_BUF_SIZE - ACTORS_OFFSET) as usize / 4;
+        unsafe { slice::from_raw_parts(self.shared_rw.ptr().add(ACTORS_OFFSET as usize) as *const i32, len) }
+    }
+}
+
+// A wasm instance with the shared buffers mapped into its linear memory.
+struct Module {
+    instance: Box<dyn Instance>,
+    context: i32,
+}
+
+impl Module {
+    // Mirrors the container setup: reserve space in linear memory, then map the buffers at
+    // page-aligned locations inside it.
+    fn new(mut instance: Box<dyn Instance>, ro_name: &str, rw_name: &str) -> Self {
+        check_abi_version(&mut *instance);
+        let alloc_index = reserve_memory(&mut *instance, WASM_ALLOC_SIZE);
+        let base = instance.memory_base();
+        let (ro_index, rw_index) = map_buffers(&*instance, alloc_index, ro_name, rw_name);
+        let context = instance
+            .call("create_context", &[ro_index, rw_index])
+            .expect("create_context returned no value");
+        // Growing linear memory in create_context can move it, leaving the buffers behind.
+        if instance.memory_base() != base {
+            let (ro_index, rw_index) = map_buffers(&*instance, alloc_index, ro_name, rw_name);
+            let ro_size = wasm_usize(READ_ONLY_BUF_SIZE - HEADER_BYTES);
+            let rw_size = wasm_usize(READ_WRITE_BUF_SIZE - ACTORS_OFFSET);
+            instance.call("update_context", &[context, ro_index, rw_index, ro_size, rw_size]);
+        }
+        Self { instance, context }
+    }
+
+    fn init(&mut self, seed: i32) {
+        self.instance.call("init", &[self.context, seed]);
+    }
+
+    fn observe(&mut self) {
+        self.instance.call("observe", &[self.context]);
+    }
+
+    fn tick(&mut self) {
+        let base = self.instance.memory_base();
+        self.instance.call("tick", &[self.context]);
+        assert_eq!(base, self.instance.memory_base(), "linear memory moved during tick");
+    }
+}
+
+// Maps the buffers at page-aligned locations in the space reserved at 'alloc_index', returning
+// their indexes for the module's context, which skips over the buffer headers and the signal
+// slots in the rw buffer.
+fn map_buffers(instance: &dyn Instance, alloc_index: i64, ro_name: &str, rw_name: &str) -> (i32, i32) {
+    let base = instance.memory_base();
+    let aligned_ro = page_align(base + alloc_index);
+    let aligned_rw = page_align(aligned_ro + READ_ONLY_BUF_SIZE as i64);
+    map_buffer(aligned_ro, ro_name, 0, READ_ONLY_BUF_SIZE, Access::ReadOnly);
+    map_buffer(aligned_rw, rw_name, 0, READ_WRITE_BUF_SIZE, Access::ReadWrite);
+    let ro_index = wasm_usize((aligned_ro - base) as u64 + HEADER_BYTES);
+    let rw_index = wasm_usize((aligned_rw - base) as u64 + ACTORS_OFFSET);
+    (ro_index, rw_index)
+}
+
+// -- wasmi --
+
+struct WasmiInstance {
+    instance: ModuleRef,
+    externs: WasmiExterns,
+    imported_memory: bool,
+}
+
+impl WasmiInstance {
+    fn new(bytes: &[u8], label: &'static str) -> Self {
+        let module = wasmi::Module::from_buffer(bytes).expect("wasmi failed to load module");
+        let resolver = WasmiResolver::default();
+        let imports = ImportsBuilder::new().with_resolver("env", &resolver);
+        let instance = ModuleInstance::new(&module, &imports)
+            .expect("wasmi failed to instantiate module")
+            .assert_no_start();
+        let imported = resolver.memory.into_inner();
+        let imported_memory = imported.is_some();
+        let memory = imported
+            .or_else(|| instance.export_by_name("memory").and_then(|export| export.as_memory().cloned()))
+            .expect("module neither imports nor exports memory");
+        Self { instance, externs: WasmiExterns { memory, label }, imported_memory }
+    }
+}
+
+impl Instance for WasmiInstance {
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError> {
+        let args: Vec<_> = args.iter().map(|&v| RuntimeValue::I32(v)).collect();
+        match self.instance.invoke_export(name, &args, &mut self.externs) {
+            Ok(Some(RuntimeValue::I32(v))) => Ok(Some(v)),
+            Ok(_) => Ok(None),
+            Err(e) => Err(CallError::new(name, format!("wasmi call '{}' failed: {:?}", name, e))),
+        }
+    }
+
+    fn memory_base(&self) -> i64 {
+        self.externs.memory.with_direct_access(|buf| buf.as_ptr() as i64)
+    }
+
+    fn grow_memory(&mut self, pages: u32) -> Option<u32> {
+        let memory = &self.externs.memory;
+        self.imported_memory.then(|| memory.grow(Pages(pages as usize)).expect("wasmi failed to grow memory").0 as u32)
+    }
+}
+
+const PRINT_CALLBACK: usize = 0;
+const ASSERT_CALLBACK: usize = 1;
+const TIME_CALLBACK: usize = 2;
+const EVENT_CALLBACK: usize = 3;
+const NOW_MICROS: usize = 4;
+const FILL_RANDOM: usize = 5;
+
+struct WasmiExterns {
+    memory: MemoryRef,
+    label: &'static str,
+}
+
+impl Externals for WasmiExterns {
+    fn invoke_index(&mut self, index: usize, args: RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        match index {
+            PRINT_CALLBACK => {
+                let mut buf = vec![0; args.nth::<u32>(0) as usize];
+                self.memory.get_into(args.nth::<u32>(1), &mut buf[..]).unwrap();
+                print!("[{}/wasmi] {}", self.label, String::from_utf8_lossy(&buf));
+                Ok(None)
+            }
+            ASSERT_CALLBACK => {
+                if args.nth::<i32>(0) == 0 {
+                    let mut buf = vec![0; args.nth::<u32>(1) as usize];
+                    self.memory.get_into(args.nth::<u32>(2), &mut buf[..]).unwrap();
+                    println!("[{}/wasmi] >> module assertion failed: {}", self.label, String::from_utf8_lossy(&buf));
+                }
+                Ok(None)
+            }
+            TIME_CALLBACK => Ok(Some(RuntimeValue::I64(now_us()))),
+            NOW_MICROS => Ok(Some(RuntimeValue::I64(monotonic_us()))),
+            FILL_RANDOM => {
+                let size = self.memory.with_direct_access(|buf| buf.len() as u64);
+                Ok(Some(RuntimeValue::I32(declined_fill_random(size, args.nth(0), args.nth(1)))))
+            }
+            EVENT_CALLBACK => {
+                let mut buf = vec![0; args.nth::<u32>(2) as usize];
+                self.memory.get_into(args.nth::<u32>(3), &mut buf[..]).unwrap();
+                let text = String::from_utf8_lossy(&buf);
+                println!("[{}/wasmi] {}", self.label, event_text(args.nth(0), args.nth(1), &text));
+                Ok(None)
+            }
+            _ => panic!("unimplemented function at {}", index),
+        }
+    }
+}
+
+// fill_random under both engines: declined, so the modules use their seeded generator, except that
+// a range outside linear memory is a fault, as it is in the containers.
+fn declined_fill_random(memory_size: u64, ptr: u32, len: u32) -> i32 {
+    if ptr as u64 + len as u64 <= memory_size {
+        FILL_RANDOM_UNAVAILABLE
+    } else {
+        FILL_RANDOM_FAULT
+    }
+}
+
+// Events from the modules are printed like the log lines, since there is no host to route them.
+fn event_text(kind: u32, value: i64, text: &str) -> String {
+    match EventKind::from(kind) {
+        Some(EventKind::Log) => text.trim_end().to_string(),
+        Some(EventKind::Metric) => format!("metric {} = {}", text, value),
+        Some(EventKind::Error) => format!(">> {}", text),
+        Some(EventKind::Custom) => format!("event {}: {}", value, text),
+        None => format!(">> unknown event kind {}: {}", kind, text),
+    }
+}
+
+// Creates the module's memory if it imports one.
+#[derive(Default)]
+struct WasmiResolver {
+    memory: RefCell<Option<MemoryRef>>,
+}
+
+impl ModuleImportResolver for WasmiResolver {
+    fn resolve_func(&self, field_name: &str, signature: &Signature) -> Result<FuncRef, Error> {
+        match field_name {
+            "print_callback" => Ok(FuncInstance::alloc_host(signature.clone(), PRINT_CALLBACK)),
+            "assert_callback" => Ok(FuncInstance::alloc_host(signature.clone(), ASSERT_CALLBACK)),
+            "time_callback" => Ok(FuncInstance::alloc_host(signature.clone(), TIME_CALLBACK)),
+            "event_callback" => Ok(FuncInstance::alloc_host(signature.clone(), EVENT_CALLBACK)),
+            "now_micros" => Ok(FuncInstance::alloc_host(signature.clone(), NOW_MICROS)),
+            "fill_random" => Ok(FuncInstance::alloc_host(signature.clone(), FILL_RANDOM)),
+            _ => panic!("unexpected import {}", field_name),
+        }
+    }
+
+    fn resolve_memory(&self, field_name: &str, descriptor: &MemoryDescriptor) -> Result<MemoryRef, Error> {
+        assert_eq!(field_name, "memory", "unexpected memory import");
+        let maximum = descriptor.maximum().map(|pages| Pages(pages as usize));
+        let memory = MemoryInstance::alloc(Pages(descriptor.initial() as usize), maximum)?;
+        *self.memory.borrow_mut() = Some(memory.clone());
+        Ok(memory)
+    }
+}
+
+// -- wasmtime --
+
+struct WasmtimeInstance {
+    store: Store<()>,
+    instance: wasmtime::Instance,
+    memory: Memory,
+    imported_memory: bool,
+}
+
+impl WasmtimeInstance {
+    fn new(bytes: &[u8], label: &'static str) -> Self {
+        let engine = Engine::default();
+        let module = wasmtime::Module::new(&engine, bytes).expect("wasmtime failed to load module");
+        let mut store = Store::new(&engine, ());
+        let mut linker = Linker::new(&engine);
+        // wasmtime reserves the whole 32-bit range for a memory created here, so it doesn't move when
+        // it grows.
+        let imported = module.imports().find_map(|import| match import.ty() {
+            ExternType::Memory(ty) => {
+                let ty = MemoryType::new(ty.minimum() as u32, None);
+                Some(Memory::new(&mut store, ty).expect("wasmtime failed to create memory"))
+            }
+            _ => None,
+        });
+        if let Some(memory) = imported {
+            linker.define("env", "memory", memory).unwrap();
+        }
+        let memory_of = move |caller: &mut Caller<'_, ()>| {
+            imported.or_else(|| caller.get_export("memory").and_then(|e| e.into_memory())).unwrap()
+        };
+        linker
+            .func_wrap("env", "print_callback", move |mut caller: Caller<'_, ()>, len: u32, msg: u32| {
+                let memory = memory_of(&mut caller);
+                let mut buf = vec![0; len as usize];
+                memory.read(&caller, msg as usize, &mut buf).unwrap();
+                print!("[{}/wasmtime] {}", label, String::from_utf8_lossy(&buf));
+            })
+            .unwrap();
+        linker
+            .func_wrap("env", "assert_callback", move |mut caller: Caller<'_, ()>, cond: i32, len: u32, msg: u32| {
+                if cond == 0 {
+                    let memory = memory_of(&mut caller);
+                    let mut buf = vec![0; len as usize];
+                    memory.read(&caller, msg as usize, &mut buf).unwrap();
+                    println!("[{}/wasmtime] >> module assertion failed: {}", label, String::from_utf8_lossy(&buf));
+                }
+            })
+            .unwrap();
+        linker.func_wrap("env", "time_callback", now_us).unwrap();
+        linker.func_wrap("env", "now_micros", monotonic_us).unwrap();
+        linker
+            .func_wrap("env", "fill_random", move |mut caller: Caller<'_, ()>, ptr: u32, len: u32| {
+                let size = memory_of(&mut caller).data_size(&caller) as u64;
+                declined_fill_random(size, ptr, len)
+            })
+            .unwrap();
+        linker
+            .func_wrap(
+                "env",
+                "event_callback",
+                move |mut caller: Caller<'_, ()>, kind: u32, value: i64, len: u32, msg: u32| {
+                    let memory = memory_of(&mut caller);
+                    let mut buf = vec![0; len as usize];
+                    memory.read(&caller, msg as usize, &mut buf).unwrap();
+                    println!("[{}/wasmtime] {}", label, event_text(kind, value, &String::from_utf8_lossy(&buf)));
+                },
+            )
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .expect("wasmtime failed to instantiate module");
+        let memory = imported
+            .or_else(|| instance.get_memory(&mut store, "memory"))
+            .expect("module neither imports nor exports memory");
+        Self { store, instance, memory, imported_memory: imported.is_some() }
+    }
+}
+
+impl Instance for WasmtimeInstance {
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError> {
+        let func = self
+            .instance
+            .get_func(&mut self.store, name)
+            .ok_or_else(|| CallError::new(name, format!("module does not export {}", name)))?;
+        let args: Vec<_> = args.iter().map(|&v| Val::I32(v)).collect();
+        let mut results = vec![Val::I32(0); func.ty(&self.store).results().len()];
+        if let Err(e) = func.call(&mut self.store, &args, &mut results) {
+            return Err(CallError::new(name, format!("wasmtime call '{}' failed: {:?}", name, e)));
+        }
+        Ok(results.first().and_then(|v| v.i32()))
+    }
+
+    fn memory_base(&self) -> i64 {
+        self.memory.data_ptr(&self.store) as i64
+    }
+
+    fn grow_memory(&mut self, pages: u32) -> Option<u32> {
+        let old_pages = self.imported_memory.then(|| self.memory.grow(&mut self.store, pages as u64));
+        old_pages.map(|old_pages| old_pages.expect("wasmtime failed to grow memory") as u32)
+    }
+}
+
@@ -13,21 +13,224 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
+use common::control_socket::ControlSocket;
 use common::host_common::*;
-use common::shared::{cptr, State};
+use common::shared::{cptr, Arena, DrawCmd, DrawList, EventKind, GridControl, SeqLocked, Shape, State, TickStats};
+use common::wasm_binary::is_memory64;
 use fork::{fork, Fork};
 use gtk::{cairo, gio, prelude::*};
-use libc::{MAP_SHARED, O_CREAT, O_RDWR, O_TRUNC, PROT_READ, PROT_WRITE, S_IRUSR, S_IWUSR};
 use rand::Rng;
-use std::{cell::RefCell, ffi::CString, process, rc::Rc, slice, thread, time::Duration};
+use shm_signal::{Notifier, WAIT_ENV};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    convert::TryInto,
+    process,
+    rc::Rc,
+    slice,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
 
 fn main() {
     println!("Host started; pid {}", process::id());
     assert_eq!(PAGE_SIZE, unsafe { libc::sysconf(libc::_SC_PAGESIZE) });
 
-    let hunter_path = std::env::args().nth(1).expect("missing hunter module path arg");
-    let runner_path = std::env::args().nth(2).expect("missing runner module path arg");
-    let ctx = Rc::new(RefCell::new(HostContext::new(&hunter_path, &runner_path)));
+    // Strip the host's own flags before the args are passed on to GTK.
+    let host_flags = ["--persist", "--latency-json", "--seal-grid", "--double-buffer-grid"];
+    let (flags, args): (Vec<_>, Vec<_>) =
+        std::env::args().partition(|arg| {
+            arg.starts_with("--chaos")
+                || arg.starts_with("--record")
+                || arg.starts_with("--session=")
+                || arg.starts_with("--notify=")
+                || arg.starts_with("--control=")
+                || arg.starts_with("--vm=")
+                || arg.starts_with("--modify-grid=")
+                || arg.starts_with("--lag=")
+                || arg.starts_with("--call=")
+                || arg.starts_with("--wait=")
+                || arg.starts_with("--fuel=")
+                || arg.starts_with("--tick-deadline")
+                || arg.starts_with("--module-cache=")
+                || arg.starts_with("--snapshots")
+                || arg.starts_with("--engine=")
+                || arg.starts_with("--module-grid")
+                || host_flags.contains(&arg.as_str())
+        });
+    let chaos = flags.iter().rfind(|arg| arg.starts_with("--chaos")).map(|arg| Chaos::parse(arg));
+    let persist = flags.iter().any(|arg| arg == "--persist");
+    let latency_json = flags.iter().any(|arg| arg == "--latency-json");
+    let seal_grid = flags.iter().any(|arg| arg == "--seal-grid");
+    let double_grid = flags.iter().any(|arg| arg == "--double-buffer-grid");
+    let recorder = flags.iter().rfind(|arg| arg.starts_with("--record")).map(|arg| Recorder::parse(arg));
+    let lag = flags.iter().rfind(|arg| arg.starts_with("--lag=")).map_or(LagPolicy::Slow, |arg| LagPolicy::parse(arg));
+    // With --tick-deadline[=MS] each tick has to be done within MS (by default TICK_MS) of being
+    // sent; containers whose engines can interrupt calls abort the tick after that.
+    let tick_deadline = flags.iter().rfind(|arg| arg.starts_with("--tick-deadline")).map(|arg| {
+        let ms = match arg.strip_prefix("--tick-deadline=") {
+            Some(ms) => ms.parse().expect("--tick-deadline takes a number of milliseconds"),
+            None if arg == "--tick-deadline" => TICK_MS,
+            None => panic!("unknown flag '{}'", arg),
+        };
+        Duration::from_millis(ms)
+    });
+    let calls: Vec<_> =
+        flags.iter().filter(|arg| arg.starts_with("--call=")).map(|arg| ExportCall::parse(arg)).collect();
+    // Each host names its buffers after its own session so that several can run at once. A
+    // persisted world has to be found again by a later host, so it uses the plain names unless a
+    // session is given.
+    let session = match flags.iter().rfind(|arg| arg.starts_with("--session=")) {
+        Some(arg) => arg["--session=".len()..].to_string(),
+        None if persist => String::new(),
+        None => process::id().to_string(),
+    };
+    println!("Shared buffer session: '{}'", session);
+    // With --notify=eventfd the containers are woken through eventfds they inherit rather than
+    // futexes on their signal slots, with --notify=semaphore through named semaphores they open,
+    // with --notify=signal by SIGUSR1, and with --notify=poll not at all, as both sides poll. The
+    // signals themselves stay in the containers' control blocks.
+    let notify =
+        flags.iter().rfind(|arg| arg.starts_with("--notify=")).map_or("futex", |arg| &arg["--notify=".len()..]);
+    // With --control=queue or --control=socket the signals go through a command queue or a Unix
+    // socket pair per container instead, and with --control=shared through one ring for both.
+    let mut control = flags.iter().rfind(|arg| arg.starts_with("--control=")).map(|arg| &arg["--control=".len()..]);
+    // With --vm=CMD each container runs in a VM that CMD starts, with a vm-bridge in its place
+    // here to pass the control socket's messages on over vsock; see vm-bridge.rs.
+    if let Some(arg) = flags.iter().rfind(|arg| arg.starts_with("--vm=")) {
+        if control.unwrap_or("socket") != "socket" {
+            panic!("--vm relays the control socket, so it can only be combined with --control=socket");
+        }
+        control = Some("socket");
+        std::env::set_var(VM_ENV, &arg["--vm=".len()..]);
+    }
+    // With --wait=SPIN_US[,MIN_US,MAX_US] the host and containers wait on each other as given (see
+    // shm_signal::WaitStrategy) rather than spinning for the default time.
+    if let Some(arg) = flags.iter().rfind(|arg| arg.starts_with("--wait=")) {
+        std::env::set_var(WAIT_ENV, &arg["--wait=".len()..]);
+    }
+    println!("Waiting with {:?}", shm_signal::wait_strategy());
+    // With --module-cache=DIR the containers keep their compiled modules in DIR (see
+    // MODULE_CACHE_ENV), so restarting a container doesn't mean compiling its module again.
+    if let Some(arg) = flags.iter().rfind(|arg| arg.starts_with("--module-cache=")) {
+        std::env::set_var(MODULE_CACHE_ENV, &arg["--module-cache=".len()..]);
+    }
+    // With --snapshots[=DIR] there are buttons to snapshot the containers' linear memory and to
+    // restore it, in DIR if it's given (see SNAPSHOT_DIR_ENV).
+    let snapshots = flags.iter().rfind(|arg| arg.starts_with("--snapshots"));
+    if let Some(arg) = snapshots {
+        match arg.strip_prefix("--snapshots=") {
+            Some(dir) => std::env::set_var(SNAPSHOT_DIR_ENV, dir),
+            None if arg == "--snapshots" => {}
+            None => panic!("unknown flag '{}'", arg),
+        }
+    }
+    // With --fuel=BUDGETS the containers whose engines can meter fuel trap any call that runs out
+    // (see FuelBudgets) and report it, rather than hanging on it.
+    if let Some(arg) = flags.iter().rfind(|arg| arg.starts_with("--fuel=")) {
+        let budgets = &arg["--fuel=".len()..];
+        // Parsed here too, so a bad budget fails before any container starts.
+        FuelBudgets::parse(budgets);
+        std::env::set_var(FUEL_ENV, budgets);
+    }
+    if control.is_some() && notify != "futex" {
+        panic!("--control doesn't use the signal slots, so it can't be combined with --notify");
+    }
+    // Each container's comms as seen by the host and by the container.
+    let create = || match control {
+        Some("queue") => (Comms::Queue, Comms::Queue),
+        Some("shared") => (Comms::Shared, Comms::Shared),
+        Some("socket") => {
+            let (host_end, container_end) = ControlSocket::pair();
+            (Comms::Socket(host_end), Comms::Socket(container_end))
+        }
+        Some(other) => panic!("unknown control '{}'", other),
+        None => {
+            let notifier = Notifier::create(notify).unwrap_or_else(|| panic!("unsupported notifier '{}'", notify));
+            (Comms::Slot(notifier), Comms::Slot(notifier))
+        }
+    };
+    let comms = [create(), create()];
+    if seal_grid && (persist || !cfg!(target_os = "linux")) {
+        panic!("--seal-grid requires Linux and can't be combined with --persist");
+    }
+    if seal_grid && double_grid {
+        panic!("a sealed grid can't be modified, so there's no point double-buffering it");
+    }
+    // With --modify-grid=NAME "Container modifies grid" goes to the named container or group of
+    // containers (see CONTAINER_GROUPS), or to "all" of them, rather than just the runner.
+    let modify_target = flags.iter().rfind(|arg| arg.starts_with("--modify-grid=")).map(|arg| {
+        let name = &arg["--modify-grid=".len()..];
+        Target::parse(name).unwrap_or_else(|| panic!("unknown container or group '{}'", name))
+    });
+    // With --module-grid[=CONTAINER] the grid is the one the container's module (by default the
+    // hunter's) fills in with its init_grid export, rather than the host's random one.
+    let module_grid = flags.iter().rfind(|arg| arg.starts_with("--module-grid")).map(|arg| {
+        let name = match arg.strip_prefix("--module-grid=") {
+            Some(name) => name,
+            None if arg == "--module-grid" => CONTAINER_NAMES[HUNTER_SIGNAL_INDEX],
+            None => panic!("unknown flag '{}'", arg),
+        };
+        let index = CONTAINER_NAMES.iter().position(|&container| container == name);
+        index.unwrap_or_else(|| panic!("unknown container '{}'", name))
+    });
+    if seal_grid && module_grid.is_some() {
+        panic!("a sealed grid is filled in before the containers start, so it can't come from a module");
+    }
+    // With --engine=CONTAINER:ENGINE the container runs its module under another engine's
+    // container binary; see CONTAINER_BINARIES.
+    let mut engines = [CONTAINER_BINARIES[0].0, CONTAINER_BINARIES[1].0];
+    let mut chosen = [false; 2];
+    for arg in flags.iter().filter(|arg| arg.starts_with("--engine=")) {
+        let (container, engine) = arg["--engine=".len()..].split_once(':').expect("--engine takes CONTAINER:ENGINE");
+        let index = CONTAINER_NAMES.iter().position(|&name| name == container);
+        let index = index.unwrap_or_else(|| panic!("unknown container '{}'", container));
+        let binary = CONTAINER_BINARIES.iter().find(|(name, _)| *name == engine);
+        engines[index] = binary.unwrap_or_else(|| panic!("unknown engine '{}'", engine)).0;
+        chosen[index] = true;
+    }
+    let hunter_path = args.get(1).expect("missing hunter module path arg");
+    let runner_path = args.get(2).expect("missing runner module path arg");
+    // A memory64 module goes to an engine that can run it, unless --engine picked one that can't.
+    for (index, path) in [hunter_path, runner_path].iter().enumerate() {
+        let memory64 = std::fs::read(path).is_ok_and(|bytes| is_memory64(&bytes));
+        if !memory64 || MEMORY64_ENGINES.contains(&engines[index]) {
+            continue;
+        }
+        let name = CONTAINER_NAMES[index];
+        if chosen[index] {
+            let engine = engines[index];
+            panic!("{} has a 64-bit memory, which {} can't run; try --engine={}:wasmtime", path, engine, name);
+        }
+        println!("{} has a 64-bit memory, so the {} runs it under {}", path, name, MEMORY64_ENGINES[0]);
+        engines[index] = MEMORY64_ENGINES[0];
+    }
+    let binaries = engines.map(|engine| CONTAINER_BINARIES.iter().find(|(name, _)| *name == engine).unwrap().1);
+    let mut ctx = HostContext::new(
+        hunter_path,
+        runner_path,
+        binaries,
+        &session,
+        comms,
+        chaos,
+        persist,
+        latency_json,
+        seal_grid,
+        double_grid,
+        module_grid,
+    );
+    ctx.recorder = recorder;
+    ctx.lag = lag;
+    ctx.tick_deadline = tick_deadline;
+    ctx.snapshots = snapshots.is_some();
+    if let Some(target) = modify_target {
+        ctx.modify_target = target;
+    }
+    for call in &calls {
+        call.run(&mut ctx);
+    }
+    let ctx = Rc::new(RefCell::new(ctx));
     let app = gtk::Application::new(None, gio::ApplicationFlags::HANDLES_OPEN);
     {
         let ctx = ctx.clone();
@@ -37,100 +240,964 @@ fn main() {
         // glib's timeout infrastructure holds a references that prevents HostContext
         // from being dropped. We need to clear the timeout to fix this.
         glib::source::source_remove(ctx.borrow_mut().timeout_id.take().expect("Timeout could not be taken!?"));
+        if let Some(poll_id) = ctx.borrow_mut().poll_id.take() {
+            glib::source::source_remove(poll_id);
+        }
     });
-    app.run();
+    app.run_with_args(&args);
     println!("Host stopping");
 }
 
 struct HostContext<'a> {
     grid: Grid<'a>,
+    // With --double-buffer-grid, the second copy of the grid and the control word for which is live.
+    back: Option<BackGrid<'a>>,
     actors: Actors<'a>,
+    // The host's side of each container's comms, indexed by signal index.
+    comms: [Comms; 2],
+    stats: Stats<'a>,
+    layers: Layers<'a>,
+    buffers: BufferSet,
+    // Every container's ControlBlock.
+    blocks: ControlBlocks,
     shared_ro: cptr,
     shared_rw: cptr,
+    shared_stats: cptr,
+    shared_draw: cptr,
     timeout_id: Option<glib::source::SourceId>,
     enable_host_modify: bool,
+    container_write: bool,
+    // The containers "Container modifies grid" is sent to.
+    modify_target: Target,
+    containers: [ContainerProcess; 2],
+    // The last container the host found dead or stalled and restarted, or that reported an error,
+    // shown with the latency.
+    alert: Option<String>,
+    // The latest value of each metric event, by container and name, also shown with the latency.
+    metrics: BTreeMap<(usize, String), i64>,
+    chaos: Option<Chaos>,
+    // With --latency-json each tick's per-module latency is also printed as a line of JSON.
+    latency_json: bool,
+    ticks: u64,
+    tick_rate: TickRate,
+    lag: LagPolicy,
+    tick_deadline: Option<Duration>,
+    snapshots: bool,
+    // Unless the policy is LagPolicy::Slow, when the tick the containers may still be running
+    // was sent, and how many ticks have been skipped or coalesced because a container lagged.
+    tick_sent_us: Option<i64>,
+    lagged_ticks: u64,
+    // With --lag=coalesce, whether a tick fell due while a container lagged, and the timer that
+    // waits for it to catch up so that the tick can be sent.
+    tick_owed: bool,
+    poll_id: Option<glib::source::SourceId>,
+    // With --record the read-write buffer is recorded after each tick so recent ticks can be
+    // replayed in the GUI.
+    recorder: Option<Recorder>,
 }
 
 impl HostContext<'_> {
-    fn new(hunter_path: &str, runner_path: &str) -> Self {
-        let shared_ro = create_shared_buffer(READ_ONLY_BUF_NAME, READ_ONLY_BUF_SIZE);
-        let shared_rw = create_shared_buffer(READ_WRITE_BUF_NAME, READ_WRITE_BUF_SIZE);
-        // TODO: Use own path to find the other binaries
-        fork_container("rust/gtk/target/debug/container-wasmer", hunter_path, HUNTER_SIGNAL_INDEX);
-        fork_container("rust/gtk/target/debug/container-wasmi", runner_path, RUNNER_SIGNAL_INDEX);
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        hunter_path: &str,
+        runner_path: &str,
+        binaries: [&'static str; 2],
+        session: &str,
+        comms: [(Comms, Comms); 2],
+        chaos: Option<Chaos>,
+        persist: bool,
+        latency_json: bool,
+        seal_grid: bool,
+        double_grid: bool,
+        module_grid: Option<usize>,
+    ) -> Self {
+        let attached = if persist { BufferSet::attach(session) } else { None };
+        let resumed = attached.is_some();
+        let mut back = None;
+        let (mut buffers, shared_ro, shared_rw, shared_stats, shared_draw) = match attached {
+            Some((mut buffers, mapped)) => {
+                println!("Re-attached to existing shared buffers");
+                buffers.claim(HOST_SLOT);
+                assert_eq!(buffers.descs()[READ_ONLY_BUF_ID].size, READ_ONLY_BUF_SIZE, "persisted grid size differs");
+                let ids = [READ_ONLY_BUF_ID, READ_WRITE_BUF_ID, STATS_BUF_ID, DRAW_BUF_ID];
+                let [ro, rw, stats, draw] = ids.map(|id| mapped[id]);
+                // A double-buffered world stays double-buffered, whatever the flags say.
+                let names: Vec<_> = buffers.descs().iter().map(|desc| desc.name().to_string()).collect();
+                if names.get(GRID_CTL_BUF_ID).is_some_and(|name| *name == buffers.name(GRID_CTL_BUF_NAME))
+                    && names.get(GRID_BACK_BUF_ID).is_some_and(|name| *name == buffers.name(GRID_BACK_BUF_NAME))
+                {
+                    back = Some(BackGrid::new(mapped[GRID_CTL_BUF_ID], mapped[GRID_BACK_BUF_ID]));
+                }
+                (buffers, ro, rw, stats, draw)
+            }
+            None => {
+                let mut buffers = BufferSet::create(session);
+                // Access matrices are [hunter, runner]; see CONTAINER_NAMES.
+                // A sealed grid can't be written by anyone once initialised, so it isn't grantable.
+                let shared_ro = if seal_grid {
+                    buffers.add_sealable(READ_ONLY_BUF_NAME, READ_ONLY_BUF_SIZE, [Access::ReadOnly; 2])
+                } else {
+                    buffers.add(READ_ONLY_BUF_NAME, READ_ONLY_BUF_SIZE, [Access::Grantable; 2])
+                };
+                let shared_rw = buffers.add(READ_WRITE_BUF_NAME, READ_WRITE_BUF_SIZE, [Access::ReadWrite; 2]);
+                let shared_stats = buffers.add(STATS_BUF_NAME, STATS_BUF_SIZE, [Access::ReadWrite; 2]);
+                let shared_draw = buffers.add(DRAW_BUF_NAME, DRAW_BUF_SIZE, [Access::ReadWrite; 2]);
+                if double_grid {
+                    let shared_ctl = buffers.add(GRID_CTL_BUF_NAME, GRID_CTL_BUF_SIZE, [Access::ReadOnly; 2]);
+                    let shared_back = buffers.add(GRID_BACK_BUF_NAME, READ_ONLY_BUF_SIZE, [Access::ReadOnly; 2]);
+                    back = Some(BackGrid::new(shared_ctl, shared_back));
+                }
+                (buffers, shared_ro, shared_rw, shared_stats, shared_draw)
+            }
+        };
+        // With --persist the shm objects are left in place on exit, and the next host started with
+        // --persist re-attaches to them and carries on with the same world.
+        if persist {
+            buffers.persist();
+        }
+
+        // The grid is initialised before the containers start, as a sealable buffer must be sealed
+        // before anything else maps it. A module's grid waits for its container; see load_grid.
+        if !resumed && module_grid.is_none() {
+            let mut grid = Grid::new(shared_ro, READ_ONLY_BUF_SIZE);
+            grid.init();
+            if let Some(back) = back.as_mut() {
+                back.grid.data.copy_from_slice(grid.data);
+            }
+        }
+        let shared_ro = if seal_grid {
+            println!("Sealing the grid buffer");
+            buffers.seal(READ_ONLY_BUF_ID)
+        } else {
+            shared_ro
+        };
+
+        // A previous host may have exited mid-signal; clear that before any containers start.
+        let host_comms = comms.map(|(host, _)| host);
+        let blocks = ControlBlocks::open_all(session);
+        let mut actors = Actors::new(shared_rw, buffers.descs()[READ_WRITE_BUF_ID].size, &blocks, host_comms);
+        actors.send_commands(&[Command::new(Signal::Idle)], Target::All, false, &mut |_| None);
+        for index in [HUNTER_SIGNAL_INDEX, RUNNER_SIGNAL_INDEX] {
+            blocks.block(index).heartbeat().set(0);
+            actor_lock(shared_rw, index).reset();
+        }
+        tick_barriers(shared_rw).reset();
+        grid_sync(shared_rw).lock.reset();
+
+        let containers = [(hunter_path, HUNTER_SIGNAL_INDEX), (runner_path, RUNNER_SIGNAL_INDEX)]
+            .map(|(module, index)| ContainerProcess::start(binaries[index], module, index, session, comms[index].1));
 
         // Grid and Actors do *not* take ownership of the shared buffers.
         let mut ctx = Self {
             grid: Grid::new(shared_ro, READ_ONLY_BUF_SIZE),
-            actors: Actors::new(shared_rw, READ_WRITE_BUF_SIZE),
+            back,
+            actors,
+            comms: host_comms,
+            stats: Stats::new(shared_stats),
+            layers: Layers::new(shared_draw),
+            buffers,
+            blocks,
             shared_ro,
             shared_rw,
+            shared_stats,
+            shared_draw,
             timeout_id: None,
             enable_host_modify: false,
+            container_write: false,
+            modify_target: Target::Container(RUNNER_SIGNAL_INDEX),
+            containers,
+            alert: None,
+            metrics: BTreeMap::new(),
+            chaos,
+            latency_json,
+            ticks: 0,
+            tick_rate: TickRate::new(),
+            lag: LagPolicy::Slow,
+            tick_deadline: None,
+            snapshots: false,
+            tick_sent_us: None,
+            lagged_ticks: 0,
+            tick_owed: false,
+            poll_id: None,
+            recorder: None,
         };
-        ctx.grid.init();
-        ctx.actors.send_signal(Signal::Init, true);
+        // A previous host may have exited with the abort flags raised.
+        ctx.stats.set_abort(false);
+        // The modules' init resets the actors, so it's skipped when resuming a persisted world.
+        if !resumed {
+            let seed = rand::thread_rng().gen();
+            println!("Initialising the modules with seed {}", seed);
+            match module_grid {
+                Some(index) => {
+                    ctx.load_grid(index, seed);
+                    let other = Target::Container(1 - index);
+                    ctx.send_commands(&[Command::with_args(Signal::Init, &[seed])], other, true);
+                }
+                None => ctx.send_commands(&[Command::with_args(Signal::Init, &[seed])], Target::All, true),
+            }
+        }
         ctx
     }
 
+    // Initialises container 'index' with INIT_LOAD_GRID, letting it write to the grid buffer
+    // meanwhile. If its module doesn't fill in the grid the host does, as it would have otherwise.
+    fn load_grid(&mut self, index: usize, seed: u32) {
+        self.buffers.set_granted(READ_ONLY_BUF_ID, true);
+        self.send_signal(Signal::Protect, true);
+        let init = Command::with_args(Signal::Init, &[seed, INIT_LOAD_GRID]);
+        self.send_commands(&[init], Target::Container(index), true);
+        self.buffers.set_granted(READ_ONLY_BUF_ID, self.container_write);
+        self.send_signal(Signal::Protect, true);
+        if self.grid.data.iter().all(|&cell| cell == 0) {
+            println!("The {}'s module left the grid empty; generating one instead", CONTAINER_NAMES[index]);
+            self.grid.init();
+        } else {
+            println!("Loaded the grid from the {}'s module", CONTAINER_NAMES[index]);
+        }
+        if let Some(back) = self.back.as_mut() {
+            back.grid.data.copy_from_slice(self.grid.data);
+        }
+    }
+
+    // The copy of the grid the modules are currently reading.
+    fn live_grid(&self) -> &Grid<'_> {
+        match &self.back {
+            Some(back) if back.active.load(Ordering::Acquire) == 1 => &back.grid,
+            _ => &self.grid,
+        }
+    }
+
+    // When double-buffered, the live grid is copied into the other buffer and modified there, then
+    // published with a single store so the modules never see a partly modified grid.
+    fn modify_grid(&mut self) {
+        let sync = grid_sync(self.shared_rw);
+        sync.lock();
+        match self.back.as_mut() {
+            Some(back) => {
+                let live = back.active.load(Ordering::Acquire);
+                let (from, to) = if live == 0 { (&self.grid, &mut back.grid) } else { (&back.grid, &mut self.grid) };
+                to.data.copy_from_slice(from.data);
+                to.modify();
+                back.active.store(1 - live, Ordering::Release);
+            }
+            None => self.grid.modify(),
+        }
+        sync.unlock(true);
+    }
+
+    fn grid_sealed(&self) -> bool {
+        self.buffers.descs()[READ_ONLY_BUF_ID].sealed()
+    }
+
     fn toggle_host_modify(&mut self) {
+        if self.grid_sealed() {
+            println!("The grid buffer is sealed; not even the host can modify it");
+            return;
+        }
         self.enable_host_modify = !self.enable_host_modify;
     }
+
+    // Lets the containers write to the grid buffer (or revokes that), so "Container modifies
+    // grid" succeeds rather than crashing the container.
+    fn toggle_container_write(&mut self) {
+        if self.grid_sealed() {
+            println!("The grid buffer is sealed; write access can't be granted");
+            return;
+        }
+        self.container_write = !self.container_write;
+        self.buffers.set_granted(READ_ONLY_BUF_ID, self.container_write);
+        self.send_signal(Signal::Protect, true);
+    }
+
+    // Returns true if the tick interval needs to change.
+    fn tick(&mut self) -> bool {
+        if self.lag != LagPolicy::Slow && !self.finish_tick() {
+            self.lagged_ticks += 1;
+            self.tick_owed = self.lag == LagPolicy::Coalesce;
+            return false;
+        }
+        self.tick_owed = false;
+        if let Some(chaos) = self.chaos {
+            if Chaos::roll(chaos.kill) {
+                let i = rand::thread_rng().gen_range(0..self.containers.len());
+                println!("[chaos] Restarting container {} (pid {})", i, self.containers[i].pid);
+                self.containers[i].kill();
+                self.blocks.block(i).heartbeat().set(0);
+                self.containers[i].spawn();
+            }
+            if Chaos::roll(chaos.drop) {
+                println!("[chaos] Dropping tick");
+                return false;
+            }
+            if Chaos::roll(chaos.delay) {
+                let ms = rand::thread_rng().gen_range(0..=chaos.max_delay_ms);
+                println!("[chaos] Delaying tick by {}ms", ms);
+                thread::sleep(Duration::from_millis(ms));
+            }
+        }
+        let sent_us = now_us();
+        self.stats.stamp(sent_us);
+        tick_barriers(self.shared_rw).start.arrive(TICK_START_PARTIES);
+        let mut tick = Command::new(Signal::Tick);
+        if let Some(deadline) = self.tick_deadline {
+            tick = tick.with_deadline(sent_us + deadline.as_micros() as i64);
+        }
+        if self.lag != LagPolicy::Slow {
+            self.send_commands(&[tick], Target::All, false);
+            self.tick_sent_us = Some(sent_us);
+            return false;
+        }
+        self.send_commands(&[tick], Target::All, true);
+        self.tick_completed()
+    }
+
+    // Checks on the containers that haven't completed the tick in flight, if there is one,
+    // restarting any that have died or stalled. Returns true once none of them are lagging.
+    fn finish_tick(&mut self) -> bool {
+        let sent_us = match self.tick_sent_us {
+            Some(sent_us) => sent_us,
+            None => return true,
+        };
+        let blocks = &self.blocks;
+        let lagging = move |index: &usize| blocks.block(*index).completed_us() < sent_us;
+        let lagging: Vec<usize> = (0..self.containers.len()).filter(lagging).collect();
+        self.checked(|_, check| {
+            let timed_out = now_us() - sent_us > SIGNAL_TIMEOUT.as_micros() as i64;
+            let timed_out = || timed_out.then(|| "didn't complete a command".to_string());
+            let mut problem = |index: usize| check(index).or_else(timed_out).map(|problem| (index, problem));
+            lagging.iter().filter_map(|&index| problem(index)).collect()
+        });
+        if (0..self.containers.len()).any(|index| self.blocks.block(index).completed_us() < sent_us) {
+            return false;
+        }
+        self.wait_tick();
+        true
+    }
+
+    // Waits for the tick in flight, if there is one, to complete.
+    fn wait_tick(&mut self) {
+        if self.tick_sent_us.take().is_some() {
+            self.checked(|actors, check| actors.wait_idle(Target::All, check));
+            self.tick_completed();
+        }
+    }
+
+    // Returns true if the tick interval needs to change, which only the LagPolicy::Slow policy does.
+    fn tick_completed(&mut self) -> bool {
+        self.ticks += 1;
+        let rw_size = self.rw_size() as usize;
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(unsafe { slice::from_raw_parts(self.shared_rw as *const u8, rw_size) });
+        }
+        if self.latency_json {
+            let fields: Vec<String> = CONTAINER_NAMES
+                .iter()
+                .zip(self.stats.latencies())
+                .map(|(name, us)| format!("\"{}_us\": {}", name, us.map_or("null".to_string(), |us| us.to_string())))
+                .collect();
+            let interval = self.tick_rate.interval_ms;
+            println!("{{\"tick\": {}, \"tick_ms\": {}, {}}}", self.ticks, interval, fields.join(", "));
+        }
+        match self.stats.latencies().into_iter().max() {
+            Some(Some(latency_us)) if self.lag == LagPolicy::Slow => self.tick_rate.update(latency_us),
+            _ => false,
+        }
+    }
+
+    fn latency_text(&self) -> String {
+        let parts: Vec<String> = CONTAINER_NAMES
+            .iter()
+            .zip(self.stats.latencies())
+            .map(|(name, us)| format!("{} {}", name, us.map_or("-".to_string(), |us| format!("{} µs", us))))
+            .collect();
+        let slowed = if self.tick_rate.is_slowed() { " (slowed)" } else { "" };
+        let lagged = match self.lag {
+            _ if self.lagged_ticks == 0 => String::new(),
+            LagPolicy::Coalesce => format!("; {} ticks coalesced", self.lagged_ticks),
+            _ => format!("; {} ticks skipped", self.lagged_ticks),
+        };
+        let metrics: String = self
+            .metrics
+            .iter()
+            .map(|((index, name), value)| format!("; {} {} {}", CONTAINER_NAMES[*index], name, value))
+            .collect();
+        let alert = self.alert.as_ref().map_or(String::new(), |alert| format!("; {}", alert));
+        let interval = self.tick_rate.interval_ms;
+        format!("Tick latency: {}; interval {} ms{}{}{}{}", parts.join(", "), interval, slowed, lagged, metrics, alert)
+    }
+
+    // Routes the events the containers have posted since the last call: log lines and errors to
+    // stdout, with errors also shown as the alert, and metrics to the latency label. A trapped
+    // call also gets its kind and backtrace printed, and replaces the alert.
+    fn drain_events(&mut self) {
+        for (index, name) in CONTAINER_NAMES.iter().enumerate() {
+            let block = self.blocks.block(index);
+            while let Some(event) = block.take_event() {
+                match event.kind {
+                    EventKind::Log => println!("Container {}: {}", index, event.text.trim_end()),
+                    EventKind::Metric => {
+                        self.metrics.insert((index, event.text), event.value);
+                    }
+                    EventKind::Error => {
+                        println!("Container {} reported an error: {}", index, event.text);
+                        self.alert = Some(format!("{} reported: {}", name, event.text));
+                    }
+                    EventKind::Custom => println!("Container {} event {}: {}", index, event.value, event.text),
+                }
+            }
+            // The error event has the message already.
+            if let Some(trap) = block.take_trap() {
+                println!("Container {} trapped in {}: {}", index, trap.export, trap.kind.name());
+                for (depth, frame) in trap.backtrace.iter().enumerate() {
+                    println!("    {}: {}", depth, frame);
+                }
+                self.alert = Some(format!("{} trapped in {}: {}", name, trap.export, trap.kind.name()));
+            }
+            let dropped = block.take_dropped_events();
+            if dropped > 0 {
+                println!("Container {} dropped {} events; its event ring was full", index, dropped);
+            }
+        }
+    }
+
+    // Whether the GUI is showing a recorded tick rather than the live world; ticks are paused
+    // until it returns to the latest one.
+    fn replaying(&self) -> bool {
+        self.recorder.as_ref().is_some_and(Recorder::replaying)
+    }
+
+    fn replay_text(&self) -> String {
+        let recorder = self.recorder.as_ref().unwrap();
+        format!(
+            "Replaying tick {} ({} of {} recorded ticks back); step forward to resume",
+            self.ticks - recorder.view as u64,
+            recorder.view,
+            recorder.frames.len()
+        )
+    }
+
+    fn send_signal(&mut self, signal: Signal, wait_for_idle: bool) {
+        self.send_signal_to(Target::All, signal, wait_for_idle);
+    }
+
+    fn send_signal_to(&mut self, target: Target, signal: Signal, wait_for_idle: bool) {
+        self.send_commands(&[Command::new(signal)], target, wait_for_idle);
+    }
+
+    // For Snapshot and Restore, which take the snapshot's slot.
+    fn send_to_slot(&mut self, signal: Signal, slot: u32) {
+        self.send_commands(&[Command::with_args(signal, &[slot])], Target::All, true);
+    }
+
+    // Sends the commands to the containers in 'target', restarting any that die or stall on one
+    // of them, which loses the rest of the commands for it. The module state is all in the shared
+    // buffers, so the new container carries on from where the old one was.
+    fn send_commands(&mut self, commands: &[Command], target: Target, wait_for_idle: bool) {
+        // A container can't take a command while it's still running a tick.
+        self.wait_tick();
+        self.checked(|actors, check| actors.send_commands(commands, target, wait_for_idle, check));
+    }
+
+    // Calls one of a container's module exports through its call slot, so exports don't each
+    // need a Signal. A call that doesn't complete is Dropped, and the container is restarted as
+    // for any command.
+    fn call(&mut self, index: usize, export: &str, args: &[CallArg]) -> CallResult {
+        let command = self.blocks.block(index).prepare_call(export, args);
+        self.send_commands(&[command], Target::Container(index), true);
+        self.blocks.block(index).call_result()
+    }
+
+    // Runs 'wait', which waits on the containers and asks the check it's given about those that
+    // haven't completed yet, then restarts the containers it gave up on.
+    fn checked(
+        &mut self,
+        wait: impl FnOnce(&mut Actors<'_>, &mut dyn FnMut(usize) -> Option<String>) -> Vec<(usize, String)>,
+    ) {
+        let shared_rw = self.shared_rw;
+        let blocks = &self.blocks;
+        let containers = &mut self.containers;
+        // The other containers may be waiting at a tick barrier for one that has died, or for the
+        // grid lock if it died modifying the grid.
+        let mut check = |index: usize| {
+            let block = blocks.block(index);
+            let problem = containers[index].check(block).or_else(|| {
+                (block.take_out_of_step() > 0).then(|| "fell out of step with its commands".to_string())
+            });
+            if problem.is_some() {
+                tick_barriers(shared_rw).reset();
+                if grid_sync(shared_rw).lock.recover(containers[index].pid as u32) {
+                    println!("Container {} died holding the grid lock; released it", index);
+                }
+            }
+            problem
+        };
+        let failed = wait(&mut self.actors, &mut check);
+        for (index, problem) in failed {
+            println!("Container {} (pid {}) {}; restarting it", index, self.containers[index].pid, problem);
+            let alert = format!("{} {} and was restarted", CONTAINER_NAMES[index], problem);
+            self.containers[index].kill();
+            self.actors.signals[index].reset();
+            let block = self.blocks.block(index);
+            block.heartbeat().set(0);
+            block.reset_trap_lock();
+            // The new container won't run the tick the old one was lagging on.
+            block.stamp_completed(now_us());
+            actor_lock(shared_rw, index).reset();
+            self.containers[index].spawn();
+            self.alert = Some(alert);
+        }
+    }
+
+    fn rw_size(&self) -> u64 {
+        self.buffers.descs()[READ_WRITE_BUF_ID].size
+    }
+
+    // Grows the read-write buffer and has the containers remap it. The containers are idle
+    // between signals, so they don't touch the buffer while its size is changing.
+    fn add_runners(&mut self, count: i32) {
+        let rw_size = self.rw_size() + count as u64 * RUNNER_BYTES;
+        self.shared_rw = self.buffers.resize(READ_WRITE_BUF_ID, rw_size);
+        self.actors = Actors::new(self.shared_rw, rw_size, &self.blocks, self.comms);
+        self.send_signal(Signal::Resize, true);
+    }
 }
 
 impl Drop for HostContext<'_> {
     fn drop(&mut self) {
-        self.actors.send_signal(Signal::Exit, false);
+        // The containers acknowledge Exit and are then reaped, so nothing else has the buffers
+        // mapped by the time the host unmaps (and possibly unlinks) them. The abort flags cut short
+        // a tick still in flight in modules that poll them. Pipelined channels take the Exit
+        // behind (or with a queue, ahead of) the tick, but a slot has to wait for it.
+        self.stats.set_abort(true);
+        if !self.actors.signals.iter().all(CommsChannel::pipelined) {
+            self.wait_tick();
+        }
+        if !self.actors.exit() {
+            println!("Not every container acknowledged the exit");
+        }
+        for container in &self.containers {
+            container.reap(SIGNAL_TIMEOUT);
+        }
+        self.drain_events();
+        // The containers opened any named semaphores when they started.
+        for comms in &self.comms {
+            comms.unlink();
+        }
 
-        let cname_ro = CString::new(READ_ONLY_BUF_NAME).unwrap();
-        let cname_rw = CString::new(READ_WRITE_BUF_NAME).unwrap();
         unsafe {
             if libc::munmap(self.shared_ro, READ_ONLY_BUF_SIZE as usize) == -1 {
                 println!("munmap failed for shared_ro");
             }
-            if libc::munmap(self.shared_rw, READ_ONLY_BUF_SIZE as usize) == -1 {
+            if libc::munmap(self.shared_rw, self.rw_size() as usize) == -1 {
                 println!("munmap failed for shared_rw");
             }
-            if libc::shm_unlink(cname_ro.as_ptr()) == -1 {
-                println!("shm_unlink failed for shared_ro");
+            if libc::munmap(self.shared_stats, STATS_BUF_SIZE as usize) == -1 {
+                println!("munmap failed for shared_stats");
             }
-            if libc::shm_unlink(cname_rw.as_ptr()) == -1 {
-                println!("shm_unlink failed for shared_rw");
+            if libc::munmap(self.shared_draw, DRAW_BUF_SIZE as usize) == -1 {
+                println!("munmap failed for shared_draw");
+            }
+            if let Some(back) = &self.back {
+                if libc::munmap(back.shared_ctl, GRID_CTL_BUF_SIZE as usize) == -1 {
+                    println!("munmap failed for shared_grid_ctl");
+                }
+                if libc::munmap(back.shared_back, READ_ONLY_BUF_SIZE as usize) == -1 {
+                    println!("munmap failed for shared_grid_back");
+                }
             }
         }
+        // The shm objects themselves are unlinked by whichever of the host and containers drops its
+        // BufferSet last, unless persisted.
+        if self.buffers.ownership() == Ownership::Persisted {
+            println!("Leaving shared buffers in place for the next host (--persist)");
+        }
     }
 }
 
-fn create_shared_buffer(name: &str, size: i32) -> cptr {
-    let cname = CString::new(name).unwrap();
-    unsafe {
-        // shm_open() creates the actual memory buffer for sharing.
-        let fd = libc::shm_open(cname.as_ptr(), O_CREAT | O_TRUNC | O_RDWR, S_IRUSR | S_IWUSR);
-        if fd == -1 {
-            panic!("shm_open failed");
+// Records the container's pid in CONTAINER_PIDS, for the notifiers that signal it.
+// Started in place of each container with --vm, from the same place as the containers.
+const VM_BRIDGE: &str = "rust/gtk/target/debug/vm-bridge";
+
+// The container binary for each engine, by the engine's name; the first two are the hunter's and
+// the runner's by default. The WasmEdge, wasm3 and WAMR containers are only built with their
+// features.
+// TODO: Use own path to find the other binaries
+const CONTAINER_BINARIES: [(&str, &str); 6] = [
+    ("wasmer", "rust/gtk/target/debug/container-wasmer"),
+    ("wasmi", "rust/gtk/target/debug/container-wasmi"),
+    ("wasmedge", "rust/gtk/target/debug/container-wasmedge"),
+    ("wasm3", "rust/gtk/target/debug/container-wasm3"),
+    ("wamr", "rust/gtk/target/debug/container-wamr"),
+    ("wasmtime", "rust/gtk/target/debug/container-wasmtime"),
+];
+
+fn fork_container(binary: &str, module: &str, index: usize, session: &str, comms: Comms) -> i32 {
+    match fork() {
+        Ok(Fork::Parent(pid)) => {
+            CONTAINER_PIDS[index].store(pid, Ordering::Release);
+            pid
         }
-        if libc::ftruncate(fd, size as i64) == -1 {
-            panic!("ftruncate failed");
+        Ok(Fork::Child) => {
+            // Before the exec, so that a signal the host sends while the container is loading its
+            // module waits for it rather than killing it.
+            comms.prepare_container();
+            let (name, value) = comms_env(comms);
+            std::env::set_var(name, value);
+            let err = match std::env::var_os(VM_ENV) {
+                Some(_) => exec::execvp(VM_BRIDGE, &[VM_BRIDGE, binary, module, &index.to_string(), session]),
+                None => exec::execvp(binary, &[binary, module, &index.to_string(), session]),
+            };
+            panic!("exec failed: {}", err); // should not be reached
         }
+        Err(_) => panic!("fork failed"),
+    }
+}
 
-        // mmap() allows the host to access the shared buffers (initialise, read for GUI display, etc).
-        let buf = libc::mmap(std::ptr::null_mut(), size as usize, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
-        if libc::close(fd) == -1 {
-            panic!("close failed");
+struct ContainerProcess {
+    binary: &'static str,
+    module: String,
+    index: usize,
+    session: String,
+    comms: Comms,
+    pid: i32,
+    // Whether the host has already reaped the process.
+    exited: bool,
+    // The heartbeat when the host last saw it change, and when that was.
+    beats: u32,
+    beat_at: Instant,
+}
+
+impl ContainerProcess {
+    fn start(binary: &'static str, module: &str, index: usize, session: &str, comms: Comms) -> Self {
+        let pid = fork_container(binary, module, index, session, comms);
+        let (module, session) = (module.to_string(), session.to_string());
+        Self { binary, module, index, session, comms, pid, exited: false, beats: 0, beat_at: Instant::now() }
+    }
+
+    fn kill(&mut self) {
+        if !self.exited {
+            unsafe {
+                libc::kill(self.pid, libc::SIGKILL);
+                libc::waitpid(self.pid, std::ptr::null_mut(), 0);
+            }
+        }
+        // Once reaped the pid can be reused, so nothing should signal it.
+        CONTAINER_PIDS[self.index].store(0, Ordering::Release);
+    }
+
+    // Only called between signals (or once the container's comms have been reset), so the
+    // container's signal byte is idle. It also has to start with a heartbeat of 0.
+    fn spawn(&mut self) {
+        self.pid = fork_container(self.binary, &self.module, self.index, &self.session, self.comms);
+        self.exited = false;
+        self.beat_at = Instant::now();
+    }
+
+    // Describes what's wrong if the container has exited, or its heartbeat has stopped for
+    // STALL_TIMEOUT while it's handling a command. A heartbeat of 0 means it's still starting.
+    // A container killed by a fault says where it was (see ControlBlock::fault).
+    fn check(&mut self, block: &ControlBlock) -> Option<String> {
+        let mut status = 0;
+        if !self.exited && unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) } == self.pid {
+            self.exited = true;
+            if libc::WIFSIGNALED(status) {
+                let signal = libc::WTERMSIG(status);
+                let name = unsafe { std::ffi::CStr::from_ptr(libc::strsignal(signal)) };
+                let fault = block.fault(signal).map_or(String::new(), |fault| format!(" {}", fault));
+                return Some(format!("was killed by signal {} ({}){}", signal, name.to_string_lossy(), fault));
+            }
+            return Some(format!("exited with status {}", libc::WEXITSTATUS(status)));
+        }
+        let beats = block.heartbeat().get();
+        if beats != self.beats {
+            self.beats = beats;
+            self.beat_at = Instant::now();
+        } else if beats != 0 && self.beat_at.elapsed() > STALL_TIMEOUT {
+            return Some(format!("stalled, with no heartbeat for {:.1?}", self.beat_at.elapsed()));
+        }
+        None
+    }
+
+    // Waits for the container to exit, killing it if it hasn't within 'timeout'.
+    fn reap(&self, timeout: Duration) {
+        let start = Instant::now();
+        // waitpid() returns the pid once the container has exited, or -1 if it was already reaped.
+        while !self.exited && unsafe { libc::waitpid(self.pid, std::ptr::null_mut(), libc::WNOHANG) } == 0 {
+            if start.elapsed() > timeout {
+                println!("Container {} (pid {}) didn't exit; killing it", self.index, self.pid);
+                unsafe {
+                    libc::kill(self.pid, libc::SIGKILL);
+                    libc::waitpid(self.pid, std::ptr::null_mut(), 0);
+                }
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
         }
-        buf
     }
 }
 
-fn fork_container(binary: &str, module: &str, index: usize) {
-    match fork() {
-        Ok(Fork::Parent(_)) => (),
-        Ok(Fork::Child) => {
-            let err = exec::execvp(binary, &[binary, module, &index.to_string()]);
-            panic!("exec failed: {}", err); // should not be reached
+// Randomly perturbs the host/container protocol during soak tests. Enabled with --chaos, or
+// --chaos=delay=P,drop=P,kill=P to set the probabilities applied on each tick.
+#[derive(Clone, Copy)]
+struct Chaos {
+    delay: f64,
+    drop: f64,
+    kill: f64,
+    max_delay_ms: u64,
+}
+
+impl Chaos {
+    fn parse(arg: &str) -> Self {
+        let mut chaos = Self { delay: 0.1, drop: 0.05, kill: 0.005, max_delay_ms: 1000 };
+        for setting in arg.strip_prefix("--chaos=").unwrap_or("").split(',').filter(|s| !s.is_empty()) {
+            let (key, value) = setting.split_once('=').expect("chaos settings are key=value");
+            let value = value.parse().unwrap_or_else(|_| panic!("bad chaos value for {}", key));
+            match key {
+                "delay" => chaos.delay = value,
+                "drop" => chaos.drop = value,
+                "kill" => chaos.kill = value,
+                "max_delay_ms" => chaos.max_delay_ms = value as u64,
+                _ => panic!("unknown chaos setting {}", key),
+            }
         }
-        Err(_) => panic!("fork failed"),
+        println!(
+            "Chaos mode: delay {}, drop {}, kill {}, max delay {}ms",
+            chaos.delay, chaos.drop, chaos.kill, chaos.max_delay_ms
+        );
+        chaos
+    }
+
+    fn roll(probability: f64) -> bool {
+        rand::thread_rng().gen_bool(probability)
+    }
+}
+
+// Adapts the tick interval to the measured module latency, doubling it when the modules keep
+// overrunning and halving it again once they are comfortably fast. The gap between the overrun
+// and fast thresholds, and the run of ticks needed before changing, stop the rate flapping.
+struct TickRate {
+    interval_ms: u64,
+    slow_ticks: u32,
+    fast_ticks: u32,
+}
+
+impl TickRate {
+    fn new() -> Self {
+        Self { interval_ms: TICK_MS, slow_ticks: 0, fast_ticks: 0 }
+    }
+
+    // Takes the slowest module's latency for the last tick; returns true if the interval changed.
+    fn update(&mut self, latency_us: i64) -> bool {
+        let budget_us = self.interval_ms as i64 * 1000;
+        if latency_us > budget_us * 8 / 10 {
+            self.slow_ticks += 1;
+            self.fast_ticks = 0;
+        } else if latency_us < budget_us / 4 {
+            self.fast_ticks += 1;
+            self.slow_ticks = 0;
+        } else {
+            self.slow_ticks = 0;
+            self.fast_ticks = 0;
+        }
+
+        let interval_ms = if self.slow_ticks >= OVERRUN_TICKS {
+            (self.interval_ms * 2).min(MAX_TICK_MS)
+        } else if self.fast_ticks >= FAST_TICKS {
+            (self.interval_ms / 2).max(TICK_MS)
+        } else {
+            return false;
+        };
+        self.slow_ticks = 0;
+        self.fast_ticks = 0;
+        if interval_ms == self.interval_ms {
+            return false;
+        }
+        println!("Tick interval {}ms -> {}ms (latency {}us)", self.interval_ms, interval_ms, latency_us);
+        self.interval_ms = interval_ms;
+        true
+    }
+
+    fn is_slowed(&self) -> bool {
+        self.interval_ms > TICK_MS
+    }
+}
+
+// What the host does about a container that is still running the last tick when the next one
+// falls due, set with --lag=. By default (slow) the host waits for each tick to complete, and
+// the TickRate slows the clock if the modules keep overrunning. Otherwise the host doesn't wait,
+// and when the next tick falls due checks each container's completion timestamp: with skip a tick
+// that falls due while a container lags is dropped, and with coalesce the ticks that fall due are
+// merged into one, sent as soon as the containers have caught up.
+#[derive(Copy, Clone, PartialEq)]
+enum LagPolicy {
+    Slow,
+    Skip,
+    Coalesce,
+}
+
+impl LagPolicy {
+    fn parse(arg: &str) -> Self {
+        match &arg["--lag=".len()..] {
+            "slow" => Self::Slow,
+            "skip" => Self::Skip,
+            "coalesce" => Self::Coalesce,
+            other => panic!("unknown lag policy '{}'", other),
+        }
+    }
+}
+
+// A call to a module export given with --call=CONTAINER:EXPORT[:ARG,...], which the host makes
+// once the modules are initialised, printing what the export returns. An ARG of "ctx" is the
+// module's context, e.g. --call=runner:count_runners:ctx,0 for the number of walking runners.
+struct ExportCall {
+    index: usize,
+    export: String,
+    args: Vec<CallArg>,
+}
+
+impl ExportCall {
+    fn parse(arg: &str) -> Self {
+        let mut parts = arg["--call=".len()..].splitn(3, ':');
+        let (container, export) = match (parts.next(), parts.next()) {
+            (Some(container), Some(export)) => (container, export),
+            _ => panic!("--call takes CONTAINER:EXPORT[:ARG,...]"),
+        };
+        let index = CONTAINER_NAMES
+            .iter()
+            .position(|&name| name == container)
+            .unwrap_or_else(|| panic!("unknown container '{}'", container));
+        let parse_arg = |arg: &str| match arg {
+            "ctx" => CallArg::Context,
+            _ => CallArg::Value(arg.parse().unwrap_or_else(|_| panic!("bad argument '{}' for {}", arg, export))),
+        };
+        let args = parts.next().unwrap_or("").split(',').filter(|arg| !arg.is_empty()).map(parse_arg).collect();
+        Self { index, export: export.to_string(), args }
+    }
+
+    fn run(&self, ctx: &mut HostContext<'_>) {
+        let name = CONTAINER_NAMES[self.index];
+        match ctx.call(self.index, &self.export, &self.args) {
+            CallResult::Returned(result) => println!("Container {}: {} returned {}", name, self.export, result),
+            CallResult::Nothing => println!("Container {}: {} returned nothing", name, self.export),
+            CallResult::Failed => println!("Container {}: the call to {} failed", name, self.export),
+            CallResult::Dropped => println!("Container {}: the call to {} didn't complete", name, self.export),
+        }
+    }
+}
+
+// Records the read-write buffer after each tick as a compressed diff against the previous tick,
+// keeping the most recent 'capacity' ticks. The diffs are XORs, so the same diff steps the
+// replayed state either way. Enabled with --record, or --record=N to set the number of ticks.
+struct Recorder {
+    frames: VecDeque<Frame>,
+    capacity: usize,
+    // The buffer contents after the latest tick.
+    latest: Vec<u8>,
+    // The replayed contents, and how many ticks back from the latest they are.
+    replayed: Vec<u8>,
+    view: usize,
+}
+
+struct Frame {
+    prev_len: usize,
+    len: usize,
+    diff: Vec<u8>,
+}
+
+impl Recorder {
+    fn parse(arg: &str) -> Self {
+        let capacity = match arg.strip_prefix("--record=") {
+            Some(ticks) => ticks.parse().unwrap_or_else(|_| panic!("bad --record tick count {}", ticks)),
+            None => 200,
+        };
+        println!("Recording the last {} ticks", capacity);
+        Self { frames: VecDeque::new(), capacity, latest: Vec::new(), replayed: Vec::new(), view: 0 }
+    }
+
+    // Ticks are paused while replaying, so this always follows on from the latest state.
+    fn record(&mut self, rw: &[u8]) {
+        let len = self.latest.len().max(rw.len());
+        let (mut prev, mut next) = (self.latest.clone(), rw.to_vec());
+        prev.resize(len, 0);
+        next.resize(len, 0);
+        let xor: Vec<u8> = prev.iter().zip(&next).map(|(a, b)| a ^ b).collect();
+        self.frames.push_back(Frame { prev_len: self.latest.len(), len: rw.len(), diff: compress(&xor) });
+        if self.frames.len() > self.capacity {
+            self.frames.pop_front();
+        }
+        self.latest = rw.to_vec();
+    }
+
+    fn replaying(&self) -> bool {
+        self.view > 0
+    }
+
+    // The oldest frame diffs against the state before it, which has aged out, so it can't be
+    // stepped back over.
+    fn step_back(&mut self) {
+        if self.view + 1 >= self.frames.len() {
+            return;
+        }
+        if self.view == 0 {
+            self.replayed = self.latest.clone();
+        }
+        let frame = &self.frames[self.frames.len() - 1 - self.view];
+        apply_diff(&mut self.replayed, &frame.diff, frame.prev_len);
+        self.view += 1;
+    }
+
+    fn step_forward(&mut self) {
+        if self.view == 0 {
+            return;
+        }
+        self.view -= 1;
+        let frame = &self.frames[self.frames.len() - 1 - self.view];
+        apply_diff(&mut self.replayed, &frame.diff, frame.len);
+    }
+}
+
+// Diffs are mostly zero, so they are stored as (zero run, literal length, literal bytes) groups.
+// A literal only ends at a run of at least 4 zeros, as single changed i32 fields often have zero
+// bytes in the middle.
+fn compress(xor: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < xor.len() {
+        let start = pos + xor[pos..].iter().take_while(|&&b| b == 0).count();
+        if start == xor.len() {
+            break;
+        }
+        let mut end = start;
+        while end < xor.len() && xor[end..].iter().take(4).any(|&b| b != 0) {
+            end += 1;
+        }
+        // Trailing zeros inside the last 4 bytes are left to the next zero run.
+        while xor[end - 1] == 0 {
+            end -= 1;
+        }
+        out.extend_from_slice(&((start - pos) as u32).to_le_bytes());
+        out.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        out.extend_from_slice(&xor[start..end]);
+        pos = end;
     }
+    out
+}
+
+// XORs a compressed diff into 'buf' and resizes it to 'len'.
+fn apply_diff(buf: &mut Vec<u8>, diff: &[u8], len: usize) {
+    buf.resize(buf.len().max(len), 0);
+    let field = |at: usize| u32::from_le_bytes(diff[at..at + 4].try_into().unwrap()) as usize;
+    let (mut pos, mut at) = (0, 0);
+    while at < diff.len() {
+        let (skip, count) = (field(at), field(at + 4));
+        pos += skip;
+        for (byte, d) in buf[pos..pos + count].iter_mut().zip(&diff[at + 8..at + 8 + count]) {
+            *byte ^= d;
+        }
+        pos += count;
+        at += 8 + count;
+    }
+    buf.truncate(len);
 }
 
 // Wraps the (unowned) read-only buffer to provide 2D-array-style access.
@@ -139,9 +1206,11 @@ struct Grid<'a> {
 }
 
 impl Grid<'_> {
-    fn new(shared_ro: cptr, len: i32) -> Self {
+    fn new(shared_ro: cptr, len: u64) -> Self {
+        // The grid follows the buffer header.
+        let grid = unsafe { shared_ro.add(HEADER_BYTES as usize) as *mut i32 };
         Self {
-            data: unsafe { slice::from_raw_parts_mut(shared_ro as *mut i32, len as usize) },
+            data: unsafe { slice::from_raw_parts_mut(grid, (len - HEADER_BYTES) as usize / 4) },
         }
     }
 
@@ -181,58 +1250,209 @@ impl Grid<'_> {
     }
 }
 
+// The second copy of a double-buffered grid. Like Grid, it doesn't own the shared buffers.
+struct BackGrid<'a> {
+    grid: Grid<'a>,
+    active: &'a AtomicU32,
+    shared_ctl: cptr,
+    shared_back: cptr,
+}
+
+impl BackGrid<'_> {
+    fn new(shared_ctl: cptr, shared_back: cptr) -> Self {
+        let ctl = unsafe { &*(shared_ctl.add(HEADER_BYTES as usize) as *const GridControl) };
+        Self {
+            grid: Grid::new(shared_back, READ_ONLY_BUF_SIZE),
+            active: &ctl.active,
+            shared_ctl,
+            shared_back,
+        }
+    }
+}
+
 fn rand_range(a: i32, b: i32) -> i32 {
     rand::thread_rng().gen_range(a..=b)
 }
 
 // Wraps the (unowned) read-write buffer to provide access to the hunter and runner
-// data and to manage communication between the host and container processes.
+// data and to manage communication between the host and container processes. The containers may
+// be moving the actors, so they're read under each container's actor lock.
 struct Actors<'a> {
-    // Layout: [sig, hx, hy, r0x, r0y, r0s, r1x, r1y, r1s, ...]
-    data: &'a mut [i32],
-    hunter_signal: *mut u8,
-    runner_signal: *mut u8,
+    hunter: SeqLocked<'a, HunterRecord>,
+    runners: SeqLocked<'a, [RunnerRecord]>,
+    signals: [CommsChannel; 2],
 }
 
 impl Actors<'_> {
-    fn new(shared_rw: cptr, len: i32) -> Self {
+    fn new(shared_rw: cptr, len: u64, blocks: &ControlBlocks, comms: [Comms; 2]) -> Self {
+        // The actors follow the header and the shared IPC config.
+        let mut arena = Arena::new(unsafe { shared_rw.add(ACTORS_OFFSET as usize) }, (len - ACTORS_OFFSET) as usize);
         Self {
-            data: unsafe { slice::from_raw_parts_mut(shared_rw as *mut i32, len as usize) },
-            hunter_signal: unsafe { shared_rw.add(HUNTER_SIGNAL_INDEX) as *mut u8 },
-            runner_signal: unsafe { shared_rw.add(RUNNER_SIGNAL_INDEX) as *mut u8 },
+            hunter: SeqLocked::new(actor_lock(shared_rw, HUNTER_SIGNAL_INDEX), arena.place()),
+            runners: SeqLocked::new(actor_lock(shared_rw, RUNNER_SIGNAL_INDEX), arena.place_rest()),
+            signals: [HUNTER_SIGNAL_INDEX, RUNNER_SIGNAL_INDEX]
+                .map(|index| CommsChannel::new(comms[index], blocks.block(index), shared_rw, index)),
         }
     }
 
-    // IPC is handled with a simple polling loop. The host always moves from zero (Signal::Idle)
-    // to non-zero and the containers always move from non-zero to zero. Each container has a
-    // dedicated i32 value in the read-write buffer.
-    fn send_signal(&mut self, signal: Signal, wait_for_idle: bool) {
-        unsafe { *self.hunter_signal = signal as u8 };
-        unsafe { *self.runner_signal = signal as u8 };
-        if wait_for_idle {
-            let idle = Signal::Idle as u8;
-            for _ in 0..SIGNAL_REPS {
-                if unsafe { *self.hunter_signal == idle && *self.runner_signal == idle } {
-                    return;
+    // IPC uses a shm_signal::SignalSlot and a Command in each container's ControlBlock, with
+    // the slot woken through the container's notifier, or a control socket (see Comms). The host
+    // always moves a slot from Signal::Idle to another signal and the containers always move it back.
+    // Sending Signal::Idle resets both slots, for when a previous host exited mid-signal.
+    //
+    // Each command goes to the containers 'target' includes, and the host only waits for those.
+    // Ticks always go to every container, since they meet at the tick barriers. Pipelined comms
+    // take all the commands before the host waits; otherwise each has to complete before the next
+    // is sent. While waiting, the host asks 'check' about each container that hasn't completed yet
+    // every HEARTBEAT_INTERVAL, and gives up on a container it describes a problem with (or that
+    // doesn't complete within SIGNAL_TIMEOUT). Returns the containers given up on, with their
+    // problems, after which nothing more is sent.
+    fn send_commands(
+        &mut self,
+        commands: &[Command],
+        target: Target,
+        wait_for_idle: bool,
+        check: &mut dyn FnMut(usize) -> Option<String>,
+    ) -> Vec<(usize, String)> {
+        assert!(
+            target == Target::All || commands.iter().all(|command| command.signal() != Signal::Tick),
+            "ticks can't be sent to {} alone",
+            target.name()
+        );
+        for (i, command) in commands.iter().enumerate() {
+            for (_, slot) in self.targeted(target) {
+                match command.signal() {
+                    Signal::Idle => slot.reset(),
+                    _ => slot.send(command),
+                }
+            }
+            let last = i + 1 == commands.len();
+            let wait = if last { wait_for_idle } else { !self.targeted(target).all(|(_, slot)| slot.pipelined()) };
+            if wait {
+                let failed = self.wait_idle(target, check);
+                if !failed.is_empty() {
+                    return failed;
                 }
-                thread::sleep(Duration::from_millis(SIGNAL_WAIT));
             }
-            panic!("failed to receive idle for signal {}", signal as i32);
         }
+        Vec::new()
+    }
+
+    fn targeted(&self, target: Target) -> impl Iterator<Item = (usize, &CommsChannel)> {
+        self.signals.iter().enumerate().filter(move |(index, _)| target.includes(*index))
+    }
+
+    fn wait_idle(&self, target: Target, check: &mut dyn FnMut(usize) -> Option<String>) -> Vec<(usize, String)> {
+        let start = Instant::now();
+        let mut failed = Vec::new();
+        for (index, slot) in self.targeted(target) {
+            loop {
+                let timed_out = || (start.elapsed() > SIGNAL_TIMEOUT).then(|| "didn't complete a command".to_string());
+                let problem = match slot.wait_idle(HEARTBEAT_INTERVAL) {
+                    IdleResult::Done => break,
+                    // check() finds the commands that completed out of step.
+                    IdleResult::Failed => Some(check(index).unwrap_or_else(|| "reported an error".to_string())),
+                    IdleResult::Pending => check(index).or_else(timed_out),
+                };
+                if let Some(problem) = problem {
+                    failed.push((index, problem));
+                    break;
+                }
+            }
+        }
+        failed
+    }
+
+    // Sends Exit ahead of any queued commands and waits for every container to acknowledge it,
+    // returning false if some didn't.
+    fn exit(&mut self) -> bool {
+        for slot in &self.signals {
+            slot.send_urgent(&Command::new(Signal::Exit));
+        }
+        self.signals.iter().all(|slot| slot.wait_idle(SIGNAL_TIMEOUT) == IdleResult::Done)
     }
 
     fn hunter(&self) -> Position {
-        // Hunter co-ords are after the i32 signal value.
-        Position { x: self.data[1], y: self.data[2] }
+        self.hunter.read(|hunter| Position { x: hunter.x, y: hunter.y })
     }
 
-    fn runner(&self, index: i32) -> (Position, State) {
-        // Runners start after i32 signal value + 2 * i32 hunter co-ords.
-        let i = 3 + 3 * index as usize;
-        (
-            Position { x: self.data[i], y: self.data[i + 1] },
-            State::from(self.data[i + 2]),
-        )
+    // All of the runners as of the same tick. The states are only checked once the read is consistent.
+    fn runners(&self) -> Vec<(Position, State)> {
+        let records = self.runners.read(|runners| runners.iter().map(|r| (r.x, r.y, r.state)).collect::<Vec<_>>());
+        records.into_iter().map(|(x, y, state)| (Position { x, y }, State::from(state))).collect()
+    }
+}
+
+// Wraps the (unowned) stats buffer holding each container's tick timestamps.
+struct Stats<'a> {
+    slots: &'a mut [TickStats],
+}
+
+impl Stats<'_> {
+    fn new(shared_stats: cptr) -> Self {
+        let slots = unsafe { shared_stats.add(HEADER_BYTES as usize) as *mut TickStats };
+        Self {
+            slots: unsafe { slice::from_raw_parts_mut(slots, CONTAINER_NAMES.len()) },
+        }
+    }
+
+    fn stamp(&mut self, sent_us: i64) {
+        for slot in self.slots.iter_mut() {
+            slot.sent_us = sent_us;
+        }
+    }
+
+    fn latencies(&self) -> Vec<Option<i64>> {
+        self.slots.iter().map(TickStats::latency_us).collect()
+    }
+
+    fn set_abort(&self, raised: bool) {
+        for slot in self.slots.iter() {
+            slot.abort.store(raised as u32, Ordering::Relaxed);
+        }
+    }
+}
+
+// Wraps the (unowned) draw buffer holding each container's draw list.
+struct Layers<'a> {
+    lists: &'a [DrawList],
+}
+
+impl Layers<'_> {
+    fn new(shared_draw: cptr) -> Self {
+        let lists = unsafe { shared_draw.add(HEADER_BYTES as usize) as *const DrawList };
+        Self {
+            lists: unsafe { slice::from_raw_parts(lists, CONTAINER_NAMES.len()) },
+        }
+    }
+
+    fn draw(&self, cr: &cairo::Context) {
+        for cmd in self.lists.iter().flat_map(DrawList::cmds) {
+            draw_cmd(cr, cmd);
+        }
+    }
+}
+
+fn draw_cmd(cr: &cairo::Context, cmd: &DrawCmd) {
+    let channel = |shift: u32| ((cmd.rgb >> shift) & 0xff) as f64 / 255.0;
+    cr.set_source_rgb(channel(16), channel(8), channel(0));
+    let [x0, y0, x1, y1] = [cmd.x0, cmd.y0, cmd.x1, cmd.y1].map(|v| v as f64 * SCALE);
+    match Shape::from(cmd.shape) {
+        Some(Shape::Line) => {
+            cr.set_line_width(2.0);
+            cr.move_to(x0, y0);
+            cr.line_to(x1, y1);
+            cr.stroke().unwrap();
+        }
+        Some(Shape::Rect) => {
+            cr.rectangle(x0, y0, x1 - x0, y1 - y0);
+            cr.fill().unwrap();
+        }
+        Some(Shape::Circle) => {
+            cr.arc(x0, y0, x1, 0.0, 2.0 * std::f64::consts::PI);
+            cr.fill().unwrap();
+        }
+        None => {}
     }
 }
 
@@ -264,41 +1484,103 @@ fn on_open(ctx: Rc<RefCell<HostContext<'static>>>, app: &gtk::Application) {
         host_modify_btn.connect_clicked(move |_btn| ctx.borrow_mut().toggle_host_modify());
     }
 
-    let container_modify_btn = gtk::Button::with_label("Container modifies grid");
+    let modify_target = ctx.borrow().modify_target;
+    let container_modify_btn = gtk::Button::with_label(&format!("Container modifies grid ({})", modify_target.name()));
     {
         let ctx = ctx.clone();
         container_modify_btn.connect_clicked(move |_btn| {
-            // Container will crash, which will cause host to panic when idle signal is not received.
-            ctx.borrow_mut().actors.send_signal(Signal::ModifyGrid, true);
+            // Unless write access has been granted, the container will crash, and the host will
+            // report it and restart the container.
+            ctx.borrow_mut().send_signal_to(modify_target, Signal::ModifyGrid, true);
         });
     }
 
+    let grant_write_btn = gtk::Button::with_label("Grant container write access");
+    {
+        let ctx = ctx.clone();
+        grant_write_btn.connect_clicked(move |_btn| ctx.borrow_mut().toggle_container_write());
+    }
+
     let large_alloc_btn = gtk::Button::with_label("Request large alloc (wasmi)");
     {
         let ctx = ctx.clone();
         large_alloc_btn.connect_clicked(move |_btn| {
-            ctx.borrow_mut().actors.send_signal(Signal::LargeAlloc, true)
+            ctx.borrow_mut().send_signal(Signal::LargeAlloc, true)
         });
     }
 
+    let add_runners_btn = gtk::Button::with_label("Add runners");
+    {
+        let ctx = ctx.clone();
+        add_runners_btn.connect_clicked(move |_btn| ctx.borrow_mut().add_runners(5));
+    }
+
+    // The containers instantiate their module files again, so rebuilt modules take over the
+    // running actors.
+    let reload_btn = gtk::Button::with_label("Reload modules");
+    {
+        let ctx = ctx.clone();
+        reload_btn.connect_clicked(move |_btn| ctx.borrow_mut().send_signal(Signal::ReloadModule, true));
+    }
+
     let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 10);
     hbox.append(&host_modify_btn);
     hbox.append(&container_modify_btn);
+    hbox.append(&grant_write_btn);
     hbox.append(&large_alloc_btn);
+    hbox.append(&add_runners_btn);
+    hbox.append(&reload_btn);
+
+    if ctx.borrow().snapshots {
+        let snapshot_btn = gtk::Button::with_label("Snapshot module memory");
+        {
+            let ctx = ctx.clone();
+            snapshot_btn.connect_clicked(move |_btn| ctx.borrow_mut().send_to_slot(Signal::Snapshot, 0));
+        }
+        let restore_btn = gtk::Button::with_label("Restore module memory");
+        {
+            let ctx = ctx.clone();
+            restore_btn.connect_clicked(move |_btn| ctx.borrow_mut().send_to_slot(Signal::Restore, 0));
+        }
+        hbox.append(&snapshot_btn);
+        hbox.append(&restore_btn);
+    }
+
+    if ctx.borrow().recorder.is_some() {
+        let back_btn = gtk::Button::with_label("◀ Step back");
+        {
+            let ctx = ctx.clone();
+            back_btn.connect_clicked(move |_btn| ctx.borrow_mut().recorder.as_mut().unwrap().step_back());
+        }
+        let forward_btn = gtk::Button::with_label("Step forward ▶");
+        {
+            let ctx = ctx.clone();
+            forward_btn.connect_clicked(move |_btn| ctx.borrow_mut().recorder.as_mut().unwrap().step_forward());
+        }
+        hbox.append(&back_btn);
+        hbox.append(&forward_btn);
+    }
+
+    let latency_label = gtk::Label::new(None);
 
     let vbox = gtk::Box::new(gtk::Orientation::Vertical, 10);
     vbox.append(&drawing_area);
     vbox.append(&hbox);
+    vbox.append(&latency_label);
 
     window.set_child(Some(&vbox));
     window.present();
-    ctx.borrow_mut().timeout_id.replace({
+    schedule_tick(ctx, drawing_area, latency_label);
+}
+
+// Starts the tick timer at the current interval, replacing the previous timer's id.
+fn schedule_tick(ctx: Rc<RefCell<HostContext<'static>>>, area: gtk::DrawingArea, latency_label: gtk::Label) {
+    let interval = Duration::from_millis(ctx.borrow().tick_rate.interval_ms);
+    let timeout_id = {
         let ctx = ctx.clone();
-        glib::timeout_add_local(
-            Duration::from_millis(TICK_MS),
-            move || on_tick(ctx.clone(), &drawing_area)
-        )
-    });
+        glib::timeout_add_local(interval, move || on_tick(ctx.clone(), &area, &latency_label))
+    };
+    ctx.borrow_mut().timeout_id.replace(timeout_id);
 }
 
 fn on_draw(ctx: Rc<RefCell<HostContext>>, _da: &gtk::DrawingArea, cr: &cairo::Context, width: i32, height: i32) {
@@ -309,7 +1591,7 @@ fn on_draw(ctx: Rc<RefCell<HostContext>>, _da: &gtk::DrawingArea, cr: &cairo::Co
     let hc = ctx.borrow();
     for y in 0..GRID_H {
         for x in 0..GRID_W {
-            if hc.grid.get(x, y) == 1 {
+            if hc.live_grid().get(x, y) == 1 {
                 cr.set_source_rgb(0.3, 0.3, 0.3);
                 cr.rectangle(x as f64 * SCALE, y as f64 * SCALE, SCALE, SCALE);
                 cr.fill().unwrap();
@@ -317,15 +1599,24 @@ fn on_draw(ctx: Rc<RefCell<HostContext>>, _da: &gtk::DrawingArea, cr: &cairo::Co
         }
     }
 
-    let hunter = hc.actors.hunter();
+    // Replayed ticks are drawn from a copy of the recorded buffer (as i32s, for alignment). Only the
+    // read-write buffer is recorded, so the module overlays are left out.
+    let mut replayed: Vec<i32> = Vec::new();
+    let replay_actors = hc.replaying().then(|| {
+        let bytes = &hc.recorder.as_ref().unwrap().replayed;
+        replayed = bytes.chunks(4).map(|word| i32::from_ne_bytes(word.try_into().unwrap())).collect();
+        Actors::new(replayed.as_mut_ptr() as cptr, bytes.len() as u64, &hc.blocks, [Comms::Slot(Notifier::Futex); 2])
+    });
+    let actors = replay_actors.as_ref().unwrap_or(&hc.actors);
+
+    let hunter = actors.hunter();
     cr.set_source_rgb(0.8, 0.5, 0.9);
     cr.rectangle(hunter.x as f64 * SCALE, hunter.y as f64 * SCALE, SCALE, SCALE);
     cr.fill().unwrap();
 
     const TWO_PI: f64 = 2.0 * std::f64::consts::PI;
     const HSCALE: f64 = SCALE / 2.0;
-    for i in 0..N_RUNNERS {
-        let (pos, state) = hc.actors.runner(i);
+    for (pos, state) in actors.runners() {
         match state {
             State::Walking => cr.set_source_rgb(0.5, 0.8, 0.9),
             State::Running => cr.set_source_rgb(1.0, 0.8, 0.5),
@@ -334,14 +1625,62 @@ fn on_draw(ctx: Rc<RefCell<HostContext>>, _da: &gtk::DrawingArea, cr: &cairo::Co
         cr.arc(pos.x as f64 * SCALE + HSCALE, pos.y as f64 * SCALE + HSCALE, HSCALE, 0.0, TWO_PI);
         cr.fill().unwrap();
     }
+
+    if replay_actors.is_none() {
+        hc.layers.draw(cr);
+    }
 }
 
-fn on_tick(ctx: Rc<RefCell<HostContext>>, area: &gtk::DrawingArea) -> glib::Continue {
+fn on_tick(
+    ctx: Rc<RefCell<HostContext<'static>>>,
+    area: &gtk::DrawingArea,
+    latency_label: &gtk::Label,
+) -> glib::Continue {
     let mut hc = ctx.borrow_mut();
-    if hc.enable_host_modify {
-        hc.grid.modify();
+    hc.drain_events();
+    if hc.replaying() {
+        area.queue_draw();
+        latency_label.set_text(&hc.replay_text());
+        return glib::Continue(true);
     }
-    hc.actors.send_signal(Signal::Tick, true);
+    // The modules read the grid while they tick.
+    if hc.enable_host_modify && hc.finish_tick() {
+        hc.modify_grid();
+    }
+    let reschedule = hc.tick();
     area.queue_draw();
+    latency_label.set_text(&hc.latency_text());
+    if hc.tick_owed && hc.poll_id.is_none() {
+        let poll_id = {
+            let (ctx, area, latency_label) = (ctx.clone(), area.clone(), latency_label.clone());
+            glib::timeout_add_local(LAG_POLL_INTERVAL, move || on_poll(ctx.clone(), &area, &latency_label))
+        };
+        hc.poll_id = Some(poll_id);
+    }
+    if reschedule {
+        // This timer stops once it returns false, so it is replaced with one at the new interval.
+        drop(hc);
+        schedule_tick(ctx, area.clone(), latency_label.clone());
+        return glib::Continue(false);
+    }
     glib::Continue(true)
 }
+
+// Sends the tick owed under --lag=coalesce once the lagging containers have caught up.
+fn on_poll(
+    ctx: Rc<RefCell<HostContext<'static>>>,
+    area: &gtk::DrawingArea,
+    latency_label: &gtk::Label,
+) -> glib::Continue {
+    let mut hc = ctx.borrow_mut();
+    if !hc.replaying() && !hc.finish_tick() {
+        return glib::Continue(true);
+    }
+    hc.poll_id = None;
+    if hc.tick_owed && !hc.replaying() {
+        hc.tick();
+        area.queue_draw();
+        latency_label.set_text(&hc.latency_text());
+    }
+    glib::Continue(false)
+}
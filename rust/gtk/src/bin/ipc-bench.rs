@@ -0,0 +1,146 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Measures the round trip from the host to a container and back over each of the comms, and
+// prints a table of them:
+//   ipc-bench <container binary> <module> [rounds] [backend ...]
+//
+// The backends are the notifiers for the signal slots (poll, futex, eventfd, semaphore and
+// signal) and the controls (queue, shared and socket); by default it runs all of them that are
+// supported here. For each one the bench starts the container on its own, with the same buffers
+// as the host's, and times 'rounds' Signal::Pings one at a time, after some to warm up. The
+// container completes a ping without touching the buffers or calling into its module, so only
+// the comms are timed.
+
+use common::{control_socket::ControlSocket, host_common::*, shared::cptr};
+use shm_signal::Notifier;
+use std::{
+    os::unix::process::CommandExt,
+    process,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+const BACKENDS: [&str; 8] = ["poll", "futex", "eventfd", "semaphore", "signal", "queue", "shared", "socket"];
+const WARMUP_ROUNDS: usize = 100;
+const INDEX: usize = HUNTER_SIGNAL_INDEX;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (binary, module) = match &args[1..] {
+        [binary, module, ..] => (binary, module),
+        _ => panic!("usage: ipc-bench <container binary> <module> [rounds] [backend ...]"),
+    };
+    let rounds = args.get(3).map_or(10_000, |arg| arg.parse().expect("rounds must be a number"));
+    assert!(rounds > 0, "rounds must be positive");
+    let backends: Vec<&str> =
+        if args.len() > 4 { args[4..].iter().map(String::as_str).collect() } else { BACKENDS.to_vec() };
+
+    let session = format!("bench{}", process::id());
+    let mut buffers = BufferSet::create(&session);
+    buffers.add(READ_ONLY_BUF_NAME, READ_ONLY_BUF_SIZE, [Access::ReadOnly; 2]);
+    let shared_rw = buffers.add(READ_WRITE_BUF_NAME, READ_WRITE_BUF_SIZE, [Access::ReadWrite; 2]);
+    buffers.add(STATS_BUF_NAME, STATS_BUF_SIZE, [Access::ReadWrite; 2]);
+    buffers.add(DRAW_BUF_NAME, DRAW_BUF_SIZE, [Access::ReadWrite; 2]);
+    let blocks = ControlBlocks::open_all(&session);
+
+    println!("{} round trips each", rounds);
+    println!("{:<10} {:>10} {:>10} {:>10} {:>10} {:>10}", "backend", "mean", "min", "p50", "p99", "max");
+    for backend in backends {
+        let comms = match comms(backend) {
+            Some(comms) => comms,
+            None => {
+                println!("{:<10} unsupported here", backend);
+                continue;
+            }
+        };
+        let mut times = bench(binary, module, &session, comms, blocks.block(INDEX), shared_rw, rounds);
+        comms.0.unlink();
+        times.sort();
+        let percentile = |p: usize| times[(times.len() * p / 100).min(times.len() - 1)];
+        let mean = times.iter().sum::<Duration>() / times.len() as u32;
+        println!(
+            "{:<10} {:>10.2?} {:>10.2?} {:>10.2?} {:>10.2?} {:>10.2?}",
+            backend,
+            mean,
+            times[0],
+            percentile(50),
+            percentile(99),
+            times[times.len() - 1]
+        );
+    }
+}
+
+// The host's and the container's comms for a backend, as the host creates them.
+fn comms(backend: &str) -> Option<(Comms, Comms)> {
+    match backend {
+        "queue" => Some((Comms::Queue, Comms::Queue)),
+        "shared" => Some((Comms::Shared, Comms::Shared)),
+        "socket" => {
+            let (host_end, container_end) = ControlSocket::pair();
+            Some((Comms::Socket(host_end), Comms::Socket(container_end)))
+        }
+        notifier => Notifier::create(notifier).map(|notifier| (Comms::Slot(notifier), Comms::Slot(notifier))),
+    }
+}
+
+// Starts a container with 'comms', pings it 'rounds' times and has it exit, returning each
+// round trip.
+fn bench(
+    binary: &str,
+    module: &str,
+    session: &str,
+    comms: (Comms, Comms),
+    block: &'static ControlBlock,
+    shared_rw: cptr,
+    rounds: usize,
+) -> Vec<Duration> {
+    let channel = CommsChannel::new(comms.0, block, shared_rw, INDEX);
+    channel.reset();
+    block.heartbeat().set(0);
+    let (name, value) = comms_env(comms.1);
+    let mut command = process::Command::new(binary);
+    command.arg(module).arg(INDEX.to_string()).arg(session).env(name, value);
+    // Comms aren't Send (a semaphore notifier holds pointers), but only a signal notifier has
+    // anything to prepare; see Comms::prepare_container.
+    if let Comms::Slot(Notifier::Signal) = comms.1 {
+        unsafe {
+            command.pre_exec(|| {
+                Notifier::Signal.prepare_receiver();
+                Ok(())
+            });
+        }
+    }
+    let mut container = command.spawn().unwrap_or_else(|err| panic!("failed to start {}: {}", binary, err));
+    CONTAINER_PIDS[INDEX].store(container.id() as i32, Ordering::Release);
+
+    // The first ping also waits for the container to load its module.
+    let ping = Command::new(Signal::Ping);
+    let mut times = Vec::with_capacity(rounds);
+    for round in 0..WARMUP_ROUNDS + rounds {
+        let start = Instant::now();
+        channel.send(&ping);
+        assert_eq!(channel.wait_idle(SIGNAL_TIMEOUT), IdleResult::Done, "the container didn't answer ping {}", round);
+        if round >= WARMUP_ROUNDS {
+            times.push(start.elapsed());
+        }
+    }
+    channel.send(&Command::new(Signal::Exit));
+    assert_eq!(channel.wait_idle(SIGNAL_TIMEOUT), IdleResult::Done, "the container didn't acknowledge the exit");
+    container.wait().expect("failed to reap the container");
+    CONTAINER_PIDS[INDEX].store(0, Ordering::Release);
+    times
+}
@@ -0,0 +1,246 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Inspects, saves and restores the full set of shared buffers, e.g. to share an interesting world:
+//   shmtool acl
+//   shmtool export --out world.tar
+//   shmtool import world.tar
+//   shmtool watch
+//
+// The archive is a plain tar file holding a text manifest followed by the raw contents of each
+// buffer. Buffer data is copied byte for byte, so archives only load on the same architecture.
+// Imported buffers are left in place for a host started with --persist to attach to. watch
+// prints a line whenever the host or a container modifies the grid, until the host exits.
+
+use common::host_common::*;
+use std::{env, fs, slice, time::Duration};
+
+const MANIFEST_NAME: &str = "manifest";
+const BLOCK: usize = 512;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let args: Vec<&str> = args.iter().skip(1).map(String::as_str).collect();
+    // The session printed by the host; without one, the plain names used by persisted worlds.
+    let (session, args) = match args[..] {
+        ["--session", session, ref rest @ ..] => (session, rest),
+        ref rest => ("", rest),
+    };
+    match args[..] {
+        ["acl"] => acl(session),
+        ["export", "--out", path] => export(session, path),
+        ["import", path] => import(session, path),
+        ["watch"] => watch(session),
+        _ => {
+            println!("Usage: shmtool [--session <id>] (acl | export --out <file.tar> | import <file.tar> | watch)");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Prints the access matrix of every registered buffer.
+fn acl(session: &str) {
+    let (buffers, _) = BufferSet::attach(session).expect("no shared buffers found; is a host running?");
+    println!("{:<20} {:>8}  {:<10}{}", "buffer", "size", "host", columns(CONTAINER_NAMES));
+    for desc in buffers.descs() {
+        let labels = desc.acl().map(|access| {
+            if access == Access::Grantable && desc.granted() { "granted" } else { access_name(access) }
+        });
+        let host = if desc.sealed() { "sealed" } else { "rw" };
+        println!("{:<20} {:>8}  {:<10}{}", desc.name(), desc.size, host, columns(labels));
+    }
+}
+
+// Sleeps until the grid changes rather than polling it.
+fn watch(session: &str) {
+    let (buffers, mapped) = BufferSet::attach(session).expect("no shared buffers found; is a host running?");
+    let sync = grid_sync(mapped[READ_WRITE_BUF_ID]);
+    let mut version = sync.version();
+    println!("Watching the grid (version {})", version);
+    while buffers.host_alive() {
+        if let Some(changed) = sync.wait_change(version, Duration::from_secs(1)) {
+            println!("The grid changed (version {})", changed);
+            version = changed;
+        }
+    }
+    println!("The host has exited");
+}
+
+fn columns<const N: usize>(cells: [&str; N]) -> String {
+    cells.iter().map(|cell| format!("{:<10}", cell)).collect::<String>().trim_end().to_string()
+}
+
+// Buffers are recorded under their names without the session, so they can be imported into another.
+fn export(session: &str, path: &str) {
+    let (buffers, mapped) = BufferSet::attach(session).expect("no shared buffers to export; is a host running?");
+    let mut manifest = config_lines();
+    let mut tar = Vec::new();
+    for (desc, &buf) in buffers.descs().iter().zip(&mapped) {
+        let acl: Vec<&str> = desc.acl().iter().map(|&access| access_name(access)).collect();
+        let name = buffers.base_name(desc);
+        manifest.push(format!("buffer {} {} {} {}", name, desc.size, desc.granted() as i32, acl.join(" ")));
+        let data = unsafe { slice::from_raw_parts(buf as *const u8, desc.size as usize) };
+        append_entry(&mut tar, &entry_name(name), data);
+    }
+
+    // The manifest goes first so import can check compatibility before reading any buffers.
+    let mut out = Vec::new();
+    append_entry(&mut out, MANIFEST_NAME, (manifest.join("\n") + "\n").as_bytes());
+    out.append(&mut tar);
+    out.resize(out.len() + 2 * BLOCK, 0);
+    fs::write(path, out).unwrap_or_else(|e| panic!("failed to write {}: {}", path, e));
+    println!("Exported {} buffers to {}", buffers.descs().len(), path);
+}
+
+fn import(session: &str, path: &str) {
+    let archive = fs::read(path).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e));
+    let entries = read_entries(&archive);
+    let (name, manifest) = entries.first().expect("empty archive");
+    assert_eq!(name, MANIFEST_NAME, "archive doesn't start with a manifest");
+    let manifest = std::str::from_utf8(manifest).expect("manifest isn't valid text");
+
+    // Config lines must match this build exactly; anything else means the data can't be reused.
+    let expected = config_lines();
+    let lines: Vec<&str> = manifest.lines().collect();
+    for (line, want) in lines.iter().zip(&expected) {
+        if line != want {
+            panic!("{} is incompatible with this build: '{}', expected '{}'", path, line, want);
+        }
+    }
+
+    if BufferSet::attach(session).is_some() {
+        panic!("shared buffers already exist; stop the host and remove them before importing");
+    }
+    // The imported buffers are left in place for a host started with --persist (and the same
+    // --session, if one was given here).
+    let mut buffers = BufferSet::create(session);
+    buffers.persist();
+    let descs = &lines[expected.len()..];
+    for (id, line) in descs.iter().enumerate() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (name, size, granted, acl) = match fields[..] {
+            ["buffer", name, size, granted, ref acl @ ..] if acl.len() == CONTAINER_NAMES.len() => {
+                let mut parsed = [Access::Denied; CONTAINER_NAMES.len()];
+                for (entry, access) in parsed.iter_mut().zip(acl) {
+                    *entry = parse_access(access);
+                }
+                (name, size.parse().unwrap(), granted == "1", parsed)
+            }
+            _ => panic!("bad manifest line: '{}'", line),
+        };
+        let data = entries
+            .iter()
+            .find(|(entry, _)| *entry == entry_name(name))
+            .map(|(_, data)| data)
+            .unwrap_or_else(|| panic!("{} is missing from the archive", name));
+        assert_eq!(data.len(), size as usize, "{} has the wrong size", name);
+
+        // add() writes a fresh header for this host, so only the contents after it are copied.
+        let buf = buffers.add(name, size, acl);
+        let dest = unsafe { slice::from_raw_parts_mut(buf as *mut u8, size as usize) };
+        dest[HEADER_BYTES as usize..].copy_from_slice(&data[HEADER_BYTES as usize..]);
+        if granted {
+            buffers.set_granted(id, granted);
+        }
+    }
+    println!("Imported {} buffers from {}; start the host with --persist to use them", descs.len(), path);
+}
+
+// The layout and build settings an archive depends on.
+fn config_lines() -> Vec<String> {
+    vec![
+        format!("layout_version {}", LAYOUT_VERSION),
+        format!("arch {} {}", env::consts::ARCH, if cfg!(target_endian = "little") { "little" } else { "big" }),
+        format!("grid {} {}", GRID_W, GRID_H),
+        format!("containers {}", CONTAINER_NAMES.join(" ")),
+    ]
+}
+
+fn access_name(access: Access) -> &'static str {
+    match access {
+        Access::ReadOnly => "ro",
+        Access::ReadWrite => "rw",
+        Access::Grantable => "grantable",
+        Access::Denied => "-",
+    }
+}
+
+fn parse_access(name: &str) -> Access {
+    match name {
+        "ro" => Access::ReadOnly,
+        "rw" => Access::ReadWrite,
+        "grantable" => Access::Grantable,
+        "-" => Access::Denied,
+        _ => panic!("unknown access mode '{}'", name),
+    }
+}
+
+// Buffer names start with '/', which tar would treat as an absolute path.
+fn entry_name(buffer_name: &str) -> String {
+    format!("{}.bin", buffer_name.trim_start_matches('/'))
+}
+
+// -- Minimal ustar support: regular files only, names under 100 bytes --
+
+fn append_entry(out: &mut Vec<u8>, name: &str, data: &[u8]) {
+    assert!(name.len() < 100, "tar entry name too long: {}", name);
+    let mut header = [0u8; BLOCK];
+    let octal = |header: &mut [u8], offset: usize, len: usize, value: usize| {
+        let digits = format!("{:0width$o}", value, width = len - 1);
+        header[offset..offset + len - 1].copy_from_slice(digits.as_bytes());
+    };
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header, 100, 8, 0o644); // mode
+    octal(&mut header, 108, 8, 0); // uid
+    octal(&mut header, 116, 8, 0); // gid
+    octal(&mut header, 124, 12, data.len());
+    octal(&mut header, 136, 12, 0); // mtime
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is calculated with its own field set to spaces.
+    header[148..156].fill(b' ');
+    let sum: usize = header.iter().map(|&b| b as usize).sum();
+    octal(&mut header, 148, 7, sum);
+
+    out.extend_from_slice(&header);
+    out.extend_from_slice(data);
+    out.resize(out.len().div_ceil(BLOCK) * BLOCK, 0);
+}
+
+fn read_entries(archive: &[u8]) -> Vec<(String, &[u8])> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + BLOCK <= archive.len() && archive[pos] != 0 {
+        let header = &archive[pos..pos + BLOCK];
+        if &header[257..262] != b"ustar" {
+            panic!("not a tar archive");
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let bytes = &header[range];
+            let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+            std::str::from_utf8(&bytes[..len]).unwrap().trim().to_string()
+        };
+        let name = field(0..100);
+        let size = usize::from_str_radix(&field(124..136), 8).expect("bad tar entry size");
+        let start = pos + BLOCK;
+        let data = archive.get(start..start + size).expect("truncated tar archive");
+        entries.push((name, data));
+        pos = start + size.div_ceil(BLOCK) * BLOCK;
+    }
+    entries
+}
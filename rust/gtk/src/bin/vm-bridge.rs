@@ -0,0 +1,78 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Runs a container in a VM for a host started with --vm=CMD. The host starts the bridge in the
+// container's place, with the same args and its end of the control socket, and the bridge runs CMD
+// with sh -c to start the VM. CMD gets the container's binary, module, signal index and session as
+// CONTAINER, MODULE, INDEX and SESSION, and the vsock port the bridge is listening on as
+// VSOCK_PORT; it should have the VM run the container with SHARED_BUFFERS_CONTROL=vsock:$VSOCK_PORT
+// and SHARED_BUFFERS_SHM_DIR set to where the host's /dev/shm is mounted. Once the container
+// connects, the bridge relays control messages between it and the host until either side closes.
+//
+// CMD should exec the VMM, as that's the process that's killed when the bridge exits (including
+// when the host kills it to restart the container).
+
+use common::{
+    control_socket::ControlSocket,
+    host_common::{comms_from_env, Comms, CONTROL_ENV, HEARTBEAT_INTERVAL, VM_ENV},
+};
+use std::{os::unix::process::CommandExt, process};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (binary, module, index, session) = match &args[1..] {
+        [binary, module, index, session] => (binary, module, index, session),
+        _ => panic!("usage: vm-bridge <container binary> <module> <signal index> <session>"),
+    };
+    let host = match comms_from_env() {
+        Comms::Socket(socket) => socket,
+        _ => panic!("vm-bridge needs the host to start it with a control socket"),
+    };
+    let command = std::env::var(VM_ENV).unwrap_or_else(|_| panic!("{} isn't set", VM_ENV));
+
+    let (listener, port) = ControlSocket::listen_vsock();
+    let mut vm = process::Command::new("sh");
+    vm.arg("-c")
+        .arg(&command)
+        .env("CONTAINER", binary)
+        .env("MODULE", module)
+        .env("INDEX", index)
+        .env("SESSION", session)
+        .env("VSOCK_PORT", port.to_string())
+        .env_remove(CONTROL_ENV)
+        .env_remove(VM_ENV);
+    unsafe {
+        vm.pre_exec(|| {
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+            Ok(())
+        });
+    }
+    let mut vm = vm.spawn().unwrap_or_else(|err| panic!("failed to start the VM: {}", err));
+    println!("Container {}: starting a VM, with the bridge on vsock port {}", index, port);
+
+    // The VM may take a while to boot, so this waits for as long as it's running.
+    let container = loop {
+        if let Some(container) = listener.accept(HEARTBEAT_INTERVAL) {
+            break container;
+        }
+        if let Ok(Some(status)) = vm.try_wait() {
+            panic!("Container {}: the VM exited before the container connected ({})", index, status);
+        }
+    };
+    container.relay(&host);
+    vm.kill().ok();
+    vm.wait().ok();
+}
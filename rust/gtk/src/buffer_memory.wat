@@ -0,0 +1,31 @@
+;;
+;; Copyright 2021 The Project Oak Authors
+;;
+;; Licensed under the Apache License, Version 2.0 (the "License");
+;; you may not use this file except in compliance with the License.
+;; You may obtain a copy of the License at
+;;
+;;     http://www.apache.org/licenses/LICENSE-2.0
+;;
+;; Unless required by applicable law or agreed to in writing, software
+;; distributed under the License is distributed on an "AS IS" BASIS,
+;; WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+;; See the License for the specific language governing permissions and
+;; limitations under the License.
+;;
+
+;; The "buffers" imports of a module that sees the shared buffers as a dedicated memory; see
+;; buffer_read and buffer_write in module_common.rs. The container links this between the module's
+;; own memory and the buffer memory, which needs the multi-memory proposal for the copies between
+;; them. Rust can't yet address a second memory itself, so the module calls through these.
+(module
+  (import "env" "memory" (memory $module 0))
+  (import "buffers" "memory" (memory $buffers 0))
+
+  ;; Copies 'len' bytes at 'offset' in the buffer memory to 'dst' in the module's memory.
+  (func (export "read") (param $dst i32) (param $offset i32) (param $len i32)
+    (memory.copy $module $buffers (local.get $dst) (local.get $offset) (local.get $len)))
+
+  ;; Copies 'len' bytes at 'src' in the module's memory to 'offset' in the buffer memory.
+  (func (export "write") (param $offset i32) (param $src i32) (param $len i32)
+    (memory.copy $buffers $module (local.get $offset) (local.get $src) (local.get $len))))
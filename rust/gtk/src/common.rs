@@ -13,6 +13,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
+
+// The "no_std" feature builds the module side against core + alloc only; see module_common.
+#![cfg_attr(feature = "no_std", no_std)]
+// wasm64 needs a nightly toolchain, where its intrinsics are still unstable.
+#![cfg_attr(target_arch = "wasm64", feature(simd_wasm64))]
+
+#[cfg(feature = "modules")]
+extern crate alloc;
+
 pub mod shared;
 
 #[cfg(feature = "modules")]
@@ -20,3 +29,12 @@ pub mod module_common;
 
 #[cfg(feature = "host")]
 pub mod host_common;
+
+#[cfg(feature = "host")]
+pub mod control_socket;
+
+#[cfg(feature = "host")]
+pub mod wasi;
+
+#[cfg(feature = "host")]
+pub mod wasm_binary;
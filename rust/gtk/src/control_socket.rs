@@ -0,0 +1,185 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::host_common::Command;
+use std::{io, mem, time::Duration};
+
+// One end of a SOCK_SEQPACKET socket pair, so each ControlMessage arrives whole.
+#[derive(Copy, Clone)]
+pub struct ControlSocket {
+    pub(crate) fd: i32,
+}
+
+impl ControlSocket {
+    // Returns the host's end, which is close-on-exec, and the container's, which is inherited.
+    // The host keeps both open, so a restarted container picks up where the last one left off.
+    pub fn pair() -> (Self, Self) {
+        let mut fds = [0; 2];
+        if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) } == -1 {
+            panic!("failed to create a control socket: {}", io::Error::last_os_error());
+        }
+        unsafe { libc::fcntl(fds[0], libc::F_SETFD, libc::FD_CLOEXEC) };
+        (Self { fd: fds[0] }, Self { fd: fds[1] })
+    }
+
+    // MSG_NOSIGNAL: a peer that has gone shows up as a timeout rather than a SIGPIPE.
+    pub(crate) fn send(&self, message: &ControlMessage) {
+        let size = mem::size_of::<ControlMessage>();
+        let sent = unsafe { libc::send(self.fd, message as *const _ as *const libc::c_void, size, libc::MSG_NOSIGNAL) };
+        if sent != size as isize {
+            println!("failed to send a control message: {}", io::Error::last_os_error());
+        }
+    }
+
+    // Returns None if no message arrived within 'timeout', or the other end has closed.
+    pub(crate) fn recv(&self, timeout: Duration) -> Option<ControlMessage> {
+        let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis().min(i32::MAX as u128) as i32) } <= 0 {
+            return None;
+        }
+        let mut message = ControlMessage::new(0, Command::default(), "");
+        let size = mem::size_of::<ControlMessage>();
+        let buf = &mut message as *mut _ as *mut libc::c_void;
+        let received = unsafe { libc::recv(self.fd, buf, size, libc::MSG_WAITALL) };
+        (received == size as isize).then_some(message)
+    }
+}
+
+// A vsock stream between a container in a VM and the vm-bridge that the host runs in its place,
+// which relays messages to and from the host's socket pair (Linux only). A stream doesn't keep
+// message boundaries, which is why recv() uses MSG_WAITALL.
+#[cfg(target_os = "linux")]
+impl ControlSocket {
+    // Called inside the VM; the bridge is on the host, so the VM's parent.
+    pub fn connect_vsock(port: u32) -> Self {
+        let socket = Self::vsock();
+        let addr = vsock_addr(libc::VMADDR_CID_HOST, port);
+        let len = mem::size_of_val(&addr) as libc::socklen_t;
+        if unsafe { libc::connect(socket.fd, &addr as *const _ as *const libc::sockaddr, len) } == -1 {
+            panic!("failed to connect to vsock port {}: {}", port, io::Error::last_os_error());
+        }
+        socket
+    }
+
+    // Listens on a port the kernel picks, and returns the socket and the port.
+    pub fn listen_vsock() -> (Self, u32) {
+        let socket = Self::vsock();
+        let mut addr = vsock_addr(libc::VMADDR_CID_ANY, libc::VMADDR_PORT_ANY);
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        unsafe {
+            if libc::bind(socket.fd, &addr as *const _ as *const libc::sockaddr, len) == -1
+                || libc::listen(socket.fd, 1) == -1
+                || libc::getsockname(socket.fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) == -1
+            {
+                panic!("failed to listen on vsock: {}", io::Error::last_os_error());
+            }
+        }
+        (socket, addr.svm_port)
+    }
+
+    // Returns None if nothing connected within 'timeout'. The connection is close-on-exec.
+    pub fn accept(&self, timeout: Duration) -> Option<Self> {
+        let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        if unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis().min(i32::MAX as u128) as i32) } <= 0 {
+            return None;
+        }
+        let fd = unsafe { libc::accept4(self.fd, std::ptr::null_mut(), std::ptr::null_mut(), libc::SOCK_CLOEXEC) };
+        (fd != -1).then_some(Self { fd })
+    }
+
+    // Passes messages between this socket and 'other' both ways until either side closes.
+    pub fn relay(&self, other: &Self) {
+        let mut pollfds = [self.fd, other.fd].map(|fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 });
+        loop {
+            if unsafe { libc::poll(pollfds.as_mut_ptr(), 2, -1) } == -1 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                panic!("poll failed: {}", io::Error::last_os_error());
+            }
+            for (pollfd, (from, to)) in pollfds.iter().zip([(self, other), (other, self)]) {
+                if pollfd.revents != 0 {
+                    match from.recv(Duration::ZERO) {
+                        Some(message) => to.send(&message),
+                        None => return,
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn close(self) {
+        unsafe { libc::close(self.fd) };
+    }
+
+    fn vsock() -> Self {
+        let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+        if fd == -1 {
+            panic!("failed to create a vsock socket: {}", io::Error::last_os_error());
+        }
+        Self { fd }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn vsock_addr(cid: u32, port: u32) -> libc::sockaddr_vm {
+    libc::sockaddr_vm {
+        svm_family: libc::AF_VSOCK as libc::sa_family_t,
+        svm_reserved1: 0,
+        svm_port: port,
+        svm_cid: cid,
+        svm_zero: [0; 4],
+    }
+}
+
+pub(crate) const MESSAGE_COMMAND: u32 = 1;
+pub(crate) const MESSAGE_DONE: u32 = 2;
+pub(crate) const MESSAGE_ERROR: u32 = 3;
+pub(crate) const MESSAGE_STATUS: u32 = 4;
+const MESSAGE_TEXT_BYTES: usize = 212;
+
+// Commands go from the host to a container; the container replies to each with Done (except
+// Exit) and its result, and may send Error (e.g. when it panics) and Status messages at any
+// time. Commands and Done use the Command field, so the command slots go unused; a Done echoes
+// the command it completes.
+#[repr(C)]
+pub(crate) struct ControlMessage {
+    pub(crate) kind: u32,
+    pub(crate) command: Command,
+    // A Done's status; see Status::encode.
+    pub(crate) code: u32,
+    pub(crate) result: i32,
+    len: u32,
+    text: [u8; MESSAGE_TEXT_BYTES],
+}
+
+impl ControlMessage {
+    // Long texts are truncated.
+    pub(crate) fn new(kind: u32, command: Command, text: &str) -> Self {
+        let mut message = Self { kind, command, code: 0, result: 0, len: 0, text: [0; MESSAGE_TEXT_BYTES] };
+        let mut len = text.len().min(MESSAGE_TEXT_BYTES);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        message.text[..len].copy_from_slice(&text.as_bytes()[..len]);
+        message.len = len as u32;
+        message
+    }
+
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.text[..(self.len as usize).min(MESSAGE_TEXT_BYTES)]).into_owned()
+    }
+}
@@ -14,35 +14,145 @@
 // limitations under the License.
 //
 
-use super::shared::cptr;
-use libc::{MAP_FIXED, MAP_SHARED, O_RDONLY, O_RDWR, PROT_READ, PROT_WRITE, S_IRUSR, S_IWUSR};
-use std::{ffi::CString, thread, time::Duration};
+use super::{
+    control_socket::{ControlMessage, ControlSocket, MESSAGE_COMMAND, MESSAGE_DONE, MESSAGE_ERROR, MESSAGE_STATUS},
+    shared::{
+        cptr, Arena, DrawList, EventKind, GridControl, Ring, RingHeader, SeqLock, SeqLocked, TickStats, ABI_VERSION,
+    },
+};
+use shm_signal::{Barrier, Channel, CommandRing, Counter, Locked, Notifier, SharedCondvar, SharedMutex, SignalSlot};
+use libc::{
+    MAP_ANONYMOUS, MAP_FIXED, MAP_PRIVATE, MAP_SHARED, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, PROT_READ, PROT_WRITE,
+    S_IRUSR, S_IWUSR,
+};
+use std::{
+    cell::{Cell, UnsafeCell},
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BinaryHeap},
+    convert::TryInto,
+    ffi::CString,
+    fs,
+    future::Future,
+    hash::Hasher,
+    io, mem,
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    process, ptr, slice,
+    sync::atomic::{AtomicI32, AtomicI64, AtomicPtr, AtomicU32, AtomicU64, Ordering},
+    sync::{mpsc, Mutex, OnceLock},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 // Shared buffer config.
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
 pub const PAGE_SIZE: i64 = 4096;
+// Apple silicon uses 16k pages.
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub const PAGE_SIZE: i64 = 16384;
+// shm_open() is variadic on macOS, which requires the mode to be passed as a c_uint.
+const SHM_MODE: libc::c_uint = (S_IRUSR | S_IWUSR) as libc::c_uint;
+// macOS only allows the size of an shm object to be set once, so objects are created with room
+// to grow and resizing just maps more of the object.
+#[cfg(target_os = "macos")]
+const SHM_CAPACITY: Option<u64> = Some(1 << 20);
+#[cfg(not(target_os = "macos"))]
+const SHM_CAPACITY: Option<u64> = None;
 pub const READ_ONLY_BUF_NAME: &str = "/shared_ro";
 pub const READ_WRITE_BUF_NAME: &str = "/shared_rw";
-pub const READ_ONLY_BUF_SIZE: i32 = GRID_W * GRID_H * 4;
-// 1 * i32 for signals, 2 * i32 for hunter, N * 3 * i32 for runners
-pub const READ_WRITE_BUF_SIZE: i32 = SIGNAL_BYTES + 8 + N_RUNNERS * 12;
-pub const WASM_ALLOC_SIZE: i32 = READ_ONLY_BUF_SIZE + READ_WRITE_BUF_SIZE + 3 * PAGE_SIZE as i32;
+// Buffer sizes include the BufferHeader at the start of each buffer.
+pub const READ_ONLY_BUF_SIZE: u64 = HEADER_BYTES + (GRID_W * GRID_H * 4) as u64;
+// The signals, then a HunterRecord and N RunnerRecords.
+pub const READ_WRITE_BUF_SIZE: u64 = ACTORS_OFFSET + HUNTER_BYTES + N_RUNNERS as u64 * RUNNER_BYTES;
+pub const HUNTER_BYTES: u64 = mem::size_of::<HunterRecord>() as u64;
+pub const RUNNER_BYTES: u64 = mem::size_of::<RunnerRecord>() as u64;
+pub const WASM_ALLOC_SIZE: u64 = READ_ONLY_BUF_SIZE + READ_WRITE_BUF_SIZE + 3 * PAGE_SIZE as u64;
 
-// IPC config.
-pub const SIGNAL_BYTES: i32 = 4;
+// Buffer registry; the first two entries are always the read-only and read-write buffers above.
+// Like every buffer name here, this is given the host's session suffix (see session_name).
+pub const BUFFER_TABLE_NAME: &str = "/shared_buffers";
+pub const MAX_BUFFERS: usize = 16;
+pub const BUFFER_NAME_LEN: usize = 32;
+pub const READ_ONLY_BUF_ID: usize = 0;
+pub const READ_WRITE_BUF_ID: usize = 1;
+// Tick timestamps, one TickStats slot per container; registered by the host after the two above.
+pub const STATS_BUF_NAME: &str = "/shared_stats";
+pub const STATS_BUF_ID: usize = 2;
+pub const STATS_BUF_SIZE: u64 = HEADER_BYTES + (CONTAINER_NAMES.len() * mem::size_of::<TickStats>()) as u64;
+// Module draw lists, one DrawList per container.
+pub const DRAW_BUF_NAME: &str = "/shared_draw";
+pub const DRAW_BUF_ID: usize = 3;
+pub const DRAW_BUF_SIZE: u64 = HEADER_BYTES + (CONTAINER_NAMES.len() * mem::size_of::<DrawList>()) as u64;
+// Only registered when the host double-buffers the grid: the control word saying which copy is
+// live, and the second copy (the read-only buffer is the first).
+pub const GRID_CTL_BUF_NAME: &str = "/shared_grid_ctl";
+pub const GRID_CTL_BUF_ID: usize = 4;
+pub const GRID_CTL_BUF_SIZE: u64 = HEADER_BYTES + mem::size_of::<GridControl>() as u64;
+pub const GRID_BACK_BUF_NAME: &str = "/shared_grid_back";
+pub const GRID_BACK_BUF_ID: usize = 5;
+
+// Each set of buffers also has a control object holding a count of the processes using the set
+// (see Control). Like the registry, it isn't itself a registered buffer.
+pub const CONTROL_NAME: &str = "/shared_control";
+// The control object's slots for processes whose references are released for them if they die
+// without detaching: the host, then the containers by signal index.
+pub const HOST_SLOT: usize = 0;
+pub const CONTROL_SLOTS: usize = 1 + CONTAINER_NAMES.len();
+// And a control blocks object, holding each container's ControlBlock in pages of its own after a
+// page for the header (see ControlBlocks).
+pub const CONTROL_BLOCKS_NAME: &str = "/shared_signals";
+pub const CONTROL_BLOCK_BYTES: u64 =
+    (mem::size_of::<ControlBlock>() as u64).div_ceil(PAGE_SIZE as u64) * PAGE_SIZE as u64;
+pub const CONTROL_BLOCKS_SIZE: u64 = PAGE_SIZE as u64 + CONTAINER_NAMES.len() as u64 * CONTROL_BLOCK_BYTES;
+
+// Buffer header; bump LAYOUT_VERSION whenever the layout of any shared buffer changes.
+pub const BUFFER_MAGIC: u32 = 0x7773_6266; // "wsbf"
+pub const LAYOUT_VERSION: u32 = 24;
+pub const HEADER_BYTES: u64 = mem::size_of::<BufferHeader>() as u64;
+
+// IPC config shared by the containers: one SharedRing for all of them, the TickBarriers, an actor
+// SeqLock per container and the GridSync. Each container's own signals are in its ControlBlock.
+pub const RING_BYTES: u64 = mem::size_of::<SharedRing>() as u64;
+pub const BARRIER_BYTES: u64 = mem::size_of::<TickBarriers>() as u64;
+pub const ACTOR_LOCK_BYTES: u64 = (CONTAINER_NAMES.len() * mem::size_of::<SeqLock>()) as u64;
+pub const GRID_SYNC_BYTES: u64 = mem::size_of::<GridSync>() as u64;
+pub const ACTORS_OFFSET: u64 = HEADER_BYTES + RING_BYTES + BARRIER_BYTES + ACTOR_LOCK_BYTES + GRID_SYNC_BYTES;
+pub const COMMAND_ARGS: usize = 4;
 pub const HUNTER_SIGNAL_INDEX: usize = 0;
 pub const RUNNER_SIGNAL_INDEX: usize = 1;
-pub const SIGNAL_REPS: i32 = 300;
-pub const SIGNAL_WAIT: u64 = 100;
+// Indexed by signal index; these are the columns of each buffer's access matrix.
+pub const CONTAINER_NAMES: [&str; 2] = ["hunter", "runner"];
+// Named groups of containers that the host can address together (see Target), by signal index;
+// here the containers are grouped by the engine the host runs their modules in.
+pub const CONTAINER_GROUPS: [(&str, &[usize]); 2] =
+    [("wasmer", &[HUNTER_SIGNAL_INDEX]), ("wasmi", &[RUNNER_SIGNAL_INDEX])];
+pub const SIGNAL_TIMEOUT: Duration = Duration::from_secs(30);
+// A waiting container beats its heartbeat at least this often, and the host checks on the
+// containers it's waiting for as often. A container that is handling a command but hasn't beaten
+// for STALL_TIMEOUT has stalled.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(10);
 
 // Grid setup.
 pub const GRID_W: i32 = 50;
 pub const GRID_H: i32 = 30;
 pub const N_BLOCKS: i32 = 150;
+// Initial number of runners; the host can add more at runtime.
 pub const N_RUNNERS: i32 = 15;
 
 // GUI settings.
 pub const SCALE: f64 = 20.0;
+// The fastest tick interval; the host backs off towards MAX_TICK_MS if the modules overrun.
 pub const TICK_MS: u64 = 150;
+pub const MAX_TICK_MS: u64 = 16 * TICK_MS;
+// A tick overruns if the slowest module takes more than 80% of the interval, and is fast if it
+// takes less than 25%. The rate only changes after this many ticks in a row.
+pub const OVERRUN_TICKS: u32 = 5;
+pub const FAST_TICKS: u32 = 20;
+// How often the host checks whether lagging containers have caught up, with --lag=coalesce.
+pub const LAG_POLL_INTERVAL: Duration = Duration::from_millis(5);
 
 // -- Definitions for both host and containers --
 
@@ -53,89 +163,4004 @@ pub enum Signal {
     Tick,
     LargeAlloc,
     ModifyGrid,
+    Resize,
+    Protect,
     Exit,
+    // Drops the commands queued behind it (see CommsChannel::send_urgent).
+    Abort,
+    // Calls the module export in the container's CallSlot (see ControlBlock::prepare_call).
+    Call,
+    // Completed straight away, without touching the buffers or the module, to time the comms
+    // (see ipc-bench).
+    Ping,
+    // Replaces the module with a new instance of the file the container loaded it from, keeping
+    // the buffers; see Container::reload.
+    ReloadModule,
+    // Save the module's linear memory to a file and load it back; see Container::snapshot.
+    Snapshot,
+    Restore,
 }
 
 impl Signal {
-    pub fn from(value: u8) -> Self {
-        assert!((0..6).contains(&value));
-        [Self::Idle, Self::Init, Self::Tick, Self::LargeAlloc, Self::ModifyGrid, Self::Exit][value as usize]
+    pub fn from(value: u32) -> Self {
+        Self::known(value).unwrap_or_else(|| panic!("unknown signal {}", value))
+    }
+
+    // None if 'value' isn't a signal.
+    pub fn known(value: u32) -> Option<Self> {
+        [
+            Self::Idle,
+            Self::Init,
+            Self::Tick,
+            Self::LargeAlloc,
+            Self::ModifyGrid,
+            Self::Resize,
+            Self::Protect,
+            Self::Exit,
+            Self::Abort,
+            Self::Call,
+            Self::Ping,
+            Self::ReloadModule,
+            Self::Snapshot,
+            Self::Restore,
+        ]
+        .get(value as usize)
+        .copied()
     }
 }
 
-// -- Definitions for containers only --
+// Init's flags. With INIT_LOAD_GRID the container first has the module fill in the grid with its
+// init_grid export; the host grants it write access to the grid buffer for that.
+pub const INIT_LOAD_GRID: u32 = 1;
 
-pub struct Buffers {
-    pub shared_ro: cptr,
-    pub shared_rw: cptr,
-    index: usize,
-    signal: *mut u8,
+// A signal with its arguments, which depend on the signal:
+//   Init: [seed for the module's random numbers, INIT_* flags]
+//   Call: [the call's sequence number in the CallSlot]
+//   Snapshot, Restore: [the snapshot's slot]
+// Arguments that don't fit in the words go in a payload of 'payload_len' bytes at
+// 'payload_offset' in the read-write buffer, which the host leaves alone until the container
+// has completed the command. The channel stamps each command with an epoch as it's sent (see
+// ControlBlock::next_epoch), which the container echoes when it completes the command. A
+// non-zero 'deadline_us' (in now_us time) is when the container should give up on the module's
+// calls for the command; see Instance::set_deadline.
+#[repr(C)]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Command {
+    pub signal: u32,
+    pub epoch: u32,
+    pub args: [u32; COMMAND_ARGS],
+    pub payload_offset: u32,
+    pub payload_len: u32,
+    pub deadline_us: i64,
 }
 
-impl Buffers {
-    pub fn new(shared_ro: cptr, shared_rw: cptr, index: usize) -> Self {
-        assert!(index == HUNTER_SIGNAL_INDEX || index == RUNNER_SIGNAL_INDEX);
-        Self {
-            shared_ro,
-            shared_rw,
-            index,
-            signal: unsafe { shared_rw.add(index) as *mut u8 },
+impl Command {
+    pub fn new(signal: Signal) -> Self {
+        Self::with_args(signal, &[])
+    }
+
+    pub fn with_args(signal: Signal, args: &[u32]) -> Self {
+        let mut command = Self { signal: signal as u32, ..Self::default() };
+        command.args[..args.len()].copy_from_slice(args);
+        command
+    }
+
+    pub fn with_deadline(self, deadline_us: i64) -> Self {
+        Self { deadline_us, ..self }
+    }
+
+    pub fn signal(&self) -> Signal {
+        Signal::from(self.signal)
+    }
+
+    pub fn deadline(&self) -> Option<i64> {
+        (self.deadline_us != 0).then_some(self.deadline_us)
+    }
+}
+
+// How a container completed a command. Ok has the result: whatever the module's export returned,
+// or 0. Error has one of the ERROR_ codes; the container also posts the details as an error
+// event, and carries on with the next command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Status {
+    Ok(i32),
+    Error(u32),
+}
+
+// A call into the module trapped or couldn't be made.
+pub const ERROR_CALL_FAILED: u32 = 1;
+// The command's signal isn't one the container knows.
+pub const ERROR_UNKNOWN_SIGNAL: u32 = 2;
+// A call into the module was still running at the command's deadline, and was interrupted.
+pub const ERROR_TIMED_OUT: u32 = 3;
+// A call into the module wrote to a buffer that's read-only to the container. The writes were
+// discarded and the call went on; see divert_write.
+pub const ERROR_READ_ONLY_WRITE: u32 = 4;
+
+impl Status {
+    // As the comms carry it: the error code, 0 for Ok, and the result.
+    fn encode(self) -> (u32, i32) {
+        match self {
+            Self::Ok(result) => (0, result),
+            Self::Error(code) => (code, 0),
+        }
+    }
+
+    fn decode(code: u32, result: i32) -> Self {
+        if code == 0 { Self::Ok(result) } else { Self::Error(code) }
+    }
+}
+
+// What went wrong in a failed call into a module, as far as the container can tell; see
+// TrapKind::classify.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TrapKind {
+    // Anything else, e.g. an import that failed.
+    Other,
+    OutOfBounds,
+    Unreachable,
+    StackOverflow,
+    DivideByZero,
+    IntegerOverflow,
+    BadConversion,
+    // A call_indirect to a missing function or one of the wrong type.
+    BadIndirectCall,
+    OutOfFuel,
+    Interrupted,
+    NoSuchExport,
+    ReadOnlyWrite,
+}
+
+impl TrapKind {
+    fn from(value: u32) -> Self {
+        [
+            Self::Other,
+            Self::OutOfBounds,
+            Self::Unreachable,
+            Self::StackOverflow,
+            Self::DivideByZero,
+            Self::IntegerOverflow,
+            Self::BadConversion,
+            Self::BadIndirectCall,
+            Self::OutOfFuel,
+            Self::Interrupted,
+            Self::NoSuchExport,
+            Self::ReadOnlyWrite,
+        ]
+        .get(value as usize)
+        .copied()
+        .unwrap_or(Self::Other)
+    }
+
+    // For engines that only describe their traps in text, which differs from engine to engine
+    // (and for wasmi is the Debug form of its TrapKind), so this looks for the likely words.
+    pub fn classify(message: &str) -> Self {
+        let message = message.to_lowercase();
+        let has = |words: &[&str]| words.iter().any(|word| message.contains(word));
+        if has(&["interrupted"]) {
+            Self::Interrupted
+        } else if has(&["fuel"]) {
+            Self::OutOfFuel
+        } else if has(&["no such export", "does not export", "doesn't have export", "not found", "failed to find"]) {
+            Self::NoSuchExport
+        } else if has(&["out of bounds", "outofbounds"]) {
+            Self::OutOfBounds
+        } else if has(&["unreachable"]) {
+            Self::Unreachable
+        } else if has(&["stack overflow", "stackoverflow", "stack exhausted"]) {
+            Self::StackOverflow
+        } else if has(&["divide by zero", "division by zero", "divisionbyzero"]) {
+            Self::DivideByZero
+        } else if has(&["integer overflow", "integeroverflow"]) {
+            Self::IntegerOverflow
+        } else if has(&["conversion"]) {
+            Self::BadConversion
+        } else if has(&["indirect", "signature", "uninitialized element", "elemuninitialized", "undefined element"]) {
+            Self::BadIndirectCall
+        } else {
+            Self::Other
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Other => "failed",
+            Self::OutOfBounds => "out of bounds memory access",
+            Self::Unreachable => "unreachable code",
+            Self::StackOverflow => "stack overflow",
+            Self::DivideByZero => "integer divide by zero",
+            Self::IntegerOverflow => "integer overflow",
+            Self::BadConversion => "invalid conversion to integer",
+            Self::BadIndirectCall => "bad indirect call",
+            Self::OutOfFuel => "out of fuel",
+            Self::Interrupted => "interrupted at its deadline",
+            Self::NoSuchExport => "no such export",
+            Self::ReadOnlyWrite => "write to a read-only buffer",
+        }
+    }
+}
+
+// A failed call into a module: the engine's message, and the frames of the wasm backtrace,
+// innermost first, for engines that keep one. The container records its last one in its
+// ControlBlock for the host (see ControlBlock::take_trap).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallError {
+    pub kind: TrapKind,
+    pub export: String,
+    pub message: String,
+    pub backtrace: Vec<String>,
+}
+
+impl CallError {
+    // The kind is guessed from the message; engines that know better use with_kind.
+    pub fn new(export: &str, message: String) -> Self {
+        Self { kind: TrapKind::classify(&message), export: export.to_string(), message, backtrace: Vec::new() }
+    }
+
+    pub fn with_kind(self, kind: TrapKind) -> Self {
+        Self { kind, ..self }
+    }
+
+    pub fn with_backtrace(self, backtrace: Vec<String>) -> Self {
+        Self { backtrace, ..self }
+    }
+}
+
+impl std::fmt::Display for CallError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+// Which containers the host sends a command to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    All,
+    // By signal index.
+    Container(usize),
+    // By name, from CONTAINER_GROUPS.
+    Group(&'static str),
+}
+
+impl Target {
+    // Accepts "all", a name from CONTAINER_NAMES or a group name.
+    pub fn parse(name: &str) -> Option<Self> {
+        if name == "all" {
+            return Some(Self::All);
+        }
+        if let Some(index) = CONTAINER_NAMES.iter().position(|&container| container == name) {
+            return Some(Self::Container(index));
+        }
+        CONTAINER_GROUPS.iter().find(|(group, _)| *group == name).map(|(group, _)| Self::Group(group))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Container(index) => CONTAINER_NAMES[*index],
+            Self::Group(group) => group,
+        }
+    }
+
+    pub fn includes(&self, index: usize) -> bool {
+        match self {
+            Self::All => true,
+            Self::Container(container) => *container == index,
+            Self::Group(group) => {
+                CONTAINER_GROUPS.iter().any(|(name, members)| name == group && members.contains(&index))
+            }
         }
     }
+}
+
+// The actor records in the read-write buffer, laid out as the modules' Hunter and Runner are in
+// wasm32. Placed with an Arena after the signals.
+#[repr(C)]
+pub struct HunterRecord {
+    pub x: i32,
+    pub y: i32,
+}
+
+#[repr(C)]
+pub struct RunnerRecord {
+    pub x: i32,
+    pub y: i32,
+    pub state: i32,
+}
+
+// Written by the host at the start of every shared buffer, so containers built against a different
+// layout fail with a clear error instead of misreading the data.
+#[repr(C)]
+pub struct BufferHeader {
+    pub magic: u32,
+    pub version: u32,
+    // Total size of the buffer, including this header.
+    pub size: u64,
+    pub creator_pid: i32,
+}
+
+impl BufferHeader {
+    pub fn validate(&self, name: &str, expected_size: u64) {
+        if self.magic != BUFFER_MAGIC {
+            panic!("{}: bad magic {:#x}; not created by a compatible host", name, self.magic);
+        }
+        if self.version != LAYOUT_VERSION {
+            panic!(
+                "{}: layout version {} from host pid {}, expected {}; rebuild the host and containers together",
+                name, self.version, self.creator_pid, LAYOUT_VERSION
+            );
+        }
+        if self.size != expected_size {
+            panic!(
+                "{}: size {} from host pid {} doesn't match the expected {}",
+                name, self.size, self.creator_pid, expected_size
+            );
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+    // Mapped read-only, but the host can grant containers write access at runtime.
+    Grantable,
+    // Not mapped by the container at all.
+    Denied,
+}
+
+impl Access {
+    fn from(value: i32) -> Self {
+        [Self::ReadOnly, Self::ReadWrite, Self::Grantable, Self::Denied][value as usize]
+    }
+}
+
+// How each container may map a buffer, indexed like CONTAINER_NAMES. The host always has
+// read-write access.
+pub type Acl = [Access; CONTAINER_NAMES.len()];
+
+// An entry in the buffer registry's descriptor table.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct BufferDesc {
+    name: [u8; BUFFER_NAME_LEN],
+    pub size: u64,
+    acl: [i32; CONTAINER_NAMES.len()],
+    granted: i32,
+    // The host's fd for a memfd-backed buffer, or 0 for a named shm object.
+    memfd: i32,
+    sealed: i32,
+}
+
+impl BufferDesc {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(BUFFER_NAME_LEN);
+        std::str::from_utf8(&self.name[..len]).unwrap()
+    }
+
+    // The access allowed to the container with the given signal index.
+    pub fn access(&self, index: usize) -> Access {
+        Access::from(self.acl[index])
+    }
+
+    pub fn acl(&self) -> Acl {
+        self.acl.map(Access::from)
+    }
+
+    pub fn granted(&self) -> bool {
+        self.granted != 0
+    }
+
+    // Whether the host has sealed the buffer's memfd, so no process can write to it or resize it.
+    pub fn sealed(&self) -> bool {
+        self.sealed != 0
+    }
 
-    pub fn wait_for_signal(&self) -> Signal {
-        for _ in 0..SIGNAL_REPS {
-            let signal = Signal::from(unsafe { *self.signal });
-            if signal != Signal::Idle {
-                return signal;
+    // Whether the given container should currently map the buffer writable.
+    pub fn writable(&self, index: usize) -> bool {
+        match self.access(index) {
+            Access::ReadOnly | Access::Denied => false,
+            Access::ReadWrite => true,
+            Access::Grantable => self.granted(),
+        }
+    }
+}
+
+#[repr(C)]
+struct BufferTable {
+    header: BufferHeader,
+    count: i32,
+    descs: [BufferDesc; MAX_BUFFERS],
+}
+
+// Every process using a set of buffers (the host, containers and shmtool) holds a reference to
+// it, and whichever drops the last one unlinks the set's shm objects, unless it is persisted. This
+// lets the containers clean up if the host crashes before its BufferSet is dropped. The count is
+// kept in its own object as containers map most buffers, and the registry, read-only.
+#[repr(C)]
+struct Control {
+    header: BufferHeader,
+    refs: AtomicU32,
+    persist: AtomicU32,
+    // The pid holding each slot's reference, or 0. A slot's reference is released by the next
+    // process to detach once its holder has died, or taken over by a replacement (e.g. a container
+    // restarted after being killed).
+    holders: [AtomicI32; CONTROL_SLOTS],
+}
+
+impl Control {
+    // Returns whether this released the last reference.
+    fn release(&self) -> bool {
+        self.refs.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+
+    // Releases the references held by processes that have died.
+    fn release_dead(&self) {
+        let me = unsafe { libc::getpid() };
+        for holder in &self.holders {
+            let pid = holder.load(Ordering::Acquire);
+            let dead = pid != 0 && pid != me && !is_alive(pid);
+            if dead && holder.compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                self.release();
             }
-            thread::sleep(Duration::from_millis(SIGNAL_WAIT));
         }
-        panic!("container {} failed to received signal", self.index);
     }
+}
+
+fn is_alive(pid: i32) -> bool {
+    // EPERM means the process exists but belongs to someone else.
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// The shared buffers declared by the host. The descriptor table is itself held in shared memory,
+// so containers map whatever the host has declared and see size changes made before a resize.
+pub struct BufferSet {
+    table: *mut BufferTable,
+    control: *mut Control,
+    session: String,
+    ownership: Ownership,
+    // The process holding the set's reference; a forked copy doesn't hold one.
+    pid: i32,
+    // The control slot this process's reference was claimed in, if any.
+    slot: Option<usize>,
+    // The host's mapping of each buffer it added or attached to, in registry order; seal() and
+    // resize() replace these, so nobody else can be left holding the old ones.
+    mapped: Vec<cptr>,
+}
 
-    pub fn send_idle(&self) {
-        unsafe { *self.signal = Signal::Idle as u8 };
+// Concurrent hosts keep their shm objects apart by adding a per-session suffix to every name. The
+// host passes its session on to the containers; the empty session gives the plain names.
+pub fn session_name(base: &str, session: &str) -> String {
+    match session {
+        "" => base.to_string(),
+        _ => format!("{}_{}", base, session),
     }
 }
 
-impl Drop for Buffers {
+// How a process came by a set of shm objects. A SharedBuffer is only removed by the process that
+// created it, when it is dropped; checking the creator pid as well means a forked copy can't
+// remove it early. A BufferSet is removed by whichever process releases the last reference to it
+// (see Control), unless it is persisted.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Ownership {
+    // Created by this process.
+    Creator,
+    // Created by this process or a previous host, and left in place for a later host.
+    Persisted,
+    // Opened from another process's objects (containers, shmtool).
+    Attacher,
+}
+
+impl BufferSet {
+    pub fn session(&self) -> &str {
+        &self.session
+    }
+
+    // The name of the buffer 'base' (one of the *_BUF_NAME constants) in this set's session.
+    pub fn name(&self, base: &str) -> String {
+        session_name(base, &self.session)
+    }
+
+    // The inverse of name(), for buffers moved between sessions.
+    pub fn base_name<'a>(&self, desc: &'a BufferDesc) -> &'a str {
+        let suffix = session_name("", &self.session);
+        desc.name().strip_suffix(suffix.as_str()).unwrap_or(desc.name())
+    }
+
+    pub fn descs(&self) -> &[BufferDesc] {
+        let table = unsafe { &*self.table };
+        &table.descs[..table.count as usize]
+    }
+
+    // memfd objects have no name, so other processes open them through the host's fd table.
+    pub fn memfd_path(&self, desc: &BufferDesc) -> Option<String> {
+        let creator_pid = unsafe { &*self.table }.header.creator_pid;
+        if desc.memfd == 0 { None } else { Some(format!("/proc/{}/fd/{}", creator_pid, desc.memfd)) }
+    }
+}
+
+impl Drop for BufferSet {
     fn drop(&mut self) {
-        unsafe {
-            if !self.shared_ro.is_null()
-                && libc::munmap(self.shared_ro, READ_ONLY_BUF_SIZE as usize) == -1 {
-                println!("munmap failed for shared_ro");
+        if self.pid == unsafe { libc::getpid() } {
+            let control = unsafe { &*self.control };
+            if let Some(slot) = self.slot {
+                control.holders[slot].store(0, Ordering::Release);
             }
-            if !self.shared_rw.is_null()
-                && libc::munmap(self.shared_rw, READ_WRITE_BUF_SIZE as usize) == -1 {
-                println!("munmap failed for shared_rw");
+            control.release_dead();
+            if control.release() && control.persist.load(Ordering::Acquire) == 0 {
+                self.unlink_all();
+            }
+        }
+        if unsafe { libc::munmap(self.table as cptr, mem::size_of::<BufferTable>()) } == -1 {
+            println!("munmap failed for {}", self.name(BUFFER_TABLE_NAME));
+        }
+        if unsafe { libc::munmap(self.control as cptr, mem::size_of::<Control>()) } == -1 {
+            println!("munmap failed for {}", self.name(CONTROL_NAME));
+        }
+    }
+}
+
+// Paging hints for a mapped buffer, passed through to madvise(). DontDump keeps a buffer (e.g. one
+// holding secrets) out of core dumps; it and DoDump are Linux-only and are ignored elsewhere.
+// DontNeed drops the pages from this mapping only; the shm object keeps its contents.
+#[derive(Copy, Clone, Debug)]
+pub enum Advice {
+    Normal,
+    Random,
+    Sequential,
+    WillNeed,
+    DontNeed,
+    DontDump,
+    DoDump,
+}
+
+impl Advice {
+    fn flag(self) -> Option<libc::c_int> {
+        match self {
+            Self::Normal => Some(libc::MADV_NORMAL),
+            Self::Random => Some(libc::MADV_RANDOM),
+            Self::Sequential => Some(libc::MADV_SEQUENTIAL),
+            Self::WillNeed => Some(libc::MADV_WILLNEED),
+            Self::DontNeed => Some(libc::MADV_DONTNEED),
+            #[cfg(target_os = "linux")]
+            Self::DontDump => Some(libc::MADV_DONTDUMP),
+            #[cfg(target_os = "linux")]
+            Self::DoDump => Some(libc::MADV_DODUMP),
+            #[cfg(not(target_os = "linux"))]
+            Self::DontDump | Self::DoDump => None,
+        }
+    }
+}
+
+// The state only one container shares with the host. Each container's block is in pages of its
+// own, and a container only maps its own block, so it can't touch another container's signals
+// (and its block is never mapped into linear memory, so neither can its module). A zero-filled
+// block is idle.
+#[repr(C)]
+pub struct ControlBlock {
+    signal: SignalSlot,
+    // The host fills in the command before sending its signal, and the container reads it once
+    // the signal arrives, so the slot's Release/Acquire ordering covers it.
+    command: UnsafeCell<Command>,
+    queue: UnsafeCell<CommandQueue>,
+    // The container advances its heartbeat each time round its command loop, skipping 0, which
+    // the host sets before starting a container so that one that is still loading its module
+    // isn't taken for stalled.
+    heartbeat: Counter,
+    // When the container last completed a tick (see now_us), stamped before it goes idle. The
+    // host compares it with when it sent the tick to find containers that are lagging.
+    completed_us: AtomicI64,
+    // A Ring of the container's events for the host, and how many didn't fit (see post_event).
+    events: UnsafeCell<[u64; EVENT_RING_BYTES / 8]>,
+    dropped_events: AtomicU32,
+    call: UnsafeCell<CallSlot>,
+    // How the container completed its last command (see Status::encode), written before it goes
+    // idle, for the comms that have no response of their own: signal slots and the shared ring.
+    status_code: AtomicU32,
+    status_result: AtomicI32,
+    // The epoch of the last command the host sent and of the last one the container completed,
+    // and how many completions the host has found out of step with the commands it sent.
+    sent_epoch: AtomicU32,
+    completed_epoch: AtomicU32,
+    out_of_step: AtomicU32,
+    trap: UnsafeCell<TrapRecord>,
+    // How many of the traps the host has reported.
+    reported_traps: AtomicU32,
+    // The export the container is calling, if any, and the last SIGSEGV or SIGBUS it took, with
+    // the address, for the host to report if the container dies of it (see catch_faults).
+    calling_len: AtomicU32,
+    calling: UnsafeCell<[u8; CALL_NAME_BYTES]>,
+    fault_signal: AtomicI32,
+    fault_address: AtomicU64,
+    // How many pages of read-only buffers the current call wrote to, and where its first such
+    // write was (see divert_write).
+    diverted_pages: AtomicU32,
+    diverted_address: AtomicU64,
+}
+
+// The space for a ControlBlock's event ring, and the longest text an event keeps.
+pub const EVENT_RING_BYTES: usize = mem::size_of::<RingHeader>() + 4096;
+pub const EVENT_TEXT_BYTES: usize = 256;
+// Each event is its kind (a u32), its value (an i64) and then its text.
+const EVENT_HEADER_BYTES: usize = 12;
+
+pub struct Event {
+    pub kind: EventKind,
+    pub value: i64,
+    pub text: String,
+}
+
+// The container's last failed call, written under the lock. The text is the message followed by
+// the backtrace's frames, a line each; what doesn't fit in TRAP_TEXT_BYTES is cut off.
+#[repr(C)]
+struct TrapRecord {
+    lock: SeqLock,
+    // How many calls have failed.
+    count: u32,
+    kind: u32,
+    export_len: u32,
+    export: [u8; CALL_NAME_BYTES],
+    message_len: u32,
+    text_len: u32,
+    text: [u8; TRAP_TEXT_BYTES],
+}
+
+pub const TRAP_TEXT_BYTES: usize = 2048;
+
+// The longest export name and the most arguments a Signal::Call can take.
+pub const CALL_NAME_BYTES: usize = 64;
+pub const CALL_ARGS: usize = 8;
+
+// An argument for an export called with Signal::Call: a value, or the module's context, which
+// most exports take first and only the container knows.
+#[derive(Copy, Clone, Debug)]
+pub enum CallArg {
+    Context,
+    Value(i32),
+}
+
+// The host fills in the export to call and its arguments before sending Signal::Call, and the
+// container writes the return value before completing it, so the signal's ordering covers both.
+// The container copies the call's sequence number to 'answered' with the return value, so the
+// host can tell a call that returned from one that was dropped (e.g. by an Abort).
+#[repr(C)]
+struct CallSlot {
+    seq: u32,
+    answered: u32,
+    name_len: u32,
+    name: [u8; CALL_NAME_BYTES],
+    arg_count: u32,
+    // Bit i is set if argument i is the context rather than args[i].
+    context_args: u32,
+    args: [i32; CALL_ARGS],
+    // CALL_RETURNED if the export returned a value, CALL_FAILED if the call failed, and the value.
+    returned: u32,
+    result: i32,
+}
+
+const CALL_RETURNED: u32 = 1;
+const CALL_FAILED: u32 = 2;
+
+// What a Signal::Call's export returned.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CallResult {
+    Returned(i32),
+    Nothing,
+    // The call failed; the container posts the details as an error event.
+    Failed,
+    // The call was dropped before it was made, e.g. by an Abort or the container dying.
+    Dropped,
+}
+
+impl ControlBlock {
+    pub fn signal(&self) -> &SignalSlot {
+        &self.signal
+    }
+
+    pub fn command(&self) -> *mut Command {
+        self.command.get()
+    }
+
+    pub fn queue(&self) -> *mut CommandQueue {
+        self.queue.get()
+    }
+
+    pub fn heartbeat(&self) -> &Counter {
+        &self.heartbeat
+    }
+
+    pub fn completed_us(&self) -> i64 {
+        self.completed_us.load(Ordering::Relaxed)
+    }
+
+    pub fn stamp_completed(&self, us: i64) {
+        self.completed_us.store(us, Ordering::Relaxed);
+    }
+
+    fn set_status(&self, status: Status) {
+        let (code, result) = status.encode();
+        self.status_code.store(code, Ordering::Relaxed);
+        self.status_result.store(result, Ordering::Relaxed);
+    }
+
+    fn status(&self) -> Status {
+        Status::decode(self.status_code.load(Ordering::Relaxed), self.status_result.load(Ordering::Relaxed))
+    }
+
+    // Epochs count the host's commands to the container, wrapping at 2^32, and carry on across
+    // container restarts and new channels (e.g. after a resize).
+    fn next_epoch(&self) -> u32 {
+        self.sent_epoch.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+
+    pub fn sent_epoch(&self) -> u32 {
+        self.sent_epoch.load(Ordering::Relaxed)
+    }
+
+    pub fn completed_epoch(&self) -> u32 {
+        self.completed_epoch.load(Ordering::Relaxed)
+    }
+
+    // Host side. Returns false, and counts the completion as out of step, if the container
+    // completed command 'echoed' when 'expected' was due: an older epoch is a stale command the
+    // container shouldn't have still been handling, and a newer one means it missed some.
+    fn check_epoch(&self, index: usize, signal: u32, expected: u32, echoed: u32) -> bool {
+        if echoed == expected {
+            return true;
+        }
+        let missed = (echoed.wrapping_sub(expected) as i32) > 0;
+        let how = if missed { "skipping the commands before it" } else { "which is stale" };
+        println!(
+            "Container {}: completed command {} (signal {}) when {} was due, {}",
+            index, echoed, signal, expected, how
+        );
+        self.out_of_step.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    // Host side: how many completions were out of step since the last call, after which the
+    // host restarts the container.
+    pub fn take_out_of_step(&self) -> u32 {
+        self.out_of_step.swap(0, Ordering::Relaxed)
+    }
+
+    // Only the container pushes to the ring and only the host pops from it.
+    fn events(&self) -> Ring<'_> {
+        Ring::place(&mut Arena::new(self.events.get() as cptr, EVENT_RING_BYTES))
+    }
+
+    // Container side. The container never waits for the host: an event that doesn't fit in the
+    // ring is dropped and counted.
+    pub fn post_event(&self, kind: EventKind, value: i64, text: &str) {
+        let mut len = text.len().min(EVENT_TEXT_BYTES);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut message = Vec::with_capacity(EVENT_HEADER_BYTES + len);
+        message.extend_from_slice(&(kind as u32).to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(&text.as_bytes()[..len]);
+        if !self.events().push(&message) {
+            self.dropped_events.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Host side. An event the host can't parse is returned as an error about the container.
+    pub fn take_event(&self) -> Option<Event> {
+        let ring = self.events();
+        let mut message = vec![0; ring.peek_len()?];
+        ring.pop(&mut message);
+        let kind = message.get(..4).and_then(|kind| EventKind::from(u32::from_le_bytes(kind.try_into().unwrap())));
+        match (kind, message.get(4..EVENT_HEADER_BYTES)) {
+            (Some(kind), Some(value)) => Some(Event {
+                kind,
+                value: i64::from_le_bytes(value.try_into().unwrap()),
+                text: String::from_utf8_lossy(&message[EVENT_HEADER_BYTES..]).into_owned(),
+            }),
+            _ => Some(Event { kind: EventKind::Error, value: 0, text: "sent a malformed event".to_string() }),
+        }
+    }
+
+    // Host side. The number of events dropped since the last call.
+    pub fn take_dropped_events(&self) -> u32 {
+        self.dropped_events.swap(0, Ordering::Relaxed)
+    }
+
+    // Host side. Fills in the call slot and returns the command to send for the call, which has
+    // to complete before the next is prepared.
+    pub fn prepare_call(&self, export: &str, args: &[CallArg]) -> Command {
+        assert!(export.len() <= CALL_NAME_BYTES, "export name '{}' is too long to call", export);
+        assert!(args.len() <= CALL_ARGS, "{} takes too many arguments to call", export);
+        let call = unsafe { &mut *self.call.get() };
+        call.seq = call.seq.wrapping_add(1);
+        call.name[..export.len()].copy_from_slice(export.as_bytes());
+        call.name_len = export.len() as u32;
+        call.arg_count = args.len() as u32;
+        call.context_args = 0;
+        for (i, arg) in args.iter().enumerate() {
+            match *arg {
+                CallArg::Context => call.context_args |= 1 << i,
+                CallArg::Value(value) => call.args[i] = value,
             }
         }
+        Command::with_args(Signal::Call, &[call.seq])
+    }
+
+    // Host side. What the call prepared last returned, once it has completed.
+    pub fn call_result(&self) -> CallResult {
+        let call = unsafe { &*self.call.get() };
+        match call.returned {
+            _ if call.answered != call.seq => CallResult::Dropped,
+            CALL_RETURNED => CallResult::Returned(call.result),
+            CALL_FAILED => CallResult::Failed,
+            _ => CallResult::Nothing,
+        }
+    }
+
+    // Container side. The export and arguments of the call in 'command', with 'context' for any
+    // CallArg::Context.
+    fn take_call(&self, command: &Command, context: i64) -> (String, Vec<i64>) {
+        let call = unsafe { &*self.call.get() };
+        assert_eq!(call.seq, command.args[0], "the call slot doesn't match the command");
+        let name = &call.name[..(call.name_len as usize).min(CALL_NAME_BYTES)];
+        let args = (0..(call.arg_count as usize).min(CALL_ARGS))
+            .map(|i| if call.context_args & 1 << i != 0 { context } else { call.args[i] as i64 })
+            .collect();
+        (String::from_utf8_lossy(name).into_owned(), args)
+    }
+
+    // The slot's result is an i32, so a memory64 module's pointers come back truncated.
+    fn answer_call(&self, command: &Command, result: &Result<Option<i64>, CallError>) {
+        let call = unsafe { &mut *self.call.get() };
+        call.returned = match result {
+            Ok(Some(_)) => CALL_RETURNED,
+            Ok(None) => 0,
+            Err(_) => CALL_FAILED,
+        };
+        call.result = result.as_ref().ok().copied().flatten().unwrap_or(0) as i32;
+        call.answered = command.args[0];
+    }
+
+    fn trap_record(&self) -> SeqLocked<'_, TrapRecord> {
+        let record = unsafe { &*self.trap.get() };
+        SeqLocked::new(&record.lock, record)
+    }
+
+    // Container side.
+    fn record_trap(&self, err: &CallError) {
+        let record = unsafe { &mut *self.trap.get() };
+        let mut text = err.message.clone();
+        for frame in &err.backtrace {
+            text.push('\n');
+            text.push_str(frame);
+        }
+        let text_len = text.len().min(TRAP_TEXT_BYTES);
+        let export_len = err.export.len().min(CALL_NAME_BYTES);
+        record.lock.begin_write();
+        record.count = record.count.wrapping_add(1);
+        record.kind = err.kind as u32;
+        record.export[..export_len].copy_from_slice(&err.export.as_bytes()[..export_len]);
+        record.export_len = export_len as u32;
+        record.text[..text_len].copy_from_slice(&text.as_bytes()[..text_len]);
+        record.text_len = text_len as u32;
+        record.message_len = err.message.len().min(text_len) as u32;
+        record.lock.end_write();
+    }
+
+    // Host side. The container's latest failed call, if it has failed one since the last call;
+    // any before it go unreported, though the container posts each one as an error event too.
+    pub fn take_trap(&self) -> Option<CallError> {
+        let (count, err) = self.trap_record().read(|record| {
+            let text = &record.text[..(record.text_len as usize).min(TRAP_TEXT_BYTES)];
+            let (message, frames) = text.split_at((record.message_len as usize).min(text.len()));
+            let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+            let err = CallError {
+                kind: TrapKind::from(record.kind),
+                export: lossy(&record.export[..(record.export_len as usize).min(CALL_NAME_BYTES)]),
+                message: lossy(message),
+                backtrace: lossy(frames).lines().filter(|frame| !frame.is_empty()).map(str::to_string).collect(),
+            };
+            (record.count, err)
+        });
+        (self.reported_traps.swap(count, Ordering::Relaxed) != count).then_some(err)
+    }
+
+    // Host side, for a container that died writing the record.
+    pub fn reset_trap_lock(&self) {
+        unsafe { &*self.trap.get() }.lock.reset();
+    }
+
+    // Container side: which export the container is about to call, or None once the call is over.
+    fn set_calling(&self, export: Option<&str>) {
+        let export = export.unwrap_or("");
+        let len = export.len().min(CALL_NAME_BYTES);
+        let calling = unsafe { &mut *self.calling.get() };
+        calling[..len].copy_from_slice(&export.as_bytes()[..len]);
+        self.calling_len.store(len as u32, Ordering::Relaxed);
+        self.fault_signal.store(0, Ordering::Relaxed);
+    }
+
+    // Container side, from the fault handler, so it only stores.
+    fn record_fault(&self, signal: i32, address: u64) {
+        self.fault_address.store(address, Ordering::Relaxed);
+        self.fault_signal.store(signal, Ordering::Relaxed);
+    }
+
+    // Container side, after a call: the number of pages divert_write swapped out during it, and
+    // the first address written, if it wrote to any read-only buffers.
+    fn take_diverted_writes(&self) -> Option<(u32, u64)> {
+        let pages = self.diverted_pages.swap(0, Ordering::Relaxed);
+        (pages > 0).then(|| (pages, self.diverted_address.load(Ordering::Relaxed)))
+    }
+
+    // Host side, once the container has been killed by 'signal': where it was, if that was a
+    // fault the container recorded.
+    pub fn fault(&self, signal: i32) -> Option<String> {
+        if self.fault_signal.load(Ordering::Relaxed) != signal {
+            return None;
+        }
+        let address = self.fault_address.load(Ordering::Relaxed);
+        let len = (self.calling_len.load(Ordering::Relaxed) as usize).min(CALL_NAME_BYTES);
+        let calling = String::from_utf8_lossy(&unsafe { &*self.calling.get() }[..len]).into_owned();
+        Some(match calling.as_str() {
+            "" => format!("at {:#x}, outside any call into its module", address),
+            export => format!("at {:#x}, calling {}", address, export),
+        })
     }
 }
 
-// Uses the libc POSIX API to map in a shared memory buffer.
-pub fn map_buffer(aligned_ptr: i64, name: &str, size: i32, read_only: bool) -> cptr {
-    let cname = CString::new(name).unwrap();
-    let (open_flags, map_flags) = match read_only {
-        false => (O_RDWR, PROT_READ | PROT_WRITE),
-        true => (O_RDONLY, PROT_READ),
-    };
-    unsafe {
-        let fd = libc::shm_open(cname.as_ptr(), open_flags, S_IRUSR | S_IWUSR);
-        if fd == -1 {
-            panic!("shm_open failed for {}", name);
+// The block of the container this process runs, once its Buffers are mapped.
+static EVENT_BLOCK: AtomicPtr<ControlBlock> = AtomicPtr::new(ptr::null_mut());
+
+// Sends an event to the host through the container's event ring, e.g. for the engines' print and
+// event callbacks. Before the ring is mapped (or in a process that isn't a container) it's printed.
+pub fn post_event(kind: EventKind, value: i64, text: &str) {
+    let block = EVENT_BLOCK.load(Ordering::Acquire);
+    if !block.is_null() {
+        return unsafe { &*block }.post_event(kind, value, text);
+    }
+    match kind {
+        EventKind::Log => print!("{}", text),
+        EventKind::Metric => println!("{} {}", text, value),
+        EventKind::Error => println!(">> {}", text),
+        EventKind::Custom => println!("event {}: {}", value, text),
+    }
+}
+
+// Posts an event from a module's event_callback import. The kind comes from the module, so an
+// unknown one is posted as an error naming it instead.
+pub fn post_module_event(kind: u32, value: i64, text: &str) {
+    match EventKind::from(kind) {
+        Some(kind) => post_event(kind, value, text),
+        None => {
+            post_event(EventKind::Error, value, &format!("module posted an event of unknown kind {}: {}", kind, text))
         }
-        let buf = libc::mmap(aligned_ptr as cptr, size as usize, map_flags, MAP_FIXED | MAP_SHARED, fd, 0);
-        assert!(buf == aligned_ptr as cptr);
-        if libc::close(fd) == -1 {
-            panic!("close failed for {}", name);
+    }
+}
+
+// The handlers catch_faults replaced, which it passes the faults on to.
+static PREVIOUS_FAULT_HANDLERS: OnceLock<Vec<(i32, libc::sigaction)>> = OnceLock::new();
+
+// The part of linear memory the buffers are mapped over, from the start of the first to the end
+// of the last; empty in copy mode, where the module's copies are all writable.
+static BUFFER_SPAN: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+// The si_code of a SIGSEGV for a mapped page the access isn't allowed to, which libc only defines
+// for some platforms.
+const SEGV_ACCERR: i32 = 2;
+// A page divert_write copies the faulting page through, mapped by catch_faults so the handler
+// doesn't have to. Calls into the module are made one at a time, so one page is enough.
+static SCRATCH_PAGE: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+// Has SIGSEGV and SIGBUS recorded in the container's block before anything else handles them, so
+// that if one kills the container the host can say where it was. A write to a read-only buffer
+// doesn't get that far (see divert_write). The engines that turn faults in linear memory into
+// traps install their handlers earlier, and get the faults next.
+pub fn catch_faults() {
+    PREVIOUS_FAULT_HANDLERS.get_or_init(|| {
+        let (len, prot, flags) = (PAGE_SIZE as usize, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS);
+        let scratch = unsafe { libc::mmap(ptr::null_mut(), len, prot, flags, -1, 0) };
+        if scratch == libc::MAP_FAILED {
+            panic!("mmap failed for the fault scratch page");
         }
-        buf
+        SCRATCH_PAGE.store(scratch as *mut u8, Ordering::Release);
+        [libc::SIGSEGV, libc::SIGBUS]
+            .iter()
+            .map(|&signal| unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = on_fault as *const () as usize;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut previous: libc::sigaction = mem::zeroed();
+                if libc::sigaction(signal, &action, &mut previous) == -1 {
+                    panic!("failed to catch signal {}: {}", signal, io::Error::last_os_error());
+                }
+                (signal, previous)
+            })
+            .collect()
+    });
+}
+
+extern "C" fn on_fault(signal: i32, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let block = EVENT_BLOCK.load(Ordering::Acquire);
+    if !block.is_null() {
+        let block = unsafe { &*block };
+        if signal == libc::SIGSEGV && divert_write(block, unsafe { &*info }) {
+            return;
+        }
+        block.record_fault(signal, unsafe { (*info).si_addr() } as u64);
+    }
+    let previous = PREVIOUS_FAULT_HANDLERS.get().and_then(|handlers| handlers.iter().find(|(s, _)| *s == signal));
+    match previous {
+        Some((_, action)) if action.sa_sigaction != libc::SIG_DFL && action.sa_sigaction != libc::SIG_IGN => unsafe {
+            if action.sa_flags & libc::SA_SIGINFO != 0 {
+                let handler: extern "C" fn(i32, *mut libc::siginfo_t, *mut libc::c_void) =
+                    mem::transmute(action.sa_sigaction);
+                handler(signal, info, context);
+            } else {
+                let handler: extern "C" fn(i32) = mem::transmute(action.sa_sigaction);
+                handler(signal);
+            }
+        },
+        // Returning retries the access, which now kills the process.
+        _ => unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+        },
     }
 }
 
-// Aligns to next largest page boundary, unless ptr is already aligned.
-pub fn page_align(ptr: i64) -> i64 {
-    ((ptr - 1) & !(PAGE_SIZE - 1)) + PAGE_SIZE
+// During a call into the module, swaps the page of a read-only buffer it wrote to for a private
+// copy, which the write goes to when the handler returns. The call carries on, and once it's
+// over Container::try_call fails it and maps the buffer again, so the container survives with
+// the shared buffer untouched. Returns whether it did.
+//
+// Besides plain copies and atomics this makes one mmap() call, to put the private page in place.
+// POSIX doesn't list mmap() as async-signal-safe, so this relies on it being a bare system call
+// that takes no locks in the process, as it is in glibc and musl on Linux (and in macOS's libc).
+// The page's contents are copied through SCRATCH_PAGE, so nothing else is mapped or unmapped.
+fn divert_write(block: &ControlBlock, info: &libc::siginfo_t) -> bool {
+    let address = unsafe { info.si_addr() } as u64;
+    let span = BUFFER_SPAN[0].load(Ordering::Relaxed)..BUFFER_SPAN[1].load(Ordering::Relaxed);
+    if info.si_code != SEGV_ACCERR || !span.contains(&address) || block.calling_len.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    let saved = SCRATCH_PAGE.load(Ordering::Acquire);
+    if saved.is_null() {
+        return false;
+    }
+    let page = (address & !(PAGE_SIZE as u64 - 1)) as cptr;
+    let (len, prot, flags) = (PAGE_SIZE as usize, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS);
+    unsafe {
+        ptr::copy_nonoverlapping(page as *const u8, saved, len);
+        if libc::mmap(page, len, prot, flags | MAP_FIXED, -1, 0) != page {
+            return false;
+        }
+        ptr::copy_nonoverlapping(saved as *const u8, page as *mut u8, len);
+    }
+    if block.diverted_pages.fetch_add(1, Ordering::Relaxed) == 0 {
+        block.diverted_address.store(address, Ordering::Relaxed);
+    }
+    true
+}
+
+// A mapping of some of the control blocks object: all of it for the host, or the container's own
+// block for a container.
+pub struct ControlBlocks {
+    map: cptr,
+    len: u64,
+    // The signal indexes of the blocks mapped, and where the first of them is.
+    indexes: Range<usize>,
+    first: *mut u8,
+}
+
+impl ControlBlocks {
+    pub fn open_all(session: &str) -> Self {
+        let name = session_name(CONTROL_BLOCKS_NAME, session);
+        let map = open_shared_buffer(&name, CONTROL_BLOCKS_SIZE).unwrap_or_else(|| panic!("{} is missing", name));
+        let first = unsafe { (map as *mut u8).add(PAGE_SIZE as usize) };
+        Self { map, len: CONTROL_BLOCKS_SIZE, indexes: 0..CONTAINER_NAMES.len(), first }
+    }
+
+    // Maps just the block for the container with signal index 'index', after checking the header.
+    pub fn open_one(session: &str, index: usize) -> Self {
+        assert!(index < CONTAINER_NAMES.len());
+        let name = session_name(CONTROL_BLOCKS_NAME, session);
+        let header = map_buffer_at(ptr::null_mut(), &name, 0, PAGE_SIZE as u64, Access::ReadOnly, Mapping::Shared, 0);
+        unsafe {
+            (*(header as *const BufferHeader)).validate(&name, CONTROL_BLOCKS_SIZE);
+            libc::munmap(header, PAGE_SIZE as usize);
+        }
+        let offset = PAGE_SIZE as u64 + index as u64 * CONTROL_BLOCK_BYTES;
+        let (access, mapping) = (Access::ReadWrite, Mapping::Shared);
+        let map = map_buffer_at(ptr::null_mut(), &name, offset, CONTROL_BLOCK_BYTES, access, mapping, 0);
+        Self { map, len: CONTROL_BLOCK_BYTES, indexes: index..index + 1, first: map as *mut u8 }
+    }
+
+    // The blocks stay mapped until self is dropped.
+    pub fn block(&self, index: usize) -> &'static ControlBlock {
+        assert!(self.indexes.contains(&index), "control block {} isn't mapped", index);
+        let offset = (index - self.indexes.start) * CONTROL_BLOCK_BYTES as usize;
+        unsafe { &*(self.first.add(offset) as *const ControlBlock) }
+    }
+}
+
+impl Drop for ControlBlocks {
+    fn drop(&mut self) {
+        if unsafe { libc::munmap(self.map, self.len as usize) } == -1 {
+            println!("munmap failed for the control blocks");
+        }
+    }
+}
+
+// How the host and a container exchange signals, fixed when the host starts the container. By
+// default the signal is in the SignalSlot in the container's ControlBlock and only the
+// wakeups go through the Notifier. With --control=queue the host pushes commands onto the
+// container's CommandQueue instead, so it can send several before waiting, and the container
+// answers each with a result. With --control=shared every container takes its commands from one
+// SharedRing, which can serve up to 32 containers. With --control=socket they're messages on a
+// Unix socket pair, which also carries the container's errors and status lines to the host,
+// leaving the shared buffers purely for data.
+//
+// Every word that says a command has been sent or completed is atomic: the SignalSlot state, the
+// queue's Counters and the ring's sequence words. Sending is a Release store (or a Release RMW)
+// and waiting an Acquire load, so everything the host wrote before sending (the Command, the
+// registry, stats and grid) is visible to the container once it sees the signal, and everything
+// the container wrote before completing (the actors, draw lists and results) is visible to the
+// host once it sees it idle. The rest of the shared data is plain memory that nobody reads while
+// the other side may be writing it, except for the actors, which the host reads under a SeqLock.
+// A socket's send and recv order memory the same way.
+#[derive(Copy, Clone)]
+pub enum Comms {
+    Slot(Notifier),
+    Queue,
+    Shared,
+    Socket(ControlSocket),
+}
+
+impl Comms {
+    // Called by the host on exit; see Notifier::unlink.
+    pub fn unlink(&self) {
+        if let Comms::Slot(notifier) = self {
+            notifier.unlink();
+        }
+    }
+
+    // Called in a container's process before the host can first signal it; see
+    // Notifier::prepare_receiver.
+    pub fn prepare_container(&self) {
+        if let Comms::Slot(notifier) = self {
+            notifier.prepare_receiver();
+        }
+    }
+}
+
+// The pids of the containers the host has started, by signal index, for --notify=signal. They're
+// kept in the host's own memory rather than the control blocks, which the containers can write.
+// A container's copy stays 0, as it never sends.
+pub static CONTAINER_PIDS: [AtomicI32; CONTAINER_NAMES.len()] = [AtomicI32::new(0), AtomicI32::new(0)];
+
+// "queue", "shared", or "socket:<fd>" for the container's end of its control socket, if the
+// host started the container with one of them. Inside a VM it's "vsock:<port>", the port that
+// vm-bridge is listening on.
+pub const CONTROL_ENV: &str = "SHARED_BUFFERS_CONTROL";
+
+// The command that vm-bridge runs (with sh -c) to start a container's VM, for a host given --vm.
+pub const VM_ENV: &str = "SHARED_BUFFERS_VM";
+
+// The comms a container was started with, from CONTROL_ENV and NOTIFY_ENV.
+pub fn comms_from_env() -> Comms {
+    let control = match std::env::var(CONTROL_ENV) {
+        Ok(control) => control,
+        Err(_) => return Comms::Slot(notifier_from_env()),
+    };
+    match control.as_str() {
+        "queue" => return Comms::Queue,
+        "shared" => return Comms::Shared,
+        _ => {}
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(port) = control.strip_prefix("vsock:").and_then(|port| port.parse().ok()) {
+        return Comms::Socket(ControlSocket::connect_vsock(port));
+    }
+    match control.strip_prefix("socket:").and_then(|fd| fd.parse().ok()) {
+        Some(fd) => Comms::Socket(ControlSocket { fd }),
+        None => panic!("bad control '{}' in {}", control, CONTROL_ENV),
+    }
+}
+
+// The environment variable and value that start a container with 'comms'.
+pub fn comms_env(comms: Comms) -> (&'static str, String) {
+    match comms {
+        Comms::Slot(notifier) => (NOTIFY_ENV, notifier.to_string()),
+        Comms::Queue => (CONTROL_ENV, "queue".to_string()),
+        Comms::Shared => (CONTROL_ENV, "shared".to_string()),
+        Comms::Socket(socket) => (CONTROL_ENV, format!("socket:{}", socket.fd)),
+    }
+}
+
+// The number of commands a CommandQueue holds that the container hasn't answered yet.
+pub const QUEUE_SLOTS: usize = 8;
+// The result of a queued command that an Exit or Abort dropped.
+pub const ABORTED: i32 = -1;
+
+#[repr(C)]
+struct Request {
+    seq: u32,
+    command: Command,
+}
+
+#[repr(C)]
+struct Response {
+    seq: u32,
+    // The command's epoch, echoed.
+    epoch: u32,
+    // See Status::encode.
+    code: u32,
+    result: i32,
+}
+
+// A single-producer, single-consumer queue of commands from the host to a container, with a
+// response for each, in the container's ControlBlock. Command 'seq' and its
+// response are in slot seq % QUEUE_SLOTS. The host only pushes a command once the one it
+// replaces has been answered and its result read, so neither side overwrites a slot the other
+// is still using. A zero-filled queue is empty.
+//
+// Next to the queue is a priority lane holding one command, which the container takes before
+// any queued ones; the host pushes to it once the last has been answered. The host bumps the
+// doorbell after pushing to either, and that's what the container waits on.
+#[repr(C)]
+pub struct CommandQueue {
+    // Commands ever pushed by the host and answered by the container, wrapping at 2^32.
+    requested: Counter,
+    answered: Counter,
+    requests: [Request; QUEUE_SLOTS],
+    responses: [Response; QUEUE_SLOTS],
+    urgent_requested: Counter,
+    urgent_answered: Counter,
+    urgent: Request,
+    doorbell: Counter,
+}
+
+impl CommandQueue {
+    fn ring(&self) {
+        self.doorbell.set(self.doorbell.get().wrapping_add(1));
+    }
+
+    // The next command for the container and whether it's the urgent one, which comes first.
+    fn next(&mut self) -> Option<(Command, bool)> {
+        let seq = self.urgent_answered.get();
+        if self.urgent_requested.get() != seq {
+            let request = unsafe { ptr::read_volatile(&self.urgent) };
+            assert_eq!(request.seq, seq, "the queue's urgent request doesn't match its position");
+            if matches!(Signal::known(request.command.signal), Some(Signal::Exit | Signal::Abort)) {
+                self.drop_queued();
+            }
+            return Some((request.command, true));
+        }
+        let seq = self.answered.get();
+        if self.requested.get() == seq {
+            return None;
+        }
+        let request = unsafe { ptr::read_volatile(&self.requests[seq as usize % QUEUE_SLOTS]) };
+        assert_eq!(request.seq, seq, "the queue's request slot doesn't match its position");
+        Some((request.command, false))
+    }
+
+    // Answers every queued command with ABORTED without handling it.
+    fn drop_queued(&mut self) {
+        let mut seq = self.answered.get();
+        while seq != self.requested.get() {
+            let slot = seq as usize % QUEUE_SLOTS;
+            let epoch = unsafe { ptr::read_volatile(&self.requests[slot]) }.command.epoch;
+            let response = Response { seq, epoch, code: 0, result: ABORTED };
+            unsafe { ptr::write_volatile(&mut self.responses[slot], response) };
+            seq = seq.wrapping_add(1);
+            self.answered.set(seq);
+        }
+    }
+}
+
+pub const RING_SLOTS: usize = 16;
+
+// The containers' commands when they share one ring, at the start of the read-write buffer. Each
+// command is addressed to the containers that should handle it, by signal index, and records
+// which of them have.
+pub type SharedRing = CommandRing<RING_SLOTS>;
+
+pub fn shared_ring(shared_rw: cptr) -> &'static SharedRing {
+    unsafe { SharedRing::from_ptr((shared_rw as *mut u8).add(HEADER_BYTES as usize)) }
+}
+
+// Keep each tick in lockstep across the containers, after the shared ring. Every container enters
+// 'start' before it starts a tick, and the host enters it too before sending the tick, so no
+// container starts tick N + 1 until the others have finished tick N. The containers then enter
+// 'observed' between the module's observe and tick exports, so none moves its actors until all
+// the others have looked at them. The host doesn't wait at 'start': it only sends a tick once
+// the last has completed, so it never arrives twice in a generation.
+#[repr(C)]
+pub struct TickBarriers {
+    pub start: Barrier,
+    pub observed: Barrier,
+}
+
+pub const TICK_START_PARTIES: u32 = CONTAINER_NAMES.len() as u32 + 1;
+pub const TICK_OBSERVED_PARTIES: u32 = CONTAINER_NAMES.len() as u32;
+
+impl TickBarriers {
+    // Releases any containers waiting at either barrier, e.g. for a container that died.
+    pub fn reset(&self) {
+        self.start.reset();
+        self.observed.reset();
+    }
+}
+
+pub fn tick_barriers(shared_rw: cptr) -> &'static TickBarriers {
+    unsafe { &*((shared_rw as *mut u8).add((HEADER_BYTES + RING_BYTES) as usize) as *const TickBarriers) }
+}
+
+// The lock the container with the given signal index holds while its module moves its actors
+// (the hunter or the runners), after the barriers; the host reads the actors under it.
+pub fn actor_lock(shared_rw: cptr, index: usize) -> &'static SeqLock {
+    assert!(index < CONTAINER_NAMES.len());
+    let offset = (ACTORS_OFFSET - GRID_SYNC_BYTES - ACTOR_LOCK_BYTES) as usize;
+    unsafe { &*((shared_rw as *mut u8).add(offset + index * mem::size_of::<SeqLock>()) as *const SeqLock) }
+}
+
+// Whoever modifies the grid holds 'lock' meanwhile, since both modules (and the host) write to it.
+// The owners are pids, so when the host finds a container dead it can recover the lock. Each
+// modification bumps 'version' and notifies 'changed', so other processes can wait for the grid
+// to change rather than polling it.
+#[repr(C)]
+pub struct GridSync {
+    pub lock: SharedMutex,
+    changed: SharedCondvar,
+    // Only changed with 'lock' held.
+    version: AtomicU32,
+}
+
+impl GridSync {
+    // Returns Locked::OwnerDied if the last process to modify the grid died doing so.
+    pub fn lock(&self) -> Locked {
+        self.lock.lock(process::id(), SIGNAL_TIMEOUT).expect("timed out waiting for the grid lock")
+    }
+
+    // Wakes everyone waiting for a change if 'modified'.
+    pub fn unlock(&self, modified: bool) {
+        if modified {
+            self.version.fetch_add(1, Ordering::Relaxed);
+            self.changed.notify_all();
+        }
+        self.lock.unlock(process::id());
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    // Returns the version once it's no longer 'version', or None if the grid hasn't changed
+    // within 'timeout'.
+    pub fn wait_change(&self, version: u32, timeout: Duration) -> Option<u32> {
+        self.lock();
+        let start = Instant::now();
+        let mut current = self.version();
+        while current == version {
+            let left = match timeout.checked_sub(start.elapsed()) {
+                Some(left) => left,
+                None => break,
+            };
+            self.changed.wait(&self.lock, process::id(), left).expect("timed out relocking the grid");
+            current = self.version();
+        }
+        self.lock.unlock(process::id());
+        (current != version).then_some(current)
+    }
+}
+
+pub fn grid_sync(shared_rw: cptr) -> &'static GridSync {
+    let offset = (ACTORS_OFFSET - GRID_SYNC_BYTES) as usize;
+    unsafe { &*((shared_rw as *mut u8).add(offset) as *const GridSync) }
+}
+
+// One side of the comms between the host and the container with signal 'index'.
+pub enum CommsChannel {
+    // 'sent' is whether the host has sent a command whose status it hasn't reported yet.
+    Slot { channel: Channel<'static>, block: &'static ControlBlock, index: usize, sent: Cell<bool> },
+    // 'checked' is how many of the responses the host has read; 'urgent' is whether the
+    // container's current command came from the priority lane.
+    Queue {
+        queue: *mut CommandQueue,
+        block: &'static ControlBlock,
+        index: usize,
+        checked: Cell<u32>,
+        urgent: Cell<bool>,
+    },
+    // 'sent' is the position of the last command the host sent, and the command, until its status
+    // is reported.
+    Shared {
+        ring: &'static SharedRing,
+        block: &'static ControlBlock,
+        index: usize,
+        sent: Cell<Option<(u32, Command)>>,
+    },
+    // 'pending' is how many commands the host has sent that haven't been answered.
+    Socket { socket: ControlSocket, block: &'static ControlBlock, index: usize, pending: Cell<u32> },
+}
+
+impl CommsChannel {
+    // 'block' is the container's control block; the read-write buffer must stay mapped while a
+    // shared channel is in use.
+    pub fn new(comms: Comms, block: &'static ControlBlock, shared_rw: cptr, index: usize) -> Self {
+        match comms {
+            Comms::Slot(notifier) => {
+                let channel = Channel::new(block.signal(), notifier).with_receiver_pid(&CONTAINER_PIDS[index]);
+                Self::Slot { channel, block, index, sent: Cell::new(false) }
+            }
+            Comms::Queue => {
+                let queue = block.queue();
+                let checked = Cell::new(unsafe { &*queue }.answered.get());
+                Self::Queue { queue, block, index, checked, urgent: Cell::new(false) }
+            }
+            Comms::Shared => Self::Shared { ring: shared_ring(shared_rw), block, index, sent: Cell::new(None) },
+            Comms::Socket(socket) => Self::Socket { socket, block, index, pending: Cell::new(0) },
+        }
+    }
+
+    // -- Host --
+
+    // Whether the host can send more commands before the last has completed.
+    pub fn pipelined(&self) -> bool {
+        !matches!(self, Self::Slot { .. })
+    }
+
+    // Clears commands left by a previous host or container, neither of which may be using the
+    // channel at the time.
+    pub fn reset(&self) {
+        match self {
+            Self::Slot { channel, sent, .. } => {
+                channel.reset();
+                sent.set(false);
+            }
+            Self::Queue { queue, checked, .. } => {
+                let queue = unsafe { &**queue };
+                queue.requested.set(queue.answered.get());
+                queue.urgent_requested.set(queue.urgent_answered.get());
+                checked.set(queue.answered.get());
+            }
+            // This clears the commands for all the containers, not just this one.
+            Self::Shared { ring, sent, .. } => {
+                ring.reset();
+                sent.set(None);
+            }
+            Self::Socket { socket, pending, .. } => {
+                while socket.recv(Duration::ZERO).is_some() {}
+                pending.set(0);
+            }
+        }
+    }
+
+    // A queue channel waits (for up to SIGNAL_TIMEOUT) for room in the queue. The command's epoch
+    // is replaced with the next one.
+    pub fn send(&self, command: &Command) {
+        let command = &Command { epoch: self.block().next_epoch(), ..*command };
+        match self {
+            Self::Slot { channel, block, sent, .. } => {
+                unsafe { block.command().write_volatile(*command) };
+                channel.send(command.signal);
+                sent.set(true);
+            }
+            Self::Queue { queue, index, checked, .. } => {
+                let queue = unsafe { &mut **queue };
+                let seq = queue.requested.get();
+                while seq.wrapping_sub(queue.answered.get()) >= QUEUE_SLOTS as u32 {
+                    if queue.answered.wait_change(queue.answered.get(), SIGNAL_TIMEOUT).is_none() {
+                        panic!("container {}'s queue stayed full", index);
+                    }
+                }
+                self.check_results(queue, self.block(), *index, checked);
+                let slot = seq as usize % QUEUE_SLOTS;
+                unsafe { ptr::write_volatile(&mut queue.requests[slot], Request { seq, command: *command }) };
+                queue.requested.set(seq.wrapping_add(1));
+                queue.ring();
+            }
+            Self::Shared { ring, index, sent, .. } => {
+                let words = unsafe { mem::transmute::<Command, [u32; shm_signal::MESSAGE_WORDS]>(*command) };
+                let position = ring.push(1 << index, &words, SIGNAL_TIMEOUT);
+                sent.set(Some((position.expect("the shared ring stayed full"), *command)));
+            }
+            Self::Socket { socket, pending, .. } => {
+                socket.send(&ControlMessage::new(MESSAGE_COMMAND, *command, ""));
+                pending.set(pending.get() + 1);
+            }
+        }
+    }
+
+    // Sends the command ahead of any the container has queued, waiting (for up to SIGNAL_TIMEOUT)
+    // for the last urgent command to be answered. Exit and Abort also drop the queued commands,
+    // which are answered with ABORTED. Only a queue channel has a priority lane; the others hold
+    // one command at a time, so the command is sent as usual.
+    pub fn send_urgent(&self, command: &Command) {
+        match self {
+            Self::Queue { queue, block, index, .. } => {
+                let command = &Command { epoch: block.next_epoch(), ..*command };
+                let queue = unsafe { &mut **queue };
+                let seq = queue.urgent_requested.get();
+                let answered = queue.urgent_answered.get();
+                if answered != seq && queue.urgent_answered.wait_change(answered, SIGNAL_TIMEOUT).is_none() {
+                    panic!("container {} didn't answer its last urgent command", index);
+                }
+                unsafe { ptr::write_volatile(&mut queue.urgent, Request { seq, command: *command }) };
+                queue.urgent_requested.set(seq.wrapping_add(1));
+                queue.ring();
+            }
+            _ => self.send(command),
+        }
+    }
+
+    // Waits up to 'timeout' for the container to complete every command sent. Status messages
+    // that arrive meanwhile are printed, as are the commands that failed and non-zero results
+    // (see report_completion). A failure is only returned once, so waiting again after one
+    // returns Done for the commands that have completed since.
+    pub fn wait_idle(&self, timeout: Duration) -> IdleResult {
+        let (socket, block, index, pending) = match self {
+            Self::Slot { channel, block, index, sent } => {
+                if !channel.wait_idle(timeout) {
+                    return IdleResult::Pending;
+                }
+                if !sent.replace(false) {
+                    return IdleResult::Done;
+                }
+                let command = unsafe { block.command().read_volatile() };
+                report_completion(*index, command.signal, block.status());
+                return block.check_epoch(*index, command.signal, command.epoch, block.completed_epoch()).into();
+            }
+            Self::Queue { queue, block, index, checked, .. } => {
+                let queue = unsafe { &**queue };
+                let start = Instant::now();
+                loop {
+                    let (urgent, answered) = (queue.urgent_answered.get(), queue.answered.get());
+                    let urgent_done = urgent == queue.urgent_requested.get();
+                    if urgent_done && answered == queue.requested.get() {
+                        return self.check_results(queue, block, *index, checked).into();
+                    }
+                    let left = match timeout.checked_sub(start.elapsed()) {
+                        Some(left) => left,
+                        None => return IdleResult::Pending,
+                    };
+                    // The container answers the urgent command first.
+                    if urgent_done {
+                        queue.answered.wait_change(answered, left);
+                    } else {
+                        queue.urgent_answered.wait_change(urgent, left);
+                    }
+                }
+            }
+            // The container handles its commands in order, so the last one sent is the last to
+            // complete. The block only has room for its status, so that's the one reported;
+            // the error events still cover every failure.
+            Self::Shared { ring, block, index, sent } => {
+                let (position, command) = match sent.get() {
+                    Some(sent) => sent,
+                    None => return IdleResult::Done,
+                };
+                if !ring.wait_done(position, timeout) {
+                    return IdleResult::Pending;
+                }
+                sent.set(None);
+                report_completion(*index, command.signal, block.status());
+                return block.check_epoch(*index, command.signal, command.epoch, block.completed_epoch()).into();
+            }
+            Self::Socket { socket, block, index, pending } => (socket, block, index, pending),
+        };
+        let start = Instant::now();
+        let mut in_step = true;
+        while pending.get() > 0 {
+            // A zero timeout still takes any message that has already arrived.
+            let message = match socket.recv(timeout.saturating_sub(start.elapsed())) {
+                Some(message) => message,
+                None if in_step => return IdleResult::Pending,
+                None => return IdleResult::Failed,
+            };
+            match message.kind {
+                MESSAGE_DONE => {
+                    // The socket keeps the commands in order, so this is the oldest still pending.
+                    let expected = block.sent_epoch().wrapping_sub(pending.get() - 1);
+                    pending.set(pending.get() - 1);
+                    let (signal, epoch) = (message.command.signal, message.command.epoch);
+                    report_completion(*index, signal, Status::decode(message.code, message.result));
+                    in_step &= block.check_epoch(*index, signal, expected, epoch);
+                }
+                MESSAGE_STATUS => println!("Container {}: {}", index, message.text()),
+                MESSAGE_ERROR => {
+                    println!("Container {} reported an error: {}", index, message.text());
+                    return IdleResult::Failed;
+                }
+                kind => println!("Container {} sent an unexpected message kind {}", index, kind),
+            }
+        }
+        in_step.into()
+    }
+
+    // Reads the responses that have arrived since the last check, returning false if any was out
+    // of step.
+    fn check_results(&self, queue: &CommandQueue, block: &ControlBlock, index: usize, checked: &Cell<u32>) -> bool {
+        let answered = queue.answered.get();
+        let mut in_step = true;
+        while checked.get() != answered {
+            let seq = checked.get();
+            let response = unsafe { ptr::read_volatile(&queue.responses[seq as usize % QUEUE_SLOTS]) };
+            let command = unsafe { ptr::read_volatile(&queue.requests[seq as usize % QUEUE_SLOTS]) }.command;
+            assert_eq!(response.seq, seq, "container {} answered out of order", index);
+            report_completion(index, command.signal, Status::decode(response.code, response.result));
+            in_step &= block.check_epoch(index, command.signal, command.epoch, response.epoch);
+            checked.set(seq.wrapping_add(1));
+        }
+        in_step
+    }
+
+    fn block(&self) -> &'static ControlBlock {
+        match self {
+            Self::Slot { block, .. }
+            | Self::Queue { block, .. }
+            | Self::Shared { block, .. }
+            | Self::Socket { block, .. } => block,
+        }
+    }
+
+    // -- Container --
+
+    pub fn wait(&self, timeout: Duration) -> Option<Command> {
+        let socket = match self {
+            Self::Slot { channel, block, .. } => {
+                let signal = channel.wait(timeout)?;
+                let command = unsafe { block.command().read_volatile() };
+                assert_eq!(command.signal, signal, "the command slot doesn't match the signal");
+                return Some(command);
+            }
+            Self::Queue { queue, urgent, .. } => {
+                let queue = unsafe { &mut **queue };
+                // Read the doorbell first, so a push after the check below still wakes us.
+                let doorbell = queue.doorbell.get();
+                let (command, is_urgent) = match queue.next() {
+                    Some(next) => next,
+                    None => {
+                        queue.doorbell.wait_change(doorbell, timeout)?;
+                        queue.next()?
+                    }
+                };
+                urgent.set(is_urgent);
+                return Some(command);
+            }
+            Self::Shared { ring, index, .. } => {
+                let (_, words) = ring.receive(*index, timeout)?;
+                return Some(unsafe { mem::transmute::<[u32; shm_signal::MESSAGE_WORDS], Command>(words) });
+            }
+            Self::Socket { socket, .. } => socket,
+        };
+        let start = Instant::now();
+        while let Some(message) = timeout.checked_sub(start.elapsed()).and_then(|left| socket.recv(left)) {
+            if message.kind == MESSAGE_COMMAND {
+                return Some(message.command);
+            }
+        }
+        None
+    }
+
+    // Completes 'command', the last one wait() returned. Slots and the shared ring put the
+    // status in the control block.
+    pub fn complete(&self, command: &Command, status: Status) {
+        let (code, result) = status.encode();
+        self.block().completed_epoch.store(command.epoch, Ordering::Relaxed);
+        match self {
+            Self::Slot { channel, block, .. } => {
+                block.set_status(status);
+                channel.complete();
+            }
+            // An urgent command's result is dropped too.
+            Self::Queue { queue, urgent, .. } if urgent.get() => {
+                let queue = unsafe { &**queue };
+                urgent.set(false);
+                queue.urgent_answered.set(queue.urgent_answered.get().wrapping_add(1));
+            }
+            Self::Queue { queue, .. } => {
+                let queue = unsafe { &mut **queue };
+                let seq = queue.answered.get();
+                let slot = seq as usize % QUEUE_SLOTS;
+                let epoch = command.epoch;
+                unsafe { ptr::write_volatile(&mut queue.responses[slot], Response { seq, epoch, code, result }) };
+                queue.answered.set(seq.wrapping_add(1));
+            }
+            Self::Shared { ring, block, index, .. } => {
+                block.set_status(status);
+                ring.complete(*index);
+            }
+            Self::Socket { socket, .. } => {
+                let mut message = ControlMessage::new(MESSAGE_DONE, *command, "");
+                message.code = code;
+                message.result = result;
+                socket.send(&message);
+            }
+        }
+    }
+
+    // Returns false if there's no socket to the host, in which case the container prints its
+    // status itself.
+    pub fn report_status(&self, text: &str) -> bool {
+        match self {
+            Self::Socket { socket, .. } => {
+                socket.send(&ControlMessage::new(MESSAGE_STATUS, Command::default(), text));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Forwards the container's panic messages to the host, which would otherwise only see a
+    // timeout. Installed once per process.
+    pub fn report_panics(&self) {
+        if let Self::Socket { socket, .. } = *self {
+            let print = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                socket.send(&ControlMessage::new(MESSAGE_ERROR, Command::default(), &info.to_string()));
+                print(info);
+            }));
+        }
+    }
+}
+
+// What CommsChannel::wait_idle() found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdleResult {
+    // Some command sent hasn't completed yet.
+    Pending,
+    // Every command sent has completed, in step with those sent.
+    Done,
+    // The container reported an error, or completed a command out of step with those sent (see
+    // ControlBlock::check_epoch).
+    Failed,
+}
+
+impl From<bool> for IdleResult {
+    // Whether the completions were in step.
+    fn from(in_step: bool) -> Self {
+        if in_step {
+            Self::Done
+        } else {
+            Self::Failed
+        }
+    }
+}
+
+// -- Async hosts --
+//
+// A host that drives many containers can wait on them with futures rather than blocking in
+// wait_idle(), in any executor (e.g. tokio's), as they only need the std Waker. None of the comms
+// can wake an executor directly (they sleep on futexes, eventfds and sockets in their own ways),
+// so a pending future is re-polled after a backoff that starts at IDLE_POLL_MIN and doubles up
+// to IDLE_POLL_MAX, woken by one timer thread for the whole process. Channels aren't Sync, so
+// the futures for them stay on one thread, e.g. joined in one task or spawned on a tokio LocalSet.
+pub const IDLE_POLL_MIN: Duration = Duration::from_micros(20);
+pub const IDLE_POLL_MAX: Duration = Duration::from_millis(1);
+
+impl CommsChannel {
+    // As wait_idle(), without blocking.
+    pub fn idle(&self, timeout: Duration) -> Idle<'_> {
+        Idle { channel: self, deadline: Instant::now() + timeout, backoff: IDLE_POLL_MIN }
+    }
+}
+
+// Resolves to false if the container didn't complete every command sent by the deadline, or
+// failed one (see IdleResult).
+pub struct Idle<'a> {
+    channel: &'a CommsChannel,
+    deadline: Instant,
+    backoff: Duration,
+}
+
+impl Future for Idle<'_> {
+    type Output = bool;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<bool> {
+        match self.channel.wait_idle(Duration::ZERO) {
+            IdleResult::Done => return Poll::Ready(true),
+            IdleResult::Failed => return Poll::Ready(false),
+            IdleResult::Pending => {}
+        }
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Poll::Ready(false);
+        }
+        wake_at((now + self.backoff).min(self.deadline), cx.waker().clone());
+        self.backoff = (self.backoff * 2).min(IDLE_POLL_MAX);
+        Poll::Pending
+    }
+}
+
+// Resolves once every channel is idle, or has failed or timed out, to whether each one went idle,
+// e.g. to wait for a command sent to all the containers.
+pub fn all_idle(channels: &[CommsChannel], timeout: Duration) -> AllIdle<'_> {
+    AllIdle { pending: channels.iter().map(|channel| Some(channel.idle(timeout))).collect(), done: Vec::new() }
+}
+
+pub struct AllIdle<'a> {
+    pending: Vec<Option<Idle<'a>>>,
+    done: Vec<bool>,
+}
+
+impl Future for AllIdle<'_> {
+    type Output = Vec<bool>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Vec<bool>> {
+        let this = &mut *self;
+        this.done.resize(this.pending.len(), false);
+        for (idle, done) in this.pending.iter_mut().zip(this.done.iter_mut()) {
+            if let Some(Poll::Ready(result)) = idle.as_mut().map(|idle| Pin::new(idle).poll(cx)) {
+                *idle = None;
+                *done = result;
+            }
+        }
+        if this.pending.iter().all(Option::is_none) {
+            Poll::Ready(mem::take(&mut this.done))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+// A waker to wake at a given time, ordered by the time only.
+struct Wakeup(Instant, Waker);
+
+impl PartialEq for Wakeup {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for Wakeup {}
+
+impl PartialOrd for Wakeup {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Wakeup {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+// Hands 'waker' to the timer thread, starting it the first time.
+fn wake_at(at: Instant, waker: Waker) {
+    static TIMER: OnceLock<Mutex<mpsc::Sender<Wakeup>>> = OnceLock::new();
+    let timer = TIMER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || run_timer(receiver));
+        Mutex::new(sender)
+    });
+    timer.lock().unwrap().send(Wakeup(at, waker)).expect("the idle timer thread has exited");
+}
+
+fn run_timer(receiver: mpsc::Receiver<Wakeup>) {
+    let mut due = BinaryHeap::new();
+    loop {
+        let now = Instant::now();
+        while let Some(Reverse(Wakeup(at, _))) = due.peek() {
+            if *at > now {
+                break;
+            }
+            let Reverse(Wakeup(_, waker)) = due.pop().unwrap();
+            waker.wake();
+        }
+        let next = match due.peek() {
+            Some(Reverse(Wakeup(at, _))) => receiver.recv_timeout(at.saturating_duration_since(now)),
+            None => receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+        };
+        match next {
+            Ok(wakeup) => due.push(Reverse(wakeup)),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+// Commands normally succeed with 0, so only failures and other results are worth printing. A
+// call's result is whatever the export returned, which the host reads from the call slot instead.
+fn report_completion(index: usize, signal: u32, status: Status) {
+    match status {
+        Status::Error(ERROR_TIMED_OUT) => println!("Container {}: signal {} timed out", index, signal),
+        Status::Error(ERROR_READ_ONLY_WRITE) => {
+            println!("Container {}: signal {} wrote to a read-only buffer", index, signal)
+        }
+        Status::Error(code) => println!("Container {}: signal {} failed with error {}", index, signal, code),
+        Status::Ok(result) if result != 0 && signal != Signal::Call as u32 => {
+            println!("Container {}: signal {} returned {}", index, signal, result)
+        }
+        Status::Ok(_) => {}
+    }
+}
+
+// Microseconds since the Unix epoch; also provided to modules as time_callback.
+pub fn now_us() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as i64
+}
+
+// Microseconds on CLOCK_MONOTONIC, which never jumps (unlike now_us) and is the same clock in
+// every process on the machine; provided to modules as now_micros.
+pub fn monotonic_us() -> i64 {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    if unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) } == -1 {
+        panic!("clock_gettime failed: {}", io::Error::last_os_error());
+    }
+    time.tv_sec * 1_000_000 + time.tv_nsec / 1000
+}
+
+// A directory to open the shm objects in instead of the shm namespace, for a container in a VM
+// that has the host's /dev/shm shared into it (e.g. over virtio-fs with DAX, so its mappings are
+// of the host's pages). Object names start with '/', so they're appended to the directory as is.
+pub const SHM_DIR_ENV: &str = "SHARED_BUFFERS_SHM_DIR";
+
+// shm_open(), or open() in SHM_DIR_ENV if that's set.
+fn shm_open(name: &str, flags: i32) -> i32 {
+    match std::env::var(SHM_DIR_ENV) {
+        Ok(dir) => {
+            let path = CString::new(format!("{}{}", dir, name)).unwrap();
+            unsafe { libc::open(path.as_ptr(), flags, SHM_MODE) }
+        }
+        Err(_) => {
+            let cname = CString::new(name).unwrap();
+            unsafe { libc::shm_open(cname.as_ptr(), flags, SHM_MODE) }
+        }
+    }
+}
+
+fn shm_unlink(name: &str) -> i32 {
+    match std::env::var(SHM_DIR_ENV) {
+        Ok(dir) => {
+            let path = CString::new(format!("{}{}", dir, name)).unwrap();
+            unsafe { libc::unlink(path.as_ptr()) }
+        }
+        Err(_) => {
+            let cname = CString::new(name).unwrap();
+            unsafe { libc::shm_unlink(cname.as_ptr()) }
+        }
+    }
+}
+
+// -- Definitions for hosts only --
+
+pub fn create_shared_buffer(name: &str, size: u64) -> cptr {
+    unsafe {
+        // shm_open() creates the actual memory buffer for sharing. macOS doesn't support O_TRUNC
+        // for shm objects, so any existing object is removed first.
+        shm_unlink(name);
+        let fd = shm_open(name, O_CREAT | O_EXCL | O_RDWR);
+        if fd == -1 {
+            panic!("shm_open failed");
+        }
+        if libc::ftruncate(fd, SHM_CAPACITY.map_or(size, |capacity| size.max(capacity)) as i64) == -1 {
+            panic!("ftruncate failed");
+        }
+
+        // mmap() allows the host to access the shared buffers (initialise, read for GUI display, etc).
+        let buf = libc::mmap(std::ptr::null_mut(), size as usize, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+        if libc::close(fd) == -1 {
+            panic!("close failed");
+        }
+        *(buf as *mut BufferHeader) = BufferHeader {
+            magic: BUFFER_MAGIC,
+            version: LAYOUT_VERSION,
+            size,
+            creator_pid: libc::getpid(),
+        };
+        buf
+    }
+}
+
+fn unlink_shared_buffer(name: &str) {
+    if shm_unlink(name) == -1 {
+        println!("shm_unlink failed for {}", name);
+    }
+}
+
+// A host's read-write mapping of a single named buffer outside any BufferSet. Like a BufferSet,
+// it is unmapped when dropped, and unlinked too if this process created it.
+pub struct SharedBuffer {
+    name: String,
+    buf: cptr,
+    size: u64,
+    ownership: Ownership,
+}
+
+impl SharedBuffer {
+    pub fn create(name: &str, size: u64) -> Self {
+        let buf = create_shared_buffer(name, size);
+        Self { name: name.to_string(), buf, size, ownership: Ownership::Creator }
+    }
+
+    pub fn open(name: &str, size: u64) -> Option<Self> {
+        let buf = open_shared_buffer(name, size)?;
+        Some(Self { name: name.to_string(), buf, size, ownership: Ownership::Attacher })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ptr(&self) -> cptr {
+        self.buf
+    }
+}
+
+impl Drop for SharedBuffer {
+    fn drop(&mut self) {
+        let creator_pid = unsafe { &*(self.buf as *const BufferHeader) }.creator_pid;
+        if self.ownership == Ownership::Creator && creator_pid == unsafe { libc::getpid() } {
+            unlink_shared_buffer(&self.name);
+        }
+        if unsafe { libc::munmap(self.buf, self.size as usize) } == -1 {
+            println!("munmap failed for {}", self.name);
+        }
+    }
+}
+
+// Maps an existing buffer read-write for the host after checking its header, or returns None if
+// it doesn't exist.
+pub fn open_shared_buffer(name: &str, size: u64) -> Option<cptr> {
+    unsafe {
+        let fd = shm_open(name, O_RDWR);
+        if fd == -1 {
+            return None;
+        }
+        let buf = libc::mmap(std::ptr::null_mut(), size as usize, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+        if libc::close(fd) == -1 {
+            panic!("close failed for {}", name);
+        }
+        (*(buf as *const BufferHeader)).validate(name, size);
+        Some(buf)
+    }
+}
+
+// Changes the size of a buffer created by create_shared_buffer and returns the host's new
+// mapping of it. Containers keep their old mappings until they are sent Signal::Resize. 'buf' must
+// be the host's only mapping of the buffer, as it is unmapped; see BufferSet::resize.
+unsafe fn resize_shared_buffer(name: &str, buf: cptr, old_size: u64, new_size: u64) -> cptr {
+    let fd = shm_open(name, O_RDWR);
+    if fd == -1 {
+        panic!("shm_open failed for {}", name);
+    }
+    match SHM_CAPACITY {
+        None => {
+            if libc::ftruncate(fd, new_size as i64) == -1 {
+                panic!("ftruncate failed for {}", name);
+            }
+        }
+        Some(capacity) => assert!(new_size <= capacity, "{} can't grow beyond {} bytes", name, capacity),
+    }
+    if libc::munmap(buf, old_size as usize) == -1 {
+        panic!("munmap failed for {}", name);
+    }
+    let buf = libc::mmap(std::ptr::null_mut(), new_size as usize, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+    if libc::close(fd) == -1 {
+        panic!("close failed for {}", name);
+    }
+    (*(buf as *mut BufferHeader)).size = new_size;
+    buf
+}
+
+// Creates an unnamed memfd buffer that can later be sealed; the fd stays open for the life of the
+// host, as it is what keeps the buffer alive.
+pub fn create_sealable_buffer(size: u64) -> (i32, cptr) {
+    let fd = create_memfd();
+    unsafe {
+        if libc::ftruncate(fd, size as i64) == -1 {
+            panic!("ftruncate failed for memfd");
+        }
+        let buf = libc::mmap(std::ptr::null_mut(), size as usize, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+        *(buf as *mut BufferHeader) = BufferHeader {
+            magic: BUFFER_MAGIC,
+            version: LAYOUT_VERSION,
+            size,
+            creator_pid: libc::getpid(),
+        };
+        (fd, buf)
+    }
+}
+
+// Seals a buffer created by create_sealable_buffer against writes and size changes, and returns
+// the host's new read-only mapping of it. Writable mappings prevent sealing, so the host's own
+// mapping is removed first, so 'buf' must not be used after this; see BufferSet::seal.
+unsafe fn seal_buffer(fd: i32, buf: cptr, size: u64) -> cptr {
+    if libc::munmap(buf, size as usize) == -1 {
+        panic!("munmap failed for memfd");
+    }
+    add_seals(fd);
+    map_sealed(std::ptr::null_mut(), fd, size, 0)
+}
+
+// Older kernels refuse shared mappings of a write-sealed memfd, even read-only ones; the contents
+// can no longer change, so a private mapping shows the same data.
+fn map_sealed(addr: cptr, fd: i32, size: u64, flags: i32) -> cptr {
+    let buf = unsafe { libc::mmap(addr, size as usize, PROT_READ, flags | MAP_PRIVATE, fd, 0) };
+    if buf == libc::MAP_FAILED {
+        panic!("mmap failed for sealed memfd");
+    }
+    buf
+}
+
+#[cfg(target_os = "linux")]
+fn create_memfd() -> i32 {
+    let fd = unsafe { libc::memfd_create(CString::new("shared_buffer").unwrap().as_ptr(), libc::MFD_ALLOW_SEALING) };
+    if fd == -1 {
+        panic!("memfd_create failed");
+    }
+    fd
+}
+
+#[cfg(target_os = "linux")]
+fn add_seals(fd: i32) {
+    let seals = libc::F_SEAL_SEAL | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+    if unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, seals) } == -1 {
+        panic!("F_ADD_SEALS failed");
+    }
+}
+
+// Checks the seals on an open memfd, as the registry alone can't be trusted to report them.
+#[cfg(target_os = "linux")]
+fn is_sealed(fd: i32) -> bool {
+    let required = libc::F_SEAL_SEAL | libc::F_SEAL_GROW | libc::F_SEAL_WRITE;
+    let seals = unsafe { libc::fcntl(fd, libc::F_GET_SEALS) };
+    seals != -1 && seals & required == required
+}
+
+// memfd sealing is Linux-only; the host refuses --seal-grid elsewhere.
+#[cfg(not(target_os = "linux"))]
+fn create_memfd() -> i32 {
+    panic!("memfd buffers are only supported on Linux");
+}
+
+#[cfg(not(target_os = "linux"))]
+fn add_seals(_fd: i32) {
+    panic!("memfd buffers are only supported on Linux");
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_sealed(_fd: i32) -> bool {
+    false
+}
+
+// Opens a memfd buffer through its /proc path (read-only; a sealed buffer can't be mapped
+// writable anyway) and maps it after checking the seals and header.
+fn open_sealed(path: &str, name: &str, addr: cptr, size: u64, flags: i32) -> Option<cptr> {
+    let cpath = CString::new(path).unwrap();
+    unsafe {
+        let fd = libc::open(cpath.as_ptr(), O_RDONLY);
+        if fd == -1 {
+            return None;
+        }
+        if !is_sealed(fd) {
+            panic!("{} is registered as sealed but its memfd isn't", name);
+        }
+        let buf = map_sealed(addr, fd, size, flags);
+        if libc::close(fd) == -1 {
+            panic!("close failed for {}", name);
+        }
+        (*(buf as *const BufferHeader)).validate(name, size);
+        Some(buf)
+    }
+}
+
+impl BufferSet {
+    pub fn create(session: &str) -> Self {
+        // ftruncate() zero-fills the table and control objects after their headers, so the table
+        // starts with no entries, the control object with no references and the control blocks
+        // idle. The control blocks are mapped separately, with ControlBlocks.
+        let name = session_name(BUFFER_TABLE_NAME, session);
+        let table = create_shared_buffer(&name, mem::size_of::<BufferTable>() as u64) as *mut BufferTable;
+        let name = session_name(CONTROL_NAME, session);
+        let control = create_shared_buffer(&name, mem::size_of::<Control>() as u64) as *mut Control;
+        let name = session_name(CONTROL_BLOCKS_NAME, session);
+        unsafe { libc::munmap(create_shared_buffer(&name, CONTROL_BLOCKS_SIZE), CONTROL_BLOCKS_SIZE as usize) };
+        unsafe { &*control }.refs.store(1, Ordering::Release);
+        let mut set = Self::new(table, control, session, Ownership::Creator);
+        set.claim(HOST_SLOT);
+        set
+    }
+
+    // Takes a reference to the set through its control object.
+    fn new(table: *mut BufferTable, control: *mut Control, session: &str, ownership: Ownership) -> Self {
+        if ownership != Ownership::Creator {
+            unsafe { &*control }.refs.fetch_add(1, Ordering::AcqRel);
+        }
+        let pid = unsafe { libc::getpid() };
+        Self { table, control, session: session.to_string(), ownership, pid, slot: None, mapped: Vec::new() }
+    }
+
+    // Whether the host holding HOST_SLOT is still running.
+    pub fn host_alive(&self) -> bool {
+        let pid = unsafe { &*self.control }.holders[HOST_SLOT].load(Ordering::Acquire);
+        pid != 0 && is_alive(pid)
+    }
+
+    // Records this process as the holder of its reference in 'slot' (HOST_SLOT, or a container's
+    // signal index plus one), so the reference is released for it if it dies without dropping the
+    // set. A reference left in the slot by a process that has died is released.
+    pub fn claim(&mut self, slot: usize) {
+        let control = unsafe { &*self.control };
+        loop {
+            let previous = control.holders[slot].load(Ordering::Acquire);
+            if previous != 0 && previous != self.pid && is_alive(previous) {
+                panic!("{} slot {} is held by running process {}", self.name(CONTROL_NAME), slot, previous);
+            }
+            let holder = &control.holders[slot];
+            if holder.compare_exchange(previous, self.pid, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                if previous != 0 && previous != self.pid {
+                    control.release();
+                }
+                break;
+            }
+        }
+        self.slot = Some(slot);
+    }
+
+    // Re-attaches to the registry and buffers left by a previous host in the same session,
+    // returning the host's mapping of each buffer in registry order. Returns None if there is no
+    // registry to attach to.
+    pub fn attach(session: &str) -> Option<(Self, Vec<cptr>)> {
+        let name = session_name(BUFFER_TABLE_NAME, session);
+        let table = open_shared_buffer(&name, mem::size_of::<BufferTable>() as u64)?;
+        let table = table as *mut BufferTable;
+        let mut set = Self::new(table, open_control(session), session, Ownership::Attacher);
+        let mapped: Vec<cptr> = set
+            .descs()
+            .iter()
+            .map(|desc| {
+                // Sealed buffers are mapped read-only; only the previous host could write to them.
+                let buf = match set.memfd_path(desc) {
+                    Some(path) => open_sealed(&path, desc.name(), std::ptr::null_mut(), desc.size, 0),
+                    None => open_shared_buffer(desc.name(), desc.size),
+                };
+                buf.unwrap_or_else(|| panic!("{} is missing", desc.name()))
+            })
+            .collect();
+        set.mapped = mapped.clone();
+        Some((set, mapped))
+    }
+
+    // Declares a new buffer, creating its shm object under the session's name for 'base'; returns
+    // the host's mapping of it. Buffers are identified by the order in which they are added.
+    pub fn add(&mut self, base: &str, size: u64, acl: Acl) -> cptr {
+        let name = self.name(base);
+        self.push_desc(&name, size, acl);
+        let buf = create_shared_buffer(&name, size);
+        self.mapped.push(buf);
+        buf
+    }
+
+    // Like add(), but backed by a memfd that can be sealed once the host has initialised it. The
+    // name is only used for display. Sealed buffers can't be written by anyone, so every
+    // container must be ReadOnly or Denied.
+    pub fn add_sealable(&mut self, base: &str, size: u64, acl: Acl) -> cptr {
+        assert!(acl.iter().all(|&access| access == Access::ReadOnly || access == Access::Denied));
+        let name = self.name(base);
+        let desc = self.push_desc(&name, size, acl);
+        let (fd, buf) = create_sealable_buffer(size);
+        desc.memfd = fd;
+        self.mapped.push(buf);
+        buf
+    }
+
+    // Seals buffer 'id', which must have been added with add_sealable(). This must happen before
+    // any container maps the buffer. The host's mapping from add_sealable() is removed, so must
+    // no longer be used; its new read-only mapping is returned.
+    pub fn seal(&mut self, id: usize) -> cptr {
+        let desc = &mut unsafe { &mut *self.table }.descs[id];
+        assert!(desc.memfd != 0, "{} is not sealable", desc.name());
+        let buf = unsafe { seal_buffer(desc.memfd, self.host_mapping(id), desc.size) };
+        desc.sealed = 1;
+        self.mapped[id] = buf;
+        buf
+    }
+
+    fn push_desc(&mut self, name: &str, size: u64, acl: Acl) -> &mut BufferDesc {
+        let table = unsafe { &mut *self.table };
+        assert!((table.count as usize) < MAX_BUFFERS, "too many shared buffers");
+        assert!(name.len() < BUFFER_NAME_LEN, "buffer name too long: {}", name);
+        let desc = &mut table.descs[table.count as usize];
+        desc.name[..name.len()].copy_from_slice(name.as_bytes());
+        desc.size = size;
+        desc.acl = acl.map(|access| access as i32);
+        table.count += 1;
+        desc
+    }
+
+    // Resizes buffer 'id' and records its new size; containers pick this up on Signal::Resize.
+    // The host's previous mapping of the buffer is removed and the new one returned.
+    pub fn resize(&mut self, id: usize, size: u64) -> cptr {
+        let desc = &mut unsafe { &mut *self.table }.descs[id];
+        assert!(desc.memfd == 0, "{} can't be resized", desc.name());
+        let buf = unsafe { resize_shared_buffer(desc.name(), self.host_mapping(id), desc.size, size) };
+        desc.size = size;
+        self.mapped[id] = buf;
+        buf
+    }
+
+    fn host_mapping(&self, id: usize) -> cptr {
+        *self.mapped.get(id).unwrap_or_else(|| panic!("buffer {} is not mapped by this host", id))
+    }
+
+    // Grants or revokes write access to a Grantable buffer; containers apply this on Signal::Protect.
+    pub fn set_granted(&mut self, id: usize, granted: bool) {
+        let desc = &mut unsafe { &mut *self.table }.descs[id];
+        assert!(desc.acl().contains(&Access::Grantable), "{} is not grantable", desc.name());
+        desc.granted = granted as i32;
+    }
+
+    // Leaves the shm objects in place when this set is dropped, for a later host to attach to.
+    // Nobody unlinks them after this, even once every reference is released.
+    pub fn persist(&mut self) {
+        self.ownership = Ownership::Persisted;
+        unsafe { &*self.control }.persist.store(1, Ordering::Release);
+    }
+
+    pub fn ownership(&self) -> Ownership {
+        self.ownership
+    }
+
+    fn unlink_all(&self) {
+        // memfd buffers go away with the host's fd.
+        let named = self.descs().iter().filter(|desc| desc.memfd == 0).map(BufferDesc::name);
+        for name in named {
+            unlink_shared_buffer(name);
+        }
+        unlink_shared_buffer(&self.name(BUFFER_TABLE_NAME));
+        unlink_shared_buffer(&self.name(CONTROL_NAME));
+        unlink_shared_buffer(&self.name(CONTROL_BLOCKS_NAME));
+    }
+}
+
+// Every process maps the control object read-write, as each one updates the count.
+fn open_control(session: &str) -> *mut Control {
+    let name = session_name(CONTROL_NAME, session);
+    let control = open_shared_buffer(&name, mem::size_of::<Control>() as u64);
+    control.unwrap_or_else(|| panic!("{} is missing", name)) as *mut Control
+}
+
+// -- Definitions for containers only --
+
+// Minimal engine-agnostic view of a module instance. The args and results of try_call are i32s;
+// try_call_wide's are i64s, for the linear memory indexes and sizes of memory64 modules.
+pub trait Instance {
+    // Err describes why the call failed, e.g. a trap or a missing export.
+    fn try_call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CallError>;
+    fn memory_base(&self) -> i64;
+
+    // For a module that imports its linear memory, which the container then creates itself, grows
+    // the memory by 'pages' and returns its old size in pages, so the buffers can go at the end
+    // without involving the module's allocator. None where the module exports its own memory, in
+    // which case the buffers are reserved with its malloc_ export.
+    fn grow_memory(&mut self, _pages: u32) -> Option<u32> {
+        None
+    }
+
+    // Whether the engine enforces the budgets in FUEL_ENV, trapping a call that runs out. Those
+    // that can need to be set up for it before the module is instantiated, so they read the
+    // budgets themselves.
+    fn meters_fuel(&self) -> bool {
+        false
+    }
+
+    // Interrupts any call still running at 'deadline_us' (in now_us time), or at once if it's
+    // past, until the deadline is cleared with None. Returns false where the engine can't
+    // interrupt its calls, which then always run to the end.
+    fn set_deadline(&mut self, _deadline_us: Option<i64>) -> bool {
+        false
+    }
+
+    // What the module exports as 'name', for validate_exports.
+    fn export(&mut self, _name: &str) -> Export {
+        Export::Unknown
+    }
+
+    // The size of linear memory in bytes, where the engine can say; only needed for Snapshot.
+    fn memory_size(&self) -> Option<u64> {
+        None
+    }
+
+    // Whether the module's memory is 64-bit (the memory64 proposal), so it takes linear memory
+    // indexes and sizes as i64s.
+    fn memory64(&self) -> bool {
+        false
+    }
+
+    // Sets whichever of the LAYOUT_GLOBALS the module imports, in that order. Engines that don't
+    // provide them refuse modules that import them at instantiation, so can ignore this.
+    fn set_layout(&mut self, _layout: [i64; 4]) {}
+
+    // As try_call, but with each arg passed as whichever of i32 and i64 the export takes it as, and
+    // an i32 result zero-extended, as it's an index or size if anything. Engines that only run
+    // wasm32 modules can leave this to narrow the args, failing the call if one doesn't fit.
+    fn try_call_wide(&mut self, name: &str, args: &[i64]) -> Result<Option<i64>, CallError> {
+        let narrow = args.iter().map(|&arg| wasm_i32(name, arg)).collect::<Result<Vec<_>, _>>()?;
+        Ok(self.try_call(name, &narrow)?.map(|result| result as u32 as i64))
+    }
+
+    // For calls the caller can't carry on without, e.g. while a container is starting.
+    fn call(&mut self, name: &str, args: &[i32]) -> Option<i32> {
+        self.try_call(name, args).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    fn call_wide(&mut self, name: &str, args: &[i64]) -> Option<i64> {
+        self.try_call_wide(name, args).unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+// A module's export, as the engine sees it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Export {
+    // A function, with its signature in WASI_FUNCTIONS' notation.
+    Function(String),
+    // Nothing, or something that isn't a function.
+    Missing,
+    // Where the engine can't look at the exports; the call fails instead, if it's wrong.
+    Unknown,
+}
+
+// A signature in WASI_FUNCTIONS' notation from the wasm types' letters ('i' for an i32, 'I' for an
+// i64, 'f' for an f32 and 'F' for an f64).
+pub fn signature(params: impl Iterator<Item = char>, results: impl Iterator<Item = char>) -> String {
+    format!("({}){}", params.collect::<String>(), results.collect::<String>())
+}
+
+// The exports a container calls whatever it's running; hunter and runner modules have the same.
+// As in WAMR's notation, '*' is a pointer or usize: an 'i', or an 'I' in memory64 modules.
+const REQUIRED_EXPORTS: [(&str, &str); 8] = [
+    ("shared_buffers_abi_version", "()i"),
+    ("create_context", "(**)*"),
+    ("update_context", "(*****)"),
+    ("init", "(*i)"),
+    ("observe", "(*)"),
+    ("tick", "(*)"),
+    ("large_alloc", "()"),
+    ("modify_grid", "(*)"),
+];
+// Exports that are only called in some setups: malloc_ unless the buffers fit in an imported memory
+// or a reserved region, set_buffer if there are buffers past the standard two, and the region's.
+const OPTIONAL_EXPORTS: [(&str, &str); 5] = [
+    ("init_grid", "(*)"),
+    ("malloc_", "(*)*"),
+    ("set_buffer", "(****)"),
+    ("reserved_region", "()*"),
+    ("reserved_region_size", "()*"),
+];
+
+// Checks the module's exports up front, so a module missing one, or with one the container would
+// call with the wrong arguments, fails at load time with everything that's wrong with it rather
+// than at the first call. Engines that can't say what's exported skip this.
+pub fn validate_exports(instance: &mut dyn Instance) {
+    if let Err(err) = export_errors(instance) {
+        panic!("{}", err);
+    }
+}
+
+fn export_errors(instance: &mut dyn Instance) -> Result<(), String> {
+    let mut problems = Vec::new();
+    let pointer = if instance.memory64() { "I" } else { "i" };
+    let exports = REQUIRED_EXPORTS.iter().map(|export| (export, true));
+    for (&(name, expected), required) in exports.chain(OPTIONAL_EXPORTS.iter().map(|export| (export, false))) {
+        let expected = expected.replace('*', pointer);
+        match instance.export(name) {
+            Export::Function(found) if found != expected => {
+                let detail = signature_mismatch(&found, &expected);
+                problems.push(format!("{} {} ({} rather than {})", name, detail, found, expected))
+            }
+            Export::Missing if required => problems.push(format!("{} {} is missing", name, expected)),
+            _ => {}
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(format!("module doesn't have the exports the container needs: {}", problems.join("; ")))
+}
+
+// Says how signature 'found' differs from 'expected', both in WASI_FUNCTIONS' notation, as wasm
+// types: the first difference in the number of params, their types, or the result.
+fn signature_mismatch(found: &str, expected: &str) -> String {
+    let split = |signature: &str| {
+        let (params, results) = signature[1..].split_once(')').unwrap();
+        (params.chars().collect::<Vec<_>>(), results.chars().collect::<Vec<_>>())
+    };
+    let type_name = |letter: &char| match letter {
+        'i' => "i32",
+        'I' => "i64",
+        'f' => "f32",
+        'F' => "f64",
+        _ => "an unsupported type",
+    };
+    let ((params, results), (expected_params, expected_results)) = (split(found), split(expected));
+    if params.len() != expected_params.len() {
+        return format!("takes {} params rather than {}", params.len(), expected_params.len());
+    }
+    let differs = params.iter().zip(&expected_params).position(|(param, expected)| param != expected);
+    if let Some(i) = differs {
+        let (param, expected) = (type_name(&params[i]), type_name(&expected_params[i]));
+        return format!("takes param {} as {} rather than {}", i + 1, param, expected);
+    }
+    let result = |results: &[char]| results.iter().map(type_name).collect::<Vec<_>>().join(", ");
+    match (results.is_empty(), expected_results.is_empty()) {
+        (_, true) => format!("returns {} rather than nothing", result(&results)),
+        (true, _) => format!("returns nothing rather than {}", result(&expected_results)),
+        _ => format!("returns {} rather than {}", result(&results), result(&expected_results)),
+    }
+}
+
+// Buffer sizes are u64s, but the modules take linear memory indexes and sizes as usize, which is
+// an i32 for wasm32 modules. (The containers pass them with try_call_wide, which also handles
+// memory64 modules.)
+pub fn wasm_usize(value: u64) -> i32 {
+    assert!(value <= u32::MAX as u64, "{} doesn't fit in a wasm32 usize", value);
+    value as u32 as i32
+}
+
+// An arg for an export that takes an i32, which may be signed or unsigned.
+pub fn wasm_i32(name: &str, arg: i64) -> Result<i32, CallError> {
+    if !(i32::MIN as i64..=u32::MAX as i64).contains(&arg) {
+        return Err(CallError::new(name, format!("{} doesn't fit in an i32 for '{}'", arg, name)));
+    }
+    Ok(arg as i32)
+}
+
+pub const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+// The number of wasm pages needed to hold 'size' bytes.
+pub fn wasm_pages(size: u64) -> u32 {
+    size.div_ceil(WASM_PAGE_SIZE).try_into().expect("too many wasm pages")
+}
+
+// The engines that run memory64 modules, by their names in the host's --engine.
+pub const MEMORY64_ENGINES: [&str; 1] = ["wasmtime"];
+
+// Set by the host (--module-cache=DIR) to a directory the wasmer and wasmtime containers keep their
+// modules in once compiled, so that a container that's restarted, or that runs a module another
+// container has compiled before, loads the compiled module rather than compiling it again. The
+// directory has to be trusted: the engines run what they load from it as it is.
+pub const MODULE_CACHE_ENV: &str = "SHARED_BUFFERS_MODULE_CACHE";
+
+pub fn module_cache_dir() -> Option<PathBuf> {
+    std::env::var_os(MODULE_CACHE_ENV).map(PathBuf::from)
+}
+
+// Names a module in the cache by its contents, so a rebuilt module doesn't pick up the compiled
+// form of the old one.
+pub fn module_cache_key(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(bytes);
+    format!("{:016x}-{}", hasher.finish(), bytes.len())
+}
+
+// Writes a cache entry by way of a temporary file, so that a container loading it at the same
+// time sees the whole entry or none of it.
+pub fn write_cache_entry(path: &Path, contents: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    let temporary = path.with_extension(format!("tmp{}", process::id()));
+    fs::write(&temporary, contents)?;
+    fs::rename(&temporary, path)
+}
+
+// Set by the host (--snapshots=DIR) to the directory for the containers' Snapshots, which are
+// otherwise in the temporary directory.
+pub const SNAPSHOT_DIR_ENV: &str = "SHARED_BUFFERS_SNAPSHOT_DIR";
+
+// A snapshot file starts with SNAPSHOT_MAGIC, the ABI_VERSION and the size of the memory image
+// that follows, all little-endian.
+const SNAPSHOT_MAGIC: u32 = 0x534e_4150;
+const SNAPSHOT_HEADER_BYTES: usize = 16;
+
+pub fn snapshot_path(index: usize, slot: u32) -> PathBuf {
+    let dir = std::env::var_os(SNAPSHOT_DIR_ENV).map_or_else(std::env::temp_dir, PathBuf::from);
+    dir.join(format!("{}-{}.mem", CONTAINER_NAMES[index], slot))
+}
+
+// Set by the host (--fuel=) to limit how far a module can run in one call before it traps, so a
+// tick that never returns is reported rather than hanging the container. A comma-separated list
+// of a budget for every export and/or budgets for particular ones, e.g. "100000000,init=1000000000".
+pub const FUEL_ENV: &str = "SHARED_BUFFERS_FUEL";
+
+// The fuel each export may use per call, from FUEL_ENV. wasmtime charges roughly a unit per
+// instruction executed; a later budget for an export replaces an earlier one.
+#[derive(Clone, Debug, Default)]
+pub struct FuelBudgets {
+    default: Option<u64>,
+    exports: Vec<(String, u64)>,
+}
+
+impl FuelBudgets {
+    pub fn parse(spec: &str) -> Self {
+        let mut budgets = Self::default();
+        for item in spec.split(',').filter(|item| !item.is_empty()) {
+            let (export, fuel) = match item.split_once('=') {
+                Some((export, fuel)) => (Some(export), fuel),
+                None => (None, item),
+            };
+            let fuel = match fuel.parse() {
+                Ok(fuel) if fuel > 0 => fuel,
+                _ => panic!("bad fuel budget '{}' in {}", item, FUEL_ENV),
+            };
+            match export {
+                Some(export) => budgets.exports.push((export.to_string(), fuel)),
+                None => budgets.default = Some(fuel),
+            }
+        }
+        budgets
+    }
+
+    pub fn from_env() -> Self {
+        std::env::var(FUEL_ENV).map_or_else(|_| Self::default(), |spec| Self::parse(&spec))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.exports.is_empty()
+    }
+
+    // None if calls to 'export' may run for as long as they like.
+    pub fn budget(&self, export: &str) -> Option<u64> {
+        let named = self.exports.iter().rev().find(|(name, _)| name == export);
+        named.map(|&(_, fuel)| fuel).or(self.default)
+    }
+}
+
+// Fails unless the module was built against this ABI_VERSION, so a stale module can't misread the
+// buffers. Containers check before mapping anything into the module.
+pub fn check_abi_version(instance: &mut dyn Instance) {
+    if let Err(err) = abi_version_error(instance) {
+        panic!("{}", err);
+    }
+}
+
+fn abi_version_error(instance: &mut dyn Instance) -> Result<(), String> {
+    match instance.try_call("shared_buffers_abi_version", &[]) {
+        Ok(Some(version)) if version as u32 == ABI_VERSION => Ok(()),
+        Ok(Some(version)) => {
+            Err(format!("module has ABI version {}, but the container expects {}; rebuild it", version, ABI_VERSION))
+        }
+        Ok(None) => Err("module's shared_buffers_abi_version returned no value".to_string()),
+        Err(err) => Err(format!("module predates ABI version {} or is broken ({}); rebuild it", ABI_VERSION, err)),
+    }
+}
+
+// The mutable globals a module can import from LAYOUT_MODULE to find the standard buffers,
+// rather than keeping what create_context and update_context are passed: the linear memory
+// indexes of the read-only and read-write buffers, then their sizes. They're 0 until the buffers
+// are mapped, which is before the module's context is created, and follow the buffers when they
+// move or resize. They take the type of the module's pointers.
+pub const LAYOUT_MODULE: &str = "layout";
+pub const LAYOUT_GLOBALS: [&str; 4] = ["ro", "rw", "ro_size", "rw_size"];
+
+// Reserves 'size' bytes of linear memory and returns their index: at the end of the memory for a
+// module that imports it, in the region a module declares with reserved_region! if it's big
+// enough, or from the module's malloc_ otherwise.
+pub fn reserve_memory(instance: &mut dyn Instance, size: u64) -> i64 {
+    if let Some(old_pages) = instance.grow_memory(wasm_pages(size)) {
+        return (old_pages as u64 * WASM_PAGE_SIZE) as i64;
+    }
+    match reserved_region(instance) {
+        Some((index, region_size)) if region_size >= size => index,
+        _ => instance.call_wide("malloc_", &[size as i64]).expect("malloc_ returned no value"),
+    }
+}
+
+// The index and size of the module's reserved region, if it declares one.
+pub fn reserved_region(instance: &mut dyn Instance) -> Option<(i64, u64)> {
+    let index = instance.try_call_wide("reserved_region", &[]).ok()??;
+    let size = instance.try_call_wide("reserved_region_size", &[]).ok()??;
+    Some((index, size as u64))
+}
+
+// The results of a module's fill_random(ptr, len) import, which fills the 'len' bytes at 'ptr'
+// with random_fill and returns 0. A range outside linear memory is left alone. The differential
+// test's engines decline, so the modules fall back to their seeded generator and a seed repeats a
+// run.
+pub const FILL_RANDOM_FAULT: i32 = 1;
+pub const FILL_RANDOM_UNAVAILABLE: i32 = 2;
+
+// Fills 'buf' from the OS's RNG, for fill_random and WASI's random_get.
+pub fn random_fill(buf: &mut [u8]) {
+    rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, buf);
+}
+
+// fill_random for an engine that exposes linear memory as a slice. The range is checked before
+// anything is written, so a bogus length doesn't make the host allocate or fill anything.
+pub fn fill_random(memory: &mut [u8], ptr: u64, len: u64) -> i32 {
+    match memory.get_mut(ptr as usize..).and_then(|rest| rest.get_mut(..len as usize)) {
+        Some(buf) => {
+            random_fill(buf);
+            0
+        }
+        None => FILL_RANDOM_FAULT,
+    }
+}
+
+// A module passed its print, assert or event callback a string outside linear memory. Nothing is
+// posted, and the engine traps the call in its own way.
+#[derive(Debug)]
+pub struct OutOfBounds;
+
+// The string 'len' bytes long at 'msg', as the callbacks below take them.
+fn module_string(memory: &[u8], len: u64, msg: u64) -> Result<String, OutOfBounds> {
+    let bytes = memory.get(msg as usize..).and_then(|rest| rest.get(..len as usize)).ok_or(OutOfBounds)?;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// The modules' print_callback, assert_callback and event_callback imports, for an engine that
+// exposes linear memory as a slice. Only debug builds of the modules import assert_callback; they
+// trap after a failure.
+pub fn print_callback(memory: &[u8], len: u64, msg: u64) -> Result<(), OutOfBounds> {
+    post_event(EventKind::Log, 0, &module_string(memory, len, msg)?);
+    Ok(())
+}
+
+pub fn assert_callback(memory: &[u8], ok: i32, len: u64, msg: u64) -> Result<(), OutOfBounds> {
+    if ok == 0 {
+        let text = module_string(memory, len, msg)?;
+        post_event(EventKind::Error, 0, &format!("module assertion failed: {}", text));
+    }
+    Ok(())
+}
+
+pub fn event_callback(memory: &[u8], kind: u32, value: i64, len: u64, msg: u64) -> Result<(), OutOfBounds> {
+    post_module_event(kind, value, &module_string(memory, len, msg)?);
+    Ok(())
+}
+
+// Instantiates a module from its bytes, for a container that can reload it.
+pub type Loader = fn(&[u8]) -> Box<dyn Instance>;
+
+// The main() of the container binaries. Their args are the module's path, the container's index
+// and the host's session; without a session (e.g. when run by hand) the plain buffer names are
+// used. A reloadable container instantiates the module again with 'load' on ReloadModule.
+pub fn run_container(load: Loader, reloadable: bool) {
+    let module_path = std::env::args().nth(1).expect("missing module path arg");
+    let index = std::env::args().nth(2).expect("missing signal index arg").parse().unwrap();
+    let session = std::env::args().nth(3).unwrap_or_default();
+    println!("Container started; module '{}', pid {}", module_path, process::id());
+
+    let bytes = fs::read(&module_path).unwrap();
+    let mut container = Container::new(load(&bytes), index, &session, &private_buffers_from_env());
+    if reloadable {
+        container = container.reloadable(&module_path, load);
+    }
+    container.run();
+}
+
+// Runs a module inside a container process, driven by signals from the host.
+pub struct Container {
+    instance: Box<dyn Instance>,
+    buffers: Buffers,
+    // A pointer in linear memory, so an i64 for memory64 modules.
+    context: i64,
+    // The ERROR_ code of the first call into the module to fail while handling the current
+    // command, if one has.
+    error: Option<u32>,
+    // The current command's deadline, if it has one and the engine can interrupt calls at it.
+    deadline: Option<i64>,
+    // Whether the container has said that its engine ignores deadlines.
+    ignoring_deadlines: bool,
+    // The module's path and how to instantiate it again, if the container handles ReloadModule.
+    reload_from: Option<(String, Loader)>,
+}
+
+impl Container {
+    pub fn new(mut instance: Box<dyn Instance>, index: usize, session: &str, private: &[usize]) -> Self {
+        if !instance.meters_fuel() && !FuelBudgets::from_env().is_empty() {
+            println!("Container {}: this engine can't meter fuel, so calls aren't limited by {}", index, FUEL_ENV);
+        }
+        check_abi_version(&mut *instance);
+        validate_exports(&mut *instance);
+        let buffers = Buffers::new(&mut *instance, index, session, private);
+        catch_faults();
+        // A module reading the layout globals has them from its first call that needs the buffers.
+        let layout = buffers.layout(&*instance);
+        instance.set_layout(layout);
+        let [ro_index, rw_index, ..] = layout;
+        let context = instance
+            .call_wide("create_context", &[ro_index, rw_index])
+            .expect("create_context returned no value");
+        buffers.log_protection();
+        let mut container = Self {
+            instance,
+            buffers,
+            context,
+            error: None,
+            deadline: None,
+            ignoring_deadlines: false,
+            reload_from: None,
+        };
+        // create_context can grow linear memory and move it. It also assumes the initial sizes,
+        // which may have changed if this container is replacing one that exited.
+        if container.buffers.memory_moved(&*container.instance) {
+            container.buffers.rebase(&mut *container.instance);
+        }
+        container.update_context();
+        container
+    }
+
+    // Lets the host replace the module with whatever is at 'module_path' now; see reload.
+    pub fn reloadable(mut self, module_path: &str, load: Loader) -> Self {
+        self.reload_from = Some((module_path.to_string(), load));
+        self
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            let command = match self.buffers.wait_for_command() {
+                Some(command) => command,
+                None => return,
+            };
+            let signal = match Signal::known(command.signal) {
+                Some(signal) => signal,
+                None => {
+                    let text = format!("unknown signal {}", command.signal);
+                    post_event(EventKind::Error, ERROR_UNKNOWN_SIGNAL as i64, &text);
+                    self.buffers.send_idle(&command, Status::Error(ERROR_UNKNOWN_SIGNAL));
+                    continue;
+                }
+            };
+            if signal == Signal::Ping {
+                self.buffers.send_idle(&command, Status::Ok(0));
+                continue;
+            }
+            self.error = None;
+            self.start_deadline(command.deadline());
+            self.buffers.copy_in();
+            // The host may draw the actors while Init or Tick moves them, so those hold the actor
+            // lock until the moves are in the shared buffer (in copy mode, after copy_out).
+            let moves_actors = matches!(signal, Signal::Init | Signal::Tick);
+            if moves_actors {
+                self.buffers.actor_lock().begin_write();
+            }
+            // Likewise for the grid.
+            let loads_grid = signal == Signal::Init && command.args[1] & INIT_LOAD_GRID != 0;
+            let modifies_grid = signal == Signal::ModifyGrid || loads_grid;
+            if modifies_grid {
+                self.buffers.lock_grid();
+            }
+            // The result is whatever the module's export returns, if anything; 0 otherwise. A
+            // failed call doesn't stop the rest of the command, so a Tick still meets the others
+            // at the barriers.
+            let result = match signal {
+                Signal::Idle | Signal::Ping => unreachable!(),
+                Signal::Init => {
+                    if loads_grid {
+                        self.load_grid();
+                    }
+                    self.call("init", &[self.context, command.args[0] as i32 as i64])
+                }
+                Signal::Tick => {
+                    let barriers = tick_barriers(self.buffers.shared(READ_WRITE_BUF_ID));
+                    self.buffers.enter(&barriers.start, TICK_START_PARTIES);
+                    self.call("observe", &[self.context]);
+                    self.buffers.enter(&barriers.observed, TICK_OBSERVED_PARTIES);
+                    self.call("tick", &[self.context])
+                }
+                Signal::LargeAlloc => self.call("large_alloc", &[]),
+                Signal::ModifyGrid => self.call("modify_grid", &[self.context]),
+                Signal::Resize => {
+                    self.resize();
+                    None
+                }
+                Signal::ReloadModule => {
+                    if let Err(err) = self.reload() {
+                        self.fail(&err);
+                    }
+                    None
+                }
+                Signal::Snapshot => {
+                    if let Err(err) = self.snapshot(command.args[0]) {
+                        self.fail(&err);
+                    }
+                    None
+                }
+                Signal::Restore => {
+                    if let Err(err) = self.restore(command.args[0]) {
+                        self.fail(&err);
+                    }
+                    None
+                }
+                Signal::Protect => {
+                    self.buffers.apply_permissions();
+                    self.buffers.log_protection();
+                    None
+                }
+                // The acknowledgement tells the host to wait for this process to exit, unmapping
+                // the buffers, before it unmaps and unlinks them itself.
+                Signal::Exit => {
+                    self.buffers.send_idle(&command, Status::Ok(0));
+                    return;
+                }
+                // Nothing to do but drop the queued commands, which the channel has done already.
+                Signal::Abort => None,
+                Signal::Call => {
+                    let (export, args) = self.buffers.block().take_call(&command, self.context);
+                    let result = self.try_call(&export, &args);
+                    if let Err(err) = &result {
+                        self.trapped(err);
+                    }
+                    self.buffers.block().answer_call(&command, &result);
+                    result.ok().flatten()
+                }
+            };
+            self.buffers.copy_out();
+            if moves_actors {
+                self.buffers.actor_lock().end_write();
+            }
+            if modifies_grid {
+                self.buffers.unlock_grid();
+            }
+            if signal == Signal::Tick {
+                self.buffers.block().stamp_completed(now_us());
+            }
+            if self.deadline.take().is_some() {
+                self.instance.set_deadline(None);
+            }
+            // The comms carry an i32, which is all the exports here return.
+            let status = self.error.map_or(Status::Ok(result.unwrap_or(0) as i32), Status::Error);
+            self.buffers.send_idle(&command, status);
+        }
+    }
+
+    // Calls an export while handling a command, failing the command if the call fails.
+    fn call(&mut self, name: &str, args: &[i64]) -> Option<i64> {
+        self.try_call(name, args).unwrap_or_else(|err| {
+            self.trapped(&err);
+            None
+        })
+    }
+
+    fn try_call(&mut self, name: &str, args: &[i64]) -> Result<Option<i64>, CallError> {
+        self.buffers.block().set_calling(Some(name));
+        let result = self.instance.try_call_wide(name, args);
+        self.buffers.block().set_calling(None);
+        let diverted = self.buffers.block().take_diverted_writes();
+        let result = match diverted {
+            Some((pages, address)) => Err(self.discard_writes(name, pages, address)),
+            None => result,
+        };
+        self.follow_memory();
+        result
+    }
+
+    // Fails a call that wrote to read-only buffers, whatever it returned, once they're mapped
+    // again. If the call moved linear memory, follow_memory maps them afresh anyway.
+    fn discard_writes(&mut self, name: &str, pages: u32, address: u64) -> CallError {
+        if !self.buffers.memory_moved(&*self.instance) {
+            self.buffers.restore_read_only();
+        }
+        let buffer = self.buffers.buffer_at(address).unwrap_or("a buffer");
+        let plural = if pages == 1 { "" } else { "s" };
+        let message = format!(
+            "wrote to {} at {:#x}, which is read-only here; its writes to {} page{} were discarded",
+            buffer, address, pages, plural
+        );
+        CallError::new(name, message).with_kind(TrapKind::ReadOnlyWrite)
+    }
+
+    // Has the module copy its own grid into the grid buffer. Modules that don't export init_grid
+    // leave the grid empty, which the host notices and fills in itself.
+    fn load_grid(&mut self) {
+        if self.instance.export("init_grid") == Export::Missing {
+            println!("Container {}: the module doesn't export init_grid", self.buffers.index);
+            return;
+        }
+        self.call("init_grid", &[self.context]);
+    }
+
+    // A call that grows linear memory can move it, leaving the buffers mapped at the old
+    // addresses, so after each call this maps them again and tells the module where they went.
+    fn follow_memory(&mut self) {
+        if self.buffers.memory_moved(&*self.instance) {
+            println!("Container {}: linear memory moved; remapping the buffers", self.buffers.index);
+            self.buffers.rebase(&mut *self.instance);
+            self.update_context();
+        }
+    }
+
+    fn trapped(&mut self, err: &CallError) {
+        self.buffers.block().record_trap(err);
+        if err.kind == TrapKind::ReadOnlyWrite {
+            self.fail_with(ERROR_READ_ONLY_WRITE, &err.message);
+        } else {
+            self.fail(&err.message);
+        }
+    }
+
+    // A call that fails once the deadline has passed was interrupted at it, or would have been.
+    fn fail(&mut self, err: &str) {
+        let timed_out = self.deadline.is_some_and(|deadline| now_us() >= deadline);
+        self.fail_with(if timed_out { ERROR_TIMED_OUT } else { ERROR_CALL_FAILED }, err);
+    }
+
+    fn fail_with(&mut self, code: u32, err: &str) {
+        post_event(EventKind::Error, code as i64, err);
+        self.error.get_or_insert(code);
+    }
+
+    fn start_deadline(&mut self, deadline: Option<i64>) {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        if self.instance.set_deadline(Some(deadline)) {
+            self.deadline = Some(deadline);
+        } else if !self.ignoring_deadlines {
+            let index = self.buffers.index;
+            println!("Container {}: this engine can't interrupt calls, so they run past their deadlines", index);
+            self.ignoring_deadlines = true;
+        }
+    }
+
+    // The host has already resized the shm objects and recorded the new sizes in the registry.
+    fn resize(&mut self) {
+        self.buffers.remap(&mut *self.instance);
+        self.update_context();
+    }
+
+    // Instantiates the module file again and moves the buffers over to the new instance, which gets
+    // a new context but isn't initialised: the actors and everything else in the buffers carry on
+    // as they were, under the new code. The old module stays if the file can't be read, was built
+    // against another ABI version or lacks exports; past that, the new one's failures panic as at
+    // startup.
+    fn reload(&mut self) -> Result<(), String> {
+        let (path, load) = self.reload_from.as_ref().ok_or("this container can't reload its module")?;
+        let bytes = fs::read(path).map_err(|err| format!("can't read {}: {}", path, err))?;
+        let mut instance = load(&bytes);
+        abi_version_error(&mut *instance)?;
+        export_errors(&mut *instance)?;
+        println!("Container {}: reloading the module from {}", self.buffers.index, path);
+        // The old instance only goes once remap has replaced the mappings in its memory.
+        let old = mem::replace(&mut self.instance, instance);
+        self.buffers.remap(&mut *self.instance);
+        drop(old);
+        let layout = self.buffers.layout(&*self.instance);
+        self.instance.set_layout(layout);
+        let [ro_index, rw_index, ..] = layout;
+        self.context = self
+            .instance
+            .call_wide("create_context", &[ro_index, rw_index])
+            .expect("create_context returned no value");
+        if self.buffers.memory_moved(&*self.instance) {
+            self.buffers.rebase(&mut *self.instance);
+        }
+        self.update_context();
+        Ok(())
+    }
+
+    // Writes linear memory to the slot's snapshot_path, with the buffers left out (as zeros): they
+    // hold the shared state, which carries on as it is when the snapshot is restored. Wasm globals
+    // aren't saved, which works for these modules as their only one is the stack pointer, and
+    // that's back at its base between calls.
+    fn snapshot(&mut self, slot: u32) -> Result<(), String> {
+        let size = self.instance.memory_size().ok_or("this engine can't snapshot linear memory")?;
+        let memory = unsafe { slice::from_raw_parts(self.instance.memory_base() as *const u8, size as usize) };
+        let mut image = Vec::with_capacity(SNAPSHOT_HEADER_BYTES + size as usize);
+        image.extend_from_slice(&SNAPSHOT_MAGIC.to_le_bytes());
+        image.extend_from_slice(&ABI_VERSION.to_le_bytes());
+        image.extend_from_slice(&size.to_le_bytes());
+        image.resize(SNAPSHOT_HEADER_BYTES + size as usize, 0);
+        for range in self.buffers.outside_buffers(size as usize) {
+            let at = SNAPSHOT_HEADER_BYTES;
+            image[at + range.start..at + range.end].copy_from_slice(&memory[range]);
+        }
+        let path = snapshot_path(self.buffers.index, slot);
+        write_cache_entry(&path, &image).map_err(|err| format!("can't write {}: {}", path.display(), err))?;
+        self.buffers.report(&format!("saved {} bytes of linear memory to {}", size, path.display()));
+        Ok(())
+    }
+
+    // Copies a snapshot back into linear memory, around the buffers as they're mapped now, and
+    // tells the module where they are, as its context is in what was restored. Memory that has
+    // grown since the snapshot keeps what's past the end of it.
+    fn restore(&mut self, slot: u32) -> Result<(), String> {
+        let path = snapshot_path(self.buffers.index, slot);
+        let image = fs::read(&path).map_err(|err| format!("can't read {}: {}", path.display(), err))?;
+        let word = |at: usize| u32::from_le_bytes(image[at..at + 4].try_into().unwrap());
+        if image.len() < SNAPSHOT_HEADER_BYTES || word(0) != SNAPSHOT_MAGIC {
+            return Err(format!("{} isn't a snapshot", path.display()));
+        }
+        if word(4) != ABI_VERSION {
+            let (path, version) = (path.display(), word(4));
+            return Err(format!("{} has ABI version {}, but the container expects {}", path, version, ABI_VERSION));
+        }
+        let saved = &image[SNAPSHOT_HEADER_BYTES..];
+        if u64::from_le_bytes(image[8..16].try_into().unwrap()) != saved.len() as u64 {
+            return Err(format!("{} is truncated", path.display()));
+        }
+        let size = self.instance.memory_size().ok_or("this engine can't restore linear memory")?;
+        if saved.len() as u64 > size {
+            let (path, len) = (path.display(), saved.len());
+            return Err(format!("{} is of {} bytes of linear memory, but there are only {}", path, len, size));
+        }
+        let memory = unsafe { slice::from_raw_parts_mut(self.instance.memory_base() as *mut u8, saved.len()) };
+        for range in self.buffers.outside_buffers(saved.len()) {
+            memory[range.clone()].copy_from_slice(&saved[range]);
+        }
+        self.buffers.report(&format!("restored {} bytes of linear memory from {}", saved.len(), path.display()));
+        self.update_context();
+        Ok(())
+    }
+
+    fn update_context(&mut self) {
+        let layout = self.buffers.layout(&*self.instance);
+        self.instance.set_layout(layout);
+        let [ro_index, rw_index, ro_size, rw_size] = layout;
+        self.call("update_context", &[self.context, ro_index, rw_index, ro_size, rw_size]);
+        self.set_extra_buffers();
+    }
+
+    // Buffers beyond the standard two are passed to the module's set_buffer export, which is only
+    // required if the host has declared any this container may access.
+    fn set_extra_buffers(&mut self) {
+        for id in READ_WRITE_BUF_ID + 1..self.buffers.count() {
+            if !self.buffers.is_mapped(id) {
+                continue;
+            }
+            let index = self.buffers.module_index(&*self.instance, id);
+            let size = self.buffers.module_size(id);
+            self.call("set_buffer", &[self.context, id as i64, index, size]);
+        }
+    }
+}
+
+pub struct Buffers {
+    registry: BufferSet,
+    // Location and size of each mapped buffer, indexed by registry id.
+    mapped: Vec<(cptr, u64)>,
+    // In copy mode, the buffers' shared mappings outside linear memory, indexed like 'mapped'.
+    copied: Vec<Option<CopiedBuffer>>,
+    copy: bool,
+    // Ids of the buffers mapped with Mapping::Private.
+    private: Vec<usize>,
+    index: usize,
+    comms: Comms,
+    blocks: ControlBlocks,
+    signal: Option<CommsChannel>,
+    memory_base: i64,
+    // Index in linear memory of the space reserved for the buffers.
+    reservation: i64,
+}
+
+// A mapping's protection, as reported by the kernel.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Protection {
+    pub writable: bool,
+    // Whether the mapping is MAP_SHARED, so writes reach the shm object.
+    pub shared: bool,
+}
+
+impl Protection {
+    pub fn describe(self) -> &'static str {
+        match (self.writable, self.shared) {
+            (false, true) => "read-only shared",
+            (true, true) => "read-write shared",
+            (false, false) => "read-only private",
+            (true, false) => "read-write private",
+        }
+    }
+}
+
+// One buffer's entry in the result of Buffers::verify_protection().
+#[derive(Debug)]
+pub struct ProtectionCheck {
+    pub id: usize,
+    pub name: String,
+    pub expected: Protection,
+    // Err if the protection couldn't be read, or differs between the buffer's pages.
+    pub actual: Result<Protection, String>,
+}
+
+impl ProtectionCheck {
+    pub fn ok(&self) -> bool {
+        self.actual == Ok(self.expected)
+    }
+}
+
+// Where the engine's linear memory can't be mapped over, each buffer is mapped elsewhere and
+// copied into linear memory when a signal arrives. When the module has handled it, the bytes it
+// changed in buffers it may write are copied back; anything else it wrote is discarded.
+struct CopiedBuffer {
+    shared: cptr,
+    // The buffer's contents as last copied in.
+    snapshot: Vec<u8>,
+}
+
+// Setting this in the environment makes containers use copy mode even where mapping would work.
+pub const COPY_MODE_ENV: &str = "SHARED_BUFFERS_COPY";
+// A comma-separated list of buffer ids for the containers to map privately, e.g. "0" for the grid.
+pub const PRIVATE_BUFFERS_ENV: &str = "SHARED_BUFFERS_PRIVATE";
+// Set by the host for each container it starts to the shm_signal::Notifier it wakes the container
+// with, e.g. "eventfd:<to container fd>,<to host fd>" for eventfds the container inherits,
+// "semaphore:<id>" for a pair of named semaphores, or "signal" for SIGUSR1.
+pub const NOTIFY_ENV: &str = "SHARED_BUFFERS_NOTIFY";
+
+// How a container maps a buffer it can read. A private mapping is copy-on-write: the module can
+// use it as scratch space, but nothing it writes is seen by the host or the other containers,
+// and it stops seeing the host's changes to any page it has written. In copy mode, a private
+// buffer is only copied in when it is mapped, so the module's changes last until a resize.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mapping {
+    Shared,
+    Private,
+}
+
+// How the container is woken for signals, from NOTIFY_ENV.
+pub fn notifier_from_env() -> Notifier {
+    let notify = std::env::var(NOTIFY_ENV).unwrap_or_else(|_| "futex".to_string());
+    let notifier = Notifier::parse(&notify).unwrap_or_else(|| panic!("bad notifier '{}' in {}", notify, NOTIFY_ENV));
+    // The host's fork_container has already done this, but a container started by hand needs it.
+    notifier.prepare_receiver();
+    notifier
+}
+
+// The buffer ids given in PRIVATE_BUFFERS_ENV, if any.
+pub fn private_buffers_from_env() -> Vec<usize> {
+    let ids = std::env::var(PRIVATE_BUFFERS_ENV).unwrap_or_default();
+    ids.split(',')
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse().unwrap_or_else(|_| panic!("bad buffer id '{}' in {}", id, PRIVATE_BUFFERS_ENV)))
+        .collect()
+}
+
+impl Buffers {
+    // 'private' lists the buffers to map with Mapping::Private; the rest are shared. Only unsealed
+    // buffers the container can't otherwise write may be private, which excludes the read-write
+    // buffer.
+    pub fn new(instance: &mut dyn Instance, index: usize, session: &str, private: &[usize]) -> Self {
+        assert!(index == HUNTER_SIGNAL_INDEX || index == RUNNER_SIGNAL_INDEX);
+        let mut registry = BufferSet::open(session);
+        registry.claim(HOST_SLOT + 1 + index);
+        let descs = registry.descs();
+        assert!(descs.len() > READ_WRITE_BUF_ID, "registry is missing the standard buffers");
+        let ro_access = descs[READ_ONLY_BUF_ID].access(index);
+        assert!(ro_access == Access::ReadOnly || ro_access == Access::Grantable);
+        assert!(descs[READ_WRITE_BUF_ID].access(index) == Access::ReadWrite);
+        for &id in private {
+            let access = descs.get(id).map(|desc| desc.access(index));
+            if access != Some(Access::ReadOnly) && access != Some(Access::Grantable) {
+                panic!("container {} can't map buffer {} privately; only readable buffers can be", index, id);
+            }
+            if descs[id].sealed() {
+                panic!("container {} can't map buffer {} privately; sealed buffers are always shared", index, id);
+            }
+        }
+        let mut buffers = Self {
+            registry,
+            mapped: Vec::new(),
+            copied: Vec::new(),
+            copy: std::env::var_os(COPY_MODE_ENV).is_some(),
+            private: private.to_vec(),
+            index,
+            comms: comms_from_env(),
+            blocks: ControlBlocks::open_one(session, index),
+            signal: None,
+            memory_base: 0,
+            reservation: 0,
+        };
+        buffers.map(instance);
+        buffers.signal.as_ref().unwrap().report_panics();
+        EVENT_BLOCK.store(buffers.block() as *const ControlBlock as *mut ControlBlock, Ordering::Release);
+        buffers
+    }
+
+    pub fn count(&self) -> usize {
+        self.mapped.len()
+    }
+
+    // Whether buffer 'id' is mapped; buffers this container is denied access to are skipped.
+    fn is_mapped(&self, id: usize) -> bool {
+        !self.mapped[id].0.is_null()
+    }
+
+    // Reserves space in linear memory (see reserve_memory), then maps every registered buffer this
+    // container may access at page-aligned locations inside it.
+    fn map(&mut self, instance: &mut dyn Instance) {
+        let index = self.index;
+        let allowed = |desc: &BufferDesc| desc.access(index) != Access::Denied;
+        let descs = self.registry.descs();
+        let alloc_size = descs.iter().filter(|desc| allowed(desc)).map(|desc| desc.size + PAGE_SIZE as u64).sum::<u64>()
+            + PAGE_SIZE as u64;
+        self.reservation = reserve_memory(instance, alloc_size);
+        self.map_reserved(instance);
+    }
+
+    // Maps the buffers at page-aligned locations in the reservation.
+    fn map_reserved(&mut self, instance: &mut dyn Instance) {
+        let index = self.index;
+        let descs: Vec<BufferDesc> = self.registry.descs().to_vec();
+        let allowed = |desc: &BufferDesc| desc.access(index) != Access::Denied;
+        self.memory_base = instance.memory_base();
+        let mut next = self.memory_base + self.reservation;
+        if !self.copy && !can_map_fixed(page_align(next) as cptr) {
+            println!(
+                "Container {}: can't map into linear memory ({}); copying buffers at each signal instead",
+                self.index,
+                io::Error::last_os_error()
+            );
+            self.copy = true;
+        }
+        self.mapped.clear();
+        self.copied.clear();
+        for (id, desc) in descs.iter().enumerate() {
+            if !allowed(desc) {
+                self.mapped.push((std::ptr::null_mut(), 0));
+                self.copied.push(None);
+                continue;
+            }
+            let aligned = page_align(next);
+            next = aligned + desc.size as i64;
+            // In copy mode the buffer is mapped wherever the kernel chooses, rather than at 'aligned'.
+            let (addr, flags) = if self.copy { (std::ptr::null_mut(), 0) } else { (aligned as cptr, MAP_FIXED) };
+            let buf = self.map_one(id, desc, addr, flags);
+            assert!(self.copy || buf == addr);
+            unsafe { &*(buf as *const BufferHeader) }.validate(desc.name(), desc.size);
+            self.mapped.push((if self.copy { aligned as cptr } else { buf }, desc.size));
+            self.copied.push(self.copy.then(|| CopiedBuffer { shared: buf, snapshot: vec![0; desc.size as usize] }));
+        }
+        let span = if self.copy { [0, 0] } else { [page_align(self.memory_base + self.reservation), next] };
+        for (bound, at) in BUFFER_SPAN.iter().zip(span) {
+            bound.store(at as u64, Ordering::Relaxed);
+        }
+        for id in 0..self.mapped.len() {
+            self.copy_buffer_in(id);
+        }
+        self.apply_permissions();
+        let block = self.blocks.block(self.index);
+        self.signal = Some(CommsChannel::new(self.comms, block, self.shared(READ_WRITE_BUF_ID), self.index));
+    }
+
+    // Maps buffer 'id' at 'addr', which is only a hint without MAP_FIXED in 'flags'.
+    fn map_one(&self, id: usize, desc: &BufferDesc, addr: cptr, flags: i32) -> cptr {
+        match self.registry.memfd_path(desc) {
+            Some(path) => {
+                assert!(desc.sealed(), "{} hasn't been sealed yet", desc.name());
+                open_sealed(&path, desc.name(), addr, desc.size, flags)
+                    .unwrap_or_else(|| panic!("failed to open {} at {}", desc.name(), path))
+            }
+            // In copy mode the module's copy serves as a private mapping.
+            None => {
+                let mapping = if self.copy { Mapping::Shared } else { self.mapping(id) };
+                map_buffer_at(addr, desc.name(), 0, desc.size, desc.access(self.index), mapping, flags)
+            }
+        }
+    }
+
+    // Maps the buffers the module can't write over themselves again, replacing any pages
+    // divert_write swapped out, so the module sees the shared contents again and its next write
+    // faults too.
+    fn restore_read_only(&self) {
+        for (id, desc) in self.registry.descs().iter().enumerate().take(self.mapped.len()) {
+            if !self.copy && self.is_mapped(id) && self.mapping(id) == Mapping::Shared && !desc.writable(self.index) {
+                let buf = self.mapped[id].0;
+                assert!(self.map_one(id, desc, buf, MAP_FIXED) == buf);
+            }
+        }
+    }
+
+    // The name of the buffer mapped at 'address', if any.
+    fn buffer_at(&self, address: u64) -> Option<&str> {
+        let descs = self.registry.descs();
+        self.mapped
+            .iter()
+            .position(|&(buf, size)| !buf.is_null() && (buf as u64..buf as u64 + size).contains(&address))
+            .map(|id| descs[id].name())
+    }
+
+    fn mapping(&self, id: usize) -> Mapping {
+        if self.private.contains(&id) { Mapping::Private } else { Mapping::Shared }
+    }
+
+    // The buffer's shared mapping; in copy mode, this isn't the one the module sees.
+    fn shared(&self, id: usize) -> cptr {
+        match &self.copied[id] {
+            Some(copied) => copied.shared,
+            None => self.mapped[id].0,
+        }
+    }
+
+    // In copy mode, refreshes the module's copy of every shared buffer from the shared mappings.
+    pub fn copy_in(&mut self) {
+        for id in 0..self.mapped.len() {
+            if self.mapping(id) == Mapping::Shared {
+                self.copy_buffer_in(id);
+            }
+        }
+    }
+
+    fn copy_buffer_in(&mut self, id: usize) {
+        let (buf, size) = self.mapped[id];
+        if let Some(copied) = &mut self.copied[id] {
+            let shared = unsafe { slice::from_raw_parts(copied.shared as *const u8, size as usize) };
+            copied.snapshot.copy_from_slice(shared);
+            unsafe { slice::from_raw_parts_mut(buf as *mut u8, size as usize) }.copy_from_slice(shared);
+        }
+    }
+
+    // In copy mode, writes back the bytes the module changed in the buffers it may write. Only
+    // changed bytes are written, so the other container's changes to the same buffer survive.
+    pub fn copy_out(&self) {
+        for (id, desc) in self.registry.descs().iter().enumerate().take(self.mapped.len()) {
+            let copied = match &self.copied[id] {
+                Some(copied) if desc.writable(self.index) && self.mapping(id) == Mapping::Shared => copied,
+                _ => continue,
+            };
+            let (buf, size) = self.mapped[id];
+            let module = unsafe { slice::from_raw_parts(buf as *const u8, size as usize) };
+            let shared = unsafe { slice::from_raw_parts_mut(copied.shared as *mut u8, size as usize) };
+            for i in module_offset(id) as usize..size as usize {
+                if module[i] != copied.snapshot[i] {
+                    shared[i] = module[i];
+                }
+            }
+        }
+    }
+
+    // Switches Grantable buffers between read-only and read-write to match the registry. Private
+    // buffers are always writable, as writes only reach the module's own copy; in copy mode that
+    // copy is already writable, and the shared mapping stays read-only.
+    pub fn apply_permissions(&self) {
+        for (id, desc) in self.registry.descs().iter().enumerate().take(self.mapped.len()) {
+            match self.mapping(id) {
+                Mapping::Private if !self.copy => self.set_writable(id, true),
+                Mapping::Private => {}
+                Mapping::Shared if desc.access(self.index) == Access::Grantable => {
+                    self.set_writable(id, desc.writable(self.index))
+                }
+                Mapping::Shared => {}
+            }
+        }
+    }
+
+    // Applies a paging hint to this container's mapping of buffer 'id'. Hints don't change the
+    // contents, so callers can treat failure as non-fatal.
+    pub fn advise(&self, id: usize, advice: Advice) -> io::Result<()> {
+        assert!(self.is_mapped(id), "buffer {} is not mapped", id);
+        let flag = match advice.flag() {
+            Some(flag) => flag,
+            None => return Ok(()),
+        };
+        if unsafe { libc::madvise(self.shared(id), self.mapped[id].1 as usize, flag) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Checks with the kernel that each mapped buffer has the protection this container should
+    // have given it, rather than trusting the flags it mapped it with. In copy mode this checks
+    // the shared mappings.
+    pub fn verify_protection(&self) -> Vec<ProtectionCheck> {
+        let descs = self.registry.descs();
+        (0..self.mapped.len())
+            .filter(|&id| self.is_mapped(id))
+            .map(|id| {
+                // See apply_permissions().
+                let expected = match self.mapping(id) {
+                    Mapping::Private if !self.copy => Protection { writable: true, shared: false },
+                    Mapping::Private => Protection { writable: false, shared: true },
+                    Mapping::Shared => Protection { writable: descs[id].writable(self.index), shared: true },
+                };
+                let actual = read_protection(self.shared(id), self.mapped[id].1);
+                ProtectionCheck { id, name: descs[id].name().to_string(), expected, actual }
+            })
+            .collect()
+    }
+
+    // Prints any buffers whose protection isn't as expected, or a summary if they all are.
+    pub fn log_protection(&self) {
+        let checks = self.verify_protection();
+        for check in checks.iter().filter(|check| !check.ok()) {
+            match &check.actual {
+                Ok(actual) => self.report(&format!(
+                    "{} is mapped {}, expected {}",
+                    check.name,
+                    actual.describe(),
+                    check.expected.describe()
+                )),
+                Err(e) => self.report(&format!("can't verify the protection of {}: {}", check.name, e)),
+            }
+        }
+        if checks.iter().all(ProtectionCheck::ok) {
+            self.report(&format!("verified the protection of {} buffers", checks.len()));
+        }
+    }
+
+    // Status lines go to the host over the control socket, if there is one, and otherwise as
+    // events.
+    pub fn report(&self, text: &str) {
+        if !self.signal.as_ref().is_some_and(|signal| signal.report_status(text)) {
+            self.block().post_event(EventKind::Log, 0, text);
+        }
+    }
+
+    // In copy mode this applies to the shared mapping; the module's copy stays writable, but
+    // copy_out() only writes back to buffers the container may write.
+    pub fn set_writable(&self, id: usize, writable: bool) {
+        let (buf, size) = (self.shared(id), self.mapped[id].1);
+        let prot = if writable { PROT_READ | PROT_WRITE } else { PROT_READ };
+        if unsafe { libc::mprotect(buf, size as usize, prot) } == -1 {
+            panic!("mprotect failed for buffer {}", id);
+        }
+    }
+
+    // Maps the resized buffers into a new reservation, which is the old one again if it's the
+    // module's reserved region and still big enough. The old mappings are replaced with anonymous
+    // memory first, so the module's (leaked) allocation or region stays usable; that's before
+    // malloc_ gets a chance to grow linear memory and move it.
+    fn remap(&mut self, instance: &mut dyn Instance) {
+        let old = mem::take(&mut self.mapped);
+        let old_copied = mem::take(&mut self.copied);
+        let copied = old_copied.iter().any(Option::is_some);
+        if !copied {
+            for &(buf, size) in old.iter().filter(|(buf, _)| !buf.is_null()) {
+                replace_with_anonymous(buf, size);
+            }
+        }
+        self.map(instance);
+        if copied {
+            unmap_copies(old_copied, &old);
+        }
+    }
+
+    // Whether linear memory has moved since the buffers were mapped, as it does in engines that
+    // reallocate it to grow it.
+    pub fn memory_moved(&self, instance: &dyn Instance) -> bool {
+        instance.memory_base() != self.memory_base
+    }
+
+    // Maps the buffers again after linear memory moved, at the same reservation in the new memory,
+    // realigned as the new base requires. The old addresses are no longer ours, so they're left alone.
+    // In copy mode, the module's changes since the last copy_in are saved from where the move
+    // left them first; when mapping, whatever the module wrote to the buffers between the move and
+    // its return went to the engine's copy of them, and is lost.
+    pub fn rebase(&mut self, instance: &mut dyn Instance) {
+        let delta = instance.memory_base() - self.memory_base;
+        for (buf, _) in self.mapped.iter_mut().filter(|(buf, _)| !buf.is_null()) {
+            *buf = (*buf as i64 + delta) as cptr;
+        }
+        self.copy_out();
+        let old = mem::take(&mut self.mapped);
+        let old_copied = mem::take(&mut self.copied);
+        self.map_reserved(instance);
+        unmap_copies(old_copied, &old);
+    }
+
+    // Returns a buffer's location as an index into linear memory, as expected by the module's
+    // exports. The module's view of each buffer skips the header, and the signals for the
+    // read-write buffer.
+    fn module_index(&self, instance: &dyn Instance, id: usize) -> i64 {
+        let base = instance.memory_base();
+        assert_eq!(base, self.memory_base, "linear memory moved");
+        self.mapped[id].0 as i64 - base + module_offset(id) as i64
+    }
+
+    // The parts of the first 'len' bytes of linear memory that aren't mapped buffers, as offsets
+    // into it. A buffer's mapping takes up the rest of its last page too.
+    fn outside_buffers(&self, len: usize) -> Vec<Range<usize>> {
+        let mut buffers: Vec<_> = self.mapped.iter().filter(|(buf, _)| !buf.is_null()).collect();
+        buffers.sort_by_key(|(buf, _)| *buf as usize);
+        let mut ranges = Vec::new();
+        let mut at = 0;
+        for &&(buf, size) in &buffers {
+            let start = ((buf as i64 - self.memory_base) as usize).min(len);
+            if start > at {
+                ranges.push(at..start);
+            }
+            let end = page_align(buf as i64 + size as i64) - self.memory_base;
+            at = at.max((end as usize).min(len));
+        }
+        if at < len {
+            ranges.push(at..len);
+        }
+        ranges
+    }
+
+    fn module_size(&self, id: usize) -> i64 {
+        (self.mapped[id].1 - module_offset(id)) as i64
+    }
+
+    // The values of LAYOUT_GLOBALS.
+    fn layout(&self, instance: &dyn Instance) -> [i64; 4] {
+        let [ro, rw] = [READ_ONLY_BUF_ID, READ_WRITE_BUF_ID];
+        [self.module_index(instance, ro), self.module_index(instance, rw), self.module_size(ro), self.module_size(rw)]
+    }
+
+    // Waits in steps of HEARTBEAT_INTERVAL, beating the heartbeat before each, so the host can
+    // tell a container waiting for a command from one that has stalled. Returns None if the host
+    // has died, so the container exits cleanly and the buffers are released.
+    pub fn wait_for_command(&self) -> Option<Command> {
+        let start = Instant::now();
+        let mut command = None;
+        while command.is_none() && start.elapsed() < SIGNAL_TIMEOUT {
+            self.beat();
+            command = self.signal.as_ref().unwrap().wait(HEARTBEAT_INTERVAL);
+        }
+        match command {
+            Some(command) => Some(command),
+            None if !self.registry.host_alive() => {
+                println!("Container {}: the host has gone; exiting", self.index);
+                None
+            }
+            None => panic!("container {} failed to received signal", self.index),
+        }
+    }
+
+    // Waits at 'barrier' for the other parties, beating the heartbeat meanwhile.
+    pub fn enter(&self, barrier: &Barrier, parties: u32) {
+        let generation = barrier.arrive(parties);
+        let start = Instant::now();
+        while !barrier.wait(generation, HEARTBEAT_INTERVAL) {
+            self.beat();
+            assert!(start.elapsed() < SIGNAL_TIMEOUT, "container {} timed out at a tick barrier", self.index);
+        }
+    }
+
+    pub fn lock_grid(&self) {
+        if grid_sync(self.shared(READ_WRITE_BUF_ID)).lock() == Locked::OwnerDied {
+            println!("Container {}: the last process to modify the grid died doing so", self.index);
+        }
+    }
+
+    pub fn unlock_grid(&self) {
+        grid_sync(self.shared(READ_WRITE_BUF_ID)).unlock(true);
+    }
+
+    pub fn actor_lock(&self) -> &'static SeqLock {
+        actor_lock(self.shared(READ_WRITE_BUF_ID), self.index)
+    }
+
+    pub fn block(&self) -> &ControlBlock {
+        self.blocks.block(self.index)
+    }
+
+    fn beat(&self) {
+        let heartbeat = self.block().heartbeat();
+        heartbeat.set(heartbeat.get().wrapping_add(1).max(1));
+    }
+
+    pub fn send_idle(&self, command: &Command, status: Status) {
+        self.signal.as_ref().unwrap().complete(command, status);
+    }
+}
+
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        EVENT_BLOCK.store(ptr::null_mut(), Ordering::Release);
+        for (id, (desc, &(buf, size))) in self.registry.descs().iter().zip(&self.mapped).enumerate() {
+            if buf.is_null() {
+                continue;
+            }
+            if unsafe { libc::munmap(self.shared(id), size as usize) } == -1 {
+                println!("munmap failed for {}", desc.name());
+            }
+        }
+    }
+}
+
+fn module_offset(id: usize) -> u64 {
+    if id == READ_WRITE_BUF_ID { ACTORS_OFFSET } else { HEADER_BYTES }
+}
+
+impl BufferSet {
+    pub fn open(session: &str) -> Self {
+        let name = session_name(BUFFER_TABLE_NAME, session);
+        unsafe {
+            let fd = shm_open(&name, O_RDONLY);
+            if fd == -1 {
+                panic!("shm_open failed for {}", name);
+            }
+            let size = mem::size_of::<BufferTable>();
+            let table = libc::mmap(std::ptr::null_mut(), size, PROT_READ, MAP_SHARED, fd, 0);
+            if libc::close(fd) == -1 {
+                panic!("close failed for {}", name);
+            }
+            let table = table as *mut BufferTable;
+            (*table).header.validate(&name, size as u64);
+            Self::new(table, open_control(session), session, Ownership::Attacher)
+        }
+    }
+}
+
+// Uses the libc POSIX API to map 'size' bytes of a shared memory buffer from 'offset', which must
+// be page aligned; mapping different offsets at different places lets one shm object be split
+// across linear memory. Grantable buffers are opened read-write so they can later be made writable
+// with mprotect(). The registry is what enforces each container's access, so Denied buffers must
+// not be passed here.
+pub fn map_buffer(aligned_ptr: i64, name: &str, offset: u64, size: u64, access: Access) -> cptr {
+    assert_eq!(offset % PAGE_SIZE as u64, 0, "{}: offset {} isn't page aligned", name, offset);
+    let buf = map_buffer_at(aligned_ptr as cptr, name, offset, size, access, Mapping::Shared, MAP_FIXED);
+    assert!(buf == aligned_ptr as cptr);
+    buf
+}
+
+// As map_buffer(), with the mapping type and the mmap() address hint and flags given by the
+// caller. Private mappings start out read-only like shared ones.
+fn map_buffer_at(addr: cptr, name: &str, offset: u64, size: u64, access: Access, mapping: Mapping, flags: i32) -> cptr {
+    let (open_flags, map_flags) = match access {
+        Access::ReadOnly => (O_RDONLY, PROT_READ),
+        Access::ReadWrite => (O_RDWR, PROT_READ | PROT_WRITE),
+        Access::Grantable => (O_RDWR, PROT_READ),
+        Access::Denied => panic!("{} is not accessible", name),
+    };
+    unsafe {
+        let fd = shm_open(name, open_flags);
+        if fd == -1 {
+            panic!("shm_open failed for {}", name);
+        }
+        let share = if mapping == Mapping::Private { MAP_PRIVATE } else { MAP_SHARED };
+        let buf = libc::mmap(addr, size as usize, map_flags, flags | share, fd, offset as libc::off_t);
+        if buf == libc::MAP_FAILED {
+            panic!("mmap failed for {}", name);
+        }
+        if libc::close(fd) == -1 {
+            panic!("close failed for {}", name);
+        }
+        buf
+    }
+}
+
+// Checks whether a page of linear memory can be mapped over, by replacing it with a fresh page;
+// some engines (or platforms) don't allow it.
+fn can_map_fixed(page: cptr) -> bool {
+    let prot = PROT_READ | PROT_WRITE;
+    let res = unsafe { libc::mmap(page, PAGE_SIZE as usize, prot, MAP_FIXED | MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+    res == page
+}
+
+// Reads the protection of the mapping at 'buf' from /proc/self/maps. The range may span several
+// entries, e.g. after an mprotect() of part of it, but they must all agree.
+#[cfg(target_os = "linux")]
+fn read_protection(buf: cptr, size: u64) -> Result<Protection, String> {
+    let maps = std::fs::read_to_string("/proc/self/maps").map_err(|e| format!("can't read /proc/self/maps: {}", e))?;
+    let (mut covered, end) = (buf as u64, buf as u64 + size);
+    let mut protection = None;
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let range = fields.next().and_then(|range| range.split_once('-'));
+        let perms = fields.next().unwrap_or("").as_bytes();
+        let (start, stop) = match range.map(|(a, b)| (u64::from_str_radix(a, 16), u64::from_str_radix(b, 16))) {
+            Some((Ok(start), Ok(stop))) => (start, stop),
+            _ => return Err(format!("bad /proc/self/maps line '{}'", line)),
+        };
+        if stop <= covered || start >= end {
+            continue;
+        }
+        if start > covered {
+            break;
+        }
+        let this = Protection { writable: perms.get(1) == Some(&b'w'), shared: perms.get(3) == Some(&b's') };
+        if protection.is_some() && protection != Some(this) {
+            return Err("its pages have different protections".to_string());
+        }
+        protection = Some(this);
+        covered = stop;
+        if covered >= end {
+            return Ok(this);
+        }
+    }
+    Err(format!("{:#x} isn't mapped", covered))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_protection(_buf: cptr, _size: u64) -> Result<Protection, String> {
+    Err("mapping protections can only be read on Linux".to_string())
+}
+
+// The old copies in copy mode are the module's own memory, so only their shared mappings go.
+fn unmap_copies(copied: Vec<Option<CopiedBuffer>>, mapped: &[(cptr, u64)]) {
+    for (copied, &(_, size)) in copied.into_iter().zip(mapped) {
+        if let Some(copied) = copied {
+            if unsafe { libc::munmap(copied.shared, size as usize) } == -1 {
+                println!("munmap failed for a copied buffer");
+            }
+        }
+    }
+}
+
+fn replace_with_anonymous(buf: cptr, size: u64) {
+    let prot = PROT_READ | PROT_WRITE;
+    let res = unsafe { libc::mmap(buf, size as usize, prot, MAP_FIXED | MAP_PRIVATE | MAP_ANONYMOUS, -1, 0) };
+    assert!(res == buf);
+}
+
+// Aligns to next largest page boundary, unless ptr is already aligned.
+pub fn page_align(ptr: i64) -> i64 {
+    ((ptr - 1) & !(PAGE_SIZE - 1)) + PAGE_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTAINER_WIT: &str = include_str!("../wit/container.wit");
+
+    // The functions of the WIT's module interface, as the exports they lower to, with their
+    // signatures reduced to the number of params and whether there's a result.
+    fn wit_exports() -> Vec<(String, usize, bool)> {
+        let start = CONTAINER_WIT.find("interface module {").expect("no module interface");
+        let body = &CONTAINER_WIT[start..];
+        let body = &body[..body.find("\n}").unwrap()];
+        body.lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with("//"))
+            .filter_map(|line| line.split_once(": func("))
+            .map(|(name, rest)| {
+                let (params, result) = rest.split_once(')').unwrap();
+                let name = if name == "malloc" { "malloc_".to_string() } else { name.replace('-', "_") };
+                (name, params.matches(':').count(), result.contains("->"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wit_matches_exports() {
+        let mut wit = wit_exports();
+        wit.sort();
+        let mut exports: Vec<_> = REQUIRED_EXPORTS
+            .iter()
+            .chain(OPTIONAL_EXPORTS.iter())
+            .map(|(name, signature)| {
+                let (params, results) = signature[1..].split_once(')').unwrap();
+                (name.to_string(), params.len(), !results.is_empty())
+            })
+            .collect();
+        exports.sort();
+        assert_eq!(wit, exports);
+    }
+
+    #[test]
+    fn wit_lists_layout_globals() {
+        let globals = LAYOUT_GLOBALS.join(", ");
+        assert!(CONTAINER_WIT.contains(&globals), "container.wit doesn't list {}", globals);
+        assert!(CONTAINER_WIT.contains(&format!("from \"{}\"", LAYOUT_MODULE)));
+    }
+
+    fn exists(name: &str) -> bool {
+        let fd = shm_open(name, O_RDONLY);
+        if fd != -1 {
+            unsafe { libc::close(fd) };
+        }
+        fd != -1
+    }
+
+    // A name or session no other test (or other run of the tests) uses.
+    fn unique(tag: &str) -> String {
+        format!("t{}{}", unsafe { libc::getpid() }, tag)
+    }
+
+    // The shm objects of a set in 'session' holding one buffer named for 'base'.
+    fn set_objects(session: &str, base: &str) -> Vec<String> {
+        let bases = [BUFFER_TABLE_NAME, CONTROL_NAME, CONTROL_BLOCKS_NAME, base];
+        bases.iter().map(|&base| session_name(base, session)).collect()
+    }
+
+    // Hands 'value' to 'child' in a forked copy of this process, which then exits at once, without
+    // unwinding into the test harness. Returns 'value' and whether 'child' returned true.
+    fn fork_with<T>(value: T, child: impl FnOnce(T) -> bool) -> (T, bool) {
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed"),
+            0 => unsafe { libc::_exit(if child(value) { 0 } else { 1 }) },
+            pid => {
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                (value, libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0)
+            }
+        }
+    }
+
+    #[test]
+    fn shared_buffer_is_unlinked_by_its_creator() {
+        let name = format!("/{}", unique("s"));
+        let created = SharedBuffer::create(&name, PAGE_SIZE as u64);
+        assert_eq!(created.ownership, Ownership::Creator);
+        let opened = SharedBuffer::open(&name, PAGE_SIZE as u64).unwrap();
+        assert_eq!(opened.ownership, Ownership::Attacher);
+        drop(opened);
+        assert!(exists(&name));
+        drop(created);
+        assert!(!exists(&name));
+    }
+
+    #[test]
+    fn shared_buffer_outlives_a_forked_copy() {
+        let name = format!("/{}", unique("sf"));
+        let created = SharedBuffer::create(&name, PAGE_SIZE as u64);
+        let (created, kept) = fork_with(created, |copy| {
+            drop(copy);
+            exists(&name)
+        });
+        assert!(kept, "a forked copy unlinked {}", name);
+        assert!(exists(&name));
+        drop(created);
+        assert!(!exists(&name));
+    }
+
+    #[test]
+    fn buffer_set_is_unlinked_by_the_last_reference() {
+        let session = unique("b");
+        let objects = set_objects(&session, "/test");
+        let mut created = BufferSet::create(&session);
+        created.add("/test", PAGE_SIZE as u64, [Access::ReadOnly; CONTAINER_NAMES.len()]);
+        assert!(objects.iter().all(|name| exists(name)));
+
+        // An attacher that goes first leaves the objects to the creator...
+        let (attached, _) = BufferSet::attach(&session).unwrap();
+        assert_eq!(attached.ownership(), Ownership::Attacher);
+        drop(attached);
+        assert!(objects.iter().all(|name| exists(name)));
+
+        // ...and one that stays longest removes them.
+        let (attached, _) = BufferSet::attach(&session).unwrap();
+        drop(created);
+        assert!(objects.iter().all(|name| exists(name)));
+        drop(attached);
+        assert!(objects.iter().all(|name| !exists(name)), "{:?} left behind", objects);
+    }
+
+    #[test]
+    fn buffer_set_outlives_a_forked_copy() {
+        let session = unique("bf");
+        let objects = set_objects(&session, "/test");
+        let mut created = BufferSet::create(&session);
+        created.add("/test", PAGE_SIZE as u64, [Access::ReadOnly; CONTAINER_NAMES.len()]);
+        let (created, kept) = fork_with(created, |copy| {
+            drop(copy);
+            objects.iter().all(|name| exists(name))
+        });
+        assert!(kept, "a forked copy removed the set");
+        assert!(objects.iter().all(|name| exists(name)));
+        drop(created);
+        assert!(objects.iter().all(|name| !exists(name)), "{:?} left behind", objects);
+    }
+
+    #[test]
+    fn persisted_buffer_set_is_left_in_place() {
+        let session = unique("bp");
+        let objects = set_objects(&session, "/test");
+        let mut created = BufferSet::create(&session);
+        created.add("/test", PAGE_SIZE as u64, [Access::ReadOnly; CONTAINER_NAMES.len()]);
+        created.persist();
+        assert_eq!(created.ownership(), Ownership::Persisted);
+        drop(created);
+        assert!(objects.iter().all(|name| exists(name)));
+
+        // Nothing removes a persisted set; a later host attaches to it instead.
+        let (attached, mapped) = BufferSet::attach(&session).unwrap();
+        assert_eq!(mapped.len(), 1);
+        drop(attached);
+        assert!(objects.iter().all(|name| exists(name)));
+        for name in &objects {
+            unlink_shared_buffer(name);
+        }
+    }
+
+    #[test]
+    fn fill_random_checks_the_range() {
+        let mut memory = vec![0; 64];
+        assert_eq!(fill_random(&mut memory, 32, 32), 0);
+        assert!(memory[32..].iter().any(|&byte| byte != 0));
+        assert_eq!(fill_random(&mut memory, 33, 32), FILL_RANDOM_FAULT);
+        assert_eq!(fill_random(&mut memory, u64::MAX, 2), FILL_RANDOM_FAULT);
+        assert!(memory[..32].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn module_strings_stay_inside_linear_memory() {
+        let memory = b"hello, world";
+        assert_eq!(module_string(memory, 5, 7).unwrap(), "world");
+        assert_eq!(module_string(memory, 0, 12).unwrap(), "");
+        assert!(module_string(memory, 6, 7).is_err());
+        assert!(module_string(memory, 2, u64::MAX).is_err());
+        assert!(print_callback(memory, 13, 0).is_err());
+        // A passing assertion doesn't need its string.
+        assert!(assert_callback(memory, 1, 13, 0).is_ok());
+        assert!(assert_callback(memory, 0, 13, 0).is_err());
+    }
 }
@@ -16,15 +16,55 @@
 
 // Imported via `use` in hunter.rs and runner.rs
 
-use super::shared::{cptr, State};
+use super::shared::{cptr, Arena, DrawList, EventKind, GridControl, State, TickStats, ABI_VERSION};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+#[cfg(target_arch = "wasm32")]
+use core::arch::wasm32::unreachable as trap;
+#[cfg(target_arch = "wasm64")]
+use core::arch::wasm64::unreachable as trap;
+
+// Re-exported for the print macros, which are expanded in the module crates.
+pub use alloc::format;
 
 // Grid setup.
 pub const GRID_W: usize = 50;
 pub const GRID_H: usize = 30;
+// Initial number of runners; the host can grow the read-write buffer to add more.
 pub const N_RUNNERS: usize = 15;
 
+// These match the host's signal indices and registry ids.
+pub const HUNTER_INDEX: usize = 0;
+pub const RUNNER_INDEX: usize = 1;
+pub const STATS_BUF_ID: usize = 2;
+pub const DRAW_BUF_ID: usize = 3;
+pub const GRID_CTL_BUF_ID: usize = 4;
+pub const GRID_BACK_BUF_ID: usize = 5;
+
+// Named explicitly, as the wasm64 target doesn't leave undefined functions to be "env" imports.
+#[link(wasm_import_module = "env")]
 extern "C" {
     pub fn print_callback(len: usize, msg: *const u8);
+    pub fn assert_callback(cond: i32, len: usize, msg: *const u8);
+    // Microseconds since the Unix epoch, on the same clock as the host.
+    pub fn time_callback() -> i64;
+    // Sends an EventKind record to the host through the container's event ring.
+    pub fn event_callback(kind: u32, value: i64, len: usize, msg: *const u8);
+    #[link_name = "now_micros"]
+    fn now_micros_callback() -> i64;
+    // Fills 'len' bytes at 'ptr' from the host's OS RNG; returns 0 for success.
+    #[link_name = "fill_random"]
+    fn fill_random_callback(ptr: *mut u8, len: usize) -> i32;
+}
+
+// Microseconds on the machine's monotonic clock, which the host and the other containers share.
+// Unlike time_callback's it doesn't jump when the system time is set, so it's the one to time
+// things with.
+pub fn now_micros() -> i64 {
+    unsafe { now_micros_callback() }
 }
 
 pub fn print_str(s: &str) {
@@ -33,10 +73,79 @@ pub fn print_str(s: &str) {
     }
 }
 
+pub fn post_event(kind: EventKind, value: i64, text: &str) {
+    unsafe {
+        event_callback(kind as u32, value, text.len(), text.as_ptr());
+    }
+}
+
+// The host keeps the latest value of each named metric per container.
+pub fn post_metric(name: &str, value: i64) {
+    post_event(EventKind::Metric, value, name);
+}
+
+// For containers that give a module the buffers as a dedicated second memory (the multi-memory
+// proposal) rather than mapping them into its own, the buffer indexes passed to its exports are
+// offsets into that memory, which the module reaches with these. They're implemented by
+// buffer_memory.wat, whose "buffers" imports are only referenced by modules that use them.
+#[link(wasm_import_module = "buffers")]
+extern "C" {
+    #[link_name = "read"]
+    fn buffers_read(dst: *mut u8, offset: usize, len: usize);
+    #[link_name = "write"]
+    fn buffers_write(offset: usize, src: *const u8, len: usize);
+}
+
+// Copies from the buffer memory at 'offset' into 'dst'.
+pub fn buffer_read(offset: usize, dst: &mut [u8]) {
+    unsafe { buffers_read(dst.as_mut_ptr(), offset, dst.len()) }
+}
+
+// Copies 'src' into the buffer memory at 'offset'.
+pub fn buffer_write(offset: usize, src: &[u8]) {
+    unsafe { buffers_write(offset, src.as_ptr(), src.len()) }
+}
+
+pub fn buffer_load_u8(offset: usize) -> u8 {
+    let mut value = [0];
+    buffer_read(offset, &mut value);
+    value[0]
+}
+
+pub fn buffer_load_i32(offset: usize) -> i32 {
+    let mut value = [0; 4];
+    buffer_read(offset, &mut value);
+    i32::from_le_bytes(value)
+}
+
+pub fn buffer_store_u8(offset: usize, value: u8) {
+    buffer_write(offset, &[value]);
+}
+
+pub fn buffer_store_i32(offset: usize, value: i32) {
+    buffer_write(offset, &value.to_le_bytes());
+}
+
+// Checked by the containers before they map the buffers; see ABI_VERSION.
+#[no_mangle]
+pub extern "C" fn shared_buffers_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+// Reports the assertion to the host, then traps if it failed.
+pub fn assert_str(cond: bool, msg: &str) {
+    unsafe {
+        assert_callback(cond as i32, msg.len(), msg.as_ptr());
+    }
+    if !cond {
+        trap();
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($fmt:expr $(, $value:expr)* ) => {
-        let s = format!($fmt $(, $value)*);
+        let s = $crate::module_common::format!($fmt $(, $value)*);
         print_str(&s);
     };
 }
@@ -44,12 +153,89 @@ macro_rules! print {
 #[macro_export]
 macro_rules! println {
     ($fmt:expr $(, $value:expr)* ) => {
-        let s = format!($fmt $(, $value)*) + "\n";
+        let s = $crate::module_common::format!($fmt $(, $value)*) + "\n";
         print_str(&s);
     };
 }
 
+// Analogue of debug_assert! that reports failures to the host log via assert_callback. As with
+// debug_assert!, release builds optimise the check away, so the import isn't referenced.
+#[macro_export]
+macro_rules! module_assert {
+    ($cond:expr, $fmt:expr $(, $value:expr)* ) => {
+        if cfg!(debug_assertions) {
+            if $cond {
+                $crate::module_common::assert_str(true, "");
+            } else {
+                let s = $crate::module_common::format!($fmt $(, $value)*);
+                $crate::module_common::assert_str(false, &s);
+            }
+        }
+    };
+}
+
+// Declares a static region of 'size' bytes, aligned to a wasm page (and so to any host page),
+// for the container to map the buffers over instead of reserving space with the module's
+// malloc_. It's exported as reserved_region and reserved_region_size. The region has to hold
+// every buffer the container maps, each rounded up to a page, plus a page; if the buffers
+// outgrow it the container falls back to malloc_, so modules still export that too.
+#[macro_export]
+macro_rules! reserved_region {
+    ($size:expr) => {
+        #[repr(C, align(65536))]
+        struct ReservedRegion([u8; $size]);
+
+        static mut RESERVED_REGION: ReservedRegion = ReservedRegion([0; $size]);
+
+        #[no_mangle]
+        pub extern "C" fn reserved_region() -> $crate::shared::cptr {
+            unsafe { core::ptr::addr_of_mut!(RESERVED_REGION) as $crate::shared::cptr }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn reserved_region_size() -> usize {
+            $size
+        }
+    };
+}
+
+// Without std, modules get dlmalloc as their allocator and a panic handler that reports
+// through print_callback.
+#[cfg(feature = "no_std")]
+#[global_allocator]
+static ALLOC: dlmalloc::GlobalDlmalloc = dlmalloc::GlobalDlmalloc;
+
+#[cfg(feature = "no_std")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // Avoid allocating while panicking by formatting into a fixed buffer.
+    let mut msg = PanicMessage { buf: [0; 256], len: 0 };
+    let _ = core::fmt::write(&mut msg, format_args!("module {}\n", info));
+    print_str(core::str::from_utf8(&msg.buf[..msg.len]).unwrap_or("module panicked\n"));
+    trap()
+}
+
+#[cfg(feature = "no_std")]
+struct PanicMessage {
+    buf: [u8; 256],
+    len: usize,
+}
+
+#[cfg(feature = "no_std")]
+impl core::fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // Silently truncates long messages.
+        let n = core::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+// The random numbers come from the host's fill_random where the container provides it. Where it
+// declines, they come from an LCG seeded with srand instead, which makes a run repeatable.
 static mut RAND_VALUE: usize = 0;
+static HOST_RANDOM: AtomicBool = AtomicBool::new(true);
 const SOME_LARGEISH_PRIME: usize = 137;
 const SOME_OTHER_LARGEISH_PRIME: usize = 7;
 
@@ -59,72 +245,157 @@ pub fn srand(rand_seed: usize) {
     }
 }
 
+// Fills 'buf' with the host's randomness, returning false if the container doesn't provide it.
+pub fn fill_random(buf: &mut [u8]) -> bool {
+    unsafe { fill_random_callback(buf.as_mut_ptr(), buf.len()) == 0 }
+}
+
 pub fn rand() -> i32 {
     rand_usize() as i32
 }
 
 pub fn rand_usize() -> usize {
+    if HOST_RANDOM.load(Ordering::Relaxed) {
+        let mut bytes = [0; mem::size_of::<usize>()];
+        if fill_random(&mut bytes) {
+            return usize::from_ne_bytes(bytes);
+        }
+        HOST_RANDOM.store(false, Ordering::Relaxed);
+    }
     unsafe {
         RAND_VALUE = (RAND_VALUE.wrapping_add(SOME_LARGEISH_PRIME)).wrapping_mul(SOME_OTHER_LARGEISH_PRIME);
         RAND_VALUE
     }
 }
 
+// Laid out to match the host's RunnerRecord and HunterRecord, so the coordinates are u32s rather
+// than usizes, which are 64-bit in wasm64.
+#[repr(C)]
 pub struct Runner {
-    pub x: usize,
-    pub y: usize,
+    pub x: u32,
+    pub y: u32,
     pub state: State,
 }
 
+#[repr(C)]
 pub struct Hunter {
-    pub x: usize,
-    pub y: usize,
+    pub x: u32,
+    pub y: u32,
 }
 
 pub type GridType = [[i32; GRID_W]; GRID_H];
-pub type RunnersType = [Runner; N_RUNNERS];
+
+static NEVER_ABORTED: AtomicU32 = AtomicU32::new(0);
 
 pub struct Context {
+    // The read-only buffer's grid. Modules should read the grid through grid(), which follows
+    // the host's swaps if it double-buffers the grid.
     pub grid: &'static mut GridType,
     pub hunter: &'static mut Hunter,
-    pub runners: &'static mut RunnersType,
+    pub runners: &'static mut [Runner],
+    // Any buffers the host has registered beyond the grid and actors, indexed by registry id - 2.
+    // Buffers this module isn't allowed to access are left empty.
+    pub extra: Vec<&'static mut [u8]>,
 }
 
 impl Context {
     pub fn new_unowned(ro_ptr: cptr, rw_ptr: cptr) -> *mut Self {
-        Box::into_raw(Box::new(unsafe {
-            Context {
-                grid: &mut *(ro_ptr as *mut GridType),
-                hunter: &mut *(rw_ptr as *mut Hunter),
-                runners: &mut *(skip_hunter(rw_ptr) as *mut RunnersType),
-            }
+        let mut actors = Arena::new(rw_ptr, mem::size_of::<Hunter>() + N_RUNNERS * mem::size_of::<Runner>());
+        Box::into_raw(Box::new(Context {
+            grid: unsafe { &mut *(ro_ptr as *mut GridType) },
+            hunter: actors.place(),
+            runners: actors.place_rest(),
+            extra: Vec::new(),
         }))
     }
 
-    pub fn update(&mut self, ro_ptr: cptr, rw_ptr: cptr) {
-        unsafe {
-            self.grid = &mut *(ro_ptr as *mut GridType);
-            self.hunter = &mut *(rw_ptr as *mut Hunter);
-            self.runners = &mut *(skip_hunter(rw_ptr) as *mut RunnersType);
+    // The buffer sizes are in bytes; the number of runners is derived from rw_size.
+    pub fn update(&mut self, ro_ptr: cptr, rw_ptr: cptr, ro_size: usize, rw_size: usize) {
+        assert!(ro_size >= mem::size_of::<GridType>());
+        let mut actors = Arena::new(rw_ptr, rw_size);
+        self.grid = unsafe { &mut *(ro_ptr as *mut GridType) };
+        self.hunter = actors.place();
+        self.runners = actors.place_rest();
+    }
+
+    // The current copy of the grid. Read this once per tick: the host only rewrites the inactive
+    // copy between ticks, so the copy returned stays intact until the tick ends.
+    pub fn grid(&self) -> &'static GridType {
+        let size = mem::size_of::<GridType>();
+        let active = match self.extra.get(GRID_CTL_BUF_ID - 2) {
+            Some(buf) if buf.len() >= mem::size_of::<GridControl>() => {
+                unsafe { &*(buf.as_ptr() as *const GridControl) }.active.load(Ordering::Acquire)
+            }
+            _ => 0,
+        };
+        match self.extra.get(GRID_BACK_BUF_ID - 2) {
+            Some(buf) if active == 1 && buf.len() >= size => unsafe { &*(buf.as_ptr() as *const GridType) },
+            _ => unsafe { &*(&*self.grid as *const GridType) },
+        }
+    }
+
+    // Echoes the host's tick timestamp into this module's stats slot and records the completion
+    // time. Does nothing if the host hasn't registered a stats buffer.
+    pub fn record_tick(&mut self, index: usize) {
+        if let Some(stats) = self.slot::<TickStats>(STATS_BUF_ID, index) {
+            stats.echo_us = stats.sent_us;
+            stats.done_us = unsafe { time_callback() };
+        }
+    }
+
+    // The flag the host raises when it wants this module to return from the current call as soon
+    // as it can, e.g. because it's about to exit. Modules check it in loops that may run for a
+    // while. It's never raised if the host hasn't registered a stats buffer.
+    pub fn abort_flag(&self, index: usize) -> &'static AtomicU32 {
+        match self.extra.get(STATS_BUF_ID - 2) {
+            Some(buf) if buf.len() >= (index + 1) * mem::size_of::<TickStats>() => {
+                &unsafe { &*(buf.as_ptr() as *const TickStats).add(index) }.abort
+            }
+            _ => &NEVER_ABORTED,
+        }
+    }
+
+    // This module's draw list, if the host has registered a draw buffer.
+    pub fn draw_list(&mut self, index: usize) -> Option<&mut DrawList> {
+        self.slot::<DrawList>(DRAW_BUF_ID, index)
+    }
+
+    // The given container's slot in a buffer holding one T per container.
+    fn slot<T>(&mut self, id: usize, index: usize) -> Option<&mut T> {
+        let size = mem::size_of::<T>();
+        match self.extra.get_mut(id - 2) {
+            Some(buf) if buf.len() >= (index + 1) * size => {
+                Some(unsafe { &mut *(buf.as_mut_ptr().add(index * size) as *mut T) })
+            }
+            _ => None,
         }
     }
-}
 
-fn skip_hunter(ptr: cptr) -> cptr {
-    unsafe { ptr.add(std::mem::size_of::<Hunter>()) }
+    // Called in id order when the container maps the buffers, and again after each resize. Ids
+    // of buffers the container has skipped are left out.
+    pub fn set_buffer(&mut self, id: usize, ptr: cptr, size: usize) {
+        let buf = unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, size) };
+        let i = id - 2;
+        while self.extra.len() <= i {
+            self.extra.push(&mut []);
+        }
+        self.extra[i] = buf;
+    }
 }
 
 pub fn rand_step() -> i32 {
     (rand().abs() % 3) - 1
 }
 
-pub fn move_by(grid: &GridType, x: &mut usize, y: &mut usize, mx: i32, my: i32) {
+pub fn move_by(grid: &GridType, x: &mut u32, y: &mut u32, mx: i32, my: i32) {
     // If the dest cell is blocked, try a random move;
     // if that's also blocked just stay still.
     let (mx, my) = (step(mx), step(my));
     let mut tx: usize = (*x as i32).saturating_add(mx) as usize;
     let mut ty: usize = (*y as i32).saturating_add(my) as usize;
     if ty >= grid.len() || tx >= grid[ty].len() {
+        // The grid is bordered by blocked cells, so actors should never get here.
+        crate::module_assert!(false, "move from ({}, {}) leaves the grid", *x, *y);
         return;
     }
     if grid[ty][tx] == 1 {
@@ -134,13 +405,13 @@ pub fn move_by(grid: &GridType, x: &mut usize, y: &mut usize, mx: i32, my: i32)
             return;
         }
     }
-    *x = tx;
-    *y = ty;
+    *x = tx as u32;
+    *y = ty as u32;
 }
 
 // Converts an arbitrary delta into a unit step.
 pub fn step(delta: i32) -> i32 {
-    use std::cmp::Ordering::*;
+    use core::cmp::Ordering::*;
     match delta.cmp(&0) {
         Equal => 0,
         Greater => 1,
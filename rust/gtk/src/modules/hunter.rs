@@ -13,16 +13,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
+#![cfg_attr(feature = "no_std", no_std, no_main)]
 
-use common::module_common::{move_by, print_str, srand, Context, GRID_H, GRID_W};
-use common::println;
-use common::shared::{cptr, State};
+extern crate alloc;
+
+use alloc::vec::Vec;
+use common::module_common::{
+    move_by, now_micros, post_metric, print_str, srand, Context, GridType, GRID_H, GRID_W, HUNTER_INDEX,
+};
+use common::{println, reserved_region};
+use common::shared::{cptr, DrawCmd, State};
+
+// Room for the buffers, which the container maps over in place of a malloc_ allocation.
+reserved_region!(1 << 20);
 
 #[no_mangle]
 pub extern "C" fn malloc_(size: usize) -> cptr {
     let vec: Vec<u8> = Vec::with_capacity(size);
     let ptr = vec.as_ptr();
-    std::mem::forget(vec); // Leak the vector
+    core::mem::forget(vec); // Leak the vector
     ptr as cptr
 }
 
@@ -32,20 +41,63 @@ pub extern "C" fn create_context(ro_ptr: cptr, rw_ptr: cptr) -> *const Context {
 }
 
 #[no_mangle]
-pub extern "C" fn update_context(ctx: &mut Context, ro_ptr: cptr, rw_ptr: cptr) {
-    ctx.update(ro_ptr, rw_ptr);
+pub extern "C" fn update_context(ctx: &mut Context, ro_ptr: cptr, rw_ptr: cptr, ro_size: usize, rw_size: usize) {
+    ctx.update(ro_ptr, rw_ptr, ro_size, rw_size);
+}
+
+#[no_mangle]
+pub extern "C" fn set_buffer(ctx: &mut Context, id: usize, ptr: cptr, size: usize) {
+    ctx.set_buffer(id, ptr, size);
 }
 
 #[no_mangle]
 pub extern "C" fn init(ctx: &mut Context, rand_seed: i32) {
     srand(rand_seed as usize);
-    ctx.hunter.x = GRID_W / 2;
-    ctx.hunter.y = GRID_H / 2;
+    ctx.hunter.x = GRID_W as u32 / 2;
+    ctx.hunter.y = GRID_H as u32 / 2;
 }
 
+// The hunter's own world: a walled grid with a cross of walls, open at the ends and in the middle
+// (where the hunter starts) so that every cell can still be reached. It's built at compile time,
+// so it sits in the module's data.
+static GRID: GridType = {
+    let mut grid = [[0; GRID_W]; GRID_H];
+    let mut x = 0;
+    while x < GRID_W {
+        grid[0][x] = 1;
+        grid[GRID_H - 1][x] = 1;
+        if x > 2 && x < GRID_W - 3 && (x + 1 < GRID_W / 2 || x > GRID_W / 2 + 1) {
+            grid[GRID_H / 2][x] = 1;
+        }
+        x += 1;
+    }
+    let mut y = 0;
+    while y < GRID_H {
+        grid[y][0] = 1;
+        grid[y][GRID_W - 1] = 1;
+        if y > 2 && y < GRID_H - 3 && (y + 1 < GRID_H / 2 || y > GRID_H / 2 + 1) {
+            grid[y][GRID_W / 2] = 1;
+        }
+        y += 1;
+    }
+    grid
+};
+
+// Copies GRID into the grid buffer, which the host only lets the module write for this.
 #[no_mangle]
-pub extern "C" fn tick(ctx: &mut Context) {
-    // Find the closest runner and move towards it.
+pub extern "C" fn init_grid(ctx: &mut Context) {
+    println!("[h] Loading the hunter's grid");
+    *ctx.grid = GRID;
+}
+
+// The offset to the closest runner and its squared distance, as observed at the start of the
+// tick. The hunter is the only one that moves itself, so the offset stays valid until it does.
+static mut CLOSEST: (i32, i32, i32) = (0, 0, 99999);
+
+// Finds the closest runner. Every container observes before any of them ticks, so this sees the
+// runners where they were at the end of the last tick rather than part way through moving.
+#[no_mangle]
+pub extern "C" fn observe(ctx: &mut Context) {
     let mut min_dx: i32 = 0;
     let mut min_dy: i32 = 0;
     let mut min_dist = 99999;
@@ -62,13 +114,39 @@ pub extern "C" fn tick(ctx: &mut Context) {
             min_dist = dist;
         }
     }
-    move_by(&ctx.grid, &mut ctx.hunter.x, &mut ctx.hunter.y, min_dx, min_dy);
+    unsafe {
+        CLOSEST = (min_dx, min_dy, min_dist);
+    }
+}
+
+// Moves towards the runner found by observe(), and reports how long that took as "tick_us".
+#[no_mangle]
+pub extern "C" fn tick(ctx: &mut Context) {
+    let start = now_micros();
+    let (min_dx, min_dy, min_dist) = unsafe { CLOSEST };
+    let target = (ctx.hunter.x as i32 + min_dx, ctx.hunter.y as i32 + min_dy);
+    move_by(ctx.grid(), &mut ctx.hunter.x, &mut ctx.hunter.y, min_dx, min_dy);
+
+    // Show the runner being chased.
+    let (x, y) = (ctx.hunter.x as f32 + 0.5, ctx.hunter.y as f32 + 0.5);
+    if let Some(list) = ctx.draw_list(HUNTER_INDEX) {
+        list.clear();
+        if min_dist < 99999 {
+            let (tx, ty) = (target.0 as f32 + 0.5, target.1 as f32 + 0.5);
+            list.push(DrawCmd::line(x, y, tx, ty, 0xcc80e6));
+            // The target's marker pulses once a second.
+            let pulse = (now_micros() / 1000 % 1000 - 500).abs() as f32 / 500.0;
+            list.push(DrawCmd::circle(tx, ty, 0.1 + 0.1 * pulse, 0xcc80e6));
+        }
+    }
+    ctx.record_tick(HUNTER_INDEX);
+    post_metric("tick_us", now_micros() - start);
 }
 
 #[no_mangle]
 pub extern "C" fn large_alloc() {
     println!("[h] Requesting large allocation");
-    std::mem::forget(Vec::<u8>::with_capacity(100000));
+    core::mem::forget(Vec::<u8>::with_capacity(100000));
 }
 
 #[no_mangle]
@@ -77,6 +155,7 @@ pub extern "C" fn modify_grid(ctx: &mut Context) {
     ctx.grid[0][0] = 2;
 }
 
+#[cfg(not(feature = "no_std"))]
 fn main() {
     println!("hunter: Not meant to be run as a main");
 }
@@ -13,18 +13,29 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
+#![cfg_attr(feature = "no_std", no_std, no_main)]
 
-use common::module_common::{move_by, print_str, rand, rand_step, rand_usize, srand, Context, GRID_H, GRID_W};
-use common::println;
+extern crate alloc;
+
+use alloc::vec::Vec;
+use common::module_common::{
+    move_by, post_metric, print_str, rand, rand_step, rand_usize, srand, Context, Runner, GRID_H, GRID_W,
+    RUNNER_INDEX,
+};
+use common::{module_assert, println, reserved_region};
 use common::shared::{cptr, State};
+use core::sync::atomic::Ordering;
 
 const SCARE_DIST: i32 = 10;
 
+// Room for the buffers, which the container maps over in place of a malloc_ allocation.
+reserved_region!(1 << 20);
+
 #[no_mangle]
 pub extern "C" fn malloc_(size: usize) -> cptr {
     let vec: Vec<u8> = Vec::with_capacity(size);
     let ptr = vec.as_ptr();
-    std::mem::forget(vec); // Leak the vector
+    core::mem::forget(vec); // Leak the vector
     ptr as cptr
 }
 
@@ -34,29 +45,62 @@ pub extern "C" fn create_context(ro_ptr: cptr, rw_ptr: cptr) -> *mut Context {
 }
 
 #[no_mangle]
-pub extern "C" fn update_context(ctx: &mut Context, ro_ptr: cptr, rw_ptr: cptr) {
-    ctx.update(ro_ptr, rw_ptr);
+pub extern "C" fn update_context(ctx: &mut Context, ro_ptr: cptr, rw_ptr: cptr, ro_size: usize, rw_size: usize) {
+    ctx.update(ro_ptr, rw_ptr, ro_size, rw_size);
+    // Runners in the zero-filled space added by a resize are at (0, 0), which is always blocked.
+    for r in ctx.runners.iter_mut().filter(|r| r.x == 0 && r.y == 0) {
+        place(r);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_buffer(ctx: &mut Context, id: usize, ptr: cptr, size: usize) {
+    ctx.set_buffer(id, ptr, size);
 }
 
 #[no_mangle]
 pub extern "C" fn init(ctx: &mut Context, rand_seed: i32) {
     srand(rand_seed as usize);
     for r in &mut *ctx.runners {
-        r.x = 1 + rand_usize() % (GRID_W - 2);
-        r.y = 1 + rand_usize() % (GRID_H - 2);
-        r.state = State::Walking;
+        place(r);
+    }
+}
+
+fn place(r: &mut Runner) {
+    r.x = (1 + rand_usize() % (GRID_W - 2)) as u32;
+    r.y = (1 + rand_usize() % (GRID_H - 2)) as u32;
+    r.state = State::Walking;
+}
+
+// Where the hunter was at the start of the tick.
+static mut HUNTER: (u32, u32) = (0, 0);
+
+// Every container observes before any of them ticks, so this sees where the hunter finished the
+// last tick rather than wherever it has got to in this one.
+#[no_mangle]
+pub extern "C" fn observe(ctx: &mut Context) {
+    unsafe {
+        HUNTER = (ctx.hunter.x, ctx.hunter.y);
     }
 }
 
 #[no_mangle]
 pub extern "C" fn tick(ctx: &mut Context) {
-    // Find the closest runner and move towards it.
+    // Run from the hunter observed at the start of the tick.
+    let (hunter_x, hunter_y) = unsafe { HUNTER };
+    let grid = ctx.grid();
+    let abort = ctx.abort_flag(RUNNER_INDEX);
     for r in &mut *ctx.runners {
+        // The host can add any number of runners, so give up part way if it's waiting to exit.
+        if abort.load(Ordering::Relaxed) != 0 {
+            println!("[r] Tick aborted");
+            return;
+        }
         if r.state == State::Dead {
             continue;
         }
-        let dx: i32 = r.x as i32 - ctx.hunter.x as i32;
-        let dy: i32 = r.y as i32 - ctx.hunter.y as i32;
+        let dx: i32 = r.x as i32 - hunter_x as i32;
+        let dy: i32 = r.y as i32 - hunter_y as i32;
         // If the hunter has reached us, we're dead.
         if dx == 0 && dy == 0 {
             r.state = State::Dead;
@@ -75,17 +119,29 @@ pub extern "C" fn tick(ctx: &mut Context) {
                 0 => (dx, rand_step()),
                 1 => (rand_step(), dy),
                 2 => (dx, dy),
-                _ => return,
+                n => {
+                    module_assert!(false, "[r] unexpected move choice {}", n);
+                    return;
+                }
             }
         };
-        move_by(&ctx.grid, &mut r.x, &mut r.y, mx, my);
+        move_by(grid, &mut r.x, &mut r.y, mx, my);
     }
+    let alive = ctx.runners.iter().filter(|r| r.state != State::Dead).count();
+    post_metric("alive", alive as i64);
+    ctx.record_tick(RUNNER_INDEX);
+}
+
+// Not used by the host's own signals; for hosts to call with Signal::Call (see --call).
+#[no_mangle]
+pub extern "C" fn count_runners(ctx: &mut Context, state: i32) -> i32 {
+    ctx.runners.iter().filter(|r| r.state as i32 == state).count() as i32
 }
 
 #[no_mangle]
 pub extern "C" fn large_alloc() {
     println!("[r] Requesting large allocation");
-    std::mem::forget(Vec::<u8>::with_capacity(100000));
+    core::mem::forget(Vec::<u8>::with_capacity(100000));
 }
 
 #[no_mangle]
@@ -93,6 +149,7 @@ pub extern "C" fn modify_grid(_ctx: &mut Context) {
     // Not implemented
 }
 
+#[cfg(not(feature = "no_std"))]
 fn main() {
     println!("runner: Not meant to be run as a main");
 }
@@ -14,6 +14,19 @@
 // limitations under the License.
 //
 
+use core::{
+    convert::TryInto,
+    marker::PhantomData,
+    mem, ptr,
+    sync::atomic::{fence, AtomicU32, Ordering},
+};
+
+// The version of the interface between containers and modules: the layout of the buffers and
+// the records in them, and the modules' exports and imports. Modules report theirs through their
+// shared_buffers_abi_version export, and containers refuse modules built against another. Bump it
+// on any incompatible change.
+pub const ABI_VERSION: u32 = 1;
+
 #[derive(Eq, PartialEq, Clone, Copy)]
 pub enum State {
     Walking,
@@ -28,5 +41,405 @@ impl State {
     }
 }
 
+// One slot per container in the stats buffer. The host stamps 'sent_us' just before signalling a
+// tick; the module echoes it and records when it finished, so latency is measured inside the
+// protocol rather than around the wasm call. The host raises 'abort' when it wants the module to
+// cut a long-running call short, e.g. because it's about to exit; modules poll it with
+// Context::abort_requested().
+#[repr(C)]
+pub struct TickStats {
+    pub sent_us: i64,
+    pub echo_us: i64,
+    pub done_us: i64,
+    pub abort: AtomicU32,
+}
+
+impl TickStats {
+    // None until the module has completed the tick the host most recently stamped.
+    pub fn latency_us(&self) -> Option<i64> {
+        if self.sent_us != 0 && self.echo_us == self.sent_us {
+            Some(self.done_us - self.sent_us)
+        } else {
+            None
+        }
+    }
+}
+
+// What a container (or its module, through event_callback) reports to the host through its event
+// ring. Metrics are named by their text; custom events are the module's own, with a code as their
+// value.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum EventKind {
+    Log,
+    Metric,
+    Error,
+    Custom,
+}
+
+impl EventKind {
+    // Event kinds come from the modules, so unknown ones are rejected rather than trusted.
+    pub fn from(value: u32) -> Option<Self> {
+        [Self::Log, Self::Metric, Self::Error, Self::Custom].get(value as usize).copied()
+    }
+}
+
+// Modules can add their own visualizations by writing draw lists: each container has one list in
+// the draw buffer, which the host renders on top of the grid and actors. Coordinates are in grid
+// cells, so (x + 0.5, y + 0.5) is the centre of cell (x, y).
+pub const MAX_DRAW_CMDS: usize = 64;
+
+#[derive(Eq, PartialEq, Clone, Copy)]
+pub enum Shape {
+    // From (x0, y0) to (x1, y1).
+    Line,
+    // Filled, with corners at (x0, y0) and (x1, y1).
+    Rect,
+    // Filled, centred on (x0, y0) with radius x1.
+    Circle,
+}
+
+impl Shape {
+    // Draw lists are written by the modules, so unknown shapes are skipped rather than trusted.
+    pub fn from(value: i32) -> Option<Self> {
+        [Self::Line, Self::Rect, Self::Circle].get(value as usize).copied()
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DrawCmd {
+    pub shape: i32,
+    // 0xRRGGBB
+    pub rgb: u32,
+    pub x0: f32,
+    pub y0: f32,
+    pub x1: f32,
+    pub y1: f32,
+}
+
+impl DrawCmd {
+    pub fn line(x0: f32, y0: f32, x1: f32, y1: f32, rgb: u32) -> Self {
+        Self { shape: Shape::Line as i32, rgb, x0, y0, x1, y1 }
+    }
+
+    pub fn rect(x0: f32, y0: f32, x1: f32, y1: f32, rgb: u32) -> Self {
+        Self { shape: Shape::Rect as i32, rgb, x0, y0, x1, y1 }
+    }
+
+    pub fn circle(x: f32, y: f32, radius: f32, rgb: u32) -> Self {
+        Self { shape: Shape::Circle as i32, rgb, x0: x, y0: y, x1: radius, y1: 0.0 }
+    }
+}
+
+#[repr(C)]
+pub struct DrawList {
+    count: i32,
+    cmds: [DrawCmd; MAX_DRAW_CMDS],
+}
+
+impl DrawList {
+    pub fn clear(&mut self) {
+        self.count = 0;
+    }
+
+    // Returns false if the list is full.
+    pub fn push(&mut self, cmd: DrawCmd) -> bool {
+        let n = self.cmds().len();
+        if n == MAX_DRAW_CMDS {
+            return false;
+        }
+        self.cmds[n] = cmd;
+        self.count = n as i32 + 1;
+        true
+    }
+
+    pub fn cmds(&self) -> &[DrawCmd] {
+        &self.cmds[..(self.count.max(0) as usize).min(MAX_DRAW_CMDS)]
+    }
+}
+
 #[allow(non_camel_case_types)]
 pub type cptr = *mut core::ffi::c_void;
+
+// A pointer to a T in a shared buffer, stored as a byte offset from an agreed base (such as the
+// start of an Arena) so it stays valid in every process that maps the buffer, wherever it's mapped.
+// Resolving one checks it against the buffer's bounds, since any process may have written it.
+#[repr(transparent)]
+pub struct Offset<T> {
+    offset: u32,
+    target: PhantomData<*const T>,
+}
+
+impl<T> Offset<T> {
+    // Offset 0 is usually a valid object, so the null offset is the largest one instead.
+    pub const NULL: Self = Self { offset: u32::MAX, target: PhantomData };
+
+    pub fn new(offset: u32) -> Self {
+        Self { offset, target: PhantomData }
+    }
+
+    // The offset of 'target' from 'base'; panics if it doesn't follow 'base' in the same buffer.
+    pub fn from_ptr(base: cptr, target: *const T) -> Self {
+        let offset = (target as usize).checked_sub(base as usize).expect("target precedes the base");
+        Self::new(offset.try_into().expect("offset too large"))
+    }
+
+    pub fn is_null(self) -> bool {
+        self.offset == u32::MAX
+    }
+
+    pub fn offset(self) -> u32 {
+        self.offset
+    }
+
+    // The target, given this process's mapping of the buffer region starting at the base. Returns
+    // None for null offsets and ones that are misaligned or reach past 'size'.
+    pub fn resolve<'a>(self, base: cptr, size: usize) -> Option<&'a T> {
+        self.target_ptr(base, size).map(|ptr| unsafe { &*ptr })
+    }
+
+    pub fn resolve_mut<'a>(self, base: cptr, size: usize) -> Option<&'a mut T> {
+        self.target_ptr(base, size).map(|ptr| unsafe { &mut *ptr })
+    }
+
+    fn target_ptr(self, base: cptr, size: usize) -> Option<*mut T> {
+        let start = self.offset as usize;
+        let fits = start.checked_add(mem::size_of::<T>()).is_some_and(|end| end <= size);
+        let addr = (base as usize).wrapping_add(start);
+        (!self.is_null() && fits && addr.is_multiple_of(mem::align_of::<T>())).then_some(addr as *mut T)
+    }
+}
+
+// Derived impls would needlessly require T: Copy.
+impl<T> Clone for Offset<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Offset<T> {}
+
+// Carves typed objects out of a shared buffer, one after another at suitably aligned offsets. The
+// host and the modules agree on a buffer's layout by placing the same objects in the same order.
+pub struct Arena<'a> {
+    base: *mut u8,
+    size: usize,
+    offset: usize,
+    buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> Arena<'a> {
+    // The buffer must stay mapped for 'a, and each part of it should only be placed once.
+    pub fn new(base: cptr, size: usize) -> Self {
+        Self { base: base as *mut u8, size, offset: 0, buffer: PhantomData }
+    }
+
+    // The number of bytes placed so far, including any alignment padding.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn place<T>(&mut self) -> &'a mut T {
+        unsafe { &mut *self.reserve::<T>(1) }
+    }
+
+    pub fn place_slice<T>(&mut self, count: usize) -> &'a mut [T] {
+        unsafe { core::slice::from_raw_parts_mut(self.reserve::<T>(count), count) }
+    }
+
+    // As many objects as fit in the rest of the buffer.
+    pub fn place_rest<T>(&mut self) -> &'a mut [T] {
+        let count = self.size.saturating_sub(self.aligned::<T>()) / mem::size_of::<T>();
+        self.place_slice(count)
+    }
+
+    // The offset of the next T, aligned within the address space rather than the buffer.
+    fn aligned<T>(&self) -> usize {
+        let align = mem::align_of::<T>();
+        let addr = self.base as usize + self.offset;
+        self.offset + (align - addr % align) % align
+    }
+
+    fn reserve<T>(&mut self, count: usize) -> *mut T {
+        let start = self.aligned::<T>();
+        let end = start + count * mem::size_of::<T>();
+        assert!(end <= self.size, "arena overflow: {} bytes needed but only {} available", end, self.size);
+        self.offset = end;
+        unsafe { self.base.add(start) as *mut T }
+    }
+}
+
+// Control word for a double-buffered grid. The host writes changes into the inactive copy, then
+// publishes it by storing its index (0 for the read-only buffer, 1 for the back buffer), so a
+// module never sees a partly modified grid.
+#[repr(C)]
+pub struct GridControl {
+    pub active: AtomicU32,
+}
+
+// A sequence lock for data that one process writes while others read it, e.g. the actor records a
+// container updates while the host draws them. The writer makes the sequence odd while it writes;
+// readers copy what they need and retry if the sequence was odd or changed meanwhile, so they
+// never see a half-written value. A zero-filled lock is unlocked.
+#[repr(C)]
+pub struct SeqLock {
+    sequence: AtomicU32,
+}
+
+impl SeqLock {
+    pub fn begin_write(&self) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        assert!(sequence.is_multiple_of(2), "seqlock is already being written");
+        self.sequence.store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+    }
+
+    pub fn end_write(&self) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(1), Ordering::Release);
+    }
+
+    // Unlocks the lock if a writer died part way through; nobody may be writing at the time.
+    pub fn reset(&self) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(sequence.wrapping_add(sequence % 2), Ordering::Release);
+    }
+}
+
+// A value in a shared buffer guarded by a SeqLock.
+pub struct SeqLocked<'a, T: ?Sized> {
+    lock: &'a SeqLock,
+    value: &'a T,
+}
+
+impl<'a, T: ?Sized> SeqLocked<'a, T> {
+    pub fn new(lock: &'a SeqLock, value: &'a T) -> Self {
+        Self { lock, value }
+    }
+
+    // Calls 'read' on the value until it runs without a write overlapping it, and returns that
+    // result. The value may change under 'read', so it should only copy from it.
+    pub fn read<R>(&self, read: impl Fn(&T) -> R) -> R {
+        loop {
+            let sequence = self.lock.sequence.load(Ordering::Acquire);
+            if sequence.is_multiple_of(2) {
+                let result = read(self.value);
+                fence(Ordering::Acquire);
+                if self.lock.sequence.load(Ordering::Relaxed) == sequence {
+                    return result;
+                }
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// A single-producer, single-consumer queue of variable-length messages in a shared buffer, e.g. for
+// a container to stream messages to the host without a fixed slot layout. A zero-filled buffer is
+// an empty ring, so a freshly created one needs no initialisation.
+#[repr(C)]
+pub struct RingHeader {
+    // Bytes ever written and read, wrapping at 2^32; each is only stored by one side, with Release
+    // ordering once the data it covers has been written or read.
+    head: AtomicU32,
+    tail: AtomicU32,
+}
+
+// Each message is stored as its u32 length then its bytes, wrapping round the end of the data area.
+// The other side may be buggy or hostile, so the indices and lengths read back are checked.
+pub struct Ring<'a> {
+    header: &'a RingHeader,
+    data: *mut u8,
+    // A power of two, so positions stay consistent when the counters wrap.
+    capacity: u32,
+    buffer: PhantomData<&'a mut [u8]>,
+}
+
+const LEN_BYTES: u32 = mem::size_of::<u32>() as u32;
+
+impl<'a> Ring<'a> {
+    // Places the header then the largest power-of-two data area that fits in the rest of the arena.
+    // Both sides must place the ring the same way.
+    pub fn place(arena: &mut Arena<'a>) -> Self {
+        let header = arena.place::<RingHeader>();
+        let data = arena.place_rest::<u8>();
+        assert!(data.len() > LEN_BYTES as usize, "no room for a ring buffer");
+        let capacity = 1u32 << (data.len().min(u32::MAX as usize) as u32).ilog2();
+        Self { header, data: data.as_mut_ptr(), capacity, buffer: PhantomData }
+    }
+
+    // The longest message that can ever be pushed.
+    pub fn max_message(&self) -> usize {
+        (self.capacity - LEN_BYTES) as usize
+    }
+
+    // Producer side. Returns false, leaving the ring unchanged, if there isn't room for 'msg'.
+    pub fn push(&self, msg: &[u8]) -> bool {
+        let head = self.header.head.load(Ordering::Relaxed);
+        let used = self.used(head, self.header.tail.load(Ordering::Acquire));
+        let len: u32 = match msg.len().try_into() {
+            Ok(len) if len <= (self.capacity - used).saturating_sub(LEN_BYTES) => len,
+            _ => return false,
+        };
+        self.write(head, &len.to_le_bytes());
+        self.write(head.wrapping_add(LEN_BYTES), msg);
+        self.header.head.store(head.wrapping_add(LEN_BYTES + len), Ordering::Release);
+        true
+    }
+
+    // Consumer side. The length of the next message, if there is one.
+    pub fn peek_len(&self) -> Option<usize> {
+        let tail = self.header.tail.load(Ordering::Relaxed);
+        let used = self.used(self.header.head.load(Ordering::Acquire), tail);
+        if used == 0 {
+            return None;
+        }
+        let mut len = [0; LEN_BYTES as usize];
+        assert!(used >= LEN_BYTES, "ring buffer corrupted: partial message length");
+        self.read(tail, &mut len);
+        let len = u32::from_le_bytes(len);
+        assert!(len <= used - LEN_BYTES, "ring buffer corrupted: message overruns the data");
+        Some(len as usize)
+    }
+
+    // Consumer side. Copies the next message into 'out' and returns its length, or None if the ring
+    // is empty. Panics if 'out' is too small; see peek_len().
+    pub fn pop(&self, out: &mut [u8]) -> Option<usize> {
+        let len = self.peek_len()?;
+        assert!(len <= out.len(), "a {} byte message doesn't fit in {} bytes", len, out.len());
+        let tail = self.header.tail.load(Ordering::Relaxed);
+        self.read(tail.wrapping_add(LEN_BYTES), &mut out[..len]);
+        self.header.tail.store(tail.wrapping_add(LEN_BYTES + len as u32), Ordering::Release);
+        Some(len)
+    }
+
+    fn used(&self, head: u32, tail: u32) -> u32 {
+        let used = head.wrapping_sub(tail);
+        assert!(used <= self.capacity, "ring buffer corrupted: {} bytes used of {}", used, self.capacity);
+        used
+    }
+
+    // Copies to or from the data area starting at counter 'at', in up to two parts either side of
+    // the wrap.
+    fn write(&self, at: u32, bytes: &[u8]) {
+        let (start, first) = self.split(at, bytes.len());
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), self.data.add(start), first);
+            ptr::copy_nonoverlapping(bytes[first..].as_ptr(), self.data, bytes.len() - first);
+        }
+    }
+
+    fn read(&self, at: u32, out: &mut [u8]) {
+        let (start, first) = self.split(at, out.len());
+        let len = out.len();
+        unsafe {
+            ptr::copy_nonoverlapping(self.data.add(start), out.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(self.data, out[first..].as_mut_ptr(), len - first);
+        }
+    }
+
+    fn split(&self, at: u32, len: usize) -> (usize, usize) {
+        let start = (at & (self.capacity - 1)) as usize;
+        (start, len.min(self.capacity as usize - start))
+    }
+}
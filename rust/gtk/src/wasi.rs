@@ -0,0 +1,185 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::{
+    host_common::{post_event, random_fill},
+    shared::EventKind,
+};
+use std::{
+    convert::TryInto,
+    sync::OnceLock,
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+// The WASI preview 1 functions the containers provide next to their "env" imports, so a module
+// built for wasm32-wasi can use std's printing, clocks and random numbers, with their signatures
+// in WAMR's notation ('i' for an i32, 'I' for an i64). There are no arguments, environment
+// variables or preopened directories; stdout and stderr go to the host as Log and Error events.
+pub const WASI_MODULE: &str = "wasi_snapshot_preview1";
+pub const WASI_FUNCTIONS: [(&str, &str); 11] = [
+    ("args_get", "(ii)i"),
+    ("args_sizes_get", "(ii)i"),
+    ("clock_res_get", "(ii)i"),
+    ("clock_time_get", "(iIi)i"),
+    ("environ_get", "(ii)i"),
+    ("environ_sizes_get", "(ii)i"),
+    ("fd_prestat_get", "(ii)i"),
+    ("fd_write", "(iiii)i"),
+    ("proc_exit", "(i)"),
+    ("random_get", "(ii)i"),
+    ("sched_yield", "()i"),
+];
+
+const WASI_EBADF: i32 = 8;
+const WASI_EFAULT: i32 = 21;
+const WASI_EINVAL: i32 = 28;
+
+// The linear memory of a module making a WASI call, as its engine exposes it. read() and write()
+// return false if the range is outside the memory; size() is its current size in bytes.
+pub trait WasiMemory {
+    fn read(&self, offset: u32, buf: &mut [u8]) -> bool;
+    fn write(&mut self, offset: u32, data: &[u8]) -> bool;
+    fn size(&self) -> u64;
+}
+
+// Runs the WASI function 'name' with 'args' (the i32s sign-extended), returning its errno, if it
+// has one. proc_exit reports the exit code and returns; WASI has it never return, so the module
+// traps straight after it.
+pub fn wasi_call(memory: &mut dyn WasiMemory, name: &str, args: &[i64]) -> Option<i32> {
+    let arg = |i: usize| args[i] as u32;
+    let errno = |ok: bool| if ok { 0 } else { WASI_EFAULT };
+    Some(match name {
+        "args_get" | "environ_get" => 0,
+        "args_sizes_get" | "environ_sizes_get" => errno(memory.write(arg(0), &[0; 4]) && memory.write(arg(1), &[0; 4])),
+        "clock_res_get" => match wasi_clock_ns(arg(0)) {
+            Some(_) => errno(memory.write(arg(1), &1u64.to_le_bytes())),
+            None => WASI_EINVAL,
+        },
+        "clock_time_get" => match wasi_clock_ns(arg(0)) {
+            Some(ns) => errno(memory.write(arg(2), &ns.to_le_bytes())),
+            None => WASI_EINVAL,
+        },
+        "fd_prestat_get" => WASI_EBADF,
+        "fd_write" => {
+            let kind = match arg(0) {
+                1 => EventKind::Log,
+                2 => EventKind::Error,
+                _ => return Some(WASI_EBADF),
+            };
+            // Gathers the iovecs, each a (buf, len) pair of u32s.
+            let mut text = Vec::new();
+            for i in 0..arg(2) {
+                let mut iovec = [0; 8];
+                let at = match i.checked_mul(8).and_then(|offset| arg(1).checked_add(offset)) {
+                    Some(at) => at,
+                    None => return Some(WASI_EFAULT),
+                };
+                if !memory.read(at, &mut iovec) {
+                    return Some(WASI_EFAULT);
+                }
+                let [buf, len] = [0, 4].map(|at| u32::from_le_bytes(iovec[at..at + 4].try_into().unwrap()));
+                if !in_bounds(memory, buf, len) {
+                    return Some(WASI_EFAULT);
+                }
+                let start = text.len();
+                text.resize(start + len as usize, 0);
+                if !memory.read(buf, &mut text[start..]) {
+                    return Some(WASI_EFAULT);
+                }
+            }
+            post_event(kind, 0, &String::from_utf8_lossy(&text));
+            errno(memory.write(arg(3), &(text.len() as u32).to_le_bytes()))
+        }
+        "proc_exit" => {
+            post_event(EventKind::Error, args[0], &format!("module exited with code {}", args[0]));
+            return None;
+        }
+        "random_get" => {
+            if !in_bounds(memory, arg(0), arg(1)) {
+                return Some(WASI_EFAULT);
+            }
+            let mut buf = vec![0; arg(1) as usize];
+            random_fill(&mut buf);
+            errno(memory.write(arg(0), &buf))
+        }
+        "sched_yield" => {
+            thread::yield_now();
+            0
+        }
+        _ => panic!("unknown WASI function {}", name),
+    })
+}
+
+// Whether the 'len' bytes at 'offset' are all inside the memory. The calls check this before
+// making a buffer for them, so a bogus length can't have the host allocate more than the memory.
+fn in_bounds(memory: &dyn WasiMemory, offset: u32, len: u32) -> bool {
+    offset.checked_add(len).is_some_and(|end| end as u64 <= memory.size())
+}
+
+// The time on a WASI clock in nanoseconds: the realtime clock is the host's (see now_us) and the
+// monotonic one counts from the first call. The CPU time clocks aren't supported.
+fn wasi_clock_ns(id: u32) -> Option<u64> {
+    static START: OnceLock<Instant> = OnceLock::new();
+    match id {
+        0 => Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64),
+        1 => Some(START.get_or_init(Instant::now).elapsed().as_nanos() as u64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host_common::WASM_PAGE_SIZE;
+
+    // A linear memory for the WASI calls.
+    struct VecMemory(Vec<u8>);
+
+    impl WasiMemory for VecMemory {
+        fn read(&self, offset: u32, buf: &mut [u8]) -> bool {
+            let range = offset as usize..offset as usize + buf.len();
+            self.0.get(range).map(|bytes| buf.copy_from_slice(bytes)).is_some()
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> bool {
+            let range = offset as usize..offset as usize + data.len();
+            self.0.get_mut(range).map(|bytes| bytes.copy_from_slice(data)).is_some()
+        }
+
+        fn size(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    #[test]
+    fn wasi_calls_stay_inside_linear_memory() {
+        let mut memory = VecMemory(vec![0; WASM_PAGE_SIZE as usize]);
+        assert_eq!(wasi_call(&mut memory, "random_get", &[16, 32]), Some(0));
+        assert_eq!(wasi_call(&mut memory, "random_get", &[16, i32::MAX as i64]), Some(WASI_EFAULT));
+        assert_eq!(wasi_call(&mut memory, "random_get", &[-1, 2]), Some(WASI_EFAULT));
+
+        // One iovec of "hi" at 8, with the count written to 0.
+        memory.write(100, &[8, 0, 0, 0, 2, 0, 0, 0]);
+        memory.write(8, b"hi");
+        assert_eq!(wasi_call(&mut memory, "fd_write", &[1, 100, 1, 0]), Some(0));
+        assert_eq!(memory.0[..4], [2, 0, 0, 0]);
+        // An iovec list that wraps around the address space, and one whose length is too long.
+        assert_eq!(wasi_call(&mut memory, "fd_write", &[1, -8, 2, 0]), Some(WASI_EFAULT));
+        memory.write(100, &[8, 0, 0, 0, 0xff, 0xff, 0xff, 0x7f]);
+        assert_eq!(wasi_call(&mut memory, "fd_write", &[1, 100, 1, 0]), Some(WASI_EFAULT));
+    }
+}
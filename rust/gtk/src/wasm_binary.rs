@@ -0,0 +1,215 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::host_common::{signature, Export};
+
+// Whether a module's memory, imported or its own, is 64-bit (the memory64 proposal), going by its
+// binary. False if it has no memory or isn't a module the reader here understands.
+pub fn is_memory64(bytes: &[u8]) -> bool {
+    const MEMORY64_FLAG: u8 = 0x04;
+    memory_limits_flags(bytes).is_some_and(|flags| flags & MEMORY64_FLAG != 0)
+}
+
+// The flags in the limits of the first memory in the import or memory section.
+fn memory_limits_flags(bytes: &[u8]) -> Option<u8> {
+    for (id, mut section) in wasm_sections(bytes)? {
+        match id {
+            WASM_IMPORT_SECTION => {
+                for _ in 0..section.leb()? {
+                    if let Import::Memory(flags) = section.import()? {
+                        return Some(flags);
+                    }
+                }
+            }
+            WASM_MEMORY_SECTION if section.leb()? > 0 => return section.limits(),
+            _ => {}
+        }
+    }
+    None
+}
+
+// The signatures of the functions a module exports, in WASI_FUNCTIONS' notation, going by its
+// binary; for engines that can't say what a module exports. None if it isn't a module the reader
+// here understands.
+pub fn binary_exports(bytes: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut types = Vec::new();
+    // Type indexes of the functions, imported ones first.
+    let mut functions = Vec::new();
+    let mut exports = Vec::new();
+    for (id, mut section) in wasm_sections(bytes)? {
+        match id {
+            WASM_TYPE_SECTION => {
+                for _ in 0..section.leb()? {
+                    if section.byte()? != 0x60 {
+                        return None;
+                    }
+                    let mut letters = || (0..section.leb()?).map(|_| section.value_type()).collect::<Option<String>>();
+                    let (params, results) = (letters()?, letters()?);
+                    types.push(signature(params.chars(), results.chars()));
+                }
+            }
+            WASM_IMPORT_SECTION => {
+                for _ in 0..section.leb()? {
+                    if let Import::Function(ty) = section.import()? {
+                        functions.push(ty);
+                    }
+                }
+            }
+            WASM_FUNCTION_SECTION => {
+                for _ in 0..section.leb()? {
+                    functions.push(section.leb()?);
+                }
+            }
+            WASM_EXPORT_SECTION => {
+                for _ in 0..section.leb()? {
+                    let name = section.name()?;
+                    let (kind, index) = (section.byte()?, section.leb()?);
+                    if kind == 0 {
+                        exports.push((name, index));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let ty = |index: u64| types.get(*functions.get(index as usize)? as usize).cloned();
+    exports.into_iter().map(|(name, index)| Some((name, ty(index)?))).collect()
+}
+
+// What binary_exports found for 'name', for the Instance::export of an engine that uses it.
+pub fn binary_export(exports: &Option<Vec<(String, String)>>, name: &str) -> Export {
+    let exports = match exports {
+        Some(exports) => exports,
+        None => return Export::Unknown,
+    };
+    match exports.iter().find(|(export, _)| export == name) {
+        Some((_, signature)) => Export::Function(signature.clone()),
+        None => Export::Missing,
+    }
+}
+
+const WASM_TYPE_SECTION: u8 = 1;
+const WASM_IMPORT_SECTION: u8 = 2;
+const WASM_FUNCTION_SECTION: u8 = 3;
+const WASM_MEMORY_SECTION: u8 = 5;
+const WASM_EXPORT_SECTION: u8 = 7;
+
+// The sections of a module's binary by id, each with a reader over its contents.
+fn wasm_sections(bytes: &[u8]) -> Option<Vec<(u8, WasmReader<'_>)>> {
+    if !bytes.starts_with(b"\0asm") {
+        return None;
+    }
+    let mut reader = WasmReader { bytes, at: 8 };
+    let mut sections = Vec::new();
+    while reader.at < bytes.len() {
+        let id = reader.byte()?;
+        let size = reader.leb()? as usize;
+        let end = reader.at.checked_add(size).filter(|&end| end <= bytes.len())?;
+        sections.push((id, WasmReader { bytes: &bytes[..end], at: reader.at }));
+        reader.at = end;
+    }
+    Some(sections)
+}
+
+enum Import {
+    // The function's type index.
+    Function(u64),
+    // The limits' flags.
+    Memory(u8),
+    Other,
+}
+
+// Reads a module's binary from 'at'; each read is None past the end of 'bytes'.
+struct WasmReader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl WasmReader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let value = *self.bytes.get(self.at)?;
+        self.at += 1;
+        Some(value)
+    }
+
+    fn leb(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let next = self.byte()?;
+            value |= ((next & 0x7f) as u64) << shift;
+            if next & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let len = self.leb()? as usize;
+        let name = self.bytes.get(self.at..self.at.checked_add(len)?)?;
+        self.at += len;
+        Some(String::from_utf8_lossy(name).into_owned())
+    }
+
+    // Returns the flags.
+    fn limits(&mut self) -> Option<u8> {
+        let flags = self.byte()?;
+        self.leb()?;
+        if flags & 1 != 0 {
+            self.leb()?;
+        }
+        Some(flags)
+    }
+
+    // A value type's letter in WASI_FUNCTIONS' notation, or '?' for one without.
+    fn value_type(&mut self) -> Option<char> {
+        Some(match self.byte()? {
+            0x7f => 'i',
+            0x7e => 'I',
+            0x7d => 'f',
+            0x7c => 'F',
+            _ => '?',
+        })
+    }
+
+    // An import's module and field names, then what's imported.
+    fn import(&mut self) -> Option<Import> {
+        self.name()?;
+        self.name()?;
+        Some(match self.byte()? {
+            0 => Import::Function(self.leb()?),
+            // A table's element type and limits.
+            1 => {
+                self.byte()?;
+                self.limits()?;
+                Import::Other
+            }
+            2 => Import::Memory(self.limits()?),
+            // A global's type and mutability.
+            3 => {
+                self.at += 2;
+                Import::Other
+            }
+            // A tag's attribute and type index.
+            4 => {
+                self.byte()?;
+                self.leb()?;
+                Import::Other
+            }
+            _ => return None,
+        })
+    }
+}
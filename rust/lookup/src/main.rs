@@ -13,46 +13,100 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
-use argparse::{ArgumentParser, Store};
-use libc::{MAP_FIXED, MAP_SHARED, O_CREAT, O_RDWR, O_TRUNC, PROT_READ, S_IRUSR, S_IWUSR};
-use rand::{distributions::{Alphanumeric, Distribution, Uniform}, Rng};
+use argparse::{ArgumentParser, Store, StoreTrue};
+use libc::{MAP_FIXED, MAP_SHARED, PROT_READ};
+use rand::{distributions::{Alphanumeric, Distribution, Uniform}, rngs::StdRng, Rng, SeedableRng};
+use table::writer::{
+    delta_entry_bytes, hash_key, segment_shift, DeltaWriter, Format, LookupWriter, Stats, TableFile, INDEX_ENTRY_BYTES,
+};
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap}, cmp, ffi::CString, fs::File,
-    hash::Hasher, io::{prelude::*, SeekFrom}, mem, ops::RangeInclusive,
-    os::unix::io::{AsRawFd, FromRawFd}, str, time::SystemTime,
+    cell::Cell, collections::HashMap, cmp, ffi::CString, fs::File, io::prelude::*, mem, ops::RangeInclusive,
+    os::unix::{fs::FileExt, io::AsRawFd}, ptr, str, time::{Duration, SystemTime},
 };
 use wasmi::{
     Error, Externals, FuncInstance, FuncRef, ImportsBuilder, LittleEndianConvert, MemoryRef,
-    Module, ModuleImportResolver, ModuleInstance, ModuleRef, RuntimeArgs, RuntimeValue,
-    RuntimeValue::I32, Signature, Trap,
+    Module, ModuleImportResolver, ModuleInstance, ModuleRef, NopExternals, RuntimeArgs, RuntimeValue,
+    RuntimeValue::{I32, I64}, Signature, Trap,
 };
 
+#[cfg(not(all(target_os = "macos", target_arch = "aarch64")))]
 const PAGE_SIZE: usize = 4096;
+// Apple silicon uses 16k pages.
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+const PAGE_SIZE: usize = 16384;
+const THP_SIZE: usize = 2 * 1024 * 1024;
 const MMAP_NAME: &str = "/lookup";
 const KEY_SIZE: RangeInclusive<usize> = 5..=40;
 const VAL_SIZE: RangeInclusive<usize> = 10..=200;
+// --quick uses a small table built from a fixed seed and checks every test key, so functional
+// regressions are caught quickly (e.g. in CI); its timings aren't meaningful.
+const QUICK_ENTRIES: usize = 10_000;
+const QUICK_SLOTS: usize = 1024;
+const QUICK_TEST_KEYS: i32 = 1000;
+const QUICK_SEED: u64 = 1;
+// With -g, the chains are split across this many segments at most; each gets its own file and its
+// own reservation in linear memory.
+const MAX_SEGMENTS: usize = 16;
+// With --hot, the table file is mapped in shards of this size.
+const SHARD_BYTES: usize = 64 * 1024;
+// wasmi has no memory64 support, so the reader is always wasm32 and anything mapped in full has to
+// fit in its linear memory along with the module's own data. A windowed table can be larger.
+const MAX_MAPPED_BYTES: u64 = i32::MAX as u64;
+// Bumped whenever the table format or the reader's exports change; must match the reader's.
+const ABI_VERSION: u32 = 1;
 
+#[derive(Clone)]
 struct Params {
     lookup_entries: usize,
     index_slots: usize,
     test_keys: i32,
     default_msg_bytes: i32,
+    huge_pages: bool,
+    window_bytes: usize,
+    hot_percent: usize,
+    segments: usize,
+    split: bool,
+    quick: bool,
+    advice: String,
+    mixed: String,
+    prefault: String,
+    copy: bool,
+    format: String,
+    delta: usize,
     module_name: String,
 }
 
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            lookup_entries: 1_000_000,
+            index_slots: 128 * 1024,
+            test_keys: 10_000,
+            default_msg_bytes: 100,
+            huge_pages: false,
+            window_bytes: 0,
+            hot_percent: 100,
+            segments: 1,
+            split: false,
+            quick: false,
+            advice: String::default(),
+            mixed: String::default(),
+            prefault: String::default(),
+            copy: false,
+            format: "chained".to_string(),
+            delta: 0,
+            module_name: String::default(),
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
 type cptr = *mut core::ffi::c_void;
 
 fn main() {
     assert_eq!(PAGE_SIZE, unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize });
 
-    let mut params = Params {
-        lookup_entries: 1_000_000,
-        index_slots: 128 * 1024,
-        test_keys: 10_000,
-        default_msg_bytes: 100,
-        module_name: String::default(),
-    };
+    let mut params = Params::default();
     {
         let mut ap = ArgumentParser::new();
         ap.refer(&mut params.lookup_entries)
@@ -63,6 +117,34 @@ fn main() {
             .add_option(&["-k"], Store, "number of test keys to use");
         ap.refer(&mut params.default_msg_bytes)
             .add_option(&["-m"], Store, "default size of message buffer for external lookup calls");
+        ap.refer(&mut params.huge_pages)
+            .add_option(&["--huge-pages"], StoreTrue, "map the lookup table with huge pages if available");
+        ap.refer(&mut params.window_bytes)
+            .add_option(&["-w"], Store, "map the chains through a sliding window of this many bytes (0 maps them all)");
+        ap.refer(&mut params.segments)
+            .add_option(&["-g"], Store, "split the chains across this many separately mapped segments");
+        ap.refer(&mut params.split)
+            .add_option(&["--split"], StoreTrue, "map the index table and the chains at separate places");
+        ap.refer(&mut params.hot_percent)
+            .add_option(&["--hot"], Store, "map only the index and this percentage of the chains, hottest first");
+        ap.refer(&mut params.quick)
+            .add_option(&["--quick"], StoreTrue, "small fixed-seed table with every test key verified (for CI)");
+        ap.refer(&mut params.advice)
+            .add_option(&["--advise"], Store, "madvise hint for the table: normal, random, sequential or willneed");
+        ap.refer(&mut params.mixed)
+            .add_option(&["--mixed"], Store, "also time lookups routed by key: len:N, prefix:C, internal or external");
+        ap.refer(&mut params.prefault)
+            .add_option(&["--prefault"], Store, "touch every mapped page before timing, from the host or wasm side");
+        ap.refer(&mut params.copy)
+            .add_option(&["--copy"], StoreTrue, "copy the table into linear memory instead of mapping it");
+        ap.refer(&mut params.format)
+            .add_option(
+                &["--format"],
+                Store,
+                "table format: chained, open, perfect, sorted, both (chained and open) or all",
+            );
+        ap.refer(&mut params.delta)
+            .add_option(&["--delta"], Store, "then update this many entries in place through the table's delta");
         ap.refer(&mut params.module_name)
             .add_argument("module_name", Store, "wasm module to run")
             .required();
@@ -70,99 +152,489 @@ fn main() {
             return;
         }
     }
-
-    println!("Loading wasm module");
-    let instance = load_wasm_module(&params.module_name);
+    if params.quick {
+        println!("Quick mode: timings aren't meaningful");
+        params.lookup_entries = QUICK_ENTRIES;
+        params.index_slots = QUICK_SLOTS;
+        params.test_keys = QUICK_TEST_KEYS;
+    }
+    if let Err(msg) = validate_params(&params) {
+        println!("Invalid parameters: {}", msg);
+        std::process::exit(1);
+    }
 
     println!("Creating lookup table: {} entries, {} slots", params.lookup_entries, params.index_slots);
     let (lookup, test_keys) = create_lookup(&params);
+    let updates = create_updates(&params, &lookup, &test_keys);
+
+    // Each format gets a fresh module instance and its own copy of the same entries.
+    let formats = parse_formats(&params.format).unwrap();
+    let mut durations = Vec::new();
+    for &format in &formats {
+        if formats.len() > 1 {
+            println!("Format: {}", format.name());
+        }
+        durations.push(run(&params, format, &lookup, &test_keys, &updates));
+    }
+    // Chained always runs first when formats are compared.
+    for (&format, &duration) in formats.iter().zip(&durations).skip(1) {
+        match duration.as_micros() {
+            0 => println!("{} vs chained: n/a (internal run too short to measure)", format.name()),
+            micros => println!(
+                "{} vs chained: internal lookups took {:.2?} against {:.2?}, {:.2}x as fast",
+                format.name(),
+                duration,
+                durations[0],
+                durations[0].as_micros() as f32 / micros as f32
+            ),
+        }
+    }
+}
+
+// Stores the table in the given format, maps it into a new instance of the reader and times the
+// lookups, then checks and times them again after applying any updates; returns the time taken
+// by the first internal ones.
+fn run(
+    params: &Params,
+    format: Format,
+    lookup: &HashMap<String, String>,
+    test_keys: &[u8],
+    updates: &[(String, String)],
+) -> Duration {
+    let index_slots = format.index_slots(params.index_slots, params.lookup_entries);
+    let params = &Params { index_slots, ..params.clone() };
+    println!("Loading wasm module");
+    let instance = load_wasm_module(&params.module_name);
 
     println!("Storing lookup table");
-    let shm_file = store_lookup(&lookup, &params);
+    let delta_bytes = updates.iter().map(|(key, val)| delta_entry_bytes(key, val)).sum();
+    let (table_files, stats) = store_lookup(lookup, params, format, delta_bytes);
+    let (huge_file, page_mode) = match params.huge_pages {
+        false => (None, PageMode::Standard),
+        true => match create_huge_page_copy(&table_files[0]) {
+            Some((huge_file, huge_page_size)) => (Some(huge_file), PageMode::HugeTlb(huge_page_size)),
+            None if cfg!(target_os = "linux") => {
+                println!("  falling back to transparent huge pages");
+                (None, PageMode::Transparent)
+            }
+            None => (None, PageMode::Standard),
+        },
+    };
+    // table_files outlives ctx (declared below), so the table is removed after it is unmapped.
+    let shm_file: &File = huge_file.as_ref().unwrap_or(&table_files[0]);
+    // What the host serves once the updates are applied; like table_files, it outlives ctx.
+    let mut updated = HashMap::new();
+    if !updates.is_empty() {
+        updated = lookup.clone();
+        updated.extend(updates.iter().cloned());
+    }
 
     let mut ctx = Context {
         instance: &instance,
         lookup,
         buffer: std::ptr::null_mut(),
         buffer_size: 0,
+        window: None,
+        shards: None,
+        segments: Vec::new(),
+        chains: None,
+        copy: Cell::new(params.copy),
         wasm_context: I32(0),
     };
+    if params.hot_percent < 100 {
+        ctx.shards = Some(choose_hot_shards(shm_file, params, test_keys));
+    }
 
     println!("Storing test keys");
-    let test_keys_index = store_test_keys(&ctx, &test_keys);
+    let test_keys_index = store_test_keys(&ctx, test_keys);
 
     println!("Initializing wasm module");
-    initialise_wasm(&mut ctx, &params, &shm_file, test_keys_index, test_keys.len() as i32);
+    let test_keys_bytes = test_keys.len();
+    initialise_wasm(
+        &mut ctx,
+        params,
+        shm_file,
+        &table_files[1..],
+        page_mode,
+        stats.max_chain_bytes,
+        test_keys_index,
+        test_keys_bytes,
+    );
+    if format != Format::Chained {
+        wasm_call(&ctx, "set_table_format", &[ctx.wasm_context, I32(format.arg())]);
+    }
+    if let Some((start, capacity)) = stats.delta {
+        let offset = start as usize - params.index_slots * INDEX_ENTRY_BYTES;
+        wasm_call(&ctx, "set_delta", &[ctx.wasm_context, wasm_u64(offset), wasm_u64(capacity)]);
+    }
+    if !params.advice.is_empty() {
+        let advice = Advice::parse(&params.advice);
+        match ctx.advise(advice) {
+            Ok(()) => println!("  applied {:?}", advice),
+            Err(e) => println!("  madvise({:?}) failed: {}", advice, e),
+        }
+    }
+    let policy = match params.mixed.is_empty() {
+        true => None,
+        false => Some(Policy::parse(&params.mixed)),
+    };
+    if let Some(policy) = policy {
+        let (kind, arg) = policy.args();
+        wasm_call(&ctx, "set_lookup_policy", &[ctx.wasm_context, I32(kind), I32(arg)]);
+    }
+    // Fault the table in before anything reads it, so the timings below only include lookups.
+    match params.prefault.as_str() {
+        "" => {}
+        side => {
+            let time = SystemTime::now();
+            let pages = match side {
+                "host" => ctx.prefault(),
+                _ => {
+                    let pages = wasm_call(&ctx, "prefault", &[ctx.wasm_context]);
+                    pages.and_then(|value| value.try_into::<i32>()).unwrap() as usize
+                }
+            };
+            println!("  prefault ({}): {:.2?}, {} pages", side, time.elapsed().unwrap(), pages);
+        }
+    }
     wasm_call(&ctx, "verify_lookups", &[ctx.wasm_context]);
+    if params.quick {
+        println!("Verifying all {} test keys", params.test_keys);
+        wasm_call(&ctx, "verify_all_lookups", &[ctx.wasm_context]);
+    }
 
     println!("Running performance tests: {} reps", params.test_keys);
     let time = SystemTime::now();
-    wasm_call(&ctx, "performance_test_internal", &[ctx.wasm_context]);
+    let internal = wasm_call(&ctx, "performance_test_internal", &[ctx.wasm_context]);
     let duration_int = time.elapsed().unwrap();
+    let internal = internal.and_then(|value| value.try_into::<i32>()).unwrap();
     println!("  internal: {:.2?}", duration_int);
 
     let time = SystemTime::now();
     wasm_call(&ctx, "performance_test_external", &[ctx.wasm_context]);
     let duration_ext = time.elapsed().unwrap();
     println!("  external: {:.2?}", duration_ext);
-    println!("  speed up: {:.1}x", duration_ext.as_micros() as f32 / duration_int.as_micros() as f32);
+    // With very few test keys the internal run can finish within a microsecond.
+    match duration_int.as_micros() {
+        0 => println!("  speed up: n/a (internal run too short to measure)"),
+        micros => println!("  speed up: {:.1}x", duration_ext.as_micros() as f32 / micros as f32),
+    }
+    if let Some(policy) = policy {
+        let time = SystemTime::now();
+        let internal = wasm_call(&ctx, "performance_test_mixed", &[ctx.wasm_context]);
+        let duration_mixed = time.elapsed().unwrap();
+        let internal = internal.and_then(|value| value.try_into::<i32>()).unwrap();
+        println!("  mixed: {:.2?} ({:?}: {} of {} internal)", duration_mixed, policy, internal, params.test_keys);
+    }
+    match ctx.copied() {
+        false => report_page_size(&ctx, page_mode),
+        true => println!("  copy mode: the table was copied into linear memory, not shared with the host"),
+    }
+    if let Some(window) = &ctx.window {
+        println!("  window: {} kB, remapped {} times", window.size / 1024, window.remaps.get());
+    }
+    if let Some(shards) = &ctx.shards {
+        let table_bytes = shm_file.metadata().unwrap().len() as usize;
+        let mapped_bytes: usize =
+            (0..shards.len()).filter(|&shard| shards[shard]).map(|shard| shard_len(shard, table_bytes)).sum();
+        println!(
+            "  mapped: {} of {} kB ({} of {} shards), {} of {} test keys internal",
+            mapped_bytes / 1024,
+            table_bytes / 1024,
+            shards.iter().filter(|&&mapped| mapped).count(),
+            shards.len(),
+            internal,
+            params.test_keys
+        );
+    }
+    if !updates.is_empty() {
+        apply_updates(&mut ctx, &table_files[0], &stats, updates, &updated, params);
+    }
+    duration_int
+}
+
+// Appends the updates to the live table's delta, then checks that the reader sees them all and
+// times its internal lookups again, now that each one checks the delta first.
+fn apply_updates<'a>(
+    ctx: &mut Context<'a>,
+    table_file: &File,
+    stats: &Stats,
+    updates: &[(String, String)],
+    updated: &'a HashMap<String, String>,
+    params: &Params,
+) {
+    println!("Applying {} updates", updates.len());
+    let mut writer = DeltaWriter::open(table_file, stats);
+    let time = SystemTime::now();
+    for (key, val) in updates {
+        assert!(writer.append(key, val), "the lookup delta is full");
+    }
+    println!("  appended: {:.2?}, generation {}", time.elapsed().unwrap(), writer.generation());
+    ctx.lookup = updated;
+
+    let generation = wasm_call(ctx, "delta_generation", &[ctx.wasm_context]);
+    assert_eq!(generation.and_then(|value| value.try_into::<i64>()).unwrap() as u64, writer.generation());
+    let checked = wasm_call(ctx, "verify_delta", &[ctx.wasm_context]);
+    assert_eq!(checked.and_then(|value| value.try_into::<i32>()).unwrap() as usize, updates.len());
+    match params.quick {
+        false => wasm_call(ctx, "verify_lookups", &[ctx.wasm_context]),
+        true => wasm_call(ctx, "verify_all_lookups", &[ctx.wasm_context]),
+    };
+    let time = SystemTime::now();
+    wasm_call(ctx, "performance_test_internal", &[ctx.wasm_context]);
+    println!("  internal with the delta: {:.2?}", time.elapsed().unwrap());
 }
 
 struct Context<'a> {
     instance: &'a ModuleInstance,
-    lookup: HashMap<String, String>,
+    lookup: &'a HashMap<String, String>,
     buffer: cptr,
     buffer_size: usize,
+    // Set if only the index table is mapped at 'buffer', with the chains reached through a window.
+    window: Option<Window>,
+    // Set with --hot: which SHARD_BYTES shards of the table are mapped at their place in 'buffer'.
+    // The rest of the reservation is left as the module's own memory, which the reader avoids.
+    shards: Option<Vec<bool>>,
+    // The mappings of segments after the first, which shares the index table's file and mapping.
+    segments: Vec<(cptr, usize)>,
+    // Set with --split: the separate mapping of the chains, from the page holding the first one.
+    chains: Option<(cptr, usize)>,
+    // Set with --copy, or once mapping into linear memory has failed; see place_table().
+    copy: Cell<bool>,
     wasm_context: RuntimeValue,
 }
 
+// A fixed-size mapping of part of the table file inside linear memory, which the reader asks the
+// host to move (via remap_callback) when a chain it needs lies outside it.
+struct Window {
+    fd: i32,
+    ptr: cptr,
+    size: usize,
+    remaps: Cell<u32>,
+    copy: Cell<bool>,
+}
+
+impl Window {
+    // Maps the window to start at the page containing 'file_offset'; returns the new start.
+    fn map(&self, file_offset: usize) -> usize {
+        let start = file_offset & !(PAGE_SIZE - 1);
+        place_table(self.ptr as usize, self.size, self.fd, start, &self.copy);
+        start
+    }
+}
+
+// Maps 'len' bytes of a table file from 'offset' to 'ptr' in linear memory. Where that isn't
+// possible (e.g. the engine's memory can't be mapped over), or in copy mode, the bytes are copied
+// there instead; a failed mapping switches to copy mode for the rest of the table too.
+fn place_table(ptr: usize, len: usize, fd: i32, offset: usize, copy: &Cell<bool>) {
+    if !copy.get() {
+        let res = unsafe { libc::mmap(ptr as cptr, len, PROT_READ, MAP_FIXED | MAP_SHARED, fd, offset as libc::off_t) };
+        if res as usize == ptr {
+            return;
+        }
+        let err = std::io::Error::last_os_error();
+        println!("  mapping into linear memory failed ({}); copying the table instead", err);
+        copy.set(true);
+    }
+    let buf = unsafe { std::slice::from_raw_parts_mut(ptr as *mut u8, len) };
+    let mut done = 0;
+    while done < len {
+        let rest = &mut buf[done..];
+        let n = unsafe { libc::pread(fd, rest.as_mut_ptr() as cptr, rest.len(), (offset + done) as libc::off_t) };
+        match n {
+            -1 => panic!("pread failed for the lookup table"),
+            // The window may extend past the end of the file.
+            0 => break,
+            n => done += n as usize,
+        }
+    }
+    buf[done..].fill(0);
+}
+
+impl Context<'_> {
+    // Applies a paging hint to the mapped lookup table.
+    fn advise(&self, advice: Advice) -> std::io::Result<()> {
+        assert!(!self.buffer.is_null());
+        if unsafe { libc::madvise(self.buffer, self.buffer_size, advice.flag()) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Whether any of the table had to be copied into linear memory rather than mapped.
+    fn copied(&self) -> bool {
+        self.copy.get() || self.window.as_ref().is_some_and(|window| window.copy.get())
+    }
+
+    // Reads a byte from every page mapped from the table files; returns the number of pages.
+    fn prefault(&self) -> usize {
+        let mut ranges = match &self.shards {
+            None => vec![(self.buffer as usize, self.buffer_size)],
+            Some(shards) => (0..shards.len())
+                .filter(|&shard| shards[shard])
+                .map(|shard| (self.buffer as usize + shard * SHARD_BYTES, shard_len(shard, self.buffer_size)))
+                .collect(),
+        };
+        ranges.extend(self.window.iter().map(|window| (window.ptr as usize, window.size)));
+        ranges.extend(self.segments.iter().map(|&(ptr, size)| (ptr as usize, size)));
+        ranges.extend(self.chains.iter().map(|&(ptr, size)| (ptr as usize, size)));
+        let mut pages = 0;
+        for (start, len) in ranges {
+            for page in (start..start + len).step_by(PAGE_SIZE) {
+                unsafe { std::ptr::read_volatile(page as *const u8) };
+                pages += 1;
+            }
+        }
+        pages
+    }
+}
+
 impl Drop for Context<'_> {
     fn drop(&mut self) {
-        if self.buffer != std::ptr::null_mut() {
+        // In copy mode the table is in the module's own memory, so there's nothing to unmap (except
+        // anything mapped before falling back, which goes with the process).
+        if !self.buffer.is_null() && !self.copied() {
             assert!(self.buffer_size > 0);
-            let cname = CString::new(MMAP_NAME).unwrap();
-            unsafe {
-                if libc::munmap(self.buffer, self.buffer_size) == -1 {
-                    println!("munmap failed for shared_ro");
-                }
-                if libc::shm_unlink(cname.as_ptr()) == -1 {
-                    println!("shm_unlink failed for shared_rw");
-                }
+            unmap_table(self.buffer, self.buffer_size, "shared_ro");
+            if let Some(window) = &self.window {
+                unmap_table(window.ptr, window.size, "the lookup window");
+            }
+            for &(ptr, size) in &self.segments {
+                unmap_table(ptr, size, "a lookup segment");
+            }
+            if let Some((ptr, size)) = self.chains {
+                unmap_table(ptr, size, "the lookup chains");
             }
         }
     }
 }
 
+// The table is mapped over the engine's linear memory, which outlives the mapping when another
+// format is run after it (see run), so rather than leave a hole in it this maps fresh memory back.
+fn unmap_table(ptr: cptr, size: usize, what: &str) {
+    let prot = PROT_READ | libc::PROT_WRITE;
+    let flags = MAP_FIXED | libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+    if unsafe { libc::mmap(ptr, size, prot, flags, -1, 0) } != ptr {
+        println!("unmapping failed for {}", what);
+    }
+}
+
 fn load_wasm_module(module_name: &str) -> ModuleRef {
     let mut bytes = Vec::new();
     File::open(module_name).unwrap().read_to_end(&mut bytes).unwrap();
+    println!("  size: {:.1} Kb", bytes.len() as f64 / 1024.0);
     let module = Module::from_buffer(&bytes).expect("failed to load wasm");
     let imports = ImportsBuilder::new().with_resolver("env", &Resolver);
-    ModuleInstance::new(&module, &imports)
+    let instance = ModuleInstance::new(&module, &imports)
         .expect("failed to instantiate wasm module")
-        .assert_no_start()
+        .assert_no_start();
+    check_abi_version(&instance);
+    instance
+}
+
+// A reader built against an older table format would misread the table rather than fail.
+fn check_abi_version(instance: &ModuleRef) {
+    match instance.invoke_export("shared_buffers_abi_version", &[], &mut NopExternals) {
+        Ok(Some(I32(version))) if version as u32 == ABI_VERSION => (),
+        Ok(Some(I32(version))) => {
+            panic!("module has ABI version {}, but the host expects {}; rebuild it", version, ABI_VERSION)
+        }
+        Ok(value) => panic!("module's shared_buffers_abi_version returned {:?}", value),
+        Err(e) => panic!("module predates ABI version {} or is broken ({:?}); rebuild it", ABI_VERSION, e),
+    }
+}
+
+// Catches configurations that would otherwise fail obscurely later on.
+fn validate_params(params: &Params) -> Result<(), String> {
+    if params.index_slots == 0 {
+        return Err("the lookup table needs at least one hash slot (-s)".to_string());
+    }
+    if params.test_keys < 0 {
+        return Err("the number of test keys (-k) can't be negative".to_string());
+    }
+    // Test keys are taken from the table's entries, so there can't be more of them.
+    if params.test_keys as usize > params.lookup_entries {
+        return Err(format!(
+            "{} test keys (-k) requested but the table only has {} entries (-e)",
+            params.test_keys, params.lookup_entries
+        ));
+    }
+    // The window moves in whole pages, which would break the alignment huge pages need.
+    if params.window_bytes > 0 && params.huge_pages {
+        return Err("a lookup window (-w) can't be combined with --huge-pages".to_string());
+    }
+    if params.segments == 0 || params.segments > MAX_SEGMENTS || params.segments > params.index_slots {
+        return Err(format!("the number of segments (-g) must be between 1 and {} (and at most -s)", MAX_SEGMENTS));
+    }
+    // The other mapping modes assume a single file.
+    if params.segments > 1 && (params.window_bytes > 0 || params.hot_percent < 100 || params.huge_pages) {
+        return Err("segments (-g) can't be combined with -w, --hot or --huge-pages".to_string());
+    }
+    // A split mapping is a fixed window over all the chains, so it rules out the other ways of
+    // mapping part of the table.
+    if params.split && (params.window_bytes > 0 || params.hot_percent < 100 || params.segments > 1) {
+        return Err("--split can't be combined with -w, --hot or -g".to_string());
+    }
+    if params.hot_percent > 100 {
+        return Err(format!("--hot is a percentage, not {}", params.hot_percent));
+    }
+    // Shards are mapped separately, which likewise rules out huge pages, and a window already
+    // maps only part of the table.
+    if params.hot_percent < 100 && (params.window_bytes > 0 || params.huge_pages) {
+        return Err("--hot can't be combined with a lookup window (-w) or --huge-pages".to_string());
+    }
+    if !["", "host", "wasm"].contains(&params.prefault.as_str()) {
+        return Err(format!("--prefault must be host or wasm, not '{}'", params.prefault));
+    }
+    // The updates are written into the table file the reader has mapped, so it has to be mapped in
+    // full and not copied.
+    if params.delta > 0 && (params.window_bytes > 0 || params.hot_percent < 100 || params.copy || params.huge_pages) {
+        return Err("--delta can't be combined with -w, --hot, --copy or --huge-pages".to_string());
+    }
+    if params.default_msg_bytes < 0 {
+        return Err("the default message size (-m) can't be negative".to_string());
+    }
+    let formats = match parse_formats(&params.format) {
+        Some(formats) => formats,
+        None => {
+            return Err(format!("--format must be chained, open, perfect, sorted, both or all, not '{}'", params.format))
+        }
+    };
+    // A key's probes can run on into any later slot, so open addressing can't tell which segment
+    // or shard holds it; nor can the reader's check of which is mapped find a perfect hash slot.
+    if formats.iter().any(|&format| format != Format::Chained) && (params.segments > 1 || params.hot_percent < 100) {
+        return Err("only --format chained can be combined with -g or --hot".to_string());
+    }
+    // The index table is always mapped in full.
+    for format in formats {
+        let index_slots = format.index_slots(params.index_slots, params.lookup_entries);
+        if (index_slots * INDEX_ENTRY_BYTES) as u64 > MAX_MAPPED_BYTES {
+            return Err(format!("too many hash slots (-s): {}", index_slots));
+        }
+    }
+    Ok(())
 }
 
 fn create_lookup(params: &Params) -> (HashMap<String, String>, Vec<u8>) {
-    let mut lookup = HashMap::new();
-    let mut test_keys = Vec::new();
-    let mut test_key_count = 0;
-    let mut rng = rand::thread_rng();
+    let mut rng = if params.quick { StdRng::seed_from_u64(QUICK_SEED) } else { StdRng::from_entropy() };
     let key_dist = Uniform::<usize>::from(KEY_SIZE);
     let val_dist = Uniform::<usize>::from(VAL_SIZE);
-    for _ in 0..params.lookup_entries {
-        let key: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(key_dist.sample(&mut rng))
-            .map(char::from)
-            .collect();
-
-        let val: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(val_dist.sample(&mut rng))
-            .map(char::from)
-            .collect();
+    fill_lookup(params, || (random_string(&mut rng, &key_dist), random_string(&mut rng, &val_dist)))
+}
 
+// Random keys can collide, so entries are taken from 'next' until there are enough distinct keys;
+// a repeated key is dropped rather than replacing the first value. Each test key is distinct too.
+fn fill_lookup(params: &Params, mut next: impl FnMut() -> (String, String)) -> (HashMap<String, String>, Vec<u8>) {
+    let mut lookup = HashMap::new();
+    let mut test_keys = Vec::new();
+    let mut test_key_count = 0;
+    let mut duplicates = 0;
+    while lookup.len() < params.lookup_entries {
+        let (key, val) = next();
+        if lookup.contains_key(&key) {
+            duplicates += 1;
+            continue;
+        }
         if test_key_count < params.test_keys {
             test_keys.extend((key.len() as u32).to_le_bytes());
             test_keys.extend(key.as_bytes());
@@ -170,128 +642,329 @@ fn create_lookup(params: &Params) -> (HashMap<String, String>, Vec<u8>) {
         }
         lookup.insert(key, val);
     }
+    if duplicates > 0 {
+        println!("  regenerated {} duplicate keys", duplicates);
+    }
     (lookup, test_keys)
 }
 
-// The lookup table is serialized with the following format:
-//
-//  | index table | bumper | packed chains |
-//
-// index table: list of u32 offsets into packed data (starting from end of the index table)
-// bumper: a single unused byte so offsets of 0 can indicate an empty slot in the index table
-// packed chains: a sequence of chains per used index slot; each chain has the format:
-//
-//  | n_pairs:u32 | key_len:u32 | key | value_len:u32 | value | key_len | ... |
-//
-// Keys are stored in ascending size order to enable a slightly faster lookup on the wasm side.
-fn store_lookup(lookup: &HashMap<String, String>, params: &Params) -> File {
-    // Convert the map to a table with vectors of key/value pairs.
-    let mut table = Vec::<Vec<KeyValue>>::with_capacity(params.index_slots);
-    table.resize(params.index_slots, Vec::new());
+fn random_string(rng: &mut StdRng, len_dist: &Uniform<usize>) -> String {
+    let len = len_dist.sample(rng);
+    rng.sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
+
+// The updates --delta applies: new values for the first half of them for test keys (as many as
+// there are), so the timed lookups find them in the delta, and new keys for the rest.
+fn create_updates(params: &Params, lookup: &HashMap<String, String>, test_keys: &[u8]) -> Vec<(String, String)> {
+    let mut rng = if params.quick { StdRng::seed_from_u64(QUICK_SEED + 1) } else { StdRng::from_entropy() };
+    let val_dist = Uniform::<usize>::from(VAL_SIZE);
+    let key_dist = Uniform::<usize>::from(KEY_SIZE);
+    let mut updates = Vec::new();
+    let mut pos = 0;
+    while updates.len() < params.delta.div_ceil(2) && pos < test_keys.len() {
+        let len = u32::from_le_bytes(test_keys[pos..pos + 4].try_into().unwrap()) as usize;
+        let key = str::from_utf8(&test_keys[pos + 4..pos + 4 + len]).unwrap();
+        updates.push((key.to_string(), random_string(&mut rng, &val_dist)));
+        pos += 4 + len;
+    }
+    while updates.len() < params.delta {
+        let key = random_string(&mut rng, &key_dist);
+        if !lookup.contains_key(&key) && !updates.iter().any(|(other, _)| *other == key) {
+            updates.push((key, random_string(&mut rng, &val_dist)));
+        }
+    }
+    updates
+}
+
+// Builds the table in the given format (see LookupWriter for the layout), with room for this many
+// bytes of updates in its delta, and reports on it.
+fn store_lookup(
+    lookup: &HashMap<String, String>,
+    params: &Params,
+    format: Format,
+    delta_bytes: usize,
+) -> (Vec<TableFile>, Stats) {
+    let mut writer = LookupWriter::new(MMAP_NAME, format, params.index_slots, params.segments).with_delta(delta_bytes);
     for (key, val) in lookup.iter() {
-        let mut hasher = DefaultHasher::new();
-        hasher.write(key.as_bytes());
-        let i = (hasher.finish() as usize) % params.index_slots;
-        table[i].push(KeyValue(key.to_string(), val.to_string()));
+        writer.add(key, val);
     }
+    let (files, stats) = writer.finalize();
+    assert_eq!(stats.index_slots, params.index_slots);
 
-    // Create the shared memory file.
-    let cname = CString::new(MMAP_NAME).unwrap();
-    let fd = unsafe {
-        libc::shm_open(cname.as_ptr(), O_CREAT | O_TRUNC | O_RDWR, S_IRUSR | S_IWUSR)
-    };
-    if fd == -1 {
-        panic!("shm_open failed");
-    }
-    let mut file = unsafe { File::from_raw_fd(fd) };
-
-    // Zero out the index table, adding a single bumper byte after it to allow indexes
-    // of zero to indicate an empty slot.
-    file.set_len((params.index_slots * 4 + 1) as u64).unwrap();
-
-    // Pack the key/value pairs onto the end of the file, tracking offsets (from the
-    // start of the packed region, not the file) in the index table.
-    let mut offset = 1u32;
-    let mut num_chains = 0usize;
-    let mut sum_chain = 0usize;
-    let mut max_chain = 0usize;
-    for i in 0..params.index_slots {
-        if table[i].len() > 0 {
-            table[i].sort();
-            let list = &table[i];
-
-            // Update index table with current offset.
-            file.seek(SeekFrom::Start((i * 4) as u64)).unwrap();
-            write_u32(&mut file, offset);
-
-            // Append the list of key/value pairs to the file.
-            file.seek(SeekFrom::End(0)).unwrap();
-            offset += write_u32(&mut file, list.len() as u32);
-            for KeyValue(key, val) in list {
-                let kbytes = key.as_bytes();
-                offset += write_u32(&mut file, kbytes.len() as u32);
-                offset += write_bytes(&mut file, kbytes);
-
-                let vbytes = val.as_bytes();
-                offset += write_u32(&mut file, vbytes.len() as u32);
-                offset += write_bytes(&mut file, vbytes);
-            }
-            num_chains += 1;
-            sum_chain += list.len();
-            max_chain = cmp::max(list.len(), max_chain);
+    // Unless only a window onto it is mapped, each file has to fit in linear memory.
+    for (segment, &file_size) in stats.file_bytes.iter().enumerate() {
+        if file_size > MAX_MAPPED_BYTES && (params.window_bytes == 0 || segment > 0) {
+            panic!("lookup table is {} bytes, which is too large to map into wasm; use fewer entries (-e)", file_size);
+        }
+    }
+    let size: u64 = stats.file_bytes.iter().sum();
+    if let Some((seed, buckets, longest)) = stats.perfect {
+        println!("  perfect hash: seed {}, {} buckets, longest displacement {}", seed, buckets, longest);
+    }
+    println!("  size: {:.1} Mb", size as f64 / (1024.0 * 1024.0));
+    if params.segments > 1 {
+        println!("  segments: {}", params.segments);
+    }
+    match format {
+        Format::Chained if stats.chains == 0 => println!("  avg chain: n/a (empty table)"),
+        Format::Chained => println!("  avg chain: {:.1}", stats.pairs as f64 / stats.chains as f64),
+        Format::Open => {
+            let full = 100.0 * stats.pairs as f64 / params.index_slots as f64;
+            println!("  slots: {}, {:.0}% full", params.index_slots, full)
+        }
+        Format::Perfect => {}
+        Format::Sorted => {
+            let steps = (params.index_slots + 1).next_power_of_two().ilog2();
+            println!("  binary search: at most {} comparisons", steps)
+        }
+    }
+    match format {
+        Format::Chained => println!("  max chain: {}", stats.max_chain),
+        Format::Open if stats.chains == 0 => println!("  avg probe: n/a (empty table)"),
+        Format::Open => {
+            let avg = stats.sum_probe as f64 / stats.chains as f64;
+            println!("  avg probe: {:.2}, max probe: {}", avg, stats.max_probe)
+        }
+        Format::Perfect | Format::Sorted => {}
+    }
+    if let Some((_, capacity)) = stats.delta {
+        println!("  delta: {:.1} Kb", capacity as f64 / 1024.0);
+    }
+    (files, stats)
+}
+
+// Picks the shards to map with --hot: those holding the index table, plus the given percentage of
+// the rest, hottest first. The test keys stand in for a measured access profile.
+fn choose_hot_shards(shm_file: &File, params: &Params, test_keys: &[u8]) -> Vec<bool> {
+    let table_bytes = shm_file.metadata().unwrap().len() as usize;
+    let num_shards = table_bytes.div_ceil(SHARD_BYTES);
+    let index_bytes = params.index_slots * INDEX_ENTRY_BYTES;
+    let mut index = vec![0; index_bytes];
+    shm_file.read_exact_at(&mut index, 0).unwrap();
+
+    // Count the test keys whose chains start in each shard.
+    let mut hits = vec![0u32; num_shards];
+    let mut pos = 0;
+    while pos < test_keys.len() {
+        let len = u32::from_le_bytes(test_keys[pos..pos + 4].try_into().unwrap()) as usize;
+        let slot = (hash_key(&test_keys[pos + 4..pos + 4 + len]) as usize) % params.index_slots;
+        let entry = &index[slot * INDEX_ENTRY_BYTES..(slot + 1) * INDEX_ENTRY_BYTES];
+        let offset = u64::from_le_bytes(entry.try_into().unwrap()) as usize;
+        hits[(index_bytes + offset) / SHARD_BYTES] += 1;
+        pos += 4 + len;
+    }
+
+    let index_shards = index_bytes.div_ceil(SHARD_BYTES);
+    let mut mapped: Vec<bool> = (0..num_shards).map(|shard| shard < index_shards).collect();
+    let mut chain_shards: Vec<usize> = (index_shards..num_shards).collect();
+    chain_shards.sort_by_key(|&shard| cmp::Reverse(hits[shard]));
+    let hot = (chain_shards.len() * params.hot_percent).div_ceil(100);
+    for &shard in &chain_shards[..hot] {
+        mapped[shard] = true;
+    }
+    mapped
+}
+
+// The last shard stops at the end of the table.
+fn shard_len(shard: usize, table_bytes: usize) -> usize {
+    cmp::min(SHARD_BYTES, table_bytes - shard * SHARD_BYTES)
+}
+
+// Huge page support. Explicit huge pages come from a hugetlbfs-backed memfd, which requires
+// pages to be reserved via /proc/sys/vm/nr_hugepages. If that isn't possible, the regular shm
+// file is mapped with a transparent huge page hint instead; whether that takes effect depends
+// on /sys/kernel/mm/transparent_hugepage/shmem_enabled.
+#[derive(Clone, Copy, PartialEq)]
+enum PageMode {
+    Standard,
+    Transparent,
+    HugeTlb(usize),
+}
+
+// Paging hints for the mapped lookup table, passed through to madvise().
+#[derive(Clone, Copy, Debug)]
+enum Advice {
+    Normal,
+    Random,
+    Sequential,
+    WillNeed,
+    #[cfg(target_os = "linux")]
+    HugePage,
+}
+
+impl Advice {
+    fn parse(name: &str) -> Self {
+        match name {
+            "normal" => Advice::Normal,
+            "random" => Advice::Random,
+            "sequential" => Advice::Sequential,
+            "willneed" => Advice::WillNeed,
+            _ => panic!("unknown madvise hint '{}'", name),
+        }
+    }
+
+    fn flag(self) -> libc::c_int {
+        match self {
+            Advice::Normal => libc::MADV_NORMAL,
+            Advice::Random => libc::MADV_RANDOM,
+            Advice::Sequential => libc::MADV_SEQUENTIAL,
+            Advice::WillNeed => libc::MADV_WILLNEED,
+            #[cfg(target_os = "linux")]
+            Advice::HugePage => libc::MADV_HUGEPAGE,
         }
     }
-    file.flush().unwrap();
+}
+
+// The formats --format runs; "both" and "all" run each on the same entries to compare them.
+fn parse_formats(name: &str) -> Option<Vec<Format>> {
+    match name {
+        "chained" => Some(vec![Format::Chained]),
+        "open" => Some(vec![Format::Open]),
+        "perfect" => Some(vec![Format::Perfect]),
+        "sorted" => Some(vec![Format::Sorted]),
+        "both" => Some(vec![Format::Chained, Format::Open]),
+        "all" => Some(vec![Format::Chained, Format::Open, Format::Perfect, Format::Sorted]),
+        _ => None,
+    }
+}
 
-    println!("  size: {:.1} Mb", file.metadata().unwrap().len() as f64 / (1024.0 * 1024.0));
-    println!("  avg chain: {:.1}", sum_chain as f64 / num_chains as f64);
-    println!("  max chain: {}", max_chain);
-    file
+// How the reader routes each key in the mixed performance test; must match the reader's Policy.
+#[derive(Clone, Copy, Debug)]
+enum Policy {
+    Internal,
+    External,
+    // Keys of at most this many bytes are looked up internally.
+    MaxKeyLen(u32),
+    // Keys starting with a character before this one are looked up internally.
+    PrefixBelow(char),
 }
 
-#[derive(Debug, Clone)]
-struct KeyValue(String, String);
+impl Policy {
+    fn parse(name: &str) -> Self {
+        let policy = match name.split_once(':') {
+            None if name == "internal" => Some(Policy::Internal),
+            None if name == "external" => Some(Policy::External),
+            Some(("len", len)) => len.parse().ok().map(Policy::MaxKeyLen),
+            Some(("prefix", prefix)) => {
+                let mut chars = prefix.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if c.is_ascii() => Some(Policy::PrefixBelow(c)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        policy.unwrap_or_else(|| panic!("unknown lookup policy '{}'", name))
+    }
 
-impl Ord for KeyValue {
-    fn cmp(&self, other: &Self) -> cmp::Ordering {
-        if self.0 < other.0 {
-            cmp::Ordering::Less
-        } else if self.0 > other.0 {
-            cmp::Ordering::Greater
-        } else {
-            self.1.cmp(&other.1)
+    // The kind and argument passed to the reader's set_lookup_policy.
+    fn args(self) -> (i32, i32) {
+        match self {
+            Policy::Internal => (0, 0),
+            Policy::External => (1, 0),
+            Policy::MaxKeyLen(len) => (2, len as i32),
+            Policy::PrefixBelow(c) => (3, c as i32),
         }
     }
 }
 
-impl PartialOrd for KeyValue {
-    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        Some(self.cmp(other))
+impl PageMode {
+    // Huge pages can only back the mapping if it is aligned to the huge page size.
+    fn alignment(&self) -> usize {
+        match self {
+            PageMode::Standard => PAGE_SIZE,
+            PageMode::Transparent => THP_SIZE,
+            PageMode::HugeTlb(size) => *size,
+        }
     }
 }
 
-impl Eq for KeyValue {}
+// hugetlbfs doesn't support write(), so the table is copied across via mappings of both files.
+#[cfg(target_os = "linux")]
+fn create_huge_page_copy(shm_file: &File) -> Option<(File, usize)> {
+    use libc::{MAP_FAILED, PROT_WRITE};
+    use std::{io, os::unix::{fs::MetadataExt, io::FromRawFd}};
+    let cname = CString::new("lookup_huge").unwrap();
+    let fd = unsafe { libc::memfd_create(cname.as_ptr(), libc::MFD_HUGETLB) };
+    if fd == -1 {
+        println!("  memfd_create(MFD_HUGETLB) failed: {}", io::Error::last_os_error());
+        return None;
+    }
+    let huge_file = unsafe { File::from_raw_fd(fd) };
 
-impl PartialEq for KeyValue {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0 && self.1 == other.1
+    // hugetlbfs reports the huge page size as the file's block size.
+    let huge_page_size = huge_file.metadata().unwrap().blksize() as usize;
+    let size = shm_file.metadata().unwrap().len() as usize;
+    let huge_size = page_align(size, huge_page_size);
+    if let Err(e) = huge_file.set_len(huge_size as u64) {
+        println!("  ftruncate of huge page file failed: {}", e);
+        return None;
+    }
+    unsafe {
+        let dst = libc::mmap(ptr::null_mut(), huge_size, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+        if dst == MAP_FAILED {
+            println!("  mmap of huge page file failed: {}", io::Error::last_os_error());
+            return None;
+        }
+        let src = libc::mmap(ptr::null_mut(), size, PROT_READ, MAP_SHARED, shm_file.as_raw_fd(), 0);
+        assert!(src != MAP_FAILED);
+        ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, size);
+        if libc::munmap(src, size) == -1 || libc::munmap(dst, huge_size) == -1 {
+            println!("munmap failed during huge page copy");
+        }
     }
+    println!("  copied to {} kB huge pages", huge_page_size / 1024);
+    Some((huge_file, huge_page_size))
 }
 
-fn write_u32(file: &mut File, num: u32) -> u32 {
-    write_bytes(file, &num.to_le_bytes())
+#[cfg(not(target_os = "linux"))]
+fn create_huge_page_copy(_shm_file: &File) -> Option<(File, usize)> {
+    println!("  huge pages are only supported on Linux");
+    None
 }
 
-fn write_bytes(file: &mut File, bytes: &[u8]) -> u32 {
-    file.write_all(bytes).unwrap();
-    bytes.len() as u32
+// Reports the page size the kernel actually used for the mapped table, based on the
+// entry for the mapping in /proc/self/smaps.
+fn report_page_size(ctx: &Context, page_mode: PageMode) {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap_or_default();
+    let mut in_mapping = false;
+    let (mut page_kb, mut rss_kb, mut pmd_kb) = (0, 0, 0);
+    for line in smaps.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [key, value, "kB"] if in_mapping => {
+                let value = value.parse().unwrap_or(0);
+                match *key {
+                    "Rss:" => rss_kb = value,
+                    "KernelPageSize:" => page_kb = value,
+                    "ShmemPmdMapped:" | "FilePmdMapped:" => pmd_kb += value,
+                    _ => (),
+                }
+            }
+            [range, ..] if !range.ends_with(':') => {
+                if in_mapping {
+                    break;
+                }
+                let start = range.split('-').next().unwrap_or_default();
+                in_mapping = usize::from_str_radix(start, 16) == Ok(ctx.buffer as usize);
+            }
+            _ => (),
+        }
+    }
+    if page_kb == 0 {
+        println!("  page size: unknown (mapping not found; wasm linear memory may have moved)");
+    } else if page_mode == PageMode::Transparent {
+        println!("  page size: {} kB, with {} of {} kB resident in huge pages", page_kb, pmd_kb, rss_kb);
+    } else {
+        println!("  page size: {} kB", page_kb);
+    }
 }
 
 // Store the test keys as "packed strings" (u32 length followed by utf8 bytes).
-fn store_test_keys(ctx: &Context, test_keys: &Vec<u8>) -> i32 {
-    let alloc_index = wasm_alloc(ctx, test_keys.len() as i32);
+fn store_test_keys(ctx: &Context, test_keys: &[u8]) -> usize {
+    let alloc_index = wasm_alloc(ctx, test_keys.len());
     get_linear_memory(ctx).with_direct_access_mut(|buf| {
-        let mut bi = alloc_index as usize;
+        let mut bi = alloc_index;
         for ki in 0..test_keys.len() {
             buf[bi] = test_keys[ki];
             bi += 1;
@@ -301,75 +974,190 @@ fn store_test_keys(ctx: &Context, test_keys: &Vec<u8>) -> i32 {
 }
 
 // Set up the mapped buffer and create the wasm's context object.
+#[allow(clippy::too_many_arguments)]
 fn initialise_wasm(
     ctx: &mut Context,
     params: &Params,
     shm_file: &File,
-    test_keys_index: i32,
-    test_keys_bytes: i32,
+    segment_files: &[TableFile],
+    page_mode: PageMode,
+    max_chain_bytes: usize,
+    test_keys_index: usize,
+    test_keys_bytes: usize,
 ) {
-    // Call wasm.malloc to reserve enough space for the mapped buffer plus alignment concerns.
-    ctx.buffer_size = shm_file.metadata().unwrap().len() as usize;
-    let alloc_size = ctx.buffer_size + 2 * PAGE_SIZE;
-    let wasm_alloc_index = wasm_alloc(ctx, alloc_size as i32);
+    // With a window, only the index table is mapped in full. The window must hold the largest
+    // chain starting anywhere in its first page; a window covering the whole table isn't needed.
+    let table_bytes = shm_file.metadata().unwrap().len() as usize;
+    let window_size = page_align(cmp::max(params.window_bytes, max_chain_bytes + PAGE_SIZE), PAGE_SIZE);
+    let windowed = params.window_bytes > 0 && window_size < table_bytes;
+    if windowed && window_size > page_align(params.window_bytes, PAGE_SIZE) {
+        println!("  rounded window up to {} bytes to fit the largest chain", window_size);
+    }
+    let index_bytes = params.index_slots * INDEX_ENTRY_BYTES;
+    ctx.buffer_size = if windowed || params.split { page_align(index_bytes, PAGE_SIZE) } else { table_bytes };
+    // With --split, the chains are mapped separately from the page holding the first of them,
+    // after a gap of a page so they are clearly apart from the index table.
+    let chains_offset = index_bytes & !(PAGE_SIZE - 1);
+    let chains_size = if params.split { table_bytes - chains_offset + PAGE_SIZE } else { 0 };
+
+    // Any other segments are mapped after the main buffer, each starting on a page boundary. Their
+    // (pointer, size) pairs of u64s are passed to the reader through a small allocation made up front, as
+    // allocating may grow linear memory and move it (and so lose the mappings).
+    let segment_sizes: Vec<usize> = segment_files.iter().map(|file| file.metadata().unwrap().len() as usize).collect();
+    let descs_index = match segment_files.len() {
+        0 => 0,
+        n => wasm_alloc(ctx, (n + 1) * 16),
+    };
+
+    // Call wasm.malloc to reserve enough space for the mapped buffers plus alignment concerns.
+    let alignment = page_mode.alignment();
+    let segments_size: usize = segment_sizes.iter().map(|&size| page_align(size, PAGE_SIZE) + PAGE_SIZE).sum();
+    let alloc_size =
+        ctx.buffer_size + if windowed { window_size } else { 0 } + chains_size + segments_size + 2 * alignment;
+    let wasm_alloc_index = wasm_alloc(ctx, alloc_size);
 
     // Get the location of wasm's linear memory buffer in our address space.
     let wasm_memory_base = get_linear_memory(ctx).with_direct_access(|buf| buf.as_ptr() as usize);
-    let wasm_alloc_ptr = wasm_memory_base + wasm_alloc_index as usize;
-
-    // Align the buffer inside wasm's linear memory against our page boundaries and map it in.
-    let aligned_ptr = page_align(wasm_alloc_ptr);
-    ctx.buffer = unsafe {
-        libc::mmap(
-            aligned_ptr as cptr,
-            ctx.buffer_size,
-            PROT_READ,
-            MAP_FIXED | MAP_SHARED,
-            shm_file.as_raw_fd(),
-            0,
-        )
+    let wasm_alloc_ptr = wasm_memory_base + wasm_alloc_index;
+
+    // Align the buffer inside wasm's linear memory against our page boundaries and map it in. With
+    // --hot, each chosen shard is mapped at its place in the buffer instead.
+    let aligned_ptr = page_align(wasm_alloc_ptr, alignment);
+    let ranges: Vec<(usize, usize)> = match &ctx.shards {
+        None => vec![(0, ctx.buffer_size)],
+        Some(shards) => (0..shards.len())
+            .filter(|&shard| shards[shard])
+            .map(|shard| (shard * SHARD_BYTES, shard_len(shard, table_bytes)))
+            .collect(),
+    };
+    for (offset, len) in ranges {
+        place_table(aligned_ptr + offset, len, shm_file.as_raw_fd(), offset, &ctx.copy);
+    }
+    ctx.buffer = aligned_ptr as cptr;
+    let mut segment_ptr = page_align(aligned_ptr + ctx.buffer_size, PAGE_SIZE);
+    for (file, &size) in segment_files.iter().zip(&segment_sizes) {
+        place_table(segment_ptr, size, file.as_raw_fd(), 0, &ctx.copy);
+        ctx.segments.push((segment_ptr as cptr, size));
+        segment_ptr = page_align(segment_ptr + size, PAGE_SIZE);
+    }
+    #[cfg(target_os = "linux")]
+    if page_mode == PageMode::Transparent {
+        if let Err(e) = ctx.advise(Advice::HugePage) {
+            println!("  madvise(MADV_HUGEPAGE) failed: {}", e);
+        }
+    }
+
+    // The window starts at the beginning of the file, after the index mapping. A split mapping of
+    // the chains serves as a window that never has to move, and without either the full mapping
+    // does.
+    let (window_ptr, window_bytes, window_offset) = if windowed {
+        let window = Window {
+            fd: shm_file.as_raw_fd(),
+            ptr: (aligned_ptr + ctx.buffer_size) as cptr,
+            size: window_size,
+            remaps: Cell::new(0),
+            copy: Cell::new(ctx.copy.get()),
+        };
+        window.map(0);
+        let location = (window.ptr as usize, window.size, 0);
+        ctx.window = Some(window);
+        location
+    } else if params.split {
+        let chains_ptr = aligned_ptr + ctx.buffer_size + PAGE_SIZE;
+        let size = table_bytes - chains_offset;
+        place_table(chains_ptr, size, shm_file.as_raw_fd(), chains_offset, &ctx.copy);
+        ctx.chains = Some((chains_ptr as cptr, size));
+        println!(
+            "  split: index table at {:#x}, chains from file offset {} at {:#x}",
+            ctx.buffer as usize - wasm_memory_base,
+            chains_offset,
+            chains_ptr - wasm_memory_base
+        );
+        (chains_ptr, size, chains_offset)
+    } else {
+        (ctx.buffer as usize, table_bytes, 0)
     };
-    assert_eq!(ctx.buffer as usize, aligned_ptr);
 
-    // Convert the aligned buffer location into its wasm linear memory index and inform the module.
-    let wasm_buf_index = (ctx.buffer as usize - wasm_memory_base) as i32;
-    let lookup_bytes = ctx.buffer_size - params.index_slots * 4;
+    // Convert the aligned buffer locations into wasm linear memory indexes and inform the module.
+    let wasm_buf_index = ctx.buffer as usize - wasm_memory_base;
+    let wasm_window_index = window_ptr - wasm_memory_base;
     ctx.wasm_context = wasm_call(
         ctx,
         "create_context",
         &[
-            I32(wasm_buf_index),
-            I32(params.index_slots as i32),
-            I32(lookup_bytes as i32),
+            wasm_ptr(wasm_buf_index),
+            wasm_u64(params.index_slots),
+            wasm_ptr(wasm_window_index),
+            wasm_u64(window_bytes),
+            wasm_u64(window_offset),
+            wasm_u64(table_bytes),
+            wasm_u64(max_chain_bytes),
             I32(params.test_keys),
-            I32(test_keys_index),
-            I32(test_keys_bytes),
+            wasm_ptr(test_keys_index),
+            wasm_u64(test_keys_bytes),
             I32(params.default_msg_bytes),
         ],
     ).expect("create_context should return a context pointer");
+
+    // Tell the reader which shards it can read, as a bitmap copied into its linear memory.
+    if let Some(shards) = &ctx.shards {
+        let mut bits = vec![0u8; shards.len().div_ceil(8)];
+        for shard in (0..shards.len()).filter(|&shard| shards[shard]) {
+            bits[shard / 8] |= 1 << (shard % 8);
+        }
+        let bits_index = wasm_alloc(ctx, bits.len());
+        get_linear_memory(ctx).set(bits_index as u32, &bits).unwrap();
+        let args = [ctx.wasm_context, wasm_ptr(bits_index), I32(shards.len() as i32), I32(SHARD_BYTES as i32)];
+        wasm_call(ctx, "set_shard_map", &args);
+    }
+
+    // The first segment's chains follow the index table in the main mapping.
+    if !segment_files.is_empty() {
+        let mut descs = vec![((wasm_buf_index + index_bytes) as u64, (table_bytes - index_bytes) as u64)];
+        for &(ptr, size) in &ctx.segments {
+            descs.push(((ptr as usize - wasm_memory_base) as u64, size as u64));
+        }
+        let bytes: Vec<u8> =
+            descs.iter().flat_map(|&(ptr, size)| [ptr.to_le_bytes(), size.to_le_bytes()]).flatten().collect();
+        get_linear_memory(ctx).set(descs_index as u32, &bytes).unwrap();
+        let shift = segment_shift(params.segments) as i32;
+        let args = [ctx.wasm_context, wasm_ptr(descs_index), I32(descs.len() as i32), I32(shift)];
+        wasm_call(ctx, "set_segments", &args);
+    }
 }
 
-fn page_align(ptr: usize) -> usize {
-    ((ptr - 1) & !(PAGE_SIZE - 1)) + PAGE_SIZE
+fn page_align(ptr: usize, page_size: usize) -> usize {
+    ((ptr - 1) & !(page_size - 1)) + page_size
 }
 
-fn wasm_alloc(ctx: &Context, size: i32) -> i32 {
-    let wasm_alloc_res = wasm_call(ctx, "malloc_", &[I32(size)])
+fn wasm_alloc(ctx: &Context, size: usize) -> usize {
+    let wasm_alloc_res = wasm_call(ctx, "malloc_", &[wasm_ptr(size)])
         .expect("no value returned from malloc_");
     match wasm_alloc_res {
-        I32(v) => v,
+        I32(v) => v as u32 as usize,
         _ => panic!("invalid value type returned from malloc_"),
     }
 }
 
+// The reader's ABI passes pointers and sizes of its own allocations as usize, which is an i32 for
+// the wasm32 modules wasmi can run. Sizes and offsets in the table are always u64s.
+fn wasm_ptr(value: usize) -> RuntimeValue {
+    I32(u32::try_from(value).expect("value doesn't fit in a wasm32 usize") as i32)
+}
+
+fn wasm_u64(value: usize) -> RuntimeValue {
+    I64(value as i64)
+}
+
 fn wasm_call(ctx: &Context, name: &str, args: &[RuntimeValue]) -> Option<RuntimeValue> {
     let mut externs = Externs {
         memory: get_linear_memory(ctx),
-        lookup: &ctx.lookup,
+        lookup: ctx.lookup,
+        window: ctx.window.as_ref(),
     };
     ctx.instance
         .invoke_export(name, args, &mut externs)
-        .unwrap_or_else(|_| panic!("wasm call '{}' failed", name))
+        .unwrap_or_else(|e| panic!("wasm call '{}' failed: {:?}", name, e))
 }
 
 fn get_linear_memory(ctx: &Context) -> MemoryRef {
@@ -383,10 +1171,12 @@ fn get_linear_memory(ctx: &Context) -> MemoryRef {
 struct Externs<'a> {
     memory: MemoryRef,
     lookup: &'a HashMap<String, String>,
+    window: Option<&'a Window>,
 }
 
 const PRINT_CALLBACK: usize = 0;
 const LOOKUP_CALLBACK: usize = 1;
+const REMAP_CALLBACK: usize = 2;
 
 const SUCCESS: i32 = 0;
 const BUFFER_TOO_SMALL: i32 = 1;
@@ -440,6 +1230,18 @@ impl Externs<'_> {
             }
         }
     }
+
+    fn remap_callback(&self, args: &RuntimeArgs) -> Result<Option<RuntimeValue>, Trap> {
+        // The function signature from the wasm side is:
+        //   (file_offset: u64) -> u64
+        //
+        // Moves the window so it holds the chain at 'file_offset' and returns the window's new
+        // starting offset in the file.
+        let window = self.window.expect("remap_callback called without a lookup window");
+        let file_offset = args.nth::<u64>(0) as usize;
+        window.remaps.set(window.remaps.get() + 1);
+        Ok(Some(wasm_u64(window.map(file_offset))))
+    }
 }
 
 impl Externals for Externs<'_> {
@@ -447,6 +1249,7 @@ impl Externals for Externs<'_> {
         match index {
             PRINT_CALLBACK => self.print_callback(&args),
             LOOKUP_CALLBACK => self.lookup_callback(&args),
+            REMAP_CALLBACK => self.remap_callback(&args),
             _ => panic!("unimplemented function at {}", index),
         }
     }
@@ -459,6 +1262,7 @@ impl ModuleImportResolver for Resolver {
         let index = match field_name {
             "print_callback" => PRINT_CALLBACK,
             "lookup_callback" => LOOKUP_CALLBACK,
+            "remap_callback" => REMAP_CALLBACK,
             _ => panic!("unexpected export {}", field_name),
         };
         Ok(FuncInstance::alloc_host(signature.clone(), index))
@@ -505,3 +1309,75 @@ impl LittleEndianConvert for StringValue {
         panic!("StringValue::from_le_bytes unused")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(lookup_entries: usize, index_slots: usize, test_keys: i32) -> Params {
+        Params { lookup_entries, index_slots, test_keys, quick: true, ..Params::default() }
+    }
+
+    fn decode_test_keys(test_keys: &[u8]) -> Vec<String> {
+        let mut keys = Vec::new();
+        let mut pos = 0;
+        while pos < test_keys.len() {
+            let len = u32::from_le_bytes(test_keys[pos..pos + 4].try_into().unwrap()) as usize;
+            keys.push(str::from_utf8(&test_keys[pos + 4..pos + 4 + len]).unwrap().to_string());
+            pos += 4 + len;
+        }
+        keys
+    }
+
+    #[test]
+    fn rejects_zero_slots() {
+        let err = validate_params(&params(100, 0, 10)).unwrap_err();
+        assert!(err.contains("(-s)"), "{}", err);
+    }
+
+    #[test]
+    fn empty_table_allows_no_test_keys() {
+        let err = validate_params(&params(0, 16, 10)).unwrap_err();
+        assert!(err.contains("(-e)"), "{}", err);
+        assert!(validate_params(&params(0, 16, 0)).is_ok());
+
+        let (lookup, test_keys) = create_lookup(&params(0, 16, 0));
+        assert!(lookup.is_empty());
+        assert!(test_keys.is_empty());
+    }
+
+    #[test]
+    fn rejects_more_test_keys_than_entries() {
+        let err = validate_params(&params(10, 16, 11)).unwrap_err();
+        assert!(err.contains("11 test keys"), "{}", err);
+        assert!(validate_params(&params(10, 16, 10)).is_ok());
+    }
+
+    #[test]
+    fn skips_duplicate_keys() {
+        // Each key after the first is produced twice in a row.
+        let mut n = 0;
+        let (lookup, test_keys) = fill_lookup(&params(5, 16, 5), || {
+            n += 1;
+            (format!("key{}", n / 2), format!("val{}", n))
+        });
+        assert_eq!(lookup.len(), 5);
+        assert_eq!(n, 8);
+        // The first value of a repeated key is the one kept.
+        assert_eq!(lookup["key1"], "val2");
+        assert_eq!(lookup["key4"], "val8");
+
+        let keys = decode_test_keys(&test_keys);
+        assert_eq!(keys, ["key0", "key1", "key2", "key3", "key4"]);
+    }
+
+    #[test]
+    fn random_test_keys_are_distinct_entries() {
+        let (lookup, test_keys) = create_lookup(&params(1000, 256, 100));
+        assert_eq!(lookup.len(), 1000);
+        let keys = decode_test_keys(&test_keys);
+        assert_eq!(keys.len(), 100);
+        assert_eq!(keys.iter().collect::<std::collections::HashSet<_>>().len(), 100);
+        assert!(keys.iter().all(|key| lookup.contains_key(key)));
+    }
+}
@@ -13,25 +13,99 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 //
-use std::{collections::hash_map::DefaultHasher, hash::Hasher, mem, slice};
+// With the "no_std" feature, the reader is built against core + alloc only, using dlmalloc
+// for allocations and a custom panic handler. This gives a noticeably smaller binary.
+#![cfg_attr(feature = "no_std", no_std, no_main)]
+
+extern crate alloc;
+
+#[cfg(feature = "no_std")]
+mod siphash;
+
+#[cfg(feature = "no_std")]
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{cell::Cell, cmp, mem, ptr, slice};
+#[cfg(not(feature = "no_std"))]
+use std::{collections::hash_map::DefaultHasher, hash::Hasher};
+
+#[cfg(feature = "no_std")]
+#[global_allocator]
+static ALLOC: dlmalloc::GlobalDlmalloc = dlmalloc::GlobalDlmalloc;
+
+// Checked by the host before it loads the table; must match the host's ABI_VERSION.
+const ABI_VERSION: u32 = 1;
 
 const SUCCESS: i32 = 0;
 const BUFFER_TOO_SMALL: i32 = 1;
 const NOT_FOUND: i32 = 2;
 
+// Policy kinds for set_lookup_policy; must match the host's Policy.
+const POLICY_INTERNAL: i32 = 0;
+const POLICY_EXTERNAL: i32 = 1;
+const POLICY_MAX_KEY_LEN: i32 = 2;
+const POLICY_PREFIX_BELOW: i32 = 3;
+
+// Table formats for set_table_format; must match the host's Format.
+const FORMAT_CHAINED: i32 = 0;
+const FORMAT_OPEN: i32 = 1;
+const FORMAT_PERFECT: i32 = 2;
+const FORMAT_SORTED: i32 = 3;
+
+// A perfect hash index table starts with the seed, the number of buckets and the number of slots,
+// followed by the buckets' displacements, two u32s per word; must match the host's PerfectHash.
+const PERFECT_HEADER_WORDS: usize = 3;
+
+// A delta region starts with its generation and the number of entry bytes in use; must match the
+// host's LookupWriter.
+const DELTA_HEADER_BYTES: usize = 16;
+
+// The smallest page size the host might map the table with.
+const PREFAULT_STRIDE: usize = 4096;
+
+// Index entries are u64s; must match the host's table format.
+const INDEX_ENTRY_BYTES: usize = mem::size_of::<u64>();
+
 extern "C" {
     fn print_callback(len: u32, msg: *const u8);
     fn lookup_callback(key_len: u32, key: *const u8, value_len: *mut u32, value: *mut u8) -> i32;
+    fn remap_callback(file_offset: u64) -> u64;
 }
 
 fn print_str(s: &str) {
     unsafe { print_callback(s.len() as u32, s.as_ptr()); }
 }
 
+// Formats the panic message into a fixed buffer so no allocation is needed while panicking.
+#[cfg(feature = "no_std")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    let mut msg = PanicMessage { buf: [0; 256], len: 0 };
+    let _ = core::fmt::write(&mut msg, format_args!("reader {}\n", info));
+    print_str(core::str::from_utf8(&msg.buf[..msg.len]).unwrap_or("reader panicked\n"));
+    core::arch::wasm32::unreachable()
+}
+
+#[cfg(feature = "no_std")]
+struct PanicMessage {
+    buf: [u8; 256],
+    len: usize,
+}
+
+#[cfg(feature = "no_std")]
+impl core::fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        // Silently truncates long messages.
+        let n = core::cmp::min(s.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($fmt:expr $(, $value:expr)* ) => {
-        let s = format!($fmt $(, $value)*);
+        let s = alloc::format!($fmt $(, $value)*);
         print_str(&s);
     };
 }
@@ -39,11 +113,16 @@ macro_rules! print {
 #[macro_export]
 macro_rules! println {
     ($fmt:expr $(, $value:expr)* ) => {
-        let s = format!($fmt $(, $value)*) + "\n";
+        let s = alloc::format!($fmt $(, $value)*) + "\n";
         print_str(&s);
     };
 }
 
+#[no_mangle]
+pub extern "C" fn shared_buffers_abi_version() -> u32 {
+    ABI_VERSION
+}
+
 #[no_mangle]
 pub extern "C" fn malloc_(size: usize) -> *const u8 {
     let vec: Vec<u8> = Vec::with_capacity(size);
@@ -52,28 +131,89 @@ pub extern "C" fn malloc_(size: usize) -> *const u8 {
     ptr
 }
 
+// The chains are read through a window onto the table. If the host has mapped the whole table it
+// covers everything, as does a separate mapping of all the chains (which starts at 'window_offset'
+// in the file rather than at the start); otherwise the host moves it on request to reach chains
+// outside it. Offsets in the table file are u64s, since a windowed table needn't fit in linear
+// memory.
 pub struct Context {
-    index: &'static [u32],
-    lookup: *const u8,
-    lookup_bytes: usize,
+    index: &'static [u64],
+    window: *const u8,
+    window_bytes: usize,
+    // Offset in the table file of the start of the window.
+    window_offset: Cell<u64>,
+    table_bytes: u64,
+    max_chain_bytes: usize,
     test_keys: Vec<&'static str>,
     default_msg_bytes: u32,
+    policy: Policy,
+    format: Format,
+    // If the host has only mapped some shards of the table, a bit per shard saying which; empty if
+    // the whole table is mapped.
+    shards: &'static [u8],
+    shard_bytes: usize,
+    // If the chains are split across segments, the location and size of each segment's chains;
+    // index entries then hold the segment above segment_shift and the offset into it below.
+    segments: Vec<(*const u8, usize)>,
+    segment_shift: u32,
+    // If the table has a delta, its offset (as for a chain) and the bytes it has room for.
+    delta: Option<(u64, usize)>,
+}
+
+// How the host laid out the table; see its store_lookup.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Chained,
+    // Each index entry points to a single pair, which may belong to a key from an earlier slot.
+    Open,
+    // Each index entry points to a single pair, and perfect_lookup finds the only one a key can be.
+    Perfect,
+    // Each index entry points to a single pair, in key order, for sorted_lookup to binary search.
+    Sorted,
+}
+
+// Decides which keys performance_test_mixed looks up internally, modelling deployments where only
+// part of the dataset is mapped into the module and the rest is served by the host.
+#[derive(Clone, Copy)]
+enum Policy {
+    Internal,
+    External,
+    // Keys of at most this many bytes are internal.
+    MaxKeyLen(usize),
+    // Keys whose first byte is less than this are internal.
+    PrefixBelow(u8),
+}
+
+impl Policy {
+    fn is_internal(self, key: &str) -> bool {
+        match self {
+            Policy::Internal => true,
+            Policy::External => false,
+            Policy::MaxKeyLen(len) => key.len() <= len,
+            Policy::PrefixBelow(byte) => key.as_bytes().first().is_some_and(|&first| first < byte),
+        }
+    }
 }
 
 #[no_mangle]
+#[allow(clippy::too_many_arguments)]
 pub extern "C" fn create_context(
     buffer: *const u8,
-    index_slots: i32,
-    lookup_bytes: i32,
+    index_slots: u64,
+    window: *const u8,
+    window_bytes: u64,
+    window_offset: u64,
+    table_bytes: u64,
+    max_chain_bytes: u64,
     num_test_keys: i32,
     test_keys_ptr: *const u8,
-    test_keys_bytes: i32,
+    test_keys_bytes: u64,
     default_msg_bytes: i32,
 ) -> *const Context {
     // Collect the keys to be used in the performance tests.
     let mut reader = Reader {
         buffer: test_keys_ptr,
-        size: test_keys_bytes as usize,
+        size: to_usize(test_keys_bytes),
         offset: 0,
     };
     let mut test_keys = Vec::<&str>::new();
@@ -82,36 +222,167 @@ pub extern "C" fn create_context(
     }
 
     // Create and release unownership of the context object.
-    let slots = index_slots as usize;
+    let slots = to_usize(index_slots);
     Box::into_raw(Box::new(unsafe {
         Context {
-            index: slice::from_raw_parts(buffer as *const u32, slots),
-            lookup: buffer.add(slots * 4) as *const u8,
-            lookup_bytes: lookup_bytes as usize,
+            index: slice::from_raw_parts(buffer as *const u64, slots),
+            window,
+            window_bytes: to_usize(window_bytes),
+            window_offset: Cell::new(window_offset),
+            table_bytes,
+            max_chain_bytes: to_usize(max_chain_bytes),
             test_keys,
             default_msg_bytes: default_msg_bytes as u32,
+            policy: Policy::Internal,
+            format: Format::Chained,
+            shards: &[],
+            shard_bytes: 0,
+            segments: Vec::new(),
+            segment_shift: 64,
+            delta: None,
         }
     }))
 }
 
+/// Called by the host when it has mapped the index table but only some shards of the chains. Keys
+/// whose chains may lie outside the mapped shards are then looked up externally.
+///
+/// # Safety
+/// 'bits' must hold a bit per shard and stay valid for as long as the context is used.
+#[no_mangle]
+pub unsafe extern "C" fn set_shard_map(ctx: &mut Context, bits: *const u8, num_shards: i32, shard_bytes: i32) {
+    ctx.shards = unsafe { slice::from_raw_parts(bits, (num_shards as usize).div_ceil(8)) };
+    ctx.shard_bytes = shard_bytes as usize;
+}
+
+/// Called by the host when the chains are split across separately mapped segments.
+///
+/// # Safety
+/// 'descs' must hold a (pointer, size) pair of u64s for each of the 'count' segments. It's only
+/// read during the call, but the segments must stay mapped for as long as the context is used.
+#[no_mangle]
+pub unsafe extern "C" fn set_segments(ctx: &mut Context, descs: *const u64, count: i32, shift: i32) {
+    let descs = unsafe { slice::from_raw_parts(descs, count as usize * 2) };
+    ctx.segments = descs.chunks(2).map(|desc| (to_usize(desc[0]) as *const u8, to_usize(desc[1]))).collect();
+    ctx.segment_shift = shift as u32;
+}
+
+#[no_mangle]
+pub extern "C" fn set_lookup_policy(ctx: &mut Context, kind: i32, arg: i32) {
+    ctx.policy = match kind {
+        POLICY_INTERNAL => Policy::Internal,
+        POLICY_EXTERNAL => Policy::External,
+        POLICY_MAX_KEY_LEN => Policy::MaxKeyLen(arg as usize),
+        POLICY_PREFIX_BELOW => Policy::PrefixBelow(arg as u8),
+        _ => panic!("invalid lookup policy: {}", kind),
+    };
+}
+
+// Called by the host for tables built with a delta, which it may append to at any time.
+#[no_mangle]
+pub extern "C" fn set_delta(ctx: &mut Context, offset: u64, capacity: u64) {
+    ctx.delta = Some((offset, to_usize(capacity)));
+}
+
+// The number of entries the host has appended to the delta so far.
+#[no_mangle]
+pub extern "C" fn delta_generation(ctx: &Context) -> u64 {
+    delta_header(ctx).0
+}
+
+// Checks every key in the delta against the host, which covers the keys it added as well as
+// those it replaced.
+#[no_mangle]
+pub extern "C" fn verify_delta(ctx: &Context) -> i32 {
+    let mut reader = delta_entries(ctx);
+    let mut count = 0;
+    while reader.offset < reader.size {
+        let key = reader.read_str();
+        reader.skip_str();
+        assert_eq!(lookup_int(ctx, key).unwrap(), lookup_ext(ctx, key).unwrap());
+        count += 1;
+    }
+    count
+}
+
+// Called by the host for tables stored in a format other than chained; see the host's store_lookup.
+#[no_mangle]
+pub extern "C" fn set_table_format(ctx: &mut Context, format: i32) {
+    ctx.format = match format {
+        FORMAT_CHAINED => Format::Chained,
+        FORMAT_OPEN => Format::Open,
+        FORMAT_PERFECT => Format::Perfect,
+        FORMAT_SORTED => Format::Sorted,
+        _ => panic!("invalid table format: {}", format),
+    };
+}
+
+// Reads a byte from every page of the index table and the mapped chains, so the lookups that
+// follow don't pay for faulting them in; returns the number of pages touched.
+#[no_mangle]
+pub extern "C" fn prefault(ctx: &Context) -> i32 {
+    let index = ctx.index.as_ptr() as *const u8;
+    let mut regions = Vec::new();
+    // Without a window the index is at the start of the mapped table, so it's covered below.
+    if ctx.window != index {
+        regions.push((index, ctx.index.len() * INDEX_ENTRY_BYTES, 0));
+    }
+    regions.push((ctx.window, ctx.window_bytes, ctx.window_offset.get()));
+    regions.extend(ctx.segments.iter().skip(1).map(|&(buffer, size)| (buffer, size, 0)));
+
+    let mut pages = 0;
+    for (buffer, size, table_offset) in regions {
+        for pos in (0..size).step_by(PREFAULT_STRIDE) {
+            // Pages of the table in shards the host hasn't mapped hold the module's own memory.
+            let shard = ((table_offset + pos as u64) / ctx.shard_bytes.max(1) as u64) as usize;
+            if !ctx.shards.is_empty() && ctx.shards[shard / 8] & (1 << (shard % 8)) == 0 {
+                continue;
+            }
+            unsafe { ptr::read_volatile(buffer.add(pos)) };
+            pages += 1;
+        }
+    }
+    pages
+}
+
 // Check that the internal and external lookup functions match for a few different keys.
 #[no_mangle]
 pub extern "C" fn verify_lookups(ctx: &Context) {
-    for key in ctx.test_keys.iter().take(10) {
-        let value_int = lookup_int(ctx, key).unwrap();
+    verify(ctx, 10);
+}
+
+// As above, but for every test key.
+#[no_mangle]
+pub extern "C" fn verify_all_lookups(ctx: &Context) {
+    verify(ctx, ctx.test_keys.len());
+}
+
+fn verify(ctx: &Context, count: usize) {
+    for key in ctx.test_keys.iter().take(count) {
         let value_ext = lookup_ext(ctx, key).unwrap();
-        assert_eq!(value_int, value_ext);
+        if is_mapped(ctx, key) {
+            assert_eq!(lookup_int(ctx, key).unwrap(), value_ext);
+        }
     }
     let key = "404 not found";
     assert!(lookup_int(ctx, key).is_none());
     assert!(lookup_ext(ctx, key).is_none());
+    assert!(!lookup(ctx, key));
 }
 
+// Keys in shards the host hasn't mapped are looked up externally; returns how many were internal.
 #[no_mangle]
-pub extern "C" fn performance_test_internal(ctx: &Context) {
+pub extern "C" fn performance_test_internal(ctx: &Context) -> i32 {
+    let mut internal = 0;
     for key in &ctx.test_keys {
-        assert!(lookup_int(ctx, key).is_some());
+        if is_mapped(ctx, key) {
+            assert!(lookup_int(ctx, key).is_some());
+            internal += 1;
+        } else {
+            assert!(lookup_ext(ctx, key).is_some());
+        }
     }
+    internal
 }
 
 #[no_mangle]
@@ -121,21 +392,68 @@ pub extern "C" fn performance_test_external(ctx: &Context) {
     }
 }
 
+// Routes each key according to the lookup policy; returns how many were looked up internally.
+#[no_mangle]
+pub extern "C" fn performance_test_mixed(ctx: &Context) -> i32 {
+    for key in &ctx.test_keys {
+        assert!(lookup(ctx, key));
+    }
+    ctx.test_keys.iter().filter(|key| routes_internally(ctx, key)).count() as i32
+}
+
+// Looks up 'key' internally or externally as the policy says; returns whether it was found.
+fn lookup(ctx: &Context, key: &str) -> bool {
+    match routes_internally(ctx, key) {
+        true => lookup_int(ctx, key).is_some(),
+        false => lookup_ext(ctx, key).is_some(),
+    }
+}
+
+// Keys the policy would look up internally still go to the host if their chain isn't mapped.
+fn routes_internally(ctx: &Context, key: &str) -> bool {
+    ctx.policy.is_internal(key) && is_mapped(ctx, key)
+}
+
+// Whether every shard the chain for 'key' might occupy is mapped. Only chained tables are sharded,
+// so the slot is only looked at then (a sorted table with no keys has no slots at all).
+fn is_mapped(ctx: &Context, key: &str) -> bool {
+    if ctx.shards.is_empty() {
+        return true;
+    }
+    let offset = ctx.index[slot(ctx, key)];
+    if offset == 0 {
+        return true;
+    }
+    // The whole table is in linear memory when mapped in shards.
+    let pos = ctx.index.len() * INDEX_ENTRY_BYTES + to_usize(offset);
+    let end = cmp::min(pos + ctx.max_chain_bytes, to_usize(ctx.table_bytes));
+    (pos / ctx.shard_bytes..=(end - 1) / ctx.shard_bytes).all(|shard| ctx.shards[shard / 8] & (1 << (shard % 8)) != 0)
+}
+
+// The key's position in the index table. The hash is reduced as a u64, as the host does; cutting
+// it down to a wasm32 usize first would pick a different slot unless the slot count is a power of
+// two.
+fn slot(ctx: &Context, key: &str) -> usize {
+    (hash_key(key) % ctx.index.len() as u64) as usize
+}
+
 // Uses the "internal" mapped buffer to find the value associated with 'key'.
 fn lookup_int(ctx: &Context, key: &str) -> Option<&'static str> {
-    // Find the key's position in the index table..
-    let mut hasher = DefaultHasher::new();
-    hasher.write(key.as_bytes());
-    let i = (hasher.finish() as usize) % ctx.index.len();
-
-    // ..to get the offest into the packed data following the table.
-    let offset = ctx.index[i] as usize;
+    if ctx.delta.is_some() {
+        if let Some(value) = delta_lookup(ctx, key) {
+            return Some(value);
+        }
+    }
+    match ctx.format {
+        Format::Chained => {}
+        Format::Open => return probe(ctx, key),
+        Format::Perfect => return perfect_lookup(ctx, key),
+        Format::Sorted => return sorted_lookup(ctx, key),
+    }
+    // Find the key's offset into the packed data following the index table.
+    let offset = ctx.index[slot(ctx, key)];
     if offset > 0 {
-        let mut reader = Reader {
-            buffer: ctx.lookup,
-            size: ctx.lookup_bytes,
-            offset,
-        };
+        let mut reader = chain_reader(ctx, offset);
 
         // The entry starts with the number of key/value pairs for this chain.
         let n_items = reader.read_u32();
@@ -152,6 +470,149 @@ fn lookup_int(ctx: &Context, key: &str) -> Option<&'static str> {
     None
 }
 
+// With open addressing, checks the pair in each slot from the key's own until it finds the key or
+// reaches an empty slot; the host always leaves one.
+fn probe(ctx: &Context, key: &str) -> Option<&'static str> {
+    let mut slot = slot(ctx, key);
+    loop {
+        let offset = ctx.index[slot];
+        if offset == 0 {
+            return None;
+        }
+        let mut reader = chain_reader(ctx, offset);
+        if reader.check_key(key) {
+            return Some(reader.read_str());
+        }
+        slot = (slot + 1) % ctx.index.len();
+    }
+}
+
+// With a perfect hash, the key can only be in the one pair its slot points to.
+fn perfect_lookup(ctx: &Context, key: &str) -> Option<&'static str> {
+    let (seed, buckets, slots) = (ctx.index[0], ctx.index[1], ctx.index[2]);
+    if slots == 0 {
+        return None;
+    }
+    let hash = hash_key(key);
+    let bucket = to_usize(perfect_bucket(hash, seed, buckets));
+    let displacement = (ctx.index[PERFECT_HEADER_WORDS + bucket / 2] >> (bucket % 2 * 32)) as u32;
+    let slot = to_usize(perfect_slot(hash, seed, displacement, slots));
+    let offset = ctx.index[PERFECT_HEADER_WORDS + to_usize(buckets).div_ceil(2) + slot];
+    let mut reader = chain_reader(ctx, offset);
+    match reader.check_key(key) {
+        true => Some(reader.read_str()),
+        false => None,
+    }
+}
+
+// With sorted keys, halves the range of slots the key could be in until it finds it or the range
+// is empty. Keys compare as bytes, as the host sorted them.
+fn sorted_lookup(ctx: &Context, key: &str) -> Option<&'static str> {
+    let (mut low, mut high) = (0, ctx.index.len());
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let mut reader = chain_reader(ctx, ctx.index[mid]);
+        match reader.read_str().cmp(key) {
+            cmp::Ordering::Less => low = mid + 1,
+            cmp::Ordering::Greater => high = mid,
+            cmp::Ordering::Equal => return Some(reader.read_str()),
+        }
+    }
+    None
+}
+
+// Finds the latest value for 'key' in the delta, if the host has put one there.
+fn delta_lookup(ctx: &Context, key: &str) -> Option<&'static str> {
+    let mut reader = delta_entries(ctx);
+    let mut value = None;
+    while reader.offset < reader.size {
+        match reader.check_key(key) {
+            true => value = Some(reader.read_str()),
+            false => reader.skip_str(),
+        }
+    }
+    value
+}
+
+// The delta's generation and bytes in use. The host publishes each entry by updating these once
+// it has written it, so they're read afresh every time.
+fn delta_header(ctx: &Context) -> (u64, usize) {
+    let (offset, capacity) = ctx.delta.expect("the table has no delta");
+    let reader = chain_reader(ctx, offset);
+    let header = unsafe { reader.buffer.add(reader.offset) as *const u64 };
+    let (generation, used) = unsafe { (ptr::read_volatile(header), ptr::read_volatile(header.add(1))) };
+    assert!(to_usize(used) <= capacity);
+    (generation, to_usize(used))
+}
+
+// A reader over just the delta's entries in use, which ends where they do.
+fn delta_entries(ctx: &Context) -> Reader {
+    let used = delta_header(ctx).1;
+    let reader = chain_reader(ctx, ctx.delta.unwrap().0);
+    let start = reader.offset + DELTA_HEADER_BYTES;
+    assert!(start + used <= reader.size);
+    Reader { buffer: reader.buffer, size: start + used, offset: start }
+}
+
+// Must match the host's perfect hash functions.
+fn mix(value: u64) -> u64 {
+    let value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+fn perfect_bucket(hash: u64, seed: u64, buckets: u64) -> u64 {
+    mix(hash ^ seed) % buckets
+}
+
+fn perfect_slot(hash: u64, seed: u64, displacement: u32, slots: u64) -> u64 {
+    mix(hash ^ mix(seed.wrapping_add(displacement as u64 + 1))) % slots
+}
+
+// Returns a reader positioned at the chain with the given offset (from the end of the index table),
+// first asking the host to move the window if the chain might not lie entirely within it. With
+// segments, the offset is an index entry naming the segment and the offset within it instead.
+fn chain_reader(ctx: &Context, offset: u64) -> Reader {
+    if !ctx.segments.is_empty() {
+        let (buffer, size) = ctx.segments[(offset >> ctx.segment_shift) as usize];
+        return Reader {
+            buffer,
+            size,
+            offset: to_usize(offset & ((1 << ctx.segment_shift) - 1)),
+        };
+    }
+    let pos = (ctx.index.len() * INDEX_ENTRY_BYTES) as u64 + offset;
+    let end = cmp::min(pos + ctx.max_chain_bytes as u64, ctx.table_bytes);
+    let start = ctx.window_offset.get();
+    if pos < start || end > start + ctx.window_bytes as u64 {
+        ctx.window_offset.set(unsafe { remap_callback(pos) });
+    }
+    Reader {
+        buffer: ctx.window,
+        size: ctx.window_bytes,
+        offset: to_usize(pos - ctx.window_offset.get()),
+    }
+}
+
+// Sizes and offsets from the host are u64s; anything read through the module's own pointers has
+// to fit in them.
+fn to_usize(value: u64) -> usize {
+    usize::try_from(value).expect("table offset out of range for this module")
+}
+
+// Must match the host's hashing in store_lookup.
+#[cfg(not(feature = "no_std"))]
+fn hash_key(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key.as_bytes());
+    hasher.finish()
+}
+
+#[cfg(feature = "no_std")]
+fn hash_key(key: &str) -> u64 {
+    siphash::hash(key.as_bytes())
+}
+
 // Calls out to the wasm host to find the value associated with 'key'.
 fn lookup_ext(ctx: &Context, key: &str) -> Option<String> {
     // We start with a small size for the 'value' parameter. The host will store the result size
@@ -203,7 +664,7 @@ impl Reader {
         let res = unsafe {
             let ptr = self.buffer.add(self.offset);
             let slc = slice::from_raw_parts(ptr, len);
-            std::str::from_utf8_unchecked(slc)
+            core::str::from_utf8_unchecked(slc)
         };
         self.offset += len;
         res
@@ -220,7 +681,7 @@ impl Reader {
         let res = unsafe {
             let ptr = self.buffer.add(self.offset);
             let slc = slice::from_raw_parts(ptr, len);
-            std::str::from_utf8_unchecked(slc)
+            core::str::from_utf8_unchecked(slc)
         };
         self.offset += len;
         res == key
@@ -233,6 +694,7 @@ impl Reader {
     }
 }
 
+#[cfg(not(feature = "no_std"))]
 fn main() {
     println!("reader: Not meant to be run as a main");
 }
@@ -0,0 +1,79 @@
+//
+// Copyright 2022 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// SipHash-1-3 with zero keys, matching the output of std's DefaultHasher::new() for a single
+// write() call. The host builds the index table with DefaultHasher, which isn't available
+// without std, so the no_std reader uses this instead.
+
+pub fn hash(bytes: &[u8]) -> u64 {
+    let mut state = State {
+        v0: 0x736f6d6570736575,
+        v1: 0x646f72616e646f6d,
+        v2: 0x6c7967656e657261,
+        v3: 0x7465646279746573,
+    };
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut word = [0; 8];
+        word.copy_from_slice(chunk);
+        state.compress(u64::from_le_bytes(word));
+    }
+
+    // The final block holds the remaining bytes with the input length in the top byte.
+    let mut last = (bytes.len() as u64 & 0xff) << 56;
+    for (i, b) in chunks.remainder().iter().enumerate() {
+        last |= (*b as u64) << (8 * i);
+    }
+    state.compress(last);
+
+    state.v2 ^= 0xff;
+    for _ in 0..3 {
+        state.round();
+    }
+    state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+}
+
+struct State {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+}
+
+impl State {
+    fn compress(&mut self, m: u64) {
+        self.v3 ^= m;
+        self.round();
+        self.v0 ^= m;
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13);
+        self.v1 ^= self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16);
+        self.v3 ^= self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21);
+        self.v3 ^= self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17);
+        self.v1 ^= self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+}
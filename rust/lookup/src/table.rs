@@ -0,0 +1,23 @@
+//
+// Copyright 2022 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// The host side of the lookup table, for the benchmark and anything else that builds tables for
+// the reader. The reader itself is built from this crate too, so without the "lookup" feature
+// this is empty (and no_std like the reader).
+#![cfg_attr(feature = "no_std", no_std)]
+
+#[cfg(feature = "lookup")]
+pub mod writer;
@@ -0,0 +1,605 @@
+//
+// Copyright 2022 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Builds lookup tables in the layout the reader expects, straight into the shm objects they're
+// mapped from. The lookup benchmark is one user; anything else that wants to hand the reader a
+// table (another host, a test) can build one with a LookupWriter.
+
+use std::{
+    cmp, collections::hash_map::DefaultHasher, ffi::CString, fs::File, hash::Hasher, ops::{Deref, DerefMut},
+    os::unix::io::AsRawFd, ptr, slice, sync::atomic::{AtomicU64, Ordering},
+};
+
+// Index entries are u64s, so a reader with 64-bit pointers could address a table over 4GB.
+pub const INDEX_ENTRY_BYTES: usize = 8;
+// The perfect hash puts about this many keys in each bucket, and gives up on a seed when a bucket
+// can't be placed in this many displacements.
+const PERFECT_BUCKET_KEYS: usize = 4;
+const MAX_DISPLACEMENT: u32 = 1 << 24;
+// The words at the start of a perfect hash index table: the seed, the number of buckets and the
+// number of slots; the buckets' displacements follow, two u32s per word.
+const PERFECT_HEADER_WORDS: usize = 3;
+// A delta region starts with its generation and the number of record bytes in use, as u64s.
+const DELTA_HEADER_BYTES: usize = 16;
+
+// How a table is laid out; must match the reader's Format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // Each used slot points to a chain of all the keys that hash to it.
+    Chained,
+    // Each used slot points to a single key, which may have hashed to an earlier slot.
+    Open,
+    // Each slot points to a single key, the only one that hashes to it.
+    Perfect,
+    // Each slot points to a single key, in key order.
+    Sorted,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Chained => "chained",
+            Format::Open => "open addressing",
+            Format::Perfect => "perfect hash",
+            Format::Sorted => "sorted keys",
+        }
+    }
+
+    // The argument passed to the reader's set_table_format.
+    pub fn arg(self) -> i32 {
+        match self {
+            Format::Chained => 0,
+            Format::Open => 1,
+            Format::Perfect => 2,
+            Format::Sorted => 3,
+        }
+    }
+
+    // The number of index slots a table of this many entries gets when asked for this many.
+    // Open addressing needs a slot per key and an empty one to end each search, and probes stay
+    // short while the table is at most half full, so it gets at least twice as many slots as keys.
+    // A perfect hash has a slot per key, after its header, and sorted keys just a slot per key; the
+    // slots asked for don't apply to either.
+    pub fn index_slots(self, slots: usize, entries: usize) -> usize {
+        match self {
+            Format::Chained => slots,
+            Format::Open => cmp::max(slots, 2 * entries),
+            Format::Perfect => PerfectHash::header_words(perfect_buckets(entries)) + entries,
+            Format::Sorted => entries,
+        }
+    }
+}
+
+// Collects the key/value pairs for a table, then lays them out in one go when finalized:
+//
+//  | index table | bumper | packed chains |
+//
+// index table: list of u64 offsets into packed data (starting from end of the index table)
+// bumper: a single unused byte so offsets of 0 can indicate an empty slot in the index table
+// packed chains: a sequence of chains per used index slot; each chain has the format:
+//
+//  | n_pairs:u32 | key_len:u32 | key | value_len:u32 | value | key_len | ... |
+//
+// Keys are stored in ascending size order to enable a slightly faster lookup on the wasm side.
+//
+// With open addressing (Format::Open) the layout is the same, but each slot's "chain" is a single
+// pair with no n_pairs: | key_len:u32 | key | value_len:u32 | value |. A key whose slot is taken
+// goes in the next free one, wrapping around at the end, so the reader checks the pairs from the
+// key's slot on until it finds the key or an empty slot. There are always more slots than keys
+// (see Format::index_slots), so the search ends.
+//
+// With a perfect hash (Format::Perfect) the pairs are stored the same way, one per slot, but
+// there are exactly as many slots as keys and each key has a slot of its own, so a lookup reads a
+// single pair. The slots come after a header in the index table: see PERFECT_HEADER_WORDS and
+// PerfectHash.
+//
+// With sorted keys (Format::Sorted) there is again one pair per slot and a slot per key, but the
+// slots are in ascending key order (comparing bytes, as str does), so the reader can binary search
+// the index rather than hash the key, and a run of slots holds a range or prefix of the keys.
+//
+// With more than one segment, each range of index slots has its chains in its own segment. The
+// first segment follows the index table as above; the others are in files of their own, as
+// | bumper | packed chains |. Index entries then hold the segment in their top bits (see
+// segment_shift) and the offset from the start of that segment's chains below them. Only chained
+// tables can be split.
+//
+// A table built with_delta ends with a delta region, after the first segment's chains (aligned to
+// 8 bytes, and not referenced by the index):
+//
+//  | generation:u64 | used:u64 | key_len:u32 | key | value_len:u32 | value | key_len | ... |
+//
+// It starts empty. While readers have the table mapped, a DeltaWriter appends entries to it, each
+// adding a key or replacing the value of an existing one; readers check the delta (the last entry
+// for a key wins) before the rest of the table. 'used' counts the bytes of the entries so far and
+// 'generation' the entries appended, both updated only once an entry is in place.
+pub struct LookupWriter {
+    name: String,
+    format: Format,
+    slots: usize,
+    segments: usize,
+    delta_bytes: usize,
+    pairs: Vec<KeyValue>,
+}
+
+// What finalize built, for reporting and for sizing what the table is mapped with.
+pub struct Stats {
+    pub index_slots: usize,
+    // The size of each segment's file.
+    pub file_bytes: Vec<u64>,
+    // The used slots, and the pairs in them.
+    pub chains: usize,
+    pub pairs: usize,
+    pub max_chain: usize,
+    // A window onto the chains must be able to hold the largest.
+    pub max_chain_bytes: usize,
+    // With open addressing, the slots each key's search visits.
+    pub sum_probe: usize,
+    pub max_probe: usize,
+    // With a perfect hash, its seed, buckets and longest displacement.
+    pub perfect: Option<(u64, usize, u32)>,
+    // With a delta, where its header is in the first file and how many bytes of entries it holds.
+    pub delta: Option<(u64, usize)>,
+}
+
+impl LookupWriter {
+    // The table's files are named after 'name' (a shm object name, starting with '/'), and get
+    // format.index_slots(slots, entries) slots.
+    pub fn new(name: &str, format: Format, slots: usize, segments: usize) -> Self {
+        assert!(segments >= 1 && (segments == 1 || format == Format::Chained), "only chained tables have segments");
+        Self { name: name.to_string(), format, slots, segments, delta_bytes: 0, pairs: Vec::new() }
+    }
+
+    // Leaves room for this many bytes of delta entries (see delta_entry_bytes) after the table.
+    pub fn with_delta(mut self, bytes: usize) -> Self {
+        self.delta_bytes = bytes;
+        self
+    }
+
+    // Keys must be distinct.
+    pub fn add(&mut self, key: &str, value: &str) {
+        self.pairs.push(KeyValue(key.to_string(), value.to_string()));
+    }
+
+    // Lays the table out and writes it into newly created files, one per segment, which are
+    // removed when dropped.
+    pub fn finalize(mut self) -> (Vec<TableFile>, Stats) {
+        // Placed in key order, so the same entries always produce the same table.
+        self.pairs.sort();
+        if let Some(pair) = self.pairs.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            panic!("duplicate lookup key: {}", pair[0].0);
+        }
+        let index_slots = self.format.index_slots(self.slots, self.pairs.len());
+        let mut stats = Stats {
+            index_slots,
+            file_bytes: Vec::new(),
+            chains: 0,
+            pairs: 0,
+            max_chain: 0,
+            max_chain_bytes: 0,
+            sum_probe: 0,
+            max_probe: 0,
+            perfect: None,
+            delta: None,
+        };
+
+        // Convert the pairs to a table with vectors of key/value pairs.
+        let mut table = vec![Vec::<KeyValue>::new(); index_slots];
+        let mut index = vec![0u64; index_slots];
+        match self.format {
+            Format::Chained => {
+                for pair in self.pairs {
+                    let i = (hash_key(pair.0.as_bytes()) as usize) % index_slots;
+                    table[i].push(pair);
+                }
+            }
+            Format::Open => {
+                for pair in self.pairs {
+                    let mut i = (hash_key(pair.0.as_bytes()) as usize) % index_slots;
+                    let mut probe = 1;
+                    while !table[i].is_empty() {
+                        i = (i + 1) % index_slots;
+                        probe += 1;
+                    }
+                    table[i].push(pair);
+                    stats.sum_probe += probe;
+                    stats.max_probe = cmp::max(probe, stats.max_probe);
+                }
+            }
+            Format::Perfect => {
+                let hashes: Vec<u64> = self.pairs.iter().map(|pair| hash_key(pair.0.as_bytes())).collect();
+                let hash = PerfectHash::build(&hashes);
+                let header = hash.header();
+                for (pair, &slot) in self.pairs.into_iter().zip(&hash.slots) {
+                    table[header.len() + slot].push(pair);
+                }
+                let longest = hash.displacements.iter().max().copied().unwrap_or(0);
+                stats.perfect = Some((hash.seed, hash.displacements.len(), longest));
+                index[..header.len()].copy_from_slice(&header);
+            }
+            Format::Sorted => {
+                for (i, pair) in self.pairs.into_iter().enumerate() {
+                    table[i].push(pair);
+                }
+            }
+        }
+
+        // Work out where each chain goes, tracking offsets (from the start of the packed region,
+        // not the file) in the index table. Offsets start at 1, after the bumper byte.
+        let shift = segment_shift(self.segments);
+        let mut offsets = vec![1u64; self.segments];
+        for (i, list) in table.iter_mut().enumerate().filter(|(_, list)| !list.is_empty()) {
+            let segment = i * self.segments / index_slots;
+            let chain_start = offsets[segment];
+            list.sort();
+            index[i] = match self.segments {
+                1 => chain_start,
+                _ if chain_start < 1 << shift => (segment as u64) << shift | chain_start,
+                _ => panic!("lookup segment {} is too large; use more segments (-g)", segment),
+            };
+            let chain_bytes = chain_len(self.format, list);
+            offsets[segment] += chain_bytes as u64;
+            stats.chains += 1;
+            stats.pairs += list.len();
+            stats.max_chain = cmp::max(list.len(), stats.max_chain);
+            stats.max_chain_bytes = cmp::max(chain_bytes, stats.max_chain_bytes);
+        }
+
+        // Size each file, map it and pack the chains in, in the same order. The files start out
+        // zeroed, which leaves the bumper bytes as they should be.
+        let index_bytes = index_slots * INDEX_ENTRY_BYTES;
+        let mut files = Vec::new();
+        let mut maps = Vec::new();
+        let mut cursors = Vec::new();
+        for (segment, &offset) in offsets.iter().enumerate() {
+            let base = if segment == 0 { index_bytes } else { 0 };
+            let file = create_table_file(&table_file_name(&self.name, segment));
+            let mut len = base + offset as usize;
+            if segment == 0 && self.delta_bytes > 0 {
+                let start = len.next_multiple_of(8);
+                stats.delta = Some((start as u64, self.delta_bytes));
+                len = start + DELTA_HEADER_BYTES + self.delta_bytes;
+            }
+            file.set_len(len as u64).unwrap();
+            stats.file_bytes.push(len as u64);
+            maps.push(MappedTable::new(&file, len));
+            files.push(file);
+            cursors.push(base + 1);
+        }
+        let index_map = maps[0].bytes();
+        for (i, &entry) in index.iter().enumerate() {
+            index_map[i * INDEX_ENTRY_BYTES..(i + 1) * INDEX_ENTRY_BYTES].copy_from_slice(&entry.to_le_bytes());
+        }
+        for (i, list) in table.iter().enumerate().filter(|(_, list)| !list.is_empty()) {
+            let segment = i * self.segments / index_slots;
+            let bytes = maps[segment].bytes();
+            let pos = &mut cursors[segment];
+            if self.format == Format::Chained {
+                put(bytes, pos, &(list.len() as u32).to_le_bytes());
+            }
+            for KeyValue(key, val) in list {
+                put(bytes, pos, &(key.len() as u32).to_le_bytes());
+                put(bytes, pos, key.as_bytes());
+                put(bytes, pos, &(val.len() as u32).to_le_bytes());
+                put(bytes, pos, val.as_bytes());
+            }
+        }
+        (files, stats)
+    }
+}
+
+// The delta bytes an entry takes up.
+pub fn delta_entry_bytes(key: &str, value: &str) -> usize {
+    8 + key.len() + value.len()
+}
+
+// Appends entries to the delta of a table that readers may already have mapped. Entries are
+// written past the end of those in use and only then published, so a reader never sees half of
+// one; there should be only one DeltaWriter for a table at a time.
+pub struct DeltaWriter {
+    map: MappedTable,
+    start: usize,
+    capacity: usize,
+}
+
+impl DeltaWriter {
+    // Maps the table's first file, which finalize described in 'stats'.
+    pub fn open(file: &File, stats: &Stats) -> Self {
+        let (start, capacity) = stats.delta.expect("the table was built without a delta");
+        let map = MappedTable::new(file, stats.file_bytes[0] as usize);
+        Self { map, start: start as usize, capacity }
+    }
+
+    // Returns false, leaving the delta as it was, if there isn't room for the entry; the table then
+    // has to be rebuilt to take more updates.
+    pub fn append(&mut self, key: &str, value: &str) -> bool {
+        let used = self.word(1).load(Ordering::Acquire) as usize;
+        if used + delta_entry_bytes(key, value) > self.capacity {
+            return false;
+        }
+        let mut pos = self.start + DELTA_HEADER_BYTES + used;
+        let bytes = self.map.bytes();
+        put(bytes, &mut pos, &(key.len() as u32).to_le_bytes());
+        put(bytes, &mut pos, key.as_bytes());
+        put(bytes, &mut pos, &(value.len() as u32).to_le_bytes());
+        put(bytes, &mut pos, value.as_bytes());
+        self.word(1).store((pos - self.start - DELTA_HEADER_BYTES) as u64, Ordering::Release);
+        self.word(0).fetch_add(1, Ordering::Release);
+        true
+    }
+
+    // The number of entries appended so far.
+    pub fn generation(&self) -> u64 {
+        self.word(0).load(Ordering::Acquire)
+    }
+
+    // The header words are 8-byte aligned in a page-aligned mapping.
+    fn word(&self, index: usize) -> &AtomicU64 {
+        unsafe { &*(self.map.ptr.add(self.start + index * 8) as *const AtomicU64) }
+    }
+}
+
+// The bytes a slot's pairs take up in the packed chains.
+fn chain_len(format: Format, list: &[KeyValue]) -> usize {
+    let count = if format == Format::Chained { 4 } else { 0 };
+    count + list.iter().map(|KeyValue(key, val)| 8 + key.len() + val.len()).sum::<usize>()
+}
+
+fn put(bytes: &mut [u8], pos: &mut usize, value: &[u8]) {
+    bytes[*pos..*pos + value.len()].copy_from_slice(value);
+    *pos += value.len();
+}
+
+// A table file mapped for writing while it's built; unmapped when dropped, which leaves the
+// contents in the file.
+struct MappedTable {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl MappedTable {
+    fn new(file: &File, len: usize) -> Self {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let ptr = unsafe { libc::mmap(ptr::null_mut(), len, prot, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            panic!("failed to map the lookup table for writing: {}", std::io::Error::last_os_error());
+        }
+        Self { ptr: ptr as *mut u8, len }
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for MappedTable {
+    fn drop(&mut self) {
+        if unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) } == -1 {
+            println!("munmap failed for a lookup table being written");
+        }
+    }
+}
+
+// The bit position of the segment in each index entry. With a single segment the whole entry is
+// the offset.
+pub fn segment_shift(segments: usize) -> u32 {
+    64 - (segments - 1).checked_ilog2().map_or(0, |log| log + 1)
+}
+
+// Must match the reader's hashing.
+pub fn hash_key(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    hasher.finish()
+}
+
+// A lookup table's shm object (or file, on macOS), removed when this is dropped. Only the process
+// that built the table ever creates it, so there's exactly one place it is removed.
+pub struct TableFile {
+    file: File,
+    name: String,
+}
+
+impl Deref for TableFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl DerefMut for TableFile {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+impl Drop for TableFile {
+    fn drop(&mut self) {
+        remove_table_file(&self.name);
+    }
+}
+
+// The first segment's file holds the index table too.
+fn table_file_name(name: &str, segment: usize) -> String {
+    match segment {
+        0 => name.to_string(),
+        _ => format!("{}_{}", name, segment),
+    }
+}
+
+// Create the shared memory file.
+#[cfg(not(target_os = "macos"))]
+fn create_table_file(name: &str) -> TableFile {
+    use libc::{O_CREAT, O_RDWR, O_TRUNC, S_IRUSR, S_IWUSR};
+    use std::os::unix::io::FromRawFd;
+    let cname = CString::new(name).unwrap();
+    let fd = unsafe {
+        libc::shm_open(cname.as_ptr(), O_CREAT | O_TRUNC | O_RDWR, S_IRUSR | S_IWUSR)
+    };
+    if fd == -1 {
+        panic!("shm_open failed");
+    }
+    TableFile { file: unsafe { File::from_raw_fd(fd) }, name: name.to_string() }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn remove_table_file(name: &str) {
+    let cname = CString::new(name).unwrap();
+    if unsafe { libc::shm_unlink(cname.as_ptr()) } == -1 {
+        println!("shm_unlink failed for {}", name);
+    }
+}
+
+// macOS shm objects don't support O_TRUNC, and can only be sized once, so the table is stored in
+// a temporary file instead. It is only mapped by this process, so the result is the same.
+#[cfg(target_os = "macos")]
+fn table_file_path(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(name.trim_start_matches('/'))
+}
+
+#[cfg(target_os = "macos")]
+fn create_table_file(name: &str) -> TableFile {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(table_file_path(name))
+        .expect("failed to create lookup table file");
+    TableFile { file, name: name.to_string() }
+}
+
+#[cfg(target_os = "macos")]
+fn remove_table_file(name: &str) {
+    if std::fs::remove_file(table_file_path(name)).is_err() {
+        println!("failed to remove {}", table_file_path(name).display());
+    }
+}
+
+#[derive(Debug, Clone)]
+struct KeyValue(String, String);
+
+impl Ord for KeyValue {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        if self.0 < other.0 {
+            cmp::Ordering::Less
+        } else if self.0 > other.0 {
+            cmp::Ordering::Greater
+        } else {
+            self.1.cmp(&other.1)
+        }
+    }
+}
+
+impl PartialOrd for KeyValue {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Eq for KeyValue {}
+
+impl PartialEq for KeyValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0 && self.1 == other.1
+    }
+}
+
+fn perfect_buckets(keys: usize) -> usize {
+    cmp::max(keys.div_ceil(PERFECT_BUCKET_KEYS), 1)
+}
+
+// A minimal perfect hash of the keys' hashes, built CHD-style ("compress, hash and displace"):
+// perfect_bucket splits the keys into buckets, and then, largest bucket first, each bucket is
+// given the first displacement under which perfect_slot sends all of its keys to free slots. The
+// reader only needs the seed and the displacements to find a key's slot. Should a bucket find no
+// free slots, the build starts again with the next seed.
+struct PerfectHash {
+    seed: u64,
+    displacements: Vec<u32>,
+    // The slot of each key, in the order they were given.
+    slots: Vec<usize>,
+}
+
+impl PerfectHash {
+    fn build(hashes: &[u64]) -> Self {
+        let buckets = perfect_buckets(hashes.len());
+        (0..)
+            .find_map(|seed| Self::try_seed(hashes, buckets, seed))
+            .unwrap()
+    }
+
+    fn try_seed(hashes: &[u64], buckets: usize, seed: u64) -> Option<Self> {
+        let slots = hashes.len() as u64;
+        let mut members = vec![Vec::new(); buckets];
+        for (key, &hash) in hashes.iter().enumerate() {
+            members[perfect_bucket(hash, seed, buckets as u64) as usize].push(key);
+        }
+        let mut order: Vec<usize> = (0..buckets).collect();
+        order.sort_by_key(|&bucket| cmp::Reverse(members[bucket].len()));
+
+        let mut hash = Self { seed, displacements: vec![0; buckets], slots: vec![0; hashes.len()] };
+        let mut taken = vec![false; hashes.len()];
+        let mut chosen = Vec::new();
+        for bucket in order.into_iter().take_while(|&bucket| !members[bucket].is_empty()) {
+            let fits = |displacement: u32, chosen: &mut Vec<usize>| {
+                chosen.clear();
+                members[bucket].iter().all(|&key| {
+                    let slot = perfect_slot(hashes[key], seed, displacement, slots) as usize;
+                    let free = !taken[slot] && !chosen.contains(&slot);
+                    chosen.push(slot);
+                    free
+                })
+            };
+            let displacement = (0..MAX_DISPLACEMENT).find(|&displacement| fits(displacement, &mut chosen))?;
+            for (&key, &slot) in members[bucket].iter().zip(&chosen) {
+                taken[slot] = true;
+                hash.slots[key] = slot;
+            }
+            hash.displacements[bucket] = displacement;
+        }
+        Some(hash)
+    }
+
+    fn header_words(buckets: usize) -> usize {
+        PERFECT_HEADER_WORDS + buckets.div_ceil(2)
+    }
+
+    // The start of the index table, as the reader's perfect_lookup expects it.
+    fn header(&self) -> Vec<u64> {
+        let mut words = vec![self.seed, self.displacements.len() as u64, self.slots.len() as u64];
+        words.extend(self.displacements.chunks(2).map(|pair| {
+            pair[0] as u64 | pair.get(1).map_or(0, |&high| (high as u64) << 32)
+        }));
+        words
+    }
+}
+
+// Must match the reader's perfect_bucket and perfect_slot. The split of each key's hash into a
+// bucket and a slot is remixed for each seed and displacement with the finalizer of SplitMix64.
+fn mix(value: u64) -> u64 {
+    let value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    let value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
+
+fn perfect_bucket(hash: u64, seed: u64, buckets: u64) -> u64 {
+    mix(hash ^ seed) % buckets
+}
+
+fn perfect_slot(hash: u64, seed: u64, displacement: u32, slots: u64) -> u64 {
+    mix(hash ^ mix(seed.wrapping_add(displacement as u64 + 1))) % slots
+}
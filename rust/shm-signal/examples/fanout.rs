@@ -0,0 +1,121 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Drives many workers from one CommandRing: creates a shm object holding the ring and a total
+// per worker, starts the workers (copies of this binary) and sends them numbers to add up:
+//   cargo build --examples && target/debug/examples/fanout [workers] [rounds]
+//
+// Each round sends one number to every worker and another to just one of them, keeping the
+// ring full, then checks the totals once every worker has completed the final EXIT.
+
+use shm_signal::{CommandRing, MAX_CONSUMERS, MESSAGE_WORDS};
+use std::{env, ffi::CString, mem, process::Command, ptr, time::Duration, time::Instant};
+
+const ADD: u32 = 1;
+const EXIT: u32 = 2;
+const SLOTS: usize = 16;
+const RING_BYTES: usize = mem::size_of::<CommandRing<SLOTS>>();
+const SIZE: usize = RING_BYTES + MAX_CONSUMERS * 8;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() {
+    if env::args().nth(1).as_deref() == Some("worker") {
+        let name = env::args().nth(2).expect("usage: fanout worker <shm name> <index>");
+        let index = env::args().nth(3).and_then(|arg| arg.parse().ok()).expect("missing or bad worker index");
+        return worker(&name, index);
+    }
+    let workers: usize = env::args().nth(1).map_or(24, |arg| arg.parse().expect("workers must be a number"));
+    let rounds: u64 = env::args().nth(2).map_or(1_000, |arg| arg.parse().expect("rounds must be a number"));
+    assert!((1..=MAX_CONSUMERS).contains(&workers), "between 1 and {} workers", MAX_CONSUMERS);
+    let name = format!("/shm_signal_{}", std::process::id());
+    let c_name = CString::new(name.clone()).unwrap();
+    let buf = map(&c_name, libc::O_RDWR | libc::O_CREAT | libc::O_EXCL);
+    let ring = unsafe { CommandRing::<SLOTS>::from_ptr(buf) };
+
+    let exe = env::current_exe().unwrap();
+    let mut children: Vec<_> = (0..workers)
+        .map(|index| Command::new(&exe).arg("worker").arg(&name).arg(index.to_string()).spawn().unwrap())
+        .collect();
+
+    let all = (u64::MAX >> (64 - workers)) as u32;
+    let send = |targets, signal, amount: u64| {
+        let mut message = [0; MESSAGE_WORDS];
+        message[0] = signal;
+        message[1] = amount as u32;
+        ring.push(targets, &message, TIMEOUT).expect("the ring stayed full")
+    };
+    let start = Instant::now();
+    for round in 0..rounds {
+        send(all, ADD, round);
+        send(1 << (round % workers as u64), ADD, 1);
+    }
+    let last = send(all, EXIT, 0);
+    assert!(ring.wait_done(last, TIMEOUT), "workers {:#x} didn't exit", ring.pending(last));
+    let elapsed = start.elapsed();
+    for child in &mut children {
+        assert!(child.wait().unwrap().success(), "a worker failed");
+    }
+
+    for index in 0..workers {
+        let total = unsafe { ptr::read_volatile(totals(buf).add(index)) };
+        let extra = (rounds + (workers - 1 - index) as u64) / workers as u64;
+        assert_eq!(total, rounds * rounds.saturating_sub(1) / 2 + extra, "worker {} has the wrong total", index);
+    }
+    unsafe {
+        libc::munmap(buf as *mut libc::c_void, SIZE);
+        libc::shm_unlink(c_name.as_ptr());
+    }
+    let messages = 2 * rounds + 1;
+    let each = elapsed / messages as u32;
+    println!("{} messages to {} workers in {:.2?} ({:.2?} each)", messages, workers, elapsed, each);
+}
+
+// Adds up the numbers sent to worker 'index' until it receives EXIT.
+fn worker(name: &str, index: usize) {
+    let buf = map(&CString::new(name).unwrap(), libc::O_RDWR);
+    let ring = unsafe { CommandRing::<SLOTS>::from_ptr(buf) };
+    let total = unsafe { totals(buf).add(index) };
+    loop {
+        let (_, message) = ring.receive(index, TIMEOUT).expect("the ring went quiet");
+        match message[0] {
+            ADD => unsafe { ptr::write_volatile(total, ptr::read_volatile(total) + message[1] as u64) },
+            EXIT => {}
+            signal => panic!("unexpected signal {}", signal),
+        }
+        // Completing a message publishes the total with it.
+        ring.complete(index);
+        if message[0] == EXIT {
+            return;
+        }
+    }
+}
+
+fn totals(buf: *mut u8) -> *mut u64 {
+    unsafe { buf.add(RING_BYTES) as *mut u64 }
+}
+
+fn map(name: &CString, flags: i32) -> *mut u8 {
+    unsafe {
+        let fd = libc::shm_open(name.as_ptr(), flags, 0o600);
+        assert!(fd >= 0, "failed to open {:?}", name);
+        if flags & libc::O_CREAT != 0 {
+            assert_eq!(libc::ftruncate(fd, SIZE as libc::off_t), 0, "failed to size {:?}", name);
+        }
+        let buf = libc::mmap(ptr::null_mut(), SIZE, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0);
+        assert_ne!(buf, libc::MAP_FAILED, "failed to map {:?}", name);
+        libc::close(fd);
+        buf as *mut u8
+    }
+}
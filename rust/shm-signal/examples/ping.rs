@@ -0,0 +1,108 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Creates a shm object holding a signal slot and a counter, starts pong (which must be next to
+// this binary, as cargo builds them) and bounces signals off it:
+//   cargo build --examples && target/debug/examples/ping [rounds] [futex|eventfd|semaphore|signal|poll|uring]
+//
+// Each round ping sends PING and waits for pong to increment the counter and complete it, so
+// the counter also checks that pong's write is visible once the slot is idle again. "uring" uses
+// eventfds, but ping sends and waits through a UringSender (Linux only).
+
+use shm_signal::{Channel, Notifier, SignalSlot};
+use std::{
+    env,
+    ffi::CString,
+    os::unix::process::CommandExt,
+    process::Command,
+    ptr,
+    sync::atomic::{AtomicI32, Ordering},
+    time::Duration,
+    time::Instant,
+};
+
+const PING: u32 = 1;
+const EXIT: u32 = 2;
+const SIZE: usize = 8;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() {
+    let rounds: u32 = env::args().nth(1).map_or(10_000, |arg| arg.parse().expect("rounds must be a number"));
+    let kind = env::args().nth(2).unwrap_or_else(|| "futex".to_string());
+    let notify = if kind == "uring" { "eventfd" } else { &kind };
+    let notifier = Notifier::create(notify).unwrap_or_else(|| panic!("unsupported notifier '{}'", kind));
+    let name = format!("/shm_signal_{}", std::process::id());
+    let c_name = CString::new(name.clone()).unwrap();
+    let buf = unsafe {
+        let fd = libc::shm_open(c_name.as_ptr(), libc::O_RDWR | libc::O_CREAT | libc::O_EXCL, 0o600);
+        assert!(fd >= 0, "failed to create {}", name);
+        assert_eq!(libc::ftruncate(fd, SIZE as libc::off_t), 0, "failed to size {}", name);
+        let buf = libc::mmap(ptr::null_mut(), SIZE, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0);
+        assert_ne!(buf, libc::MAP_FAILED, "failed to map {}", name);
+        libc::close(fd);
+        buf as *mut u8
+    };
+    let pong_pid = AtomicI32::new(0);
+    let signal_slot = unsafe { SignalSlot::from_ptr(buf) };
+    let slot = Channel::new(signal_slot, notifier).with_receiver_pid(&pong_pid);
+    let counter = unsafe { buf.add(4) as *mut u32 };
+
+    let pong = env::current_exe().unwrap().with_file_name("pong");
+    let mut command = Command::new(&pong);
+    command.arg(&name).arg(notifier.to_string());
+    // pong has to be ready for a signal notifier's first wakeup before it has even started.
+    if let Notifier::Signal = notifier {
+        unsafe {
+            command.pre_exec(|| {
+                Notifier::Signal.prepare_receiver();
+                Ok(())
+            });
+        }
+    }
+    let mut child = command.spawn().unwrap_or_else(|e| panic!("failed to start {}: {}", pong.display(), e));
+    pong_pid.store(child.id() as i32, Ordering::Release);
+
+    let mut round_trip: Box<dyn FnMut() -> bool> = match notifier {
+        #[cfg(target_os = "linux")]
+        Notifier::EventFd(fds) if kind == "uring" => {
+            let mut sender = shm_signal::UringSender::new(signal_slot, fds).expect("io_uring isn't available");
+            Box::new(move || {
+                sender.send(PING);
+                sender.wait_idle(TIMEOUT)
+            })
+        }
+        _ => Box::new(|| {
+            slot.send(PING);
+            slot.wait_idle(TIMEOUT)
+        }),
+    };
+    let start = Instant::now();
+    for round in 0..rounds {
+        assert!(round_trip(), "pong didn't answer round {}", round);
+        assert_eq!(unsafe { ptr::read_volatile(counter) }, round + 1);
+    }
+    drop(round_trip);
+    let elapsed = start.elapsed();
+    slot.send(EXIT);
+    assert!(child.wait().unwrap().success(), "pong failed");
+
+    unsafe {
+        libc::munmap(buf as *mut libc::c_void, SIZE);
+        libc::shm_unlink(c_name.as_ptr());
+    }
+    notifier.unlink();
+    println!("{} round trips with {} in {:.2?} ({:.2?} each)", rounds, kind, elapsed, elapsed / rounds.max(1));
+}
@@ -0,0 +1,58 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// The receiving side of ping: attaches to the shm object it's given and answers each PING by
+// incrementing the counter and completing the signal, until it receives EXIT. The second arg is
+// the notifier ping created, whose fds (if any) pong has inherited.
+
+use shm_signal::{Channel, Notifier, SignalSlot};
+use std::{env, ffi::CString, ptr, time::Duration};
+
+const PING: u32 = 1;
+const EXIT: u32 = 2;
+const SIZE: usize = 8;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn main() {
+    let name = env::args().nth(1).expect("usage: pong <shm name> <notifier>");
+    let notifier = env::args().nth(2).and_then(|arg| Notifier::parse(&arg)).expect("missing or bad notifier");
+    // ping has already done this if it started us, but it's harmless to repeat.
+    notifier.prepare_receiver();
+    let c_name = CString::new(name.clone()).unwrap();
+    let buf = unsafe {
+        let fd = libc::shm_open(c_name.as_ptr(), libc::O_RDWR, 0);
+        assert!(fd >= 0, "failed to open {}", name);
+        let buf = libc::mmap(ptr::null_mut(), SIZE, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0);
+        assert_ne!(buf, libc::MAP_FAILED, "failed to map {}", name);
+        libc::close(fd);
+        buf as *mut u8
+    };
+    let slot = Channel::new(unsafe { SignalSlot::from_ptr(buf) }, notifier);
+    let counter = unsafe { buf.add(4) as *mut u32 };
+
+    loop {
+        match slot.wait(TIMEOUT) {
+            Some(PING) => {
+                unsafe { ptr::write_volatile(counter, ptr::read_volatile(counter) + 1) };
+                slot.complete();
+            }
+            // The sender doesn't wait for EXIT to complete.
+            Some(EXIT) => return,
+            Some(signal) => panic!("unexpected signal {}", signal),
+            None => panic!("ping went quiet"),
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Signalling between processes through a word in shared memory.
+//
+// A SignalSlot carries signals from one sender to one receiver. Its state is IDLE (zero) or the
+// non-zero signal currently being handled, and each side only ever makes one transition:
+//
+//            send(signal)
+//   IDLE  ----------------->  signal
+//        <-----------------
+//             complete()
+//
+// The sender may only send when the slot is idle, then waits with wait_idle() to learn that the
+// signal has been handled. The receiver waits with wait() for a signal, handles it, and calls
+// complete(). Every transition is a Release store and every wait an Acquire load, so whatever
+// one side wrote to shared memory before changing the state is visible to the other once it
+// sees the change.
+//
+// On Linux the waits sleep on a futex, which works across processes as long as the slot is in
+// a shared mapping (not a private one). Elsewhere they poll. Either way they first spin for a
+// little while, as set by the WaitStrategy, since the other side often answers within a few
+// microseconds, sooner than a sleep would be woken. A Channel can use another
+// Notifier instead, eventfds, named semaphores, SIGUSR1 or busy polling, while the state word
+// still carries the signal.
+// A Barrier holds processes until all of them have reached the same point, and a SharedMutex
+// lets them take turns writing, even if one dies holding it, with a SharedCondvar to wait for
+// each other's writes. A CommandRing carries messages from any number of senders to up to 32
+// receivers, for driving many processes from one region. A UringSender is an experimental sender
+// for eventfd channels that batches its syscalls through io_uring.
+
+mod mutex;
+mod notify;
+mod ring;
+#[cfg(target_os = "linux")]
+mod uring;
+
+#[cfg(target_os = "linux")]
+pub use notify::EventFds;
+pub use mutex::{Locked, SharedCondvar, SharedMutex, OWNER_DIED};
+pub use notify::{Channel, Notifier, Semaphores, WAKEUP_SIGNAL};
+pub use ring::{CommandRing, MAX_CONSUMERS, MESSAGE_WORDS};
+#[cfg(target_os = "linux")]
+pub use uring::UringSender;
+
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+pub const IDLE: u32 = 0;
+
+// How the waits of every slot, counter and barrier in the process wait: they check the state in
+// a busy loop (with spin_loop, which tells the CPU) for up to 'spin', then sleep. Where there's
+// no futex the sleeps start at 'min_sleep' and double up to 'max_sleep' between checks; on Linux
+// the futex wakes them, so only 'spin' applies. Channels with other notifiers don't spin, as each
+// wakeup they skip would still have to be drained later, but they poll at most 'max_sleep' apart
+// where they poll.
+#[derive(Copy, Clone, Debug)]
+pub struct WaitStrategy {
+    pub spin: Duration,
+    pub min_sleep: Duration,
+    pub max_sleep: Duration,
+}
+
+// The environment variable a process reads its WaitStrategy from, as "SPIN_US[,MIN_US,MAX_US]"
+// in microseconds, e.g. "0" not to spin. A process started by another inherits it, so setting it
+// once configures a whole deployment.
+pub const WAIT_ENV: &str = "SHM_SIGNAL_WAIT";
+
+// Spin checks are this many spin_loop()s apart, so the clock is read less often.
+const SPIN_BATCH: u32 = 64;
+
+// Spinning only helps if the other side can run meanwhile, so by default there's none on a
+// single CPU.
+impl Default for WaitStrategy {
+    fn default() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+        let spin = if cpus > 1 { Duration::from_micros(20) } else { Duration::ZERO };
+        Self { spin, min_sleep: Duration::from_micros(10), max_sleep: Duration::from_millis(1) }
+    }
+}
+
+impl WaitStrategy {
+    // The default, with any of it given in WAIT_ENV.
+    pub fn from_env() -> Self {
+        let mut strategy = Self::default();
+        if let Ok(value) = std::env::var(WAIT_ENV) {
+            let us: Vec<u64> = value
+                .split(',')
+                .map(|us| us.trim().parse().unwrap_or_else(|_| panic!("bad {} '{}'", WAIT_ENV, value)))
+                .collect();
+            match us[..] {
+                [spin] => strategy.spin = Duration::from_micros(spin),
+                [spin, min, max] if min <= max => {
+                    strategy = Self {
+                        spin: Duration::from_micros(spin),
+                        min_sleep: Duration::from_micros(min),
+                        max_sleep: Duration::from_micros(max),
+                    }
+                }
+                _ => panic!("{} takes SPIN_US[,MIN_US,MAX_US], not '{}'", WAIT_ENV, value),
+            }
+        }
+        strategy
+    }
+}
+
+static STRATEGY: OnceLock<WaitStrategy> = OnceLock::new();
+
+// The process's strategy, read from WAIT_ENV by the first wait unless it was set before.
+pub fn wait_strategy() -> WaitStrategy {
+    *STRATEGY.get_or_init(WaitStrategy::from_env)
+}
+
+// Returns false if the strategy was already set, or a wait has already read it.
+pub fn set_wait_strategy(strategy: WaitStrategy) -> bool {
+    STRATEGY.set(strategy).is_ok()
+}
+
+// Calls 'ready' in a busy loop until it returns something, or for up to the strategy's spin.
+fn spin<T>(mut ready: impl FnMut() -> Option<T>) -> Option<T> {
+    let spin = wait_strategy().spin;
+    let start = Instant::now();
+    loop {
+        if let Some(value) = ready() {
+            return Some(value);
+        }
+        if start.elapsed() >= spin {
+            return None;
+        }
+        for _ in 0..SPIN_BATCH {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+#[repr(C)]
+pub struct SignalSlot {
+    state: AtomicU32,
+}
+
+impl SignalSlot {
+    /// The slot at 'ptr'. A zero-filled slot is idle.
+    ///
+    /// # Safety
+    /// 'ptr' must be 4-byte aligned, stay mapped for as long as the slot is used, and only be
+    /// accessed through SignalSlots.
+    pub unsafe fn from_ptr<'a>(ptr: *mut u8) -> &'a SignalSlot {
+        assert_eq!(ptr as usize % std::mem::align_of::<SignalSlot>(), 0, "misaligned signal slot");
+        &*(ptr as *const SignalSlot)
+    }
+
+    pub fn state(&self) -> u32 {
+        self.state.load(Ordering::Acquire)
+    }
+
+    // Returns the slot to idle regardless of its state, e.g. when a previous sender or receiver
+    // exited mid-signal. Neither side may be using the slot at the time.
+    pub fn reset(&self) {
+        self.state.store(IDLE, Ordering::Release);
+        wake(&self.state);
+    }
+
+    // -- Sender --
+
+    pub fn send(&self, signal: u32) {
+        assert_ne!(signal, IDLE, "can't send the idle state");
+        let previous = self.state.swap(signal, Ordering::AcqRel);
+        assert_eq!(previous, IDLE, "sent signal {} while signal {} was being handled", signal, previous);
+        wake(&self.state);
+    }
+
+    // Returns false if the receiver hasn't completed the signal within 'timeout'.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        wait_until(&self.state, |state| state == IDLE, timeout).is_some()
+    }
+
+    // -- Receiver --
+
+    // Returns the signal, or None if none arrived within 'timeout'.
+    pub fn wait(&self, timeout: Duration) -> Option<u32> {
+        wait_until(&self.state, |state| state != IDLE, timeout)
+    }
+
+    pub fn complete(&self) {
+        let previous = self.state.swap(IDLE, Ordering::AcqRel);
+        assert_ne!(previous, IDLE, "completed a signal that wasn't sent");
+        wake(&self.state);
+    }
+}
+
+// A count in shared memory that one side advances and the other waits on, e.g. how many items
+// have been pushed to a queue. It wraps at 2^32. Like a slot, it sleeps on a futex on Linux and
+// polls elsewhere, and its Release store and Acquire loads order the items it counts.
+#[repr(C)]
+pub struct Counter {
+    value: AtomicU32,
+}
+
+impl Counter {
+    pub fn get(&self) -> u32 {
+        self.value.load(Ordering::Acquire)
+    }
+
+    // Only one side may set a given counter.
+    pub fn set(&self, value: u32) {
+        self.value.store(value, Ordering::Release);
+        wake(&self.value);
+    }
+
+    // Returns the count once it's no longer 'current', or None if it hasn't changed within
+    // 'timeout'.
+    pub fn wait_change(&self, current: u32, timeout: Duration) -> Option<u32> {
+        wait_until(&self.value, |value| value != current, timeout)
+    }
+}
+
+// A barrier in shared memory for a fixed number of parties: each generation ends, releasing its
+// waiters, once the last party arrives. A party that arrives needn't wait; it still counts, so
+// nobody may arrive twice in a generation. A zero-filled barrier is ready for the first.
+#[repr(C)]
+pub struct Barrier {
+    arrived: AtomicU32,
+    generation: AtomicU32,
+}
+
+impl Barrier {
+    // Counts the caller as arrived and returns the generation, to wait() for the end of. The
+    // last of 'parties' to arrive ends the generation, so for it wait() returns immediately.
+    pub fn arrive(&self, parties: u32) -> u32 {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.arrived.fetch_add(1, Ordering::AcqRel) + 1 >= parties {
+            self.arrived.store(0, Ordering::Relaxed);
+            self.generation.store(generation.wrapping_add(1), Ordering::Release);
+            wake(&self.generation);
+        }
+        generation
+    }
+
+    // Returns false if 'generation' hasn't ended within 'timeout'.
+    pub fn wait(&self, generation: u32, timeout: Duration) -> bool {
+        wait_until(&self.generation, |current| current != generation, timeout).is_some()
+    }
+
+    // Ends the current generation early, releasing its waiters, e.g. when a party has died. The
+    // parties that had arrived don't count towards the next one. Nobody may be arriving at the
+    // time.
+    pub fn reset(&self) {
+        self.arrived.store(0, Ordering::Relaxed);
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        wake(&self.generation);
+    }
+}
+
+// Waits for 'done' to hold for the state, returning the state it held for.
+fn wait_until(state: &AtomicU32, done: impl Fn(u32) -> bool, timeout: Duration) -> Option<u32> {
+    let start = Instant::now();
+    let check = || Some(state.load(Ordering::Acquire)).filter(|current| done(*current));
+    if let Some(current) = spin(check) {
+        return Some(current);
+    }
+    let mut sleep = wait_strategy().min_sleep;
+    loop {
+        let current = state.load(Ordering::Acquire);
+        if done(current) {
+            return Some(current);
+        }
+        let remaining = timeout.checked_sub(start.elapsed())?;
+        if cfg!(target_os = "linux") {
+            sleep_while(state, current, remaining);
+        } else {
+            sleep_while(state, current, remaining.min(sleep));
+            sleep = (sleep * 2).min(wait_strategy().max_sleep);
+        }
+    }
+}
+
+// Both sides wake on every transition, so there's at most one waiter to wake; waking all of
+// them also covers a slot that is (wrongly) shared by several waiters.
+#[cfg(target_os = "linux")]
+fn wake(state: &AtomicU32) {
+    unsafe { libc::syscall(libc::SYS_futex, state.as_ptr(), libc::FUTEX_WAKE, i32::MAX) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wake(_state: &AtomicU32) {}
+
+// Sleeps until the state may have changed from 'current', or for at most 'timeout'. This can
+// return early (e.g. on EINTR or if the state already changed), so callers check again.
+#[cfg(target_os = "linux")]
+fn sleep_while(state: &AtomicU32, current: u32, timeout: Duration) {
+    let timeout = libc::timespec { tv_sec: timeout.as_secs() as libc::time_t, tv_nsec: timeout.subsec_nanos() as _ };
+    unsafe { libc::syscall(libc::SYS_futex, state.as_ptr(), libc::FUTEX_WAIT, current, &timeout) };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sleep_while(_state: &AtomicU32, _current: u32, timeout: Duration) {
+    std::thread::sleep(timeout.min(wait_strategy().max_sleep));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    const ROUNDS: u32 = 1000;
+
+    // A zero-filled value, as these types are found in a fresh shared mapping.
+    fn zeroed<T>() -> Box<T> {
+        Box::new(unsafe { std::mem::zeroed() })
+    }
+
+    #[test]
+    fn slot_handshake_orders_writes() {
+        let slot = zeroed::<SignalSlot>();
+        let payload = AtomicU32::new(0);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for round in 1..=ROUNDS {
+                    assert_eq!(slot.wait(TIMEOUT), Some(round));
+                    assert_eq!(payload.load(Ordering::Relaxed), round * 2);
+                    payload.store(round * 3, Ordering::Relaxed);
+                    slot.complete();
+                }
+            });
+            for round in 1..=ROUNDS {
+                payload.store(round * 2, Ordering::Relaxed);
+                slot.send(round);
+                assert!(slot.wait_idle(TIMEOUT));
+                assert_eq!(payload.load(Ordering::Relaxed), round * 3);
+            }
+        });
+        assert_eq!(slot.state(), IDLE);
+    }
+
+    #[test]
+    fn slot_waits_time_out() {
+        let slot = zeroed::<SignalSlot>();
+        assert_eq!(slot.wait(Duration::from_millis(10)), None);
+        assert!(slot.wait_idle(Duration::ZERO));
+        slot.send(7);
+        assert!(!slot.wait_idle(Duration::from_millis(10)));
+        assert_eq!(slot.wait(Duration::ZERO), Some(7));
+        slot.reset();
+        assert_eq!(slot.state(), IDLE);
+    }
+
+    #[test]
+    #[should_panic(expected = "while signal 1 was being handled")]
+    fn slot_refuses_a_second_send() {
+        let slot = zeroed::<SignalSlot>();
+        slot.send(1);
+        slot.send(2);
+    }
+
+    #[test]
+    #[should_panic(expected = "completed a signal that wasn't sent")]
+    fn slot_refuses_completing_idle() {
+        zeroed::<SignalSlot>().complete();
+    }
+
+    #[test]
+    fn counter_orders_the_items_it_counts() {
+        let counter = zeroed::<Counter>();
+        let items: Vec<AtomicU32> = (0..ROUNDS).map(|_| AtomicU32::new(0)).collect();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut seen = 0;
+                while seen < ROUNDS {
+                    let count = counter.wait_change(seen, TIMEOUT).expect("counter stalled");
+                    assert!(count > seen && count <= ROUNDS);
+                    for (index, item) in items.iter().enumerate().take(count as usize).skip(seen as usize) {
+                        assert_eq!(item.load(Ordering::Relaxed), index as u32 + 1);
+                    }
+                    seen = count;
+                }
+            });
+            for (index, item) in items.iter().enumerate() {
+                item.store(index as u32 + 1, Ordering::Relaxed);
+                counter.set(index as u32 + 1);
+            }
+        });
+        assert_eq!(counter.wait_change(0, Duration::ZERO), Some(ROUNDS));
+        assert_eq!(counter.wait_change(ROUNDS, Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn barrier_holds_each_generation() {
+        const PARTIES: u32 = 4;
+        let barrier = zeroed::<Barrier>();
+        let arrived = AtomicU32::new(0);
+        thread::scope(|scope| {
+            for _ in 0..PARTIES {
+                scope.spawn(|| {
+                    for round in 1..=100 {
+                        arrived.fetch_add(1, Ordering::Relaxed);
+                        let generation = barrier.arrive(PARTIES);
+                        assert!(barrier.wait(generation, TIMEOUT));
+                        // Everyone has arrived for this round once its generation has ended.
+                        assert!(arrived.load(Ordering::Relaxed) >= round * PARTIES);
+                    }
+                });
+            }
+        });
+        assert_eq!(arrived.load(Ordering::Relaxed), 100 * PARTIES);
+    }
+
+    #[test]
+    fn barrier_reset_releases_waiters() {
+        let barrier = zeroed::<Barrier>();
+        let generation = barrier.arrive(2);
+        assert!(!barrier.wait(generation, Duration::from_millis(10)));
+        barrier.reset();
+        assert!(barrier.wait(generation, Duration::ZERO));
+        // The party that had arrived doesn't count towards the next generation.
+        let next = barrier.arrive(2);
+        assert!(!barrier.wait(next, Duration::from_millis(10)));
+    }
+
+    // The futex waits and wakes only reach another process through a shared mapping. The signals
+    // are sent well after the receiver has stopped spinning, so it's asleep when they arrive, and
+    // it only sees them before its timeout if the wake crossed over.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn slot_wakes_another_process() {
+        const SLEEP: Duration = Duration::from_millis(20);
+        let (len, prot, flags) = (4096, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_ANONYMOUS);
+        let map = unsafe { libc::mmap(std::ptr::null_mut(), len, prot, flags, -1, 0) };
+        assert_ne!(map, libc::MAP_FAILED);
+        let slot = unsafe { SignalSlot::from_ptr(map as *mut u8) };
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed"),
+            0 => {
+                // No panicking into the test harness from the forked copy.
+                let received = (1..=10).all(|round| {
+                    let start = Instant::now();
+                    let signal = slot.wait(TIMEOUT);
+                    if signal.is_some() {
+                        slot.complete();
+                    }
+                    signal == Some(round) && start.elapsed() < TIMEOUT / 2
+                });
+                unsafe { libc::_exit(if received { 0 } else { 1 }) }
+            }
+            pid => {
+                for round in 1..=10 {
+                    thread::sleep(SLEEP);
+                    let start = Instant::now();
+                    slot.send(round);
+                    assert!(slot.wait_idle(TIMEOUT), "signal {} wasn't completed", round);
+                    assert!(start.elapsed() < TIMEOUT / 2);
+                }
+                let mut status = 0;
+                assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "the receiver missed a signal");
+                unsafe { libc::munmap(map, len) };
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// A mutex in shared memory for processes that write to the same region.
+//
+// The lock word holds the owner's id (any non-zero id below OWNER_DIED that the processes agree
+// on, such as their pids) while the mutex is locked, and 0 while it's unlocked. The CONTENDED bit
+// is set once anyone has had to wait, so that unlocking only makes a syscall when it's needed.
+//
+// A process that dies holding the mutex can't unlock it, so whoever notices (e.g. the parent that
+// reaps it) calls recover() with its id. That unlocks it in the OWNER_DIED state, and the next
+// process to lock it is told that the data it guards may have been left half-written.
+
+use crate::{sleep_while, wake};
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+const CONTENDED: u32 = 1 << 31;
+pub const OWNER_DIED: u32 = 1 << 30;
+
+#[repr(C)]
+pub struct SharedMutex {
+    state: AtomicU32,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Locked {
+    Consistent,
+    // The previous owner died holding the mutex, so the data may be inconsistent.
+    OwnerDied,
+}
+
+impl SharedMutex {
+    // Locks the mutex for 'owner', waiting for the current owner to unlock it if need be. Returns
+    // None if it wasn't unlocked within 'timeout'.
+    pub fn lock(&self, owner: u32, timeout: Duration) -> Option<Locked> {
+        assert!(owner != 0 && owner < OWNER_DIED, "invalid mutex owner {}", owner);
+        let start = Instant::now();
+        // Once we've waited others may be waiting too, so keep waking them when we unlock.
+        let mut contended = 0;
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state == 0 || state == OWNER_DIED {
+                let locked = owner | contended;
+                if self.state.compare_exchange(state, locked, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                    return Some(if state == 0 { Locked::Consistent } else { Locked::OwnerDied });
+                }
+                continue;
+            }
+            if state & CONTENDED == 0
+                && self.state.compare_exchange(state, state | CONTENDED, Ordering::Relaxed, Ordering::Relaxed).is_err()
+            {
+                continue;
+            }
+            contended = CONTENDED;
+            sleep_while(&self.state, state | CONTENDED, timeout.checked_sub(start.elapsed())?);
+        }
+    }
+
+    pub fn unlock(&self, owner: u32) {
+        let state = self.state.swap(0, Ordering::Release);
+        assert_eq!(state & !CONTENDED, owner, "mutex unlocked by {} but held by someone else", owner);
+        if state & CONTENDED != 0 {
+            wake(&self.state);
+        }
+    }
+
+    // The id of the owner, if the mutex is locked.
+    pub fn holder(&self) -> Option<u32> {
+        match self.state.load(Ordering::Relaxed) & !CONTENDED {
+            0 | OWNER_DIED => None,
+            owner => Some(owner),
+        }
+    }
+
+    // Unlocks the mutex if it's held by 'owner', which has died, so that the next process to lock
+    // it gets Locked::OwnerDied. Returns false if 'owner' didn't hold it.
+    pub fn recover(&self, owner: u32) -> bool {
+        loop {
+            // Waiters may set CONTENDED meanwhile.
+            let state = self.state.load(Ordering::Relaxed);
+            if state & !CONTENDED != owner {
+                return false;
+            }
+            if self.state.compare_exchange(state, OWNER_DIED, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                wake(&self.state);
+                return true;
+            }
+        }
+    }
+
+    // Unlocks the mutex whoever holds it, e.g. when the processes that used it are all gone.
+    pub fn reset(&self) {
+        self.state.store(0, Ordering::Release);
+        wake(&self.state);
+    }
+}
+
+// A condition variable to go with a SharedMutex, for waiting until another process changes the
+// data the mutex guards. A notification wakes every waiter, and waiters can also wake early, so
+// they check the data again.
+#[repr(C)]
+pub struct SharedCondvar {
+    sequence: AtomicU32,
+}
+
+impl SharedCondvar {
+    // Unlocks 'mutex', which 'owner' holds, and waits for a notification or for 'timeout', then
+    // locks it again, waiting up to 'timeout' more. Returns None if it couldn't relock it.
+    pub fn wait(&self, mutex: &SharedMutex, owner: u32, timeout: Duration) -> Option<Locked> {
+        // Notifiers hold the mutex, so none can notify between this load and the unlock.
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        mutex.unlock(owner);
+        sleep_while(&self.sequence, sequence, timeout);
+        mutex.lock(owner, timeout)
+    }
+
+    // Wakes all of the waiters; the caller must hold the mutex.
+    pub fn notify_all(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+        wake(&self.sequence);
+    }
+}
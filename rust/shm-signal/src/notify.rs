@@ -0,0 +1,387 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Alternative ways for the two sides of a SignalSlot to wake each other.
+//
+// The slot's state word always carries the signal; a Notifier only decides how a side that is
+// waiting for the state to change is woken. Every wait still checks the state after waking, so
+// a spurious or stale wakeup costs a syscall but can't be mistaken for a signal.
+
+use crate::{SignalSlot, IDLE};
+use std::{
+    ffi::CString,
+    sync::atomic::{AtomicI32, AtomicU32, Ordering},
+    time::Duration,
+};
+
+// The signal the sender sends with Notifier::Signal.
+pub const WAKEUP_SIGNAL: i32 = libc::SIGUSR1;
+
+#[derive(Copy, Clone)]
+pub enum Notifier {
+    // Sleep on a futex on the state word itself (or poll, where there are no futexes).
+    Futex,
+    // Block on a pair of eventfds shared by the two processes, e.g. by inheriting them.
+    #[cfg(target_os = "linux")]
+    EventFd(EventFds),
+    // Wait on a pair of POSIX named semaphores, which the other process opens by name.
+    Semaphore(Semaphores),
+    // Send WAKEUP_SIGNAL to the receiver's process, which parks in sigwait with it blocked, for
+    // where neither futexes nor eventfds are usable. The sender needs the receiver's pid (see
+    // Channel::with_receiver_pid) and waits for completions as Futex does.
+    Signal,
+    // Neither side sleeps: each checks the state in a loop, yielding the CPU in between, so a
+    // signal costs no syscalls but each waiter keeps a core busy. Mostly there for comparison.
+    Poll,
+}
+
+impl Notifier {
+    // A new notifier of the given kind ("futex", "eventfd", "semaphore", "signal" or "poll"), if
+    // it's supported here.
+    pub fn create(kind: &str) -> Option<Self> {
+        match kind {
+            "futex" => Some(Notifier::Futex),
+            #[cfg(target_os = "linux")]
+            "eventfd" => Some(Notifier::EventFd(EventFds::create())),
+            "semaphore" => Some(Notifier::Semaphore(Semaphores::create())),
+            "signal" => Some(Notifier::Signal),
+            "poll" => Some(Notifier::Poll),
+            _ => None,
+        }
+    }
+
+    // Called in the receiver's process before the sender can first notify it. With Signal this
+    // blocks WAKEUP_SIGNAL, whose default action would otherwise kill the receiver. The signal
+    // mask is inherited across fork and exec, so a creator that forks the receiver can call this
+    // in the child before it execs; only async-signal-safe calls are made.
+    pub fn prepare_receiver(&self) {
+        if let Notifier::Signal = self {
+            unsafe {
+                let mut set = std::mem::zeroed();
+                libc::sigemptyset(&mut set);
+                libc::sigaddset(&mut set, WAKEUP_SIGNAL);
+                libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            }
+        }
+    }
+
+    // Removes the names of a notifier's semaphores, once nothing else will open them. Called by
+    // the creator; processes that already have them open carry on using them.
+    pub fn unlink(&self) {
+        if let Notifier::Semaphore(sems) = self {
+            sems.unlink();
+        }
+    }
+
+    // The inverse of to_string(), for passing a notifier to a process that has inherited its fds.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (kind, args) = s.split_once(':').unwrap_or((s, ""));
+        match kind {
+            "futex" => Some(Notifier::Futex),
+            #[cfg(target_os = "linux")]
+            "eventfd" => {
+                let (to_receiver, to_sender) = args.split_once(',')?;
+                let fds = EventFds { to_receiver: to_receiver.parse().ok()?, to_sender: to_sender.parse().ok()? };
+                Some(Notifier::EventFd(fds))
+            }
+            "semaphore" => Semaphores::open(args).map(Notifier::Semaphore),
+            "signal" => Some(Notifier::Signal),
+            "poll" => Some(Notifier::Poll),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Notifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Notifier::Futex => write!(f, "futex"),
+            #[cfg(target_os = "linux")]
+            Notifier::EventFd(fds) => write!(f, "eventfd:{},{}", fds.to_receiver, fds.to_sender),
+            Notifier::Semaphore(sems) => write!(f, "semaphore:{}", sems.id),
+            Notifier::Signal => write!(f, "signal"),
+            Notifier::Poll => write!(f, "poll"),
+        }
+    }
+}
+
+// One eventfd per direction: the sender writes 'to_receiver' after sending a signal and the
+// receiver writes 'to_sender' after completing it.
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone)]
+pub struct EventFds {
+    pub to_receiver: i32,
+    pub to_sender: i32,
+}
+
+#[cfg(target_os = "linux")]
+impl EventFds {
+    // The fds aren't close-on-exec, so they're passed on to processes the creator starts.
+    pub fn create() -> Self {
+        let create = || {
+            let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK) };
+            assert!(fd >= 0, "failed to create an eventfd: {}", std::io::Error::last_os_error());
+            fd
+        };
+        Self { to_receiver: create(), to_sender: create() }
+    }
+}
+
+// One semaphore per direction, posted like the eventfds. They're named "/shm_signal_<id>_r" (to
+// the receiver) and "/shm_signal_<id>_s" (to the sender), where the id is the creator's pid and a
+// counter, which keeps the names within macOS's 31-byte limit.
+#[derive(Copy, Clone)]
+pub struct Semaphores {
+    id: SemaphoreId,
+    to_receiver: *mut libc::sem_t,
+    to_sender: *mut libc::sem_t,
+}
+
+#[derive(Copy, Clone)]
+struct SemaphoreId {
+    pid: u32,
+    index: u32,
+}
+
+impl std::fmt::Display for SemaphoreId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}_{}", self.pid, self.index)
+    }
+}
+
+impl SemaphoreId {
+    fn names(&self) -> [CString; 2] {
+        ["r", "s"].map(|end| CString::new(format!("/shm_signal_{}_{}", self, end)).unwrap())
+    }
+}
+
+impl Semaphores {
+    fn create() -> Self {
+        static NEXT_INDEX: AtomicU32 = AtomicU32::new(0);
+        let id = SemaphoreId { pid: std::process::id(), index: NEXT_INDEX.fetch_add(1, Ordering::Relaxed) };
+        // A previous process with the same pid may have left semaphores behind.
+        let [to_receiver, to_sender] = id.names().map(|name| unsafe {
+            libc::sem_unlink(name.as_ptr());
+            let sem = libc::sem_open(name.as_ptr(), libc::O_CREAT | libc::O_EXCL, 0o600 as libc::c_uint, 0);
+            assert_ne!(sem, libc::SEM_FAILED, "failed to create {:?}: {}", name, std::io::Error::last_os_error());
+            sem
+        });
+        Self { id, to_receiver, to_sender }
+    }
+
+    fn open(id: &str) -> Option<Self> {
+        let (pid, index) = id.split_once('_')?;
+        let id = SemaphoreId { pid: pid.parse().ok()?, index: index.parse().ok()? };
+        let [to_receiver, to_sender] = id.names().map(|name| unsafe {
+            let sem = libc::sem_open(name.as_ptr(), 0);
+            assert_ne!(sem, libc::SEM_FAILED, "failed to open {:?}: {}", name, std::io::Error::last_os_error());
+            sem
+        });
+        Some(Self { id, to_receiver, to_sender })
+    }
+
+    fn unlink(&self) {
+        for name in self.id.names() {
+            unsafe { libc::sem_unlink(name.as_ptr()) };
+        }
+    }
+}
+
+// A SignalSlot together with the way its sides wake each other. Both sides must use the same
+// notifier kind; with Notifier::Futex this behaves exactly like the slot's own methods.
+pub struct Channel<'a> {
+    slot: &'a SignalSlot,
+    notifier: Notifier,
+    // With Notifier::Signal, where the sender finds the receiver's pid; 0 while there's none.
+    receiver_pid: Option<&'a AtomicI32>,
+}
+
+impl<'a> Channel<'a> {
+    pub fn new(slot: &'a SignalSlot, notifier: Notifier) -> Self {
+        Self { slot, notifier, receiver_pid: None }
+    }
+
+    // The sender of a Notifier::Signal channel reads the receiver's pid from 'pid' each time it
+    // sends, so the receiver can be replaced. The pid should be somewhere the receiver can't
+    // write, or it could have the sender signal any process.
+    pub fn with_receiver_pid(self, pid: &'a AtomicI32) -> Self {
+        Self { receiver_pid: Some(pid), ..self }
+    }
+
+    pub fn state(&self) -> u32 {
+        self.slot.state()
+    }
+
+    // Nobody waits for the idle state across a reset, so there's no one to notify.
+    pub fn reset(&self) {
+        self.slot.reset();
+    }
+
+    // -- Sender --
+
+    pub fn send(&self, signal: u32) {
+        self.slot.send(signal);
+        match self.notifier {
+            Notifier::Futex | Notifier::Poll => {}
+            #[cfg(target_os = "linux")]
+            Notifier::EventFd(fds) => notify_eventfd(fds.to_receiver),
+            Notifier::Semaphore(sems) => post_semaphore(sems.to_receiver),
+            Notifier::Signal => {
+                let pid = self.receiver_pid.expect("a signal notifier needs the receiver's pid");
+                signal_process(pid.load(Ordering::Acquire));
+            }
+        }
+    }
+
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        match self.notifier {
+            Notifier::Futex | Notifier::Signal => self.slot.wait_idle(timeout),
+            #[cfg(target_os = "linux")]
+            Notifier::EventFd(fds) => {
+                self.wait_with(|state| state == IDLE, timeout, |left| eventfd_wait_for(fds.to_sender, left)).is_some()
+            }
+            Notifier::Semaphore(sems) => {
+                self.wait_with(|state| state == IDLE, timeout, |left| sem_wait_for(sems.to_sender, left)).is_some()
+            }
+            Notifier::Poll => self.wait_with(|state| state == IDLE, timeout, |_| std::thread::yield_now()).is_some(),
+        }
+    }
+
+    // -- Receiver --
+
+    pub fn wait(&self, timeout: Duration) -> Option<u32> {
+        match self.notifier {
+            Notifier::Futex => self.slot.wait(timeout),
+            #[cfg(target_os = "linux")]
+            Notifier::EventFd(fds) => {
+                self.wait_with(|state| state != IDLE, timeout, |left| eventfd_wait_for(fds.to_receiver, left))
+            }
+            Notifier::Semaphore(sems) => {
+                self.wait_with(|state| state != IDLE, timeout, |left| sem_wait_for(sems.to_receiver, left))
+            }
+            Notifier::Signal => self.wait_with(|state| state != IDLE, timeout, signal_wait_for),
+            Notifier::Poll => self.wait_with(|state| state != IDLE, timeout, |_| std::thread::yield_now()),
+        }
+    }
+
+    pub fn complete(&self) {
+        self.slot.complete();
+        match self.notifier {
+            Notifier::Futex | Notifier::Signal | Notifier::Poll => {}
+            #[cfg(target_os = "linux")]
+            Notifier::EventFd(fds) => notify_eventfd(fds.to_sender),
+            Notifier::Semaphore(sems) => post_semaphore(sems.to_sender),
+        }
+    }
+
+    // Checks the state and sleeps with 'sleep' (for at most the time remaining) until 'done'
+    // holds for it. A wakeup can outlive the signal it was for (if the state was seen to change
+    // before sleeping), so the state is checked again after every sleep.
+    fn wait_with(&self, done: impl Fn(u32) -> bool, timeout: Duration, sleep: impl Fn(Duration)) -> Option<u32> {
+        let start = std::time::Instant::now();
+        loop {
+            let current = self.slot.state();
+            if done(current) {
+                return Some(current);
+            }
+            sleep(timeout.checked_sub(start.elapsed())?);
+        }
+    }
+}
+
+// Blocks in the kernel until 'fd' has been written to, then drains its count. The fds are
+// non-blocking so that the wait can time out (poll()) and so a drained count doesn't hang.
+#[cfg(target_os = "linux")]
+fn eventfd_wait_for(fd: i32, timeout: Duration) {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let ms = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+    if unsafe { libc::poll(&mut pollfd, 1, ms) } > 0 {
+        let mut count = 0u64;
+        unsafe { libc::read(fd, &mut count as *mut u64 as *mut libc::c_void, 8) };
+    }
+}
+
+// Waits for a post to 'sem', for at most 'timeout'. sem_timedwait() takes a deadline on the
+// realtime clock.
+#[cfg(target_os = "linux")]
+fn sem_wait_for(sem: *mut libc::sem_t, timeout: Duration) {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) };
+    let nanos = now.tv_nsec as u64 + timeout.subsec_nanos() as u64;
+    let deadline = libc::timespec {
+        tv_sec: now.tv_sec + timeout.as_secs() as libc::time_t + (nanos / 1_000_000_000) as libc::time_t,
+        tv_nsec: (nanos % 1_000_000_000) as _,
+    };
+    unsafe { libc::sem_timedwait(sem, &deadline) };
+}
+
+// macOS has named semaphores but no sem_timedwait(), so this polls them.
+#[cfg(not(target_os = "linux"))]
+fn sem_wait_for(sem: *mut libc::sem_t, timeout: Duration) {
+    if unsafe { libc::sem_trywait(sem) } != 0 {
+        std::thread::sleep(timeout.min(crate::wait_strategy().max_sleep));
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn notify_eventfd(fd: i32) {
+    let one = 1u64;
+    let written = unsafe { libc::write(fd, &one as *const u64 as *const libc::c_void, 8) };
+    assert_eq!(written, 8, "failed to write eventfd {}: {}", fd, std::io::Error::last_os_error());
+}
+
+// A receiver that has exited (or not started) is left for whoever reaps it to notice.
+fn signal_process(pid: i32) {
+    if pid > 0 && unsafe { libc::kill(pid, WAKEUP_SIGNAL) } != 0 {
+        let err = std::io::Error::last_os_error();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH), "failed to signal pid {}: {}", pid, err);
+    }
+}
+
+// Waits for WAKEUP_SIGNAL, which prepare_receiver() has blocked, for at most 'timeout'.
+#[cfg(target_os = "linux")]
+fn signal_wait_for(timeout: Duration) {
+    let timeout = libc::timespec { tv_sec: timeout.as_secs() as libc::time_t, tv_nsec: timeout.subsec_nanos() as _ };
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, WAKEUP_SIGNAL);
+        libc::sigtimedwait(&set, std::ptr::null_mut(), &timeout);
+    }
+}
+
+// macOS has sigwait() but no sigtimedwait(), so this polls for the signal being pending.
+#[cfg(not(target_os = "linux"))]
+fn signal_wait_for(timeout: Duration) {
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, WAKEUP_SIGNAL);
+        let mut pending = std::mem::zeroed();
+        libc::sigpending(&mut pending);
+        if libc::sigismember(&pending, WAKEUP_SIGNAL) == 1 {
+            let mut signal = 0;
+            libc::sigwait(&set, &mut signal);
+        } else {
+            std::thread::sleep(timeout.min(crate::wait_strategy().max_sleep));
+        }
+    }
+}
+
+fn post_semaphore(sem: *mut libc::sem_t) {
+    let posted = unsafe { libc::sem_post(sem) };
+    assert_eq!(posted, 0, "failed to post a semaphore: {}", std::io::Error::last_os_error());
+}
@@ -0,0 +1,313 @@
+//
+// Copyright 2021 The Project Oak Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// A multi-producer, multi-consumer ring of fixed-size messages in shared memory, so that one
+// region can drive up to MAX_CONSUMERS receivers.
+//
+// Each message is addressed to a set of consumers, given as a bitmask. Every consumer reads the
+// whole ring in order, handling the messages with its bit set and skipping the rest, and sets
+// its bit in the slot's completion flags once it has handled one. The last of the addressed
+// consumers to do so frees the slot for the producers, so a slow consumer holds up the ring
+// only for the messages sent to it.
+//
+// Slot i holds positions i, i + SLOTS, i + 2 * SLOTS, ... and its sequence word says which of
+// them and whether it's free or published, as in Vyukov's bounded MPMC queue. The word is
+// stored relative to the slot's index so that a zero-filled ring is empty, and each consumer's
+// position is in the ring too, so a consumer that restarts carries on where it left off.
+
+use crate::{sleep_while, wake};
+use std::{
+    sync::atomic::{fence, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+pub const MESSAGE_WORDS: usize = 10;
+pub const MAX_CONSUMERS: usize = 32;
+
+#[repr(C)]
+pub struct CommandRing<const SLOTS: usize> {
+    // The next position a producer will claim, wrapping at 2^32.
+    head: AtomicU32,
+    // The next position each consumer will look at.
+    cursors: [AtomicU32; MAX_CONSUMERS],
+    slots: [RingSlot; SLOTS],
+}
+
+#[repr(C)]
+struct RingSlot {
+    sequence: AtomicU32,
+    targets: AtomicU32,
+    done: AtomicU32,
+    message: [AtomicU32; MESSAGE_WORDS],
+}
+
+impl<const SLOTS: usize> CommandRing<SLOTS> {
+    /// The ring at 'ptr'. A zero-filled ring is empty. SLOTS must be a power of two (so that
+    /// positions stay in step with the slots when they wrap) and at least 2.
+    ///
+    /// # Safety
+    /// 'ptr' must be 4-byte aligned, point to size_of::<CommandRing<SLOTS>>() bytes that stay
+    /// mapped for as long as the ring is used, and only be accessed through CommandRings.
+    pub unsafe fn from_ptr<'a>(ptr: *mut u8) -> &'a Self {
+        assert!(SLOTS >= 2 && SLOTS.is_power_of_two(), "a ring needs a power of two slots, not {}", SLOTS);
+        assert_eq!(ptr as usize % std::mem::align_of::<Self>(), 0, "misaligned command ring");
+        &*(ptr as *const Self)
+    }
+
+    // Empties the ring, e.g. when a previous producer or consumer exited mid-message. Nobody may
+    // be using the ring at the time.
+    pub fn reset(&self) {
+        self.head.store(0, Ordering::Relaxed);
+        for cursor in &self.cursors {
+            cursor.store(0, Ordering::Relaxed);
+        }
+        for slot in &self.slots {
+            slot.sequence.store(0, Ordering::Release);
+            wake(&slot.sequence);
+        }
+    }
+
+    // -- Producers --
+
+    // Pushes 'message' for the consumers in 'targets', waiting for a free slot if the ring is
+    // full. Returns the message's position, or None if no slot came free within 'timeout'.
+    pub fn push(&self, targets: u32, message: &[u32; MESSAGE_WORDS], timeout: Duration) -> Option<u32> {
+        assert_ne!(targets, 0, "a message must be sent to at least one consumer");
+        let start = Instant::now();
+        loop {
+            let position = self.head.load(Ordering::Relaxed);
+            let (slot, sequence, lag) = self.slot(position);
+            if lag == 0 {
+                let next = position.wrapping_add(1);
+                if self.head.compare_exchange_weak(position, next, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+                    for (word, value) in slot.message.iter().zip(message) {
+                        word.store(*value, Ordering::Relaxed);
+                    }
+                    slot.done.store(0, Ordering::Relaxed);
+                    slot.targets.store(targets, Ordering::Relaxed);
+                    slot.sequence.store(Self::stored(position, 1), Ordering::Release);
+                    wake(&slot.sequence);
+                    return Some(position);
+                }
+            } else if lag < 0 {
+                // The slot still holds the message from SLOTS positions ago.
+                sleep_while(&slot.sequence, sequence, timeout.checked_sub(start.elapsed())?);
+            }
+            // Otherwise another producer claimed the position first.
+        }
+    }
+
+    // The consumers in the message's targets that haven't completed it yet; 0 once they all
+    // have.
+    pub fn pending(&self, position: u32) -> u32 {
+        let (slot, sequence, lag) = self.slot(position);
+        assert!(lag >= 1, "position {} hasn't been pushed", position);
+        let pending = slot.targets.load(Ordering::Relaxed) & !slot.done.load(Ordering::Acquire);
+        // A slot that has moved on was completed by all its targets.
+        fence(Ordering::Acquire);
+        if lag == 1 && slot.sequence.load(Ordering::Relaxed) == sequence {
+            pending
+        } else {
+            0
+        }
+    }
+
+    // Returns false if some of the message's targets haven't completed it within 'timeout'.
+    pub fn wait_done(&self, position: u32, timeout: Duration) -> bool {
+        let start = Instant::now();
+        loop {
+            let done = self.slot(position).0.done.load(Ordering::Acquire);
+            if self.pending(position) == 0 {
+                return true;
+            }
+            let left = match timeout.checked_sub(start.elapsed()) {
+                Some(left) => left,
+                None => return false,
+            };
+            sleep_while(&self.slot(position).0.done, done, left);
+        }
+    }
+
+    // -- Consumers --
+
+    // Waits for the next message for 'consumer', returning its position and contents, or None
+    // if none arrived within 'timeout'. The same message is returned until it's completed, even to
+    // a consumer that has restarted.
+    pub fn receive(&self, consumer: usize, timeout: Duration) -> Option<(u32, [u32; MESSAGE_WORDS])> {
+        let bit = Self::bit(consumer);
+        let cursor = &self.cursors[consumer];
+        let start = Instant::now();
+        loop {
+            let position = cursor.load(Ordering::Relaxed);
+            let (slot, sequence, lag) = self.slot(position);
+            if lag == 1 {
+                let targets = slot.targets.load(Ordering::Relaxed);
+                let mut message = [0; MESSAGE_WORDS];
+                for (value, word) in message.iter_mut().zip(&slot.message) {
+                    *value = word.load(Ordering::Relaxed);
+                }
+                // If the slot was freed and reused meanwhile, the message wasn't for us (or we
+                // would have had to complete it first) and the reads may be from the next one.
+                fence(Ordering::Acquire);
+                if slot.sequence.load(Ordering::Relaxed) == sequence {
+                    if targets & bit != 0 {
+                        return Some((position, message));
+                    }
+                    cursor.store(position.wrapping_add(1), Ordering::Relaxed);
+                }
+            } else if lag > 1 {
+                // Completed by its targets and freed, so not for us.
+                cursor.store(position.wrapping_add(1), Ordering::Relaxed);
+            } else {
+                sleep_while(&slot.sequence, sequence, timeout.checked_sub(start.elapsed())?);
+            }
+        }
+    }
+
+    // Marks the last message received by 'consumer' as handled.
+    pub fn complete(&self, consumer: usize) {
+        let bit = Self::bit(consumer);
+        let position = self.cursors[consumer].load(Ordering::Relaxed);
+        let (slot, _, lag) = self.slot(position);
+        assert_eq!(lag, 1, "completed a message that isn't in the ring");
+        let targets = slot.targets.load(Ordering::Relaxed);
+        let done = slot.done.fetch_or(bit, Ordering::AcqRel) | bit;
+        if done == targets {
+            slot.sequence.store(Self::stored(position, SLOTS as u32), Ordering::Release);
+            wake(&slot.sequence);
+        }
+        wake(&slot.done);
+        self.cursors[consumer].store(position.wrapping_add(1), Ordering::Relaxed);
+    }
+
+    // The slot for 'position', its sequence word, and how far the slot has got with the
+    // position: 0 if it's free for it, 1 if it holds its message, negative if it still holds an
+    // earlier one, and more than 1 if the message has been completed.
+    fn slot(&self, position: u32) -> (&RingSlot, u32, i32) {
+        let index = position as usize % SLOTS;
+        let slot = &self.slots[index];
+        let sequence = slot.sequence.load(Ordering::Acquire);
+        (slot, sequence, sequence.wrapping_add(index as u32).wrapping_sub(position) as i32)
+    }
+
+    // The sequence word for 'position' plus 'lag'.
+    fn stored(position: u32, lag: u32) -> u32 {
+        position.wrapping_add(lag).wrapping_sub((position as usize % SLOTS) as u32)
+    }
+
+    fn bit(consumer: usize) -> u32 {
+        assert!(consumer < MAX_CONSUMERS, "consumer {} is out of range", consumer);
+        1 << consumer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn zeroed<const SLOTS: usize>() -> Box<CommandRing<SLOTS>> {
+        Box::new(unsafe { std::mem::zeroed() })
+    }
+
+    fn message(first: u32) -> [u32; MESSAGE_WORDS] {
+        let mut message = [0; MESSAGE_WORDS];
+        for (offset, word) in message.iter_mut().enumerate() {
+            *word = first + offset as u32;
+        }
+        message
+    }
+
+    #[test]
+    fn message_is_freed_by_its_last_target() {
+        let ring = zeroed::<4>();
+        let position = ring.push(0b11, &message(1), TIMEOUT).unwrap();
+        assert_eq!(ring.pending(position), 0b11);
+        assert_eq!(ring.receive(0, TIMEOUT), Some((position, message(1))));
+        // Until it's completed, a (restarted) consumer gets the same message again.
+        assert_eq!(ring.receive(0, TIMEOUT), Some((position, message(1))));
+        ring.complete(0);
+        assert_eq!(ring.pending(position), 0b10);
+        assert!(!ring.wait_done(position, Duration::from_millis(10)));
+        assert_eq!(ring.receive(1, TIMEOUT), Some((position, message(1))));
+        ring.complete(1);
+        assert_eq!(ring.pending(position), 0);
+        assert!(ring.wait_done(position, Duration::ZERO));
+        assert_eq!(ring.receive(0, Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn consumers_skip_messages_for_others() {
+        let ring = zeroed::<4>();
+        let first = ring.push(0b10, &message(1), TIMEOUT).unwrap();
+        let second = ring.push(0b01, &message(2), TIMEOUT).unwrap();
+        assert_eq!(ring.receive(0, TIMEOUT), Some((second, message(2))));
+        ring.complete(0);
+        assert_eq!(ring.receive(1, TIMEOUT), Some((first, message(1))));
+        ring.complete(1);
+        assert_eq!(ring.receive(1, Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn full_ring_waits_for_a_slot() {
+        let ring = zeroed::<2>();
+        let first = ring.push(1, &message(1), TIMEOUT).unwrap();
+        ring.push(1, &message(2), TIMEOUT).unwrap();
+        assert_eq!(ring.push(1, &message(3), Duration::from_millis(10)), None);
+        ring.receive(0, TIMEOUT).unwrap();
+        ring.complete(0);
+        assert!(ring.wait_done(first, Duration::ZERO));
+        assert!(ring.push(1, &message(3), Duration::ZERO).is_some());
+    }
+
+    #[test]
+    fn concurrent_producers_and_consumers() {
+        const PRODUCERS: u32 = 3;
+        const CONSUMERS: usize = 3;
+        const MESSAGES: u32 = 500;
+        let ring = zeroed::<4>();
+        thread::scope(|scope| {
+            for producer in 0..PRODUCERS {
+                let ring = &ring;
+                scope.spawn(move || {
+                    for sent in 0..MESSAGES {
+                        // Every consumer is sent the first word; the rest vary the targets.
+                        let targets = if sent == 0 { (1 << CONSUMERS) - 1 } else { 1 << (sent % CONSUMERS as u32) };
+                        ring.push(targets, &message(producer << 16 | sent), TIMEOUT).expect("ring stalled");
+                    }
+                });
+            }
+            for consumer in 0..CONSUMERS {
+                let ring = &ring;
+                scope.spawn(move || {
+                    let mut next = [0; PRODUCERS as usize];
+                    let mine = (0..MESSAGES).filter(|&sent| sent == 0 || sent % CONSUMERS as u32 == consumer as u32);
+                    for _ in 0..PRODUCERS as usize * mine.count() {
+                        let (_, received) = ring.receive(consumer, TIMEOUT).expect("consumer stalled");
+                        assert_eq!(received, message(received[0]), "torn message");
+                        let (producer, sent) = ((received[0] >> 16) as usize, received[0] & 0xffff);
+                        // Each producer's messages arrive in the order it sent them.
+                        assert!(sent >= next[producer], "message {} from {} out of order", sent, producer);
+                        next[producer] = sent + 1;
+                        ring.complete(consumer);
+                    }
+                });
+            }
+        });
+    }
+}